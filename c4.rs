@@ -1,12 +1,53 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
+use std::fmt;
 use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 type Int=i64;
 
+mod vm;
+pub use vm::{
+  CancellationToken, FaultSite, HeapLeak, OutputBuffer, Profile, ResourceUsage, Retired, RunAsync, RunState,
+  RuntimeError, SandboxPolicy, Snapshot, StackFrame, TraceCheckpoint, Vm, WatchHit,
+};
+
+// Bytes in one VM word (int and pointer size)
+const WORD_SIZE: usize = std::mem::size_of::<Int>();
+// Default size of the VM stack in bytes, and the range --stack-size accepts
+const STACK_SIZE: usize = 256 * 1024;
+const MIN_STACK_SIZE: usize = 64 * WORD_SIZE;
+const MAX_STACK_SIZE: usize = 1 << 30;
+// VM address where the stack region starts (the data segment starts at 0)
+const STACK_BASE: Int = 0x1000_0000;
+// Most bytes malloc can hand out, and the VM address where the heap starts
+const HEAP_SIZE: usize = 16 * 1024 * 1024;
+const HEAP_BASE: Int = 0x0200_0000;
+// Largest value the rand syscall returns
+const RAND_MAX: Int = 0x7fff_ffff;
+// Virtual clock of deterministic mode: time() starts at 2000-01-01 00:00:00 UTC and advances
+// one second per million instructions
+const VIRTUAL_EPOCH: Int = 946_684_800;
+const VIRTUAL_CYCLES_PER_SECOND: Int = 1_000_000;
+// Granularity of the randomized region bases chosen by --aslr
+const PAGE_SIZE: Int = 4096;
+
+// splitmix64: small, seedable generator so a printed seed reproduces a layout exactly
+fn splitmix64(state: &mut u64) -> u64 {
+  *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  let mut z = *state;
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  z ^ (z >> 31)
+}
+
 //Token types 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
-enum TokenType {
+pub enum TokenType {
   Num=128,
   Fun,
   Sys,
@@ -21,7 +62,31 @@ enum TokenType {
   Return,
   Sizeof,
   While,
+  Unsigned,
+  Short,
+  Long,
+  Const,
+  Static,
+  Extern,
+  Switch,
+  Case,
+  Default,
+  Break,
+  VaArg,
+  VaCount,
+  Void,
+  Ellipsis,
   Assign,
+  AddAssign,
+  SubAssign,
+  MulAssign,
+  DivAssign,
+  ModAssign,
+  AndAssign,
+  OrAssign,
+  XorAssign,
+  ShlAssign,
+  ShrAssign,
   Cond,
   Lor,
   Lan,
@@ -46,231 +111,1936 @@ enum TokenType {
   Brak,
 }
 
-//VM instruction opcodes 
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[allow(dead_code)]
-enum OpCode {
-  LEA, IMN, JMP, JSR, BZMBNZ,ENT, ADJ, LEV, LI, LC, SI, SC, PSH,OR, XOR, AND, EQ, NE, LT, GT, LE, GE,
-  SHL, SHR, ADD, SUB, MUL, DIV, MOD, OPEN, READ, CLOS, PRTF, MALC, FREE, MSET, MCMP, EXIT, FUN
+impl TokenType {
+  // Every token type in discriminant order, starting at Num = 128
+  const ALL: [TokenType; 61] = [
+    TokenType::Num, TokenType::Fun, TokenType::Sys, TokenType::Glo, TokenType::Loc, TokenType::Id,
+    TokenType::Char, TokenType::Else, TokenType::Enum, TokenType::If, TokenType::Int,
+    TokenType::Return, TokenType::Sizeof, TokenType::While, TokenType::Unsigned, TokenType::Short,
+    TokenType::Long, TokenType::Const, TokenType::Static, TokenType::Extern, TokenType::Switch,
+    TokenType::Case, TokenType::Default, TokenType::Break, TokenType::VaArg, TokenType::VaCount,
+    TokenType::Void, TokenType::Ellipsis, TokenType::Assign, TokenType::AddAssign,
+    TokenType::SubAssign, TokenType::MulAssign, TokenType::DivAssign, TokenType::ModAssign,
+    TokenType::AndAssign, TokenType::OrAssign, TokenType::XorAssign, TokenType::ShlAssign,
+    TokenType::ShrAssign, TokenType::Cond, TokenType::Lor, TokenType::Lan, TokenType::Or,
+    TokenType::Xor, TokenType::And, TokenType::Eq, TokenType::Ne, TokenType::Lt, TokenType::Gt,
+    TokenType::Le, TokenType::Ge, TokenType::Shl, TokenType::Shr, TokenType::Add, TokenType::Sub,
+    TokenType::Mul, TokenType::Div, TokenType::Mod, TokenType::Inc, TokenType::Dec, TokenType::Brak,
+  ];
+
+  pub fn from_int(value: i32) -> Option<TokenType> {
+    let index = usize::try_from(value.checked_sub(TokenType::Num as i32)?).ok()?;
+    TokenType::ALL.get(index).copied()
+  }
 }
 
-//Types
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[allow(dead_code)]
-enum Type {
-  CHAR = 0,
-  INT = 1,
-  PTR = 2,
+// Index of a symbol table entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(pub usize);
+
+// A string literal already placed in the data segment (without its NUL terminator)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRef {
+  pub addr: Int,
+  pub len: usize,
 }
 
-#[derive(Debug, Clone)]
-struct Symbol {
-  token: i32,              // Token type
-  hash: i32,               // Hash value
-  name: String,            // Symbol name
-  class: i32,              // Storage class (Glo, Loc, etc)
-  type_: i32,              // Data type
-  value: Int,              // Value
-  // Fields for local symbol handling
-  #[allow(dead_code)]
-  h_class: i32,
-  #[allow(dead_code)]
-  h_type: i32,
-  #[allow(dead_code)]
-  h_val: Int,
+// What a token is, with the value it carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+  Num(Int),           // Integer or character literal
+  Str(DataRef),       // String literal, adjacent literals already joined
+  Ident(SymbolId),    // Identifier
+  Keyword(TokenType), // Reserved word, e.g. TokenType::While
+  Op(TokenType),      // Operator that has a TokenType, from `...` and `=` to `[`
+  Punct(char),        // Any other single character: ; , ( ) { } ] : ~ !
+  Eof,
 }
 
-#[allow(dead_code)]
-struct C4 {
-  p: usize,
-  lp: usize,
-  source: String,
-  e: Vec<Int>,
-  le: usize,
-  symbols: Vec<Symbol>,
-  token: i32,
-  token_val: Int,
-  #[allow(dead_code)]
-  type_: i32,
-  loc: Int,
-  line: i32,
-  src: bool,
-  debug: bool,
-  data: Vec<u8>,
-  data_index: usize,
-  id: usize,
-  cycle: i32,
+// One token of the source, as tools see it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+  pub kind: TokenKind,
+  pub span: Span,
 }
 
-//Implementation of the compiler
+//VM instruction opcodes 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[allow(dead_code)]
-impl C4 {
-  fn new() -> Self {
-    C4 {
-      p: 0,
-      lp: 0,
-      source: String::new(),
-      e: vec![0; 256*1024],
-      le: 0,
-      symbols: Vec::new(),
-      token: 0,
-      token_val: 0,
-      type_: 0,
-      loc: 0,
-      line: 1,
-      src: false,
-      debug: false,
-      data: vec![0; 256*1024],
-      data_index: 0,
-      id: 0,
-      cycle: 0,
-    }
+pub enum OpCode {
+  LEA, IMM, JMP, JSR, BZ, BNZ, JMPI, ENT, ADJ, LEV, LI, LC, SI, SC, LS, SS, PSH, OR, XOR, AND, EQ, NE, LT, GT, LE, GE,
+  SHL, SHR, ADD, SUB, MUL, DIV, MOD, ULT, UGT, ULE, UGE, USHR, UDIV, UMOD, OPEN, READ, CLOS, PRTF, MALC, FREE, MSET, MCMP,
+  ISDG, ISAL, ISSP, TOUP, TOLO, ATEX, EXIT, FUN, NOP, LCU, LSU,
+  // Appended after LSU so older code words, traces and snapshots keep their numbering
+  PUTC, GETC, PUTS, WRIT, FPRT, SPRF, SNPR, SCNF, GETS, SLEN, SCPY, SNCP, SCMP, SCAT, MCPY, ABS, RAND, SRND,
+  TIME, ATOI, GENV
+}
+
+impl OpCode {
+  // Every opcode in discriminant order, used to decode instruction words
+  pub const ALL: [OpCode; 80] = [
+    OpCode::LEA, OpCode::IMM, OpCode::JMP, OpCode::JSR, OpCode::BZ, OpCode::BNZ, OpCode::JMPI, OpCode::ENT,
+    OpCode::ADJ, OpCode::LEV, OpCode::LI, OpCode::LC, OpCode::SI, OpCode::SC, OpCode::LS, OpCode::SS,
+    OpCode::PSH, OpCode::OR, OpCode::XOR,
+    OpCode::AND, OpCode::EQ, OpCode::NE, OpCode::LT, OpCode::GT, OpCode::LE, OpCode::GE, OpCode::SHL,
+    OpCode::SHR, OpCode::ADD, OpCode::SUB, OpCode::MUL, OpCode::DIV, OpCode::MOD, OpCode::ULT, OpCode::UGT,
+    OpCode::ULE, OpCode::UGE, OpCode::USHR, OpCode::UDIV, OpCode::UMOD, OpCode::OPEN, OpCode::READ,
+    OpCode::CLOS, OpCode::PRTF, OpCode::MALC, OpCode::FREE, OpCode::MSET, OpCode::MCMP, OpCode::ISDG,
+    OpCode::ISAL, OpCode::ISSP, OpCode::TOUP, OpCode::TOLO, OpCode::ATEX, OpCode::EXIT, OpCode::FUN,
+    OpCode::NOP, OpCode::LCU, OpCode::LSU, OpCode::PUTC, OpCode::GETC, OpCode::PUTS, OpCode::WRIT,
+    OpCode::FPRT, OpCode::SPRF, OpCode::SNPR, OpCode::SCNF, OpCode::GETS, OpCode::SLEN, OpCode::SCPY,
+    OpCode::SNCP, OpCode::SCMP, OpCode::SCAT, OpCode::MCPY, OpCode::ABS, OpCode::RAND, OpCode::SRND,
+    OpCode::TIME, OpCode::ATOI, OpCode::GENV,
+  ];
+
+  // Whether the instruction is followed by an operand word
+  fn has_operand(self) -> bool {
+    matches!(self, OpCode::LEA | OpCode::IMM | OpCode::JMP | OpCode::JSR | OpCode::BZ | OpCode::BNZ |
+      OpCode::JMPI | OpCode::ENT | OpCode::ADJ)
   }
 
-  //Get current character
-  fn current_char(&self) -> char{
-    if self.p < self.source.len(){
-      self.source.chars().nth(self.p).unwrap_or('\0')
+  // Whether this opcode is a system call rather than a VM instruction
+  fn is_syscall(self) -> bool {
+    (self as Int >= OpCode::OPEN as Int && self as Int <= OpCode::EXIT as Int) || self as Int >= OpCode::PUTC as Int
+  }
+
+  // Whether this is an ALU instruction combining the pushed value with ax
+  fn is_binary(self) -> bool {
+    self as Int >= OpCode::OR as Int && self as Int <= OpCode::UMOD as Int
+  }
+
+  // Decode an instruction word
+  pub fn from_int(value: Int) -> Option<OpCode> {
+    if value >= 0 && (value as usize) < OpCode::ALL.len() {
+      Some(OpCode::ALL[value as usize])
     } else {
-      '\0'
+      None
     }
   }
 
-  //Advance to next character
-  #[allow(dead_code)]
-  fn next_char(&mut self) -> char{
-    self.p +=1;
-    self.current_char()
+  // Look up an opcode by its listing mnemonic
+  fn from_name(name: &str) -> Option<OpCode> {
+    OpCode::ALL.iter().copied().find(|op| format!("{:?}", op) == name)
   }
+}
 
-  //Symbol table with keywords and system calls
-  fn init_symbol_table(&mut self){
-    //Add keywords
-    let keywords = [
-      ("char", TokenType::Char as i32),
-      ("else", TokenType::Else as i32),
-      ("enum", TokenType::Enum as i32),
-      ("if", TokenType::If as i32),
-      ("int", TokenType::Int as i32),
-      ("return", TokenType::Return as i32),
-      ("sizeof", TokenType::Sizeof as i32),
-      ("while", TokenType::While as i32),
-    ];
+// Assemble listing text back into code words, as if loaded at code address 1
+fn assemble(text: &str) -> Result<Vec<Int>, String> {
+  assemble_at(text, 1).map(|(words, _)| words)
+}
 
-    for (word, token) in keywords {
-      self.add_keyword(word, token);
-    }
+// Whether `name` can be an assembler label (an identifier, so `12: source` lines never are)
+fn is_label(name: &str) -> bool {
+  name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-    //Add system calls
-    let syscalls = [
-      ("open", OpCode::OPEN as i32),
-      ("read", OpCode::READ as i32),
-      ("close", OpCode::CLOS as i32),
-      ("printf", OpCode::PRTF as i32),
-      ("malloc", OpCode::MALC as i32),
-      ("free", OpCode::FREE as i32),
-      ("memset", OpCode::MSET as i32),
-      ("memcmp", OpCode::MCMP as i32),
-      ("exit", OpCode::EXIT as i32),
-    ];
+// Assemble text in the listing syntax into code words that will be loaded at code address
+// `base`. Lines are `[label:] MNEMONIC [operand]` where an operand is a number or a label;
+// `;` starts a comment, and the `N: source` lines -s interleaves are skipped. Also returns
+// the address of every label.
+fn assemble_at(text: &str, base: usize) -> Result<(Vec<Int>, HashMap<String, usize>), String> {
+  // First pass: split lines into instructions and find where each label lands
+  let mut labels = HashMap::new();
+  let mut lines = Vec::new();
+  let mut size = 0;
+  for (n, line) in text.lines().enumerate() {
+    let mut line = line.split(';').next().unwrap_or("").trim();
+    let is_source = line.split_once(':').is_some_and(|(num, _)| !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()));
+    if is_source {
+      continue;
+    }
+    if let Some((label, rest)) = line.split_once(':') {
+      if !is_label(label.trim()) {
+        return Err(format!("line {}: bad label '{}'", n + 1, label.trim()));
+      }
+      if labels.insert(label.trim().to_string(), base + size).is_some() {
+        return Err(format!("line {}: duplicate label '{}'", n + 1, label.trim()));
+      }
+      line = rest.trim();
+    }
+    if line.is_empty() {
+      continue;
+    }
+    let mut parts = line.split_whitespace();
+    let name = parts.next().unwrap_or("");
+    let op = match OpCode::from_name(name) {
+      Some(op) => op,
+      None => return Err(format!("line {}: unknown instruction '{}'", n + 1, name)),
+    };
+    let operand = parts.next();
+    match (op.has_operand(), operand, parts.next()) {
+      (_, _, Some(extra)) => return Err(format!("line {}: unexpected '{}'", n + 1, extra)),
+      (true, None, _) => return Err(format!("line {}: {} needs an operand", n + 1, name)),
+      (false, Some(extra), _) => return Err(format!("line {}: unexpected operand '{}'", n + 1, extra)),
+      _ => {},
+    }
+    size += if op.has_operand() { 2 } else { 1 };
+    lines.push((n, op, operand));
+  }
 
-    for (name, code) in syscalls {
-      self.add_syscall(name, code);
+  // Second pass: emit, resolving label operands
+  let mut words = Vec::with_capacity(size);
+  for (n, op, operand) in lines {
+    words.push(op as Int);
+    if let Some(operand) = operand {
+      let value = match operand.parse::<Int>() {
+        Ok(value) => value,
+        Err(_) => match labels.get(operand) {
+          Some(&addr) => addr as Int,
+          None => return Err(format!("line {}: bad operand '{}'", n + 1, operand)),
+        },
+      };
+      words.push(value);
     }
+  }
+  Ok((words, labels))
+}
+
+// Case labels collected while compiling the body of a switch statement
+#[derive(Debug, Clone, Default)]
+struct SwitchCases {
+  cases: Vec<(Int, usize)>,     // (case value, code address of the label)
+  default: Option<usize>,       // code address of `default:`
+}
+
+// Switches with at least this many cases are candidates for a jump table
+const JUMP_TABLE_MIN_CASES: usize = 4;
+
+// Settings fixed when a C4 is created
+#[derive(Debug, Clone, PartialEq)]
+pub struct C4Options {
+  pub stack_size: usize,   // VM stack size in bytes
+  // Variables getenv sees; None passes the host environment through
+  pub environment: Option<HashMap<String, String>>,
+  // Seed for deterministic mode (see C4::set_deterministic), None for normal runs
+  pub deterministic: Option<u64>,
+  // Limits on host files and variables for untrusted programs, None for no limits
+  pub sandbox: Option<SandboxPolicy>,
+}
 
-    self.add_keyword("void", TokenType::Char as i32);
+impl Default for C4Options {
+  fn default() -> Self {
+    C4Options { stack_size: STACK_SIZE, environment: None, deterministic: None, sandbox: None }
   }
+}
 
-  fn add_keyword(&mut self, name: &str, token: i32){
-    let mut hash: i32 = 0;
+// Parse a stack size as given to --stack-size: bytes, optionally with a k or m suffix, or a
+// number of stack slots (words) with a w suffix
+pub fn parse_stack_size(text: &str) -> Result<usize, String> {
+  let (digits, unit) = match text.char_indices().last() {
+    Some((i, 'k')) | Some((i, 'K')) => (&text[..i], 1024),
+    Some((i, 'm')) | Some((i, 'M')) => (&text[..i], 1024 * 1024),
+    Some((i, 'w')) => (&text[..i], WORD_SIZE),
+    _ => (text, 1),
+  };
+  digits.parse::<usize>().ok().and_then(|n| n.checked_mul(unit)).ok_or(format!("bad stack size '{}'", text))
+}
 
-    for c in name.chars() {
-      hash = hash.wrapping_mul(147).wrapping_add(c as i32);
-    }
-    hash = (hash << 6).wrapping_add(name.len() as i32);
+// Hash the lexer gives an identifier
+fn symbol_hash(name: &str) -> i32 {
+  let mut hash: i32 = 0;
+  for c in name.chars() {
+    hash = hash.wrapping_mul(147).wrapping_add(c as i32);
+  }
+  (hash << 6).wrapping_add(name.len() as i32)
+}
 
-    self.symbols.push(Symbol {
-      token,
-      hash,
-      name: name.to_string(),
-      class: 0,
-      type_: 0,
-      value: 0,
-      h_class: 0,
-      h_type: 0,
-      h_val: 0,
-    });
+// What a name in the symbol table stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolClass {
+  Keyword,   // Reserved word; the symbol's value is its token type
+  Syscall,   // Builtin the VM implements; the value is its opcode
+  Global,    // Variable in the data segment; the value is its address
+  Local,     // Parameter or local variable; the value is its frame slot
+  Function,  // Compiled function; the value is its entry point
+  EnumConst, // Enumerator; the value is the constant itself
+}
+
+// What hosts, the REPL and the debugger can see of a named symbol
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolInfo {
+  pub name: String,
+  pub class: SymbolClass,
+  pub type_: i32,              // Data type
+  pub address: Option<Int>,    // Data address of a global
+  pub value: Int,              // Current contents of a global, otherwise the symbol's value
+}
+
+// A compiled function, as listed by `Program::functions`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionInfo {
+  pub name: String,
+  pub entry: usize,            // Code address of the first instruction
+  pub size: usize,             // Code words up to the next function (or the end of the code)
+  pub params: Vec<String>,
+  pub variadic: bool,          // Declared with a trailing `...`
+}
+
+// A global variable, as listed by `Program::globals`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalInfo {
+  pub name: String,
+  pub address: Int,
+  pub type_: i32,
+  pub size: Int,
+}
+
+// A string literal in the data segment, as listed by `Program::strings`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StringInfo {
+  pub address: Int,
+  pub value: String,
+}
+
+// Snapshot of what a compiled program contains, for tools that would otherwise parse dumps
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+  functions: Vec<FunctionInfo>,
+  globals: Vec<GlobalInfo>,
+  strings: Vec<StringInfo>,
+}
+
+impl Program {
+  // Defined functions, in code order
+  pub fn functions(&self) -> &[FunctionInfo] {
+    &self.functions
   }
 
-  fn add_syscall(&mut self, name: &str, code: i32) {
-    let mut hash: i32 = 0;
+  // Defined globals, in data segment order
+  pub fn globals(&self) -> &[GlobalInfo] {
+    &self.globals
+  }
 
-    for c in name.chars() {
-      hash = hash.wrapping_mul(147).wrapping_add(c as i32);
-    }
-    hash = (hash << 6).wrapping_add(name.len() as i32);
-    
-    self.symbols.push(Symbol {
-      token: TokenType::Id as i32,
-      hash,
-      name: name.to_string(),
-      class: TokenType::Sys as i32,
-      type_: Type::INT as i32,
-      value: code as Int,
-      h_class: 0,
-      h_type: 0,
-      h_val: 0,
-    });
+  // String literals, in data segment order
+  pub fn strings(&self) -> &[StringInfo] {
+    &self.strings
   }
 
-  fn find_symbol(&self, hash: i32, name: &str) -> Option<usize> {
-    for (i, sym) in self.symbols.iter().enumerate() {
-      if sym.hash == hash && sym.name == name {
-        return Some(i);
-      }
+  pub fn function(&self, name: &str) -> Option<&FunctionInfo> {
+    self.functions.iter().find(|func| func.name == name)
+  }
+}
+
+// A stretch of one source file, in character positions [start, end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+  pub file_id: usize,
+  pub start: usize,
+  pub end: usize,
+}
+
+// A file registered with a SourceMap
+#[derive(Debug, Clone)]
+struct SourceFile {
+  name: String,
+  text: Vec<char>,
+}
+
+// The source files of a compilation; turns spans into file:line:column and code snippets
+// so every part of the compiler reports locations the same way
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+  files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+  pub fn new() -> Self {
+    SourceMap { files: Vec::new() }
+  }
+
+  // Register a file and return its id
+  pub fn add_file(&mut self, name: &str, text: &str) -> usize {
+    self.files.push(SourceFile { name: name.to_string(), text: text.chars().collect() });
+    self.files.len() - 1
+  }
+
+  pub fn file_name(&self, file_id: usize) -> Option<&str> {
+    self.files.get(file_id).map(|file| file.name.as_str())
+  }
+
+  // 1-based line and column of character position `pos` in a file
+  pub fn line_col(&self, file_id: usize, pos: usize) -> Option<(usize, usize)> {
+    let file = self.files.get(file_id)?;
+    let pos = pos.min(file.text.len());
+    let before = &file.text[..pos];
+    let line = before.iter().filter(|&&c| c == '\n').count() + 1;
+    let col = pos - before.iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1) + 1;
+    Some((line, col))
+  }
+
+  // Character position of column `col` (counted from 0, and clamped to the line) of 1-based
+  // `line` in a file, if the file has that line
+  pub fn position(&self, file_id: usize, line: usize, col: usize) -> Option<usize> {
+    let file = self.files.get(file_id)?;
+    let start = match line {
+      0 => return None,
+      1 => 0,
+      _ => file.text.iter().enumerate().filter(|&(_, &c)| c == '\n').nth(line - 2)?.0 + 1,
+    };
+    let end = file.text[start..].iter().position(|&c| c == '\n').map_or(file.text.len(), |i| start + i);
+    Some((start + col).min(end))
+  }
+
+  // `file:line:col` for the start of a span
+  pub fn location(&self, span: Span) -> Option<String> {
+    let (line, col) = self.line_col(span.file_id, span.start)?;
+    Some(format!("{}:{}:{}", self.file_name(span.file_id)?, line, col))
+  }
+
+  // The line a span starts on with the span underlined, e.g.
+  //  --> main.c:3:9
+  //   |
+  // 3 | int x = 12abc;
+  //   |         ^^^^^
+  pub fn snippet(&self, span: Span) -> Option<String> {
+    let file = self.files.get(span.file_id)?;
+    let (line, col) = self.line_col(span.file_id, span.start)?;
+    let line_start = span.start.min(file.text.len()) + 1 - col;
+    let line_end = file.text[line_start..].iter().position(|&c| c == '\n').map_or(file.text.len(), |i| line_start + i);
+    let text: String = file.text[line_start..line_end].iter().collect();
+    let width = span.end.min(line_end).saturating_sub(span.start).max(1);
+    let gutter = " ".repeat(line.to_string().len());
+    Some(format!("{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+      gutter, file.name, line, col, gutter, line, text.trim_end_matches('\r'), gutter, " ".repeat(col - 1), "^".repeat(width)))
+  }
+}
+
+// Where a token came from before preprocessing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+  pub file: String,
+  pub line: i32,
+  // Macro whose expansion produced the token, with the file and line it was defined at
+  pub expanded_from: Option<(String, String, i32)>,
+}
+
+impl fmt::Display for Origin {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.file, self.line)?;
+    if let Some((name, file, line)) = &self.expanded_from {
+      write!(f, " (expanded from macro {} at {}:{})", name, file, line)?;
     }
-    None
+    Ok(())
   }
+}
 
-  //Next token lexer function
-  fn next(&mut self) {
-    self.token = 0;
+// Start of a stretch of preprocessed source copied from `file`, beginning at `line`
+#[derive(Debug, Clone)]
+struct LineMark {
+  offset: usize,
+  file: String,
+  line: i32,
+}
 
-    while self.p < self.source.len() {
-      let ch = self.current_char();
+// Where compiled code came from before preprocessing: (index into C4::origin_files, line)
+type LineOrigin = (usize, i32);
 
-      if ch == '\n' {
-        self.line += 1;
-        if self.src {
-          // Print source line and assembly
-          let line_end = self.source[self.lp..self.p].find('\n')
-            .map_or(self.p, |pos| self.lp + pos + 1);
-          print!("{}: {}", self.line - 1, &self.source[self.lp..line_end]);
+// Stretch of preprocessed source [start, end) produced by expanding a macro
+#[derive(Debug, Clone)]
+struct Expansion {
+  start: usize,
+  end: usize,
+  name: String,
+  file: String,
+  line: i32,
+}
+
+// Nesting limit for #include, so a header that includes itself fails instead of recursing forever
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+// Preprocessed source: the text the lexer sees, and where each stretch of it came from
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessed {
+  pub text: String,
+  // Characters in `text` (positions count characters, like the lexer's)
+  len: usize,
+  marks: Vec<LineMark>,
+  expansions: Vec<Expansion>,
+  // Diagnostics that did not stop preprocessing, as file:line: message
+  pub warnings: Vec<String>,
+  // Each file read, with its text as written, in the order first read
+  files: Vec<(String, String)>,
+}
+
+impl Preprocessed {
+  fn push_str(&mut self, s: &str) {
+    self.text.push_str(s);
+    self.len += s.chars().count();
+  }
+
+  fn mark(&mut self, file: &str, line: i32) {
+    self.marks.push(LineMark { offset: self.len, file: file.to_string(), line });
+  }
+}
+
+// A macro defined with #define
+#[derive(Debug, Clone)]
+struct Macro {
+  params: Option<Vec<String>>, // Parameter names of a function-like macro
+  body: String,            // Replacement text, whitespace runs collapsed
+  file: String,            // Where it was defined
+  line: i32,
+}
+
+// The stage between reading a source file and lexing it: splices `#include`d files into the
+// text and expands macros. Directive lines become blank lines, so lines keep their numbers
+// within each file.
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessor {
+  // Directories searched for `#include <...>`, and for `#include "..."` after the directory
+  // of the including file, in the order they were added
+  include_paths: Vec<PathBuf>,
+  // Macros defined so far, by name; they stay defined across runs
+  macros: HashMap<String, Macro>,
+  // File and line being preprocessed, for __FILE__ and __LINE__
+  file: String,
+  line: i32,
+  // Value of __DATE__ (with its quotes), fixed when a run starts
+  date: String,
+  // Seconds since the epoch __DATE__ is taken from instead of the current time, if set
+  clock: Option<Int>,
+  // Files that said #pragma once, never included again
+  once: HashSet<PathBuf>,
+  // Guard macro of each file wrapped in `#ifndef X #define X ... #endif`; once X is defined
+  // the file is not even read again
+  guards: HashMap<PathBuf, String>,
+}
+
+impl Preprocessor {
+  pub fn new() -> Self {
+    Preprocessor::default()
+  }
+
+  // Add a directory to search for included files (-I)
+  pub fn add_include_path(&mut self, dir: &str) {
+    self.include_paths.push(PathBuf::from(dir));
+  }
+
+  // Predefine a macro as `#define name value` would (-D name=value); `name` may carry a
+  // parameter list, as in `MAX(a,b)`
+  pub fn define_macro(&mut self, name: &str, value: &str) -> Result<(), String> {
+    self.define(&format!("{} {}", name, value), "<command line>", 1, &mut Preprocessed::default())
+  }
+
+  // Take __DATE__ from `secs` since the epoch instead of the current time (None to go back)
+  pub fn set_clock(&mut self, secs: Option<Int>) {
+    self.clock = secs;
+  }
+
+  // Preprocess `text`, the contents of the file `name`
+  pub fn run(&mut self, name: &str, text: &str) -> Result<Preprocessed, String> {
+    let mut out = Preprocessed::default();
+    let secs = self.clock.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as Int));
+    self.date = format_date(secs.div_euclid(86400));
+    self.process_file(name, text, 0, &mut out)?;
+    Ok(out)
+  }
+
+  fn process_file(&mut self, name: &str, text: &str, depth: usize, out: &mut Preprocessed) -> Result<(), String> {
+    out.mark(name, 1);
+    if !out.files.iter().any(|(file, _)| file == name) {
+      out.files.push((name.to_string(), text.to_string()));
+    }
+    if let Some(guard) = include_guard(text) {
+      self.guards.insert(canonical_path(name), guard);
+    }
+    // Whether a /* comment runs on from an earlier line
+    let mut in_comment = false;
+    // Open #ifdef/#ifndef blocks, innermost last: whether the current branch is kept, whether
+    // the condition held, whether #else was seen, and the line of the #if
+    let mut conditions: Vec<(bool, bool, bool, i32)> = Vec::new();
+    // A macro call whose argument list runs on past its line: the lines so far, joined by
+    // newlines, the line it started on, and how many lines it spans
+    let mut call: Option<(String, i32, usize)> = None;
+    for (line_no, line, joined) in splice_lines(text) {
+      // Spliced lines still end in a newline each, so later lines keep their numbers
+      let newlines = "\n".repeat(joined);
+      let line = line.as_str();
+      let active = conditions.last().is_none_or(|c| c.0);
+      let directive = match line.trim_start().strip_prefix('#').filter(|_| !in_comment && call.is_none()) {
+        Some(directive) => directive.trim_start(),
+        None if !active => {
+          out.push_str(&newlines);
+          continue;
         }
-        self.lp = self.p +1;
-        self.p += 1;
-        continue;
-      } else if ch.is_whitespace() {
-        self.p += 1;
-        continue;
+        None => {
+          let (text, first, lines) = match call.take() {
+            Some((text, first, lines)) => (format!("{}\n{}", text, line), first, lines + joined),
+            None => (line.to_string(), line_no, joined),
+          };
+          if self.unclosed_call(&text, in_comment) {
+            call = Some((text, first, lines));
+            continue;
+          }
+          self.expand_lines(&text, lines, name, first, &mut in_comment, out)?;
+          continue;
+        }
+      };
+      let (keyword, rest) = split_identifier(directive);
+      let rest = strip_comments(rest);
+      // Whether the region around the innermost #ifdef block is kept
+      let outer = conditions.len() < 2 || conditions[..conditions.len() - 1].iter().all(|c| c.0);
+      if keyword == "ifdef" || keyword == "ifndef" {
+        let (macro_name, _) = split_identifier(rest.trim_start());
+        if macro_name.is_empty() {
+          return Err(format!("{}:{}: macro name missing in #{}", name, line_no, keyword));
+        }
+        let defined = self.macros.contains_key(macro_name) || self.builtin_macro(macro_name).is_some();
+        let holds = defined == (keyword == "ifdef");
+        conditions.push((active && holds, holds, false, line_no));
+      } else if keyword == "if" || keyword == "elif" {
+        // An #elif could select a branch even when the one before it is skipped, so it is an
+        // error whenever the block it belongs to is live
+        if (keyword == "if" && active) || (keyword == "elif" && outer) {
+          return Err(format!("{}:{}: #{} is not supported, only #ifdef and #ifndef", name, line_no, keyword));
+        }
+        if keyword == "if" {
+          conditions.push((false, true, false, line_no));
+        }
+      } else if keyword == "else" {
+        match conditions.last_mut() {
+          Some(c) if !c.2 => *c = (outer && !c.1, c.1, true, c.3),
+          Some(_) => return Err(format!("{}:{}: #else after #else", name, line_no)),
+          None => return Err(format!("{}:{}: #else without #ifdef", name, line_no)),
+        }
+      } else if keyword == "endif" {
+        if conditions.pop().is_none() {
+          return Err(format!("{}:{}: #endif without #ifdef", name, line_no));
+        }
+      } else if !active {
+        // Directives in a skipped branch are ignored
+      } else if keyword == "include" {
+        let path = self.resolve_include(rest.trim(), name).map_err(|e| format!("{}:{}: {}", name, line_no, e))?;
+        let key = canonical_path(&path.to_string_lossy());
+        let guarded = self.guards.get(&key).is_some_and(|guard| self.macros.contains_key(guard));
+        if !self.once.contains(&key) && !guarded {
+          if depth >= MAX_INCLUDE_DEPTH {
+            return Err(format!("{}:{}: #include nested too deeply", name, line_no));
+          }
+          let header = fs::read_to_string(&path)
+            .map_err(|e| format!("{}:{}: cannot read {}: {}", name, line_no, path.display(), e))?;
+          self.process_file(&path.to_string_lossy(), &header, depth + 1, out)?;
+          out.mark(name, line_no);
+        }
+      } else if keyword == "pragma" && rest.trim() == "once" {
+        self.once.insert(canonical_path(name));
+      } else if keyword == "define" {
+        self.define(&rest, name, line_no, out)?;
+      } else if keyword == "undef" {
+        let (macro_name, _) = split_identifier(rest.trim_start());
+        if macro_name.is_empty() {
+          return Err(format!("{}:{}: macro name missing in #undef", name, line_no));
+        }
+        self.macros.remove(macro_name);
+      } else if keyword == "error" {
+        return Err(format!("{}:{}: #error {}", name, line_no, rest.trim()).trim_end().to_string());
+      } else if keyword == "warning" {
+        out.warnings.push(format!("{}:{}: #warning {}", name, line_no, rest.trim()).trim_end().to_string());
       }
-      break;
+      // Other directives are dropped; the lexer would skip them anyway
+      out.push_str(&newlines);
     }
-    
-    if self.p < self.source.len(){
-      println!("Next token starts with character: '{}' at position {}", self.current_char(), self.p);
-    } else {
-      println!("Reached end of source");
-      return;
+    // Expanding what is left reports the argument list that never closed
+    if let Some((text, first, lines)) = call {
+      self.expand_lines(&text, lines, name, first, &mut in_comment, out)?;
+    }
+    if let Some(c) = conditions.last() {
+      return Err(format!("{}:{}: unterminated conditional directive", name, c.3));
     }
+    Ok(())
+  }
 
-    let ch = self.current_char();
+  // Expand `text`, the source lines of file:line onwards, and pad the output to `lines` newlines;
+  // a macro call spread over several lines expands onto the first of them
+  fn expand_lines(&mut self, text: &str, lines: usize, file: &str, line: i32, in_comment: &mut bool, out: &mut Preprocessed) -> Result<(), String> {
+    self.file = file.to_string();
+    self.line = line;
+    let start = out.text.len();
+    self.expand(text, in_comment, &mut Vec::new(), out).map_err(|e| format!("{}:{}: {}", file, line, e))?;
+    let kept = out.text[start..].matches('\n').count();
+    out.push_str(&"\n".repeat(lines - kept));
+    Ok(())
+  }
 
-    //Parse identifiers
-    if ch.is_alphabetic() || ch=='_'{
-      let start = self.p;
-      let mut hash: i32 = ch as i32;
-      self.p +=1;
+  // Whether `text` ends inside the argument list of a function-like macro call
+  fn unclosed_call(&self, text: &str, mut in_comment: bool) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+      let c = chars[i];
+      if in_comment {
+        while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+          i += 1;
+        }
+        i += 2;
+        in_comment = false;
+      } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+        i = (i..chars.len()).find(|&j| chars[j] == '\n').unwrap_or(chars.len());
+      } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+        i += 2;
+        in_comment = true;
+      } else if c == '"' || c == '\'' {
+        i = skip_literal(&chars, i);
+      } else if c.is_ascii_alphabetic() || c == '_' {
+        let start = i;
+        while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+          i += 1;
+        }
+        let word: String = chars[start..i].iter().collect();
+        if self.macros.get(&word).is_some_and(|m| m.params.is_some()) {
+          if let Some(open) = (i..chars.len()).find(|&j| !chars[j].is_whitespace()).filter(|&j| chars[j] == '(') {
+            match collect_arguments(&chars, open) {
+              Some((_, end)) => i = end,
+              None => return true,
+            }
+          }
+        }
+      } else if c.is_ascii_digit() {
+        while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+          i += 1;
+        }
+      } else {
+        i += 1;
+      }
+    }
+    false
+  }
+
+  // Handle `#define NAME body` read from file:line; redefining a macro differently warns
+  fn define(&mut self, text: &str, file: &str, line: i32, out: &mut Preprocessed) -> Result<(), String> {
+    let (name, mut body) = split_identifier(text.trim_start());
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+      return Err(format!("{}:{}: macro name missing in #define", file, line));
+    }
+    // A ( right after the name starts a parameter list; after a space it is part of the body
+    let mut params = None;
+    if let Some(list) = body.strip_prefix('(') {
+      let (list, rest) = list.split_once(')').ok_or_else(|| format!("{}:{}: missing ) in parameters of macro '{}'", file, line, name))?;
+      let names: Vec<String> = list.split(',').map(|param| param.trim().to_string()).filter(|param| !param.is_empty()).collect();
+      for (i, param) in names.iter().enumerate() {
+        if !split_identifier(param).1.is_empty() || param.starts_with(|c: char| c.is_ascii_digit()) || names[..i].contains(param) {
+          return Err(format!("{}:{}: bad parameter '{}' of macro '{}'", file, line, param, name));
+        }
+      }
+      params = Some(names);
+      body = rest;
+    }
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    if let Some(old) = self.macros.get(name) {
+      if old.body != body || old.params != params {
+        out.warnings.push(format!("{}:{}: '{}' redefined (previous definition at {}:{})", file, line, name, old.file, old.line));
+      }
+    }
+    self.macros.insert(name.to_string(), Macro { params, body, file: file.to_string(), line });
+    Ok(())
+  }
+
+  // Copy `text` to the output with macro names replaced by their expansions, rescanned for
+  // further macros. Macros in `hidden` are being expanded and stay as they are, so a macro
+  // that mentions itself does not expand forever. Comments and literals are copied verbatim.
+  fn expand(&self, text: &str, in_comment: &mut bool, hidden: &mut Vec<String>, out: &mut Preprocessed) -> Result<(), String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+      let start = i;
+      let c = chars[i];
+      if *in_comment {
+        while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+          i += 1;
+        }
+        if i < chars.len() {
+          i += 2;
+          *in_comment = false;
+        }
+      } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+        i = (i..chars.len()).find(|&j| chars[j] == '\n').unwrap_or(chars.len());
+      } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+        i += 2;
+        *in_comment = true;
+      } else if c == '"' || c == '\'' {
+        i = skip_literal(&chars, i);
+      } else if c.is_ascii_alphanumeric() || c == '_' {
+        while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+          i += 1;
+        }
+        let word: String = chars[start..i].iter().collect();
+        if let Some(value) = self.builtin_macro(&word) {
+          out.push_str(&value);
+          continue;
+        }
+        if let Some(m) = self.macros.get(&word).filter(|_| !c.is_ascii_digit() && !hidden.contains(&word)) {
+          // A function-like macro name not followed by ( is left alone
+          let body = match &m.params {
+            None => m.body.clone(),
+            Some(params) => {
+              let open = match (i..chars.len()).find(|&j| !chars[j].is_whitespace()) {
+                Some(j) if chars[j] == '(' => j,
+                _ => {
+                  out.push_str(&word);
+                  continue;
+                }
+              };
+              let (args, end) = collect_arguments(&chars, open)
+                .ok_or_else(|| format!("unterminated argument list invoking macro '{}'", word))?;
+              i = end;
+              self.substitute(&word, params, &m.body, &args, hidden)?
+            }
+          };
+          let index = out.expansions.len();
+          out.expansions.push(Expansion { start: out.len, end: out.len, name: word.clone(), file: m.file.clone(), line: m.line });
+          hidden.push(word);
+          self.expand(&body, &mut false, hidden, out)?;
+          hidden.pop();
+          out.expansions[index].end = out.len;
+          continue;
+        }
+      } else {
+        i += 1;
+      }
+      out.push_str(&chars[start..i].iter().collect::<String>());
+    }
+    Ok(())
+  }
+
+  // Expansion of a predefined macro: __FILE__ and __LINE__ give where it is used (after
+  // includes, the line within the including file again), __DATE__ when preprocessing began.
+  // A #define of the same name takes precedence.
+  fn builtin_macro(&self, name: &str) -> Option<String> {
+    if self.macros.contains_key(name) {
+      return None;
+    }
+    match name {
+      "__FILE__" => Some(format!("\"{}\"", self.file.replace('\\', "\\\\").replace('"', "\\\""))),
+      "__LINE__" => Some(self.line.to_string()),
+      "__DATE__" => Some(self.date.clone()),
+      _ => None,
+    }
+  }
+
+  // The body of function-like macro `name` with each parameter replaced by its argument,
+  // macro-expanded first as C does
+  fn substitute(&self, name: &str, params: &[String], body: &str, args: &[String], hidden: &mut Vec<String>) -> Result<String, String> {
+    let no_args = args.len() == 1 && args[0].trim().is_empty();
+    if args.len() != params.len() && !(params.is_empty() && no_args) {
+      return Err(format!("macro '{}' expects {} arguments, got {}", name, params.len(), if no_args { 0 } else { args.len() }));
+    }
+    let mut expanded = Vec::new();
+    for arg in args {
+      let mut out = Preprocessed::default();
+      // An argument may span lines; its expansion stays on the line of the call
+      self.expand(&arg.trim().replace('\n', " "), &mut false, hidden, &mut out)?;
+      expanded.push(out.text);
+    }
+    let chars: Vec<char> = body.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+      let start = i;
+      if chars[i] == '"' || chars[i] == '\'' {
+        i = skip_literal(&chars, i);
+      } else if chars[i].is_ascii_alphanumeric() || chars[i] == '_' {
+        while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+          i += 1;
+        }
+        let word: String = chars[start..i].iter().collect();
+        if let Some(p) = params.iter().position(|param| *param == word) {
+          result.push_str(&expanded[p]);
+          continue;
+        }
+      } else {
+        i += 1;
+      }
+      result.extend(&chars[start..i]);
+    }
+    Ok(result)
+  }
+
+  // Find the file named by the operand of an #include in `including`: "file" is looked up
+  // next to the including file first, <file> only in the include paths
+  fn resolve_include(&self, operand: &str, including: &str) -> Result<PathBuf, String> {
+    let (file, quoted) = if let Some(file) = operand.strip_prefix('"').and_then(|rest| rest.split_once('"')) {
+      (file.0, true)
+    } else if let Some(file) = operand.strip_prefix('<').and_then(|rest| rest.split_once('>')) {
+      (file.0, false)
+    } else {
+      return Err("#include expects \"file\" or <file>".to_string());
+    };
+    let local = quoted.then(|| Path::new(including).parent().unwrap_or(Path::new("")).to_path_buf());
+    local.iter().chain(&self.include_paths)
+      .map(|dir| dir.join(file))
+      .find(|path| path.is_file())
+      .ok_or_else(|| format!("include file '{}' not found", file))
+  }
+}
+
+// `"Mmm dd yyyy"` (day padded with a space), the __DATE__ format, for `days` since 1970-01-01
+pub(crate) fn format_date(days: i64) -> String {
+  const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+  // Civil date from a day count, counting in 400-year eras that start on March 1st
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z - era * 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + (month <= 2) as i64;
+  format!("\"{} {:>2} {}\"", MONTHS[month as usize - 1], day, year)
+}
+
+// The logical lines of `text`: a backslash at the end of a line joins the next line to it.
+// Each comes with the number of its first line and the number of lines it spans.
+pub(crate) fn splice_lines(text: &str) -> Vec<(i32, String, usize)> {
+  let mut lines = Vec::new();
+  let mut pending: Option<(i32, String, usize)> = None;
+  for (i, line) in text.lines().enumerate() {
+    let (line_no, mut logical, joined) = pending.take().unwrap_or((i as i32 + 1, String::new(), 0));
+    match line.strip_suffix('\\') {
+      Some(start) => {
+        logical.push_str(start);
+        pending = Some((line_no, logical, joined + 1));
+      },
+      None => {
+        logical.push_str(line);
+        lines.push((line_no, logical, joined + 1));
+      }
+    }
+  }
+  // A backslash on the last line has nothing to join
+  lines.extend(pending);
+  lines
+}
+
+// A file's identity for #pragma once and include guards, however the path spelled it
+fn canonical_path(path: &str) -> PathBuf {
+  fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+// The macro X if everything in `text` but comments and blank lines sits inside an
+// `#ifndef X` / `#define X` ... `#endif` block, the classic include guard
+pub(crate) fn include_guard(text: &str) -> Option<String> {
+  let lines: Vec<&str> = text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with("//")).collect();
+  fn directive(line: &str) -> Option<(&str, &str)> {
+    line.strip_prefix('#').map(|d| split_identifier(d.trim_start()))
+  }
+  let (keyword, rest) = directive(lines.first()?)?;
+  let guard = split_identifier(rest.trim_start()).0;
+  if keyword != "ifndef" || guard.is_empty() {
+    return None;
+  }
+  let (keyword, rest) = directive(lines.get(1)?)?;
+  if keyword != "define" || split_identifier(rest.trim_start()).0 != guard {
+    return None;
+  }
+  // The #endif closing the #ifndef must be the last line
+  let mut depth = 0;
+  for (i, line) in lines.iter().enumerate() {
+    match directive(line).map(|(keyword, _)| keyword) {
+      Some("if" | "ifdef" | "ifndef") => depth += 1,
+      Some("endif") => {
+        depth -= 1;
+        if depth == 0 {
+          return (i == lines.len() - 1).then(|| guard.to_string());
+        }
+      },
+      _ => {}
+    }
+  }
+  None
+}
+
+// Split `text` into a leading identifier (possibly empty) and the rest
+fn split_identifier(text: &str) -> (&str, &str) {
+  let len = text.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(text.len());
+  text.split_at(len)
+}
+
+// Index just past the string or character literal starting at `chars[start]`
+fn skip_literal(chars: &[char], start: usize) -> usize {
+  let quote = chars[start];
+  let mut i = start + 1;
+  while i < chars.len() && chars[i] != quote {
+    i += if chars[i] == '\\' { 2 } else { 1 };
+  }
+  (i + 1).min(chars.len())
+}
+
+// Arguments of a macro invocation whose ( is at `chars[open]`, split at top-level commas, and
+// the index just past the closing ); None if the ) is missing. Comments become a space.
+fn collect_arguments(chars: &[char], open: usize) -> Option<(Vec<String>, usize)> {
+  let mut args = Vec::new();
+  let mut current = String::new();
+  let mut depth = 0;
+  let mut i = open + 1;
+  while i < chars.len() {
+    let c = chars[i];
+    if c == '"' || c == '\'' {
+      let end = skip_literal(chars, i);
+      current.extend(&chars[i..end]);
+      i = end;
+      continue;
+    }
+    if c == '/' && matches!(chars.get(i + 1), Some('/' | '*')) {
+      let end = if chars[i + 1] == '/' {
+        (i..chars.len()).find(|&j| chars[j] == '\n').unwrap_or(chars.len())
+      } else {
+        (i + 2..chars.len()).find(|&j| chars[j] == '*' && chars.get(j + 1) == Some(&'/')).map_or(chars.len(), |j| j + 2)
+      };
+      current.push(' ');
+      i = end;
+      continue;
+    }
+    if c == ')' && depth == 0 {
+      args.push(current);
+      return Some((args, i + 1));
+    }
+    if c == ',' && depth == 0 {
+      args.push(std::mem::take(&mut current));
+    } else {
+      if c == '(' {
+        depth += 1;
+      } else if c == ')' {
+        depth -= 1;
+      }
+      current.push(c);
+    }
+    i += 1;
+  }
+  None
+}
+
+// `text` with its comments replaced by a space (string and character literals kept intact)
+fn strip_comments(text: &str) -> String {
+  let chars: Vec<char> = text.chars().collect();
+  let mut result = String::new();
+  let mut i = 0;
+  while i < chars.len() {
+    if chars[i] == '/' && chars.get(i + 1) == Some(&'/') {
+      break;
+    } else if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+      i += 2;
+      while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+        i += 1;
+      }
+      i += 2;
+      result.push(' ');
+    } else if chars[i] == '"' || chars[i] == '\'' {
+      let end = skip_literal(&chars, i);
+      result.extend(&chars[i..end]);
+      i = end;
+    } else {
+      result.push(chars[i]);
+      i += 1;
+    }
+  }
+  result
+}
+
+// Optimization passes in pipeline order, each with the lowest -O level that runs it
+const PASSES: [(&str, u8); 5] = [("fold", 1), ("peephole", 1), ("dce", 1), ("inline", 2), ("layout", 2)];
+const MAX_OPT_LEVEL: u8 = 2;
+
+//Types
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum Type {
+  CHAR = 0,
+  INT = 1,
+  PTR = 2,
+}
+
+// Qualifier bit carried in a type code for unsigned integer types
+const UNSIGNED: i32 = 1 << 16;
+// Width bits for `short` (16-bit) and `long` (64-bit) integers; plain int is already 64-bit
+const SHORT: i32 = 1 << 17;
+const LONG: i32 = 1 << 18;
+// Qualifier bit for `const`; on a pointer type it applies to the pointed-to object
+const CONST: i32 = 1 << 19;
+// Marks `void`, which is char-based so that `void *` points at bytes; a plain void has no value
+const VOID: i32 = 1 << 20;
+// Mask that strips qualifier bits, leaving the base type and pointer depth
+const TYPE_MASK: i32 = UNSIGNED - 1;
+
+// Base type and pointer depth of a type code
+fn type_base(t: i32) -> i32 {
+  t & TYPE_MASK
+}
+
+// Whether a type code is an unsigned integer (pointers are never "unsigned" here)
+fn is_unsigned(t: i32) -> bool {
+  t & UNSIGNED != 0 && type_base(t) < Type::PTR as i32
+}
+
+// Whether `t` is void itself (not a pointer to void)
+fn is_void(t: i32) -> bool {
+  t & VOID != 0 && type_base(t) < Type::PTR as i32
+}
+
+// Whether `t` is `void *`, which converts to and from any other object pointer
+fn is_void_pointer(t: i32) -> bool {
+  t & VOID != 0 && type_base(t) == Type::CHAR as i32 + Type::PTR as i32
+}
+
+// Whether pointer types `a` and `b` may be compared: they point at the same type (const
+// aside), or one of them is `void *`
+fn pointers_compatible(a: i32, b: i32) -> bool {
+  a & !CONST == b & !CONST || is_void_pointer(a) || is_void_pointer(b)
+}
+
+// Whether an object of type `t` is read-only (`const int`, or `*p` for `const char *p`)
+fn is_const(t: i32) -> bool {
+  t & CONST != 0 && type_base(t) < Type::PTR as i32
+}
+
+// Type of `c ? a : b` given the types of a and b: a pointer wins over an integer (the null
+// constant), otherwise integers promote to int, unsigned if either side is
+fn common_type(a: i32, b: i32) -> i32 {
+  if type_base(a) >= Type::PTR as i32 {
+    a
+  } else if type_base(b) >= Type::PTR as i32 {
+    b
+  } else if is_unsigned(a) || is_unsigned(b) {
+    Type::INT as i32 | UNSIGNED
+  } else {
+    Type::INT as i32
+  }
+}
+
+// Size in bytes of a value of type `t`
+fn type_size(t: i32) -> Int {
+  if type_base(t) >= Type::PTR as i32 {
+    WORD_SIZE as Int
+  } else if type_base(t) == Type::CHAR as i32 {
+    1
+  } else if t & SHORT != 0 {
+    2
+  } else {
+    WORD_SIZE as Int
+  }
+}
+
+// Instruction that loads a value of type `t` from the address in ax, sign- or zero-extending
+// narrow values by the type's signedness
+fn load_op(t: i32) -> OpCode {
+  match (type_size(t), is_unsigned(t)) {
+    (1, false) => OpCode::LC,
+    (1, true) => OpCode::LCU,
+    (2, false) => OpCode::LS,
+    (2, true) => OpCode::LSU,
+    _ => OpCode::LI,
+  }
+}
+
+// Instruction that stores ax as a value of type `t`. SC and SS leave the stored value
+// sign-extended in ax; `C4::emit_store` zero-extends it for unsigned types.
+fn store_op(t: i32) -> OpCode {
+  match type_size(t) {
+    1 => OpCode::SC,
+    2 => OpCode::SS,
+    _ => OpCode::SI,
+  }
+}
+
+// Whether a code word is one of the load instructions
+fn is_load(word: Int) -> bool {
+  matches!(OpCode::from_int(word), Some(OpCode::LC | OpCode::LCU | OpCode::LS | OpCode::LSU | OpCode::LI))
+}
+
+// (min, len) of the jump table for a switch's case values, or None if they are too sparse
+fn jump_table_range(cases: &[(Int, usize)]) -> Option<(Int, usize)> {
+  if cases.len() < JUMP_TABLE_MIN_CASES {
+    return None;
+  }
+  let min = cases.iter().map(|&(v, _)| v).min()?;
+  let max = cases.iter().map(|&(v, _)| v).max()?;
+  let len = (max as i128 - min as i128 + 1) as u128;
+  if len > (cases.len() * 2) as u128 {
+    return None;
+  }
+  Some((min, len as usize))
+}
+
+// The binary operator a compound assignment token applies (`+=` -> `+`)
+fn compound_assign_op(token: TokenKind) -> Option<TokenType> {
+  let TokenKind::Op(op) = token else { return None };
+  let op = if op == TokenType::AddAssign {
+    TokenType::Add
+  } else if op == TokenType::SubAssign {
+    TokenType::Sub
+  } else if op == TokenType::MulAssign {
+    TokenType::Mul
+  } else if op == TokenType::DivAssign {
+    TokenType::Div
+  } else if op == TokenType::ModAssign {
+    TokenType::Mod
+  } else if op == TokenType::AndAssign {
+    TokenType::And
+  } else if op == TokenType::OrAssign {
+    TokenType::Or
+  } else if op == TokenType::XorAssign {
+    TokenType::Xor
+  } else if op == TokenType::ShlAssign {
+    TokenType::Shl
+  } else if op == TokenType::ShrAssign {
+    TokenType::Shr
+  } else {
+    return None;
+  };
+  Some(op)
+}
+
+// Binding strength of an operator token that can follow an operand (higher binds tighter),
+// 0 if the token ends the expression. Assignments (1) and `?:` (2) group right to left,
+// the binary operators left to right; postfix `++`, `--` and `[` bind tightest.
+fn precedence(token: TokenKind) -> i32 {
+  let TokenKind::Op(op) = token else { return 0 };
+  let token = op as i32;
+  if token >= TokenType::Assign as i32 && token <= TokenType::ShrAssign as i32 {
+    1
+  } else if token == TokenType::Cond as i32 {
+    2
+  } else if token == TokenType::Lor as i32 {
+    3
+  } else if token == TokenType::Lan as i32 {
+    4
+  } else if token == TokenType::Or as i32 {
+    5
+  } else if token == TokenType::Xor as i32 {
+    6
+  } else if token == TokenType::And as i32 {
+    7
+  } else if token == TokenType::Eq as i32 || token == TokenType::Ne as i32 {
+    8
+  } else if token >= TokenType::Lt as i32 && token <= TokenType::Ge as i32 {
+    9
+  } else if token == TokenType::Shl as i32 || token == TokenType::Shr as i32 {
+    10
+  } else if token == TokenType::Add as i32 || token == TokenType::Sub as i32 {
+    11
+  } else if token >= TokenType::Mul as i32 && token <= TokenType::Mod as i32 {
+    12
+  } else if token >= TokenType::Inc as i32 && token <= TokenType::Brak as i32 {
+    13
+  } else {
+    0
+  }
+}
+
+// Precedence of a binary operator token (`||` through `%`), 0 for anything else
+fn binary_precedence(token: TokenKind) -> i32 {
+  match token {
+    TokenKind::Op(op) if op as i32 >= TokenType::Lor as i32 && op as i32 <= TokenType::Mod as i32 => precedence(token),
+    _ => 0,
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+  hash: i32,               // Hash value
+  pub name: String,            // Symbol name
+  pub class: Option<SymbolClass>, // What the name is; None until it is declared
+  pub type_: i32,              // Data type
+  pub value: Int,              // Value (a keyword's token type)
+  pub is_static: bool,         // Internal linkage: not exported to other translation units
+  pub is_extern: bool,         // Declared but not yet defined; resolved by link()
+  // What the name meant outside the innermost block that shadows it
+  h_class: Option<SymbolClass>,
+  h_type: i32,
+  h_val: Int,
+}
+
+// Parameter types of a declared function; the return type is the symbol's type
+#[derive(Debug, Clone, PartialEq)]
+struct Signature {
+  params: Option<Vec<i32>>,  // None for `f()`, which leaves the parameters unspecified
+  variadic: bool,            // Trailing `...`
+}
+
+#[allow(dead_code)]
+pub struct C4 {
+  pub p: usize,
+  lp: usize,
+  pub source: String,
+  pub symbols: Vec<Symbol>,
+  // The token the parser is looking at, with its value; the span is in `source` as compiled
+  pub token: Token,
+  #[allow(dead_code)]
+  pub type_: i32,
+  pub loc: Int,
+  // Return type of the function being compiled
+  return_type: i32,
+  pub line: i32,
+  pub src: bool,
+  debug: bool,
+  data_index: usize,
+  // The machine the compiled program runs on: code and data segments, memory and registers
+  pub vm: Vm,
+  // Seed the region bases were randomized with, if --aslr is on
+  aslr_seed: Option<u64>,
+  // Where preprocessed lines came from, one entry per line mark: (first preprocessed line,
+  // index into the VM's origin_files, original line there); rebuilt by compile()
+  line_origins: Vec<(i32, usize, i32)>,
+  // -O level, and passes switched off by name regardless of it
+  opt_level: u8,
+  disabled_passes: Vec<String>,
+  // With --opt-bisect-limit, only the first this many pass applications are performed
+  opt_bisect_limit: Option<usize>,
+  // Pass applications numbered so far by the current optimize() run
+  opt_applications: usize,
+  // Code words (operand index, symbol index) that refer to extern symbols, patched by link()
+  link_fixups: Vec<(usize, usize)>,
+  // Case labels of the switch statements being compiled, innermost last
+  switch_stack: Vec<SwitchCases>,
+  // Operands of `break` jumps to patch at the end of each enclosing switch, innermost last
+  break_stack: Vec<Vec<usize>>,
+  // Type of the last number token (int, or as its suffix says)
+  num_type: i32,
+  // First error the lexer ran into (it has no other way to fail), reported by the parser
+  lex_error: Option<String>,
+  // Last code word already printed by the -s listing
+  listed: usize,
+  // Files compiled so far, and the id of the one in `source`
+  source_map: SourceMap,
+  source_file: usize,
+  // When `source` was preprocessed: the ids the files it came from have in `source_map`, by
+  // name, so diagnostics point into the files as written
+  origin_sources: Vec<(String, usize)>,
+  // Source position where the current token starts
+  pub token_start: usize,
+  // Original file/line of each stretch of the source, in offset order
+  line_marks: Vec<LineMark>,
+  // Macro expansions in the source, outermost first
+  expansions: Vec<Expansion>,
+  // Include search paths used by preprocess_source
+  preprocessor: Preprocessor,
+  // Data address and length of every string literal lexed so far
+  string_literals: Vec<(Int, usize)>,
+  // Parameter names of each declared function, by symbol index
+  function_params: HashMap<usize, Vec<String>>,
+  // Frame slots of each compiled function, by symbol index: (name, LEA offset, type) for every
+  // parameter and local, and the slots of the function being compiled
+  function_locals: HashMap<usize, Vec<(String, Int, i32)>>,
+  frame_locals: Vec<(String, Int, i32)>,
+  // Signature of each declared function, by symbol index
+  signatures: HashMap<usize, Signature>,
+  // Symbols declared as locals in the current function, innermost last, each with the h_*
+  // fields it had before, so a name can be shadowed again in a nested block
+  locals: Vec<(usize, Option<SymbolClass>, i32, Int)>,
+  // Where each open block's declarations start in `locals`
+  scopes: Vec<usize>,
+  // Stack slots used by locals of the current function (the ENT operand)
+  pub local_count: Int,
+}
+
+impl Default for C4 {
+  fn default() -> Self {
+    C4::new()
+  }
+}
+
+//Implementation of the compiler
+#[allow(dead_code)]
+impl C4 {
+  pub fn new() -> Self {
+    C4 {
+      p: 0,
+      lp: 0,
+      source: String::new(),
+      symbols: Vec::new(),
+      token: Token { kind: TokenKind::Eof, span: Span { file_id: 0, start: 0, end: 0 } },
+      type_: 0,
+      loc: 0,
+      return_type: Type::INT as i32,
+      line: 1,
+      src: false,
+      debug: false,
+      // Nothing lives at address 0, so it can serve as the null pointer
+      data_index: WORD_SIZE,
+      vm: Vm::new(),
+      aslr_seed: None,
+      line_origins: Vec::new(),
+      opt_level: 0,
+      disabled_passes: Vec::new(),
+      opt_bisect_limit: None,
+      opt_applications: 0,
+      link_fixups: Vec::new(),
+      switch_stack: Vec::new(),
+      break_stack: Vec::new(),
+      num_type: Type::INT as i32,
+      lex_error: None,
+      listed: 0,
+      source_map: SourceMap::new(),
+      source_file: 0,
+      origin_sources: Vec::new(),
+      token_start: 0,
+      line_marks: Vec::new(),
+      expansions: Vec::new(),
+      preprocessor: Preprocessor::new(),
+      string_literals: Vec::new(),
+      function_params: HashMap::new(),
+      function_locals: HashMap::new(),
+      frame_locals: Vec::new(),
+      signatures: HashMap::new(),
+      locals: Vec::new(),
+      scopes: Vec::new(),
+      local_count: 0,
+    }
+  }
+
+  // A C4 set up with `options` instead of the defaults
+  pub fn with_options(options: C4Options) -> Result<Self, String> {
+    let mut c4 = C4::new();
+    c4.set_stack_size(options.stack_size)?;
+    c4.set_environment(options.environment);
+    c4.set_deterministic(options.deterministic);
+    c4.set_sandbox(options.sandbox);
+    Ok(c4)
+  }
+
+  // Make `text` the source to compile, registered under `name` for diagnostics
+  pub fn set_source(&mut self, name: &str, text: &str) {
+    self.source_file = self.source_map.add_file(name, text);
+    self.load_source(name, text);
+  }
+
+  // Make `text` the source to compile, its first line being line 1 of `name`
+  fn load_source(&mut self, name: &str, text: &str) {
+    self.origin_sources.clear();
+    self.source = text.to_string();
+    self.p = 0;
+    self.lp = 0;
+    self.line = 1;
+    self.mark_origin(0, name, 1);
+  }
+
+  // Run the preprocessor over `text`, the contents of file `name`, and make the result the
+  // source to compile; token origins point back into the original files and macros. Returns
+  // the preprocessor's warnings.
+  pub fn preprocess_source(&mut self, name: &str, text: &str) -> Result<Vec<String>, String> {
+    let out = self.preprocessor.run(name, text)?;
+    self.load_source(name, &out.text);
+    // The source map gets the files as written, not the preprocessed text
+    for (file, text) in &out.files {
+      let id = self.source_map.add_file(file, text);
+      self.origin_sources.push((file.clone(), id));
+    }
+    self.source_file = self.origin_sources[0].1;
+    self.line_marks.extend(out.marks);
+    self.expansions.extend(out.expansions);
+    Ok(out.warnings)
+  }
+
+  // Add a directory to search for #include files
+  pub fn add_include_path(&mut self, dir: &str) {
+    self.preprocessor.add_include_path(dir);
+  }
+
+  // Define a macro for the programs preprocessed from now on
+  pub fn define_macro(&mut self, name: &str, value: &str) -> Result<(), String> {
+    self.preprocessor.define_macro(name, value)
+  }
+
+  pub fn source_map(&self) -> &SourceMap {
+    &self.source_map
+  }
+
+  // Where the current token is in the source
+  pub fn token_span(&self) -> Span {
+    if self.origin_sources.is_empty() {
+      return Span { file_id: self.source_file, start: self.token_start, end: self.p };
+    }
+    // Preprocessed: the same column of the line the token came from
+    let origin = self.origin_at(self.token_start);
+    let file_id = self.origin_sources.iter().find(|(file, _)| *file == origin.file).map_or(self.source_file, |&(_, id)| id);
+    let col = self.token_start - self.line_start(self.token_start);
+    let start = self.source_map.position(file_id, origin.line as usize, col).unwrap_or(0);
+    Span { file_id, start, end: start + (self.p - self.token_start) }
+  }
+
+  // The current token with its payload
+  pub fn token(&self) -> Token {
+    Token { kind: self.token.kind, span: self.token_span() }
+  }
+
+  // Lex `src` to the end. String literals are placed in the data segment as they are met and
+  // identifiers are entered in the symbol table, as when compiling.
+  pub fn tokenize(&mut self, src: &str) -> Result<Vec<Token>, String> {
+    self.source = src.to_string();
+    self.p = 0;
+    self.lp = 0;
+    self.line = 1;
+    let mut tokens = Vec::new();
+    loop {
+      self.next();
+      self.check_lex_error()?;
+      let token = self.token();
+      tokens.push(token);
+      if token.kind == TokenKind::Eof {
+        return Ok(tokens);
+      }
+    }
+  }
+
+  // Record that the source from `offset` on was copied from `file` starting at `line`
+  pub fn mark_origin(&mut self, offset: usize, file: &str, line: i32) {
+    self.line_marks.push(LineMark { offset, file: file.to_string(), line });
+  }
+
+  // Record that source positions [start, end) came from expanding macro `name` defined at file:line
+  pub fn mark_expansion(&mut self, start: usize, end: usize, name: &str, file: &str, line: i32) {
+    self.expansions.push(Expansion { start, end, name: name.to_string(), file: file.to_string(), line });
+  }
+
+  // Original file and line of source position `pos`
+  pub fn origin_at(&self, pos: usize) -> Origin {
+    let mark = self.line_marks.iter().rev().find(|mark| mark.offset <= pos);
+    let (file, offset, first_line) = match mark {
+      Some(mark) => (mark.file.clone(), mark.offset, mark.line),
+      None => ("<input>".to_string(), 0, 1),
+    };
+    let newlines = self.source.chars().skip(offset).take(pos - offset).filter(|&c| c == '\n').count();
+    // The innermost expansion wins for nested macros
+    let expanded_from = self.expansions.iter().rev()
+      .find(|exp| exp.start <= pos && pos < exp.end)
+      .map(|exp| (exp.name.clone(), exp.file.clone(), exp.line));
+    Origin { file, line: first_line + newlines as i32, expanded_from }
+  }
+
+  // Original file and line of the current token
+  pub fn token_origin(&self) -> Origin {
+    self.origin_at(self.token_start)
+  }
+
+  // Deterministic mode, for runs that must give byte-identical output: rand starts from
+  // `seed`, time() reads a virtual clock driven by the cycle count, getenv sees no host
+  // variables (only a map given to set_environment) and __DATE__ is the virtual epoch's date.
+  // None turns it off.
+  pub fn set_deterministic(&mut self, seed: Option<u64>) {
+    self.vm.set_deterministic(seed);
+    self.preprocessor.set_clock(seed.map(|_| VIRTUAL_EPOCH));
+  }
+
+  // Move the data segment, heap and stack to random page-aligned bases derived from `seed`, or back
+  // to the fixed layout with None. Must be called before compiling: code embeds data addresses.
+  pub fn set_aslr(&mut self, seed: Option<u64>) {
+    self.aslr_seed = seed;
+    match seed {
+      Some(seed) => {
+        let mut state = seed;
+        // Data lands somewhere in the first 16MB but never at 0, so null stays unmapped
+        let data_pages = (0x0100_0000 - self.vm.data.len() as Int) / PAGE_SIZE;
+        self.vm.data_base = (1 + (splitmix64(&mut state) % (data_pages - 1) as u64) as Int) * PAGE_SIZE;
+        let stack_pages = 0x1000_0000 / PAGE_SIZE;
+        self.vm.stack_base = STACK_BASE + (splitmix64(&mut state) % stack_pages as u64) as Int * PAGE_SIZE;
+        // The heap goes between the end of the data segment's range (16MB) and STACK_BASE
+        let heap_pages = (STACK_BASE - 0x0100_0000 - HEAP_SIZE as Int) / PAGE_SIZE;
+        self.vm.heap_base = 0x0100_0000 + (splitmix64(&mut state) % heap_pages as u64) as Int * PAGE_SIZE;
+      },
+      None => {
+        self.vm.data_base = 0;
+        self.vm.heap_base = HEAP_BASE;
+        self.vm.stack_base = STACK_BASE;
+      },
+    }
+    self.vm.sp = self.vm.stack_base + self.vm.stack.len() as Int;
+    self.vm.bp = self.vm.sp;
+  }
+
+  // Seed of the current randomized layout, if any
+  pub fn aslr_seed(&self) -> Option<u64> {
+    self.aslr_seed
+  }
+
+  // Select the optimization level (0 to 2) used by optimize()
+  pub fn set_opt_level(&mut self, level: u8) -> Result<(), String> {
+    if level > MAX_OPT_LEVEL {
+      return Err(format!("unknown optimization level -O{}", level));
+    }
+    self.opt_level = level;
+    Ok(())
+  }
+
+  // Leave the named pass out of the pipeline, e.g. to find the one miscompiling a program
+  pub fn disable_pass(&mut self, name: &str) -> Result<(), String> {
+    if !PASSES.iter().any(|&(pass, _)| pass == name) {
+      let names: Vec<&str> = PASSES.iter().map(|&(pass, _)| pass).collect();
+      return Err(format!("unknown pass '{}' (passes: {})", name, names.join(", ")));
+    }
+    if !self.disabled_passes.iter().any(|pass| pass == name) {
+      self.disabled_passes.push(name.to_string());
+    }
+    Ok(())
+  }
+
+  // Perform only the first `limit` pass applications (None for all). Every rewrite a pass
+  // makes is one application, numbered in pipeline and code order, so the numbering is
+  // stable across runs and the limit can be binary-searched to find the one that breaks
+  // a program.
+  pub fn set_opt_bisect_limit(&mut self, limit: Option<usize>) {
+    self.opt_bisect_limit = limit;
+  }
+
+  // Names of the passes optimize() runs, in order
+  pub fn pass_pipeline(&self) -> Vec<&'static str> {
+    PASSES.iter()
+      .filter(|&&(name, level)| level <= self.opt_level && !self.disabled_passes.iter().any(|pass| pass == name))
+      .map(|&(name, _)| name)
+      .collect()
+  }
+
+  // Whether the next token after p is a string literal, with only whitespace and comments before it
+  fn string_follows(&self) -> bool {
+    let mut chars = self.source.chars().skip(self.p).peekable();
+    while let Some(c) = chars.next() {
+      match (c, chars.peek()) {
+        (c, _) if c.is_whitespace() => {},
+        ('/', Some('/')) => {
+          chars.find(|&c| c == '\n');
+        },
+        ('/', Some('*')) => {
+          chars.next();
+          let mut prev = ' ';
+          if !chars.any(|c| std::mem::replace(&mut prev, c) == '*' && c == '/') {
+            return false;
+          }
+        },
+        (c, _) => return c == '"',
+      }
+    }
+    false
+  }
+
+  //Get current character
+  fn current_char(&self) -> char{
+    if self.p < self.source.len(){
+      self.source.chars().nth(self.p).unwrap_or('\0')
+    } else {
+      '\0'
+    }
+  }
+
+  // Value of the escape sequence after a backslash, leaving p after it. Unknown escapes
+  // are recorded in lex_error and stand for the character itself.
+  fn escape_sequence(&mut self) -> i32 {
+    let ch = self.current_char();
+    self.p += 1;
+    match ch {
+      'n' => '\n' as i32,
+      't' => '\t' as i32,
+      'r' => '\r' as i32,
+      'a' => 7,
+      'b' => 8,
+      'f' => 12,
+      'v' => 11,
+      '\\' | '\'' | '"' | '?' => ch as i32,
+      'x' => {
+        let mut val = 0;
+        let mut digits = 0;
+        while digits < 2 && self.current_char().is_ascii_hexdigit() {
+          val = val * 16 + self.current_char().to_digit(16).unwrap_or(0) as i32;
+          self.p += 1;
+          digits += 1;
+        }
+        if digits == 0 && self.lex_error.is_none() {
+          self.lex_error = Some(format!("{}: \\x used with no following hex digits", self.line));
+        }
+        val
+      },
+      '0'..='7' => {
+        let mut val = ch as i32 - '0' as i32;
+        let mut digits = 1;
+        while digits < 3 && ('0'..='7').contains(&self.current_char()) {
+          val = val * 8 + (self.current_char() as i32 - '0' as i32);
+          self.p += 1;
+          digits += 1;
+        }
+        val & 0xff
+      },
+      _ => {
+        if self.lex_error.is_none() {
+          self.lex_error = Some(format!("{}: unknown escape sequence '\\{}'", self.line, ch));
+        }
+        ch as i32
+      },
+    }
+  }
+
+  // Accumulate digits in `radix` into `value`, returning how many were read. A `_` between
+  // two digits is skipped (1_000_000), a tolerated extension.
+  fn lex_digits(&mut self, radix: u32, value: &mut Int) -> usize {
+    let mut count = 0;
+    loop {
+      let mut ch = self.current_char();
+      if ch == '_' && count > 0 && self.source.chars().nth(self.p + 1).is_some_and(|c| c.is_digit(radix)) {
+        self.p += 1;
+        ch = self.current_char();
+      }
+      match ch.to_digit(radix) {
+        Some(digit) => {
+          *value = value.wrapping_mul(radix as Int).wrapping_add(digit as Int);
+          self.p += 1;
+          count += 1;
+        },
+        None => return count,
+      }
+    }
+  }
+
+  // Read an integer suffix (u, l, ll in either order and case) into num_type; anything else
+  // glued onto the number is an error rather than a separate identifier
+  fn lex_integer_suffix(&mut self) {
+    let mut unsigned = false;
+    let mut long = false;
+    loop {
+      let ch = self.current_char();
+      if (ch == 'u' || ch == 'U') && !unsigned {
+        unsigned = true;
+        self.p += 1;
+      } else if (ch == 'l' || ch == 'L') && !long {
+        long = true;
+        self.p += 1;
+        // `ll` must use the same case twice
+        if self.current_char() == ch {
+          self.p += 1;
+        }
+      } else {
+        break;
+      }
+    }
+    if unsigned {
+      self.num_type |= UNSIGNED;
+    }
+    if long {
+      self.num_type |= LONG;
+    }
+    let ch = self.current_char();
+    if (ch.is_alphanumeric() || ch == '_') && self.lex_error.is_none() {
+      self.lex_error = Some(format!("{}: invalid suffix '{}' on integer constant", self.line, ch));
+    }
+  }
+
+  // Report the first error the lexer ran into since the last check
+  pub fn check_lex_error(&mut self) -> Result<(), String> {
+    match self.lex_error.take() {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+
+  // Source text between character positions `start` and `end` (positions count characters,
+  // like `p`, so this never splits a multi-byte character); empty if the span is reversed
+  pub fn source_span(&self, start: usize, end: usize) -> String {
+    self.source.chars().skip(start).take(end.saturating_sub(start)).collect()
+  }
+
+  // Character position where the line containing position `pos` starts
+  pub fn line_start(&self, pos: usize) -> usize {
+    self.source.chars().take(pos).collect::<Vec<char>>().iter()
+      .rposition(|&c| c == '\n').map_or(0, |i| i + 1)
+  }
+
+  //Advance to next character
+  #[allow(dead_code)]
+  fn next_char(&mut self) -> char{
+    self.p +=1;
+    self.current_char()
+  }
+
+  //Symbol table with keywords and system calls
+  pub fn init_symbol_table(&mut self){
+    //Add keywords
+    let keywords = [
+      ("char", TokenType::Char as i32),
+      ("else", TokenType::Else as i32),
+      ("enum", TokenType::Enum as i32),
+      ("if", TokenType::If as i32),
+      ("int", TokenType::Int as i32),
+      ("return", TokenType::Return as i32),
+      ("sizeof", TokenType::Sizeof as i32),
+      ("while", TokenType::While as i32),
+      ("unsigned", TokenType::Unsigned as i32),
+      ("short", TokenType::Short as i32),
+      ("long", TokenType::Long as i32),
+      ("const", TokenType::Const as i32),
+      ("static", TokenType::Static as i32),
+      ("extern", TokenType::Extern as i32),
+      ("switch", TokenType::Switch as i32),
+      ("case", TokenType::Case as i32),
+      ("default", TokenType::Default as i32),
+      ("break", TokenType::Break as i32),
+      ("va_arg", TokenType::VaArg as i32),
+      ("va_count", TokenType::VaCount as i32),
+    ];
+
+    for (word, token) in keywords {
+      self.add_keyword(word, token);
+    }
+
+    //Add system calls
+    let syscalls = [
+      ("open", OpCode::OPEN as i32),
+      ("read", OpCode::READ as i32),
+      ("close", OpCode::CLOS as i32),
+      ("printf", OpCode::PRTF as i32),
+      ("malloc", OpCode::MALC as i32),
+      ("free", OpCode::FREE as i32),
+      ("memset", OpCode::MSET as i32),
+      ("memcmp", OpCode::MCMP as i32),
+      ("isdigit", OpCode::ISDG as i32),
+      ("isalpha", OpCode::ISAL as i32),
+      ("isspace", OpCode::ISSP as i32),
+      ("toupper", OpCode::TOUP as i32),
+      ("tolower", OpCode::TOLO as i32),
+      ("putchar", OpCode::PUTC as i32),
+      ("getchar", OpCode::GETC as i32),
+      ("puts", OpCode::PUTS as i32),
+      ("write", OpCode::WRIT as i32),
+      ("fprintf", OpCode::FPRT as i32),
+      ("sprintf", OpCode::SPRF as i32),
+      ("snprintf", OpCode::SNPR as i32),
+      ("scanf", OpCode::SCNF as i32),
+      ("gets", OpCode::GETS as i32),
+      ("strlen", OpCode::SLEN as i32),
+      ("strcpy", OpCode::SCPY as i32),
+      ("strncpy", OpCode::SNCP as i32),
+      ("strcmp", OpCode::SCMP as i32),
+      ("strcat", OpCode::SCAT as i32),
+      ("memcpy", OpCode::MCPY as i32),
+      ("abs", OpCode::ABS as i32),
+      ("rand", OpCode::RAND as i32),
+      ("srand", OpCode::SRND as i32),
+      ("time", OpCode::TIME as i32),
+      ("atoi", OpCode::ATOI as i32),
+      ("getenv", OpCode::GENV as i32),
+      ("atexit", OpCode::ATEX as i32),
+      ("exit", OpCode::EXIT as i32),
+    ];
+
+    for (name, code) in syscalls {
+      self.add_syscall(name, code);
+    }
+
+    self.add_keyword("void", TokenType::Void as i32);
+
+    // NULL is the null pointer constant, the standard streams are the fds fprintf takes,
+    // and RAND_MAX is the largest value rand returns
+    for (name, value) in [("NULL", 0), ("stdin", 0), ("stdout", 1), ("stderr", 2), ("RAND_MAX", RAND_MAX)] {
+      self.symbols.push(Symbol {
+        hash: symbol_hash(name),
+        name: name.to_string(),
+        class: Some(SymbolClass::EnumConst),
+        type_: Type::INT as i32,
+        value,
+        is_static: false,
+        is_extern: false,
+        h_class: None,
+        h_type: 0,
+        h_val: 0,
+      });
+    }
+  }
+
+  fn add_keyword(&mut self, name: &str, token: i32){
+    let hash = symbol_hash(name);
+
+    self.symbols.push(Symbol {
+      hash,
+      name: name.to_string(),
+      class: Some(SymbolClass::Keyword),
+      type_: 0,
+      value: token as Int,
+      is_static: false,
+      is_extern: false,
+      h_class: None,
+      h_type: 0,
+      h_val: 0,
+    });
+  }
+
+  fn add_syscall(&mut self, name: &str, code: i32) {
+    let hash = symbol_hash(name);
+
+    self.symbols.push(Symbol {
+      hash,
+      name: name.to_string(),
+      class: Some(SymbolClass::Syscall),
+      type_: Type::INT as i32,
+      value: code as Int,
+      is_static: false,
+      is_extern: false,
+      h_class: None,
+      h_type: 0,
+      h_val: 0,
+    });
+  }
+
+  fn find_symbol(&self, hash: i32, name: &str) -> Option<usize> {
+    for (i, sym) in self.symbols.iter().enumerate() {
+      if sym.hash == hash && sym.name == name {
+        return Some(i);
+      }
+    }
+    None
+  }
+
+  //Next token lexer function
+  pub fn next(&mut self) {
+    let kind = self.lex(None);
+    // Mapping back through the preprocessor is left to token(), the parser only needs the kind
+    self.token = Token { kind, span: Span { file_id: self.source_file, start: self.token_start, end: self.p } };
+  }
+
+  // Read the token at p, leaving p after it. `joining` is the string literal just read when
+  // the token is looked for right after it, so an adjacent literal extends it ("a" "b").
+  fn lex(&mut self, joining: Option<DataRef>) -> TokenKind {
+
+    while self.p < self.source.len() {
+      let ch = self.current_char();
+
+      if ch == '\n' {
+        self.line += 1;
+        if self.src {
+          // Print source line and assembly. lp is stale if p was moved back behind it.
+          if self.lp > self.p {
+            self.lp = self.line_start(self.p);
+          }
+          println!("{}: {}", self.line - 1, self.source_span(self.lp, self.p));
+          while self.listed < self.vm.le {
+            let (text, next) = self.format_instruction(self.listed + 1);
+            println!("{}", text);
+            self.listed = next - 1;
+          }
+        }
+        self.lp = self.p +1;
+        self.p += 1;
+        continue;
+      } else if ch.is_whitespace() {
+        self.p += 1;
+        continue;
+      }
+      break;
+    }
+    
+    self.token_start = self.p;
+    if self.p < self.source.len(){
+      if self.debug {
+        eprintln!("Next token starts with character: '{}' at position {}", self.current_char(), self.p);
+      }
+    } else {
+      if self.debug {
+        eprintln!("Reached end of source");
+      }
+      return TokenKind::Eof;
+    }
+
+    let ch = self.current_char();
+
+    //Parse identifiers
+    if ch.is_alphabetic() || ch=='_'{
+      let start = self.p;
+      let mut hash: i32 = ch as i32;
+      self.p +=1;
 
       //Collect identifiers characters
       while self.p < self.source.len() {
@@ -285,107 +2055,92 @@ impl C4 {
       
       //Calculating Hash
       hash = (hash<<6).wrapping_add((self.p - start) as i32);
-      let name = &self.source[start..self.p];
-      if let Some(idx) = self.find_symbol(hash, name) {
-        self.token = self.symbols[idx].token;
-        self.id = idx;
+      let name = self.source_span(start, self.p);
+      let name = name.as_str();
+      let kind = if let Some(idx) = self.find_symbol(hash, name) {
+        match (self.symbols[idx].class, TokenType::from_int(self.symbols[idx].value as i32)) {
+          (Some(SymbolClass::Keyword), Some(keyword)) => TokenKind::Keyword(keyword),
+          _ => TokenKind::Ident(SymbolId(idx)),
+        }
       } else {
-        self.id = self.symbols.len();
         self.symbols.push(Symbol {
-          token: TokenType::Id as i32,
           hash,
           name: name.to_string(),
-          class: 0,
+          class: None,
           type_: 0,
           value: 0,
-          h_class: 0,
+          is_static: false,
+          is_extern: false,
+          h_class: None,
           h_type: 0,
           h_val: 0,
         });
-        self.token = TokenType::Id as i32;
-      }
+        TokenKind::Ident(SymbolId(self.symbols.len() - 1))
+      };
       
-      println!("Parsed identifier: '{}', token = {}, id={}", name, self.token, self.id);
-      return;
+      if self.debug {
+        eprintln!("Parsed identifier: '{}', token = {:?}", name, kind);
+      }
+      return kind;
     }
     
     //Parse numbers
-    if ch.is_digit(10) {
-      let is_zero = ch == '0';
-      self.token_val = (ch as u8 - b'0') as Int;
-      self.p +=1;
-      
-      if is_zero && self.p < self.source.len() {
-        let next_ch = self.current_char();
-        
-        if next_ch == 'x' || next_ch == 'X' {
-          self.p += 1;
-          self.token_val = 0;
-          while self.p < self.source.len() {
-            let ch = self.current_char();
-            if ch.is_digit(16) {
-              let digit_val = if ch.is_digit(10) {
-                ch as u8 - b'0'
-              } else if ch >= 'a' && ch <= 'f' {
-                (ch as u8 - b'a') + 10
-              } else {
-                (ch as u8 - b'A') + 10
-              };
-              self.token_val = self.token_val * 16 + digit_val as Int;
-              self.p += 1;
-            } else {
-              break;
-            }
-          }
-        }
-        else if next_ch.is_digit(8) {
-          while self.p < self.source.len(){
-            let ch = self.current_char();
-            if ch.is_digit(8) {
-              self.token_val = self.token_val * 8 + (ch as u8 - b'0') as Int;
-              self.p += 1;
-            } else {
-              break;
-            }
-          }
+    if ch.is_ascii_digit() {
+      let mut value = 0;
+      self.num_type = Type::INT as i32;
+      self.p += 1;
+      let next_ch = self.current_char();
+      if ch == '0' && (next_ch == 'x' || next_ch == 'X') {
+        self.p += 1;
+        if self.lex_digits(16, &mut value) == 0 && self.lex_error.is_none() {
+          self.lex_error = Some(format!("{}: hex constant has no digits", self.line));
         }
-      }
-      // Handle decimal numbers
-      else if !is_zero {
-        while self.p < self.source.len() {
-          let ch = self.current_char();
-          if ch.is_digit(10) {
-            self.token_val = self.token_val * 10 + (ch as u8 - b'0') as Int;
-            self.p += 1;
-          } else {
-            break;
-          }
+      } else if ch == '0' && (next_ch == 'b' || next_ch == 'B') {
+        // Binary constants (0b1010), a GNU extension adopted by C23
+        self.p += 1;
+        if self.lex_digits(2, &mut value) == 0 && self.lex_error.is_none() {
+          self.lex_error = Some(format!("{}: binary constant has no digits", self.line));
         }
+      } else {
+        // Re-read the first digit so a separator may follow it
+        self.p -= 1;
+        self.lex_digits(if ch == '0' { 8 } else { 10 }, &mut value);
       }
-      self.token = TokenType::Num as i32;
-      return;
+      self.lex_integer_suffix();
+      return TokenKind::Num(value);
     }
     
     //Handle string and character literals
     if ch == '"' || ch == '\'' {
       let string_type = ch;
-      let data_start = self.data_index;
+      let mut data_start = self.data_index;
+      // "a" "b" is one string: append to the previous literal, over its terminator
+      let continued = joining.filter(|_| string_type == '"');
+      if let Some(prev) = continued {
+        data_start = (prev.addr - self.vm.data_base) as usize;
+        self.data_index = data_start + prev.len;
+      }
       self.p += 1;
+      let mut chars = 0;
+      let mut value: Int = 0;
       
       while self.p < self.source.len() && self.current_char() != string_type {
         let mut val = self.current_char() as i32;
         self.p += 1;
         if val == '\\' as i32 && self.p < self.source.len() {
-          val = self.current_char() as i32;
-          self.p += 1;
-          if val == 'n' as i32 {
-            val = '\n' as i32;
-          }
+          val = self.escape_sequence();
         }
         
         if string_type == '"' {
-          self.data[self.data_index] = val as u8;
+          self.vm.data[self.data_index] = val as u8;
           self.data_index += 1;
+        } else {
+          // 'ab' packs its characters into one integer, the first in the highest byte
+          chars += 1;
+          if chars > WORD_SIZE && self.lex_error.is_none() {
+            self.lex_error = Some(format!("{}: character constant too long", self.line));
+          }
+          value = (value << 8) | (val as u8 as Int);
         }
       }
       
@@ -394,13 +2149,27 @@ impl C4 {
       }
       
       if string_type == '"' {
-        self.token_val = data_start as Int;
-        // Align data pointer
+        let literal = DataRef { addr: self.vm.data_addr(data_start), len: self.data_index - data_start };
+        if continued.is_some() {
+          let last = self.string_literals.len() - 1;
+          self.string_literals[last] = (literal.addr, literal.len);
+        } else {
+          self.string_literals.push((literal.addr, literal.len));
+        }
+        // Keep a NUL terminator (the data segment is zeroed), then align data pointer
+        self.data_index += 1;
         self.data_index = (self.data_index + std::mem::size_of::<Int>() - 1) & !(std::mem::size_of::<Int>() - 1);
-      } else {
-        self.token = TokenType::Num as i32;
+        // Pull an adjacent literal into this one, so the parser sees a single string token
+        if self.string_follows() {
+          let token_start = self.token_start;
+          let kind = self.lex(Some(literal));
+          self.token_start = token_start;
+          return kind;
+        }
+        return TokenKind::Str(literal);
       }
-      return;
+      self.num_type = Type::INT as i32;
+      return TokenKind::Num(value);
     }
     
     // Handle operators and other tokens
@@ -413,204 +2182,577 @@ impl C4 {
           while self.p < self.source.len() && self.current_char() != '\n' {
             self.p += 1;
           }
-          self.next(); 
-          return;
+          return self.lex(joining);
+        }
+        if self.current_char() == '*' {
+          // Block comment, possibly spanning lines
+          let start_line = self.line;
+          self.p += 1;
+          loop {
+            if self.p >= self.source.len() {
+              if self.lex_error.is_none() {
+                self.lex_error = Some(format!("{}: unterminated comment", start_line));
+              }
+              break;
+            }
+            let ch = self.current_char();
+            self.p += 1;
+            if ch == '\n' {
+              self.line += 1;
+              self.lp = self.p;
+            } else if ch == '*' && self.current_char() == '/' {
+              self.p += 1;
+              break;
+            }
+          }
+          return self.lex(joining);
+        }
+        if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::DivAssign)
+        } else {
+          TokenKind::Op(TokenType::Div)
         }
-        self.token = TokenType::Div as i32;
       },
       '=' => {
         self.p += 1;
         if self.current_char() == '=' {
           self.p += 1;
-          self.token = TokenType::Eq as i32;
+          TokenKind::Op(TokenType::Eq)
         } else {
-          self.token = TokenType::Assign as i32;
+          TokenKind::Op(TokenType::Assign)
         }
       },
       '+' => {
         self.p += 1;
         if self.current_char() == '+' {
           self.p += 1;
-          self.token = TokenType::Inc as i32;
+          TokenKind::Op(TokenType::Inc)
+        } else if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::AddAssign)
         } else {
-          self.token = TokenType::Add as i32;
+          TokenKind::Op(TokenType::Add)
         }
       },
       '-' => {
         self.p += 1;
         if self.current_char() == '-' {
           self.p += 1;
-          self.token = TokenType::Dec as i32;
+          TokenKind::Op(TokenType::Dec)
+        } else if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::SubAssign)
         } else {
-          self.token = TokenType::Sub as i32;
+          TokenKind::Op(TokenType::Sub)
         }
       },
       '!' => {
         self.p += 1;
         if self.current_char() == '=' {
           self.p += 1;
-          self.token = TokenType::Ne as i32;
+          TokenKind::Op(TokenType::Ne)
         } else {
-          self.token = '!' as i32;
+          TokenKind::Punct('!')
         }
       },
       '<' => {
         self.p += 1;
         if self.current_char() == '=' {
           self.p += 1;
-          self.token = TokenType::Le as i32;
+          TokenKind::Op(TokenType::Le)
         } else if self.current_char() == '<' {
           self.p += 1;
-          self.token = TokenType::Shl as i32;
+          if self.current_char() == '=' {
+            self.p += 1;
+            TokenKind::Op(TokenType::ShlAssign)
+          } else {
+            TokenKind::Op(TokenType::Shl)
+          }
         } else {
-          self.token = TokenType::Lt as i32;
+          TokenKind::Op(TokenType::Lt)
         }
       },
       '>' => {
         self.p += 1;
         if self.current_char() == '=' {
           self.p += 1;
-          self.token = TokenType::Ge as i32;
+          TokenKind::Op(TokenType::Ge)
         } else if self.current_char() == '>' {
           self.p += 1;
-          self.token = TokenType::Shr as i32;
+          if self.current_char() == '=' {
+            self.p += 1;
+            TokenKind::Op(TokenType::ShrAssign)
+          } else {
+            TokenKind::Op(TokenType::Shr)
+          }
         } else {
-          self.token = TokenType::Gt as i32;
+          TokenKind::Op(TokenType::Gt)
         }
       },
       '|' => {
         self.p += 1;
         if self.current_char() == '|' {
           self.p += 1;
-          self.token = TokenType::Lor as i32;
+          TokenKind::Op(TokenType::Lor)
+        } else if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::OrAssign)
         } else {
-          self.token = TokenType::Or as i32;
+          TokenKind::Op(TokenType::Or)
         }
       },
       '&' => { 
         self.p += 1;
         if self.current_char() == '&' {
           self.p += 1;
-          self.token = TokenType::Lan as i32;
+          TokenKind::Op(TokenType::Lan)
+        } else if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::AndAssign)
         } else {
-          self.token = TokenType::And as i32;
+          TokenKind::Op(TokenType::And)
         }
       }, 
       '^' => {
         self.p += 1;
-        self.token = TokenType::Xor as i32;
+        if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::XorAssign)
+        } else {
+          TokenKind::Op(TokenType::Xor)
+        }
       },
       '%' => {
         self.p += 1;
-        self.token = TokenType::Mod as i32;
+        if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::ModAssign)
+        } else {
+          TokenKind::Op(TokenType::Mod)
+        }
       },
       '*' => {
         self.p += 1;
-        self.token = TokenType::Mul as i32;
+        if self.current_char() == '=' {
+          self.p += 1;
+          TokenKind::Op(TokenType::MulAssign)
+        } else {
+          TokenKind::Op(TokenType::Mul)
+        }
       },
       '[' => {
         self.p += 1;
-        self.token = TokenType::Brak as i32;
+        TokenKind::Op(TokenType::Brak)
       },
       '?' => {
         self.p += 1;
-        self.token = TokenType::Cond as i32;
+        TokenKind::Op(TokenType::Cond)
+      },
+      '.' => {
+        self.p += 1;
+        if self.current_char() == '.' && self.source.chars().nth(self.p + 1) == Some('.') {
+          self.p += 2;
+          TokenKind::Op(TokenType::Ellipsis)
+        } else {
+          TokenKind::Punct('.')
+        }
       },
       '#' => {
         self.p += 1;
         while self.p < self.source.len() && self.current_char() != '\n' {
           self.p += 1;
         }
-        self.next(); // next token
-        return;
+        self.lex(joining)
       },
       '~' | ';' | '{' | '}' | '(' | ')' | ']' | ',' | ':' => {
-        self.token = ch as i32;
         self.p += 1;
+        TokenKind::Punct(ch)
       },
       _ => {
-        self.token = ch as i32;
         self.p += 1;
+        TokenKind::Punct(ch)
       } 
     }
   }
 
-  // Emit an instruction
-  fn emit(&mut self, op: OpCode) {
-    self.le += 1;
-    self.e[self.le] = op as Int;
-  }
- 
-  // Emit an instruction with an operand
-  fn emit_with_operand(&mut self, op: OpCode, operand: Int) {
-    self.emit(op);
-    self.le += 1;
-    self.e[self.le] = operand;
+  // Emit an instruction
+  pub fn emit(&mut self, op: OpCode) {
+    self.vm.le += 1;
+    self.vm.code[self.vm.le] = op as Int;
+    if self.vm.code_lines.last().is_none_or(|&(_, line, _)| line != self.line) {
+      self.vm.code_lines.push((self.vm.le, self.line, self.origin_of_line(self.line)));
+    }
+  }
+
+  // Original (file index, line) of preprocessed source line `line`
+  fn origin_of_line(&self, line: i32) -> Option<LineOrigin> {
+    let i = self.line_origins.partition_point(|&(start, _, _)| start <= line);
+    match i {
+      0 => None,
+      _ => {
+        let (start, file, first) = self.line_origins[i - 1];
+        Some((file, first + line - start))
+      }
+    }
+  }
+
+  // Turn the line marks' character offsets into preprocessed line numbers, in one pass over
+  // the source, so emit() can resolve every line it records cheaply
+  fn index_line_marks(&mut self) {
+    let mut marks: Vec<&LineMark> = self.line_marks.iter().collect();
+    marks.sort_by_key(|mark| mark.offset);
+    let mut origins = Vec::with_capacity(marks.len());
+    let mut chars = self.source.chars();
+    let (mut pos, mut line) = (0, 1);
+    for mark in marks {
+      for c in chars.by_ref().take(mark.offset.saturating_sub(pos)) {
+        if c == '\n' {
+          line += 1;
+        }
+      }
+      pos = pos.max(mark.offset);
+      let file = match self.vm.origin_files.iter().position(|name| *name == mark.file) {
+        Some(file) => file,
+        None => {
+          self.vm.origin_files.push(mark.file.clone());
+          self.vm.origin_files.len() - 1
+        }
+      };
+      origins.push((line, file, mark.line));
+    }
+    self.line_origins = origins;
+  }
+ 
+  // Listing line for the instruction at `pos` (`     IMM 42`), and the position after it
+  fn format_instruction(&self, pos: usize) -> (String, usize) {
+    match OpCode::from_int(self.vm.code[pos]) {
+      Some(op) if op.has_operand() => (format!("{:>8} {}", format!("{:?}", op), self.vm.code[pos + 1]), pos + 2),
+      Some(op) => (format!("{:>8}", format!("{:?}", op)), pos + 1),
+      None => (format!("{:>8} {}", "?", self.vm.code[pos]), pos + 1),
+    }
+  }
+
+  // The instruction listing of all code emitted so far, in the format -s prints
+  pub fn listing(&self) -> String {
+    let mut out = String::new();
+    let mut pos = 1;
+    while pos <= self.vm.le {
+      let (text, next) = self.format_instruction(pos);
+      out.push_str(&text);
+      out.push('\n');
+      pos = next;
+    }
+    out
+  }
+
+  // Reassemble the listing and check it reproduces the emitted code word for word;
+  // returns the number of code words checked
+  pub fn compare_listing(&self) -> Result<usize, String> {
+    let words = assemble(&self.listing())?;
+    let emitted = &self.vm.code[1..=self.vm.le];
+    if let Some(i) = emitted.iter().zip(&words).position(|(a, b)| a != b) {
+      return Err(format!("code word {}: emitted {} but the listing reassembles to {}", i + 1, emitted[i], words[i]));
+    }
+    if words.len() != emitted.len() {
+      return Err(format!("listing reassembles to {} code words, {} were emitted", words.len(), emitted.len()));
+    }
+    Ok(words.len())
+  }
+
+  // Insert code words at `at`, moving everything after it up and fixing jumps into the moved code
+  fn insert_code(&mut self, at: usize, words: &[Int]) {
+    let n = words.len();
+    let mut pos = at;
+    while pos <= self.vm.le {
+      let op = OpCode::from_int(self.vm.code[pos]);
+      if let Some(op) = op.filter(|op| op.has_operand()) {
+        let target = self.vm.code[pos + 1];
+        if matches!(op, OpCode::JMP | OpCode::BZ | OpCode::BNZ) && target >= at as Int {
+          self.vm.code[pos + 1] = target + n as Int;
+        }
+        pos += 2;
+      } else {
+        pos += 1;
+      }
+    }
+    self.vm.code.copy_within(at..=self.vm.le, at + n);
+    self.vm.code[at..at + n].copy_from_slice(words);
+    self.vm.le += n;
+    for fixup in self.link_fixups.iter_mut() {
+      if fixup.0 >= at {
+        fixup.0 += n;
+      }
+    }
+    for entry in self.vm.code_lines.iter_mut() {
+      if entry.0 > at {
+        entry.0 += n;
+      }
+    }
+  }
+
+  // Emit an instruction with an operand and return the operand's index, so jumps emitted
+  // before their target is known can be patched with `patch_jump`
+  pub fn emit_with_operand(&mut self, op: OpCode, operand: Int) -> usize {
+    self.emit(op);
+    self.vm.le += 1;
+    self.vm.code[self.vm.le] = operand;
+    self.vm.le
+  }
+
+  // Point the jump whose operand is at `slot` at the next instruction to be emitted
+  fn patch_jump(&mut self, slot: usize) {
+    self.vm.code[slot] = (self.vm.le + 1) as Int;
+  }
+
+  // Whether the current token starts a type name
+  fn is_type_start(&self) -> bool {
+    self.token.kind == TokenKind::Keyword(TokenType::Int) || self.token.kind == TokenKind::Keyword(TokenType::Char) ||
+      self.token.kind == TokenKind::Keyword(TokenType::Unsigned) || self.token.kind == TokenKind::Keyword(TokenType::Short) ||
+      self.token.kind == TokenKind::Keyword(TokenType::Long) || self.token.kind == TokenKind::Keyword(TokenType::Const) ||
+      self.token.kind == TokenKind::Keyword(TokenType::Void)
+  }
+
+  // Parse a base type specifier (`int`, `char`, `void`, `unsigned short`, `const long int`, ...)
+  pub fn parse_base_type(&mut self) -> i32 {
+    let mut flags = 0;
+    if self.token.kind == TokenKind::Keyword(TokenType::Const) {
+      flags |= CONST;
+      self.next();
+    }
+    if self.token.kind == TokenKind::Keyword(TokenType::Unsigned) {
+      flags |= UNSIGNED;
+      self.next();
+    }
+    if self.token.kind == TokenKind::Keyword(TokenType::Short) {
+      flags |= SHORT;
+      self.next();
+    } else if self.token.kind == TokenKind::Keyword(TokenType::Long) {
+      flags |= LONG;
+      self.next();
+      // `long long` is the same 64-bit type
+      if self.token.kind == TokenKind::Keyword(TokenType::Long) {
+        self.next();
+      }
+    }
+    let base = if flags & (SHORT | LONG) == 0 && self.token.kind == TokenKind::Keyword(TokenType::Char) {
+      self.next();
+      Type::CHAR as i32
+    } else if flags & (UNSIGNED | SHORT | LONG) == 0 && self.token.kind == TokenKind::Keyword(TokenType::Void) {
+      self.next();
+      Type::CHAR as i32 | VOID
+    } else {
+      if self.token.kind == TokenKind::Keyword(TokenType::Int) {
+        self.next();
+      }
+      Type::INT as i32
+    };
+    // `int const x` is the same as `const int x`
+    if self.token.kind == TokenKind::Keyword(TokenType::Const) {
+      flags |= CONST;
+      self.next();
+    }
+    base | flags
+  }
+
+  // Opcode for a binary operator token, picking the unsigned variant where it differs
+  pub fn binary_op(&self, op: TokenType, unsigned: bool) -> Option<OpCode> {
+    let op = match op {
+      TokenType::Add => OpCode::ADD,
+      TokenType::Sub => OpCode::SUB,
+      TokenType::Mul => OpCode::MUL,
+      TokenType::Div => if unsigned { OpCode::UDIV } else { OpCode::DIV },
+      TokenType::Mod => if unsigned { OpCode::UMOD } else { OpCode::MOD },
+      TokenType::And => OpCode::AND,
+      TokenType::Or => OpCode::OR,
+      TokenType::Xor => OpCode::XOR,
+      TokenType::Eq => OpCode::EQ,
+      TokenType::Ne => OpCode::NE,
+      TokenType::Lt => if unsigned { OpCode::ULT } else { OpCode::LT },
+      TokenType::Gt => if unsigned { OpCode::UGT } else { OpCode::GT },
+      TokenType::Le => if unsigned { OpCode::ULE } else { OpCode::LE },
+      TokenType::Ge => if unsigned { OpCode::UGE } else { OpCode::GE },
+      TokenType::Shl => OpCode::SHL,
+      TokenType::Shr => if unsigned { OpCode::USHR } else { OpCode::SHR },
+      _ => return None,
+    };
+    Some(op)
+  }
+
+  // Evaluate a constant expression at compile time (global initializers, enum values)
+  fn const_expr(&mut self, min_prec: i32) -> Result<Int, String> {
+    let mut value = self.const_unary()?;
+    loop {
+      let TokenKind::Op(op) = self.token.kind else { return Ok(value) };
+      let prec = binary_precedence(self.token.kind);
+      if prec == 0 || prec < min_prec {
+        return Ok(value);
+      }
+      self.next();
+      let rhs = self.const_expr(prec + 1)?;
+      value = if op == TokenType::Lan {
+        (value != 0 && rhs != 0) as Int
+      } else if op == TokenType::Lor {
+        (value != 0 || rhs != 0) as Int
+      } else {
+        let opcode = match self.binary_op(op, false) {
+          Some(opcode) => opcode,
+          None => return Err(format!("{}: bad operator in constant expression", self.line)),
+        };
+        self.vm.binary(opcode, value, rhs).map_err(|e| format!("{}: {} in constant expression", self.line, e.description()))?
+      };
+    }
+  }
+
+  // Operand of a constant expression: literal, string address, enum constant, sizeof,
+  // unary op or parens
+  fn const_unary(&mut self) -> Result<Int, String> {
+    self.check_lex_error()?;
+    match self.token.kind {
+      TokenKind::Num(value) => {
+        self.next();
+        Ok(value)
+      }
+      TokenKind::Str(literal) => {
+        self.next();
+        Ok(literal.addr)
+      }
+      TokenKind::Ident(SymbolId(idx)) if self.symbols[idx].class == Some(SymbolClass::EnumConst) => {
+        let value = self.symbols[idx].value;
+        self.next();
+        Ok(value)
+      }
+      TokenKind::Keyword(TokenType::Sizeof) => Ok(type_size(self.sizeof_operand()?)),
+      TokenKind::Punct('(') => {
+        self.next();
+        let value = self.const_expr(1)?;
+        if self.token.kind != TokenKind::Punct(')') {
+          return Err(format!("{}: close paren expected", self.line));
+        }
+        self.next();
+        Ok(value)
+      }
+      TokenKind::Op(TokenType::Sub) => {
+        self.next();
+        Ok(self.const_unary()?.wrapping_neg())
+      }
+      TokenKind::Op(TokenType::Add) => {
+        self.next();
+        self.const_unary()
+      }
+      TokenKind::Punct('~') => {
+        self.next();
+        Ok(!self.const_unary()?)
+      }
+      TokenKind::Punct('!') => {
+        self.next();
+        Ok((self.const_unary()? == 0) as Int)
+      }
+      _ => Err(format!("{}: constant expression expected", self.line)),
+    }
+  }
+
+  // Parse the operand of `sizeof` -- `(type)`, `(expr)` or `expr` -- and return its type.
+  // An expression operand is only typed: its code is compiled, then dropped.
+  fn sizeof_operand(&mut self) -> Result<i32, String> {
+    self.next();
+    let mark = self.vm.le;
+    let save_type = self.type_;
+    if self.token.kind == TokenKind::Punct('(') {
+      self.next();
+      if self.is_type_start() {
+        self.type_ = self.parse_base_type();
+        while self.token.kind == TokenKind::Op(TokenType::Mul) {
+          self.next();
+          self.type_ += Type::PTR as i32;
+        }
+      } else {
+        self.expr(TokenType::Assign)?;
+      }
+      if self.token.kind == TokenKind::Punct(')') {
+        self.next();
+      } else {
+        return Err(format!("{}: close paren expected in sizeof", self.line));
+      }
+    } else {
+      self.expr(TokenType::Inc)?;
+    }
+    self.vm.le = mark;
+    self.link_fixups.retain(|&(pos, _)| pos <= mark);
+    let ty = self.type_;
+    self.type_ = save_type;
+    Ok(ty)
   }
 
   // Expression parsing 
-  fn expr(&mut self, level: i32) -> Result<(), String> {
-    // Save the current type before parsing expressions
-    let save_type = self.type_;
+  // On return self.type_ holds the type of the parsed expression
+  // Parse an expression, consuming only operators that bind at least as tightly as the
+  // operator token `level` (`TokenType::Assign` for a full expression, `TokenType::Inc` for
+  // the operand of a unary operator)
+  pub fn expr(&mut self, level: TokenType) -> Result<(), String> {
+    self.expr_at(precedence(TokenKind::Op(level)))
+  }
+
+  // Precedence climbing: parse a unary expression, then fold in operators of precedence
+  // `min_prec` or higher, each right operand parsed one level tighter (left to right) or at
+  // the operator's own level (right to left)
+  fn expr_at(&mut self, min_prec: i32) -> Result<(), String> {
     let mut t: i32;
     
-    if self.token == 0 {
+    self.check_lex_error()?;
+    if self.token.kind == TokenKind::Eof {
       return Err(format!("{}: unexpected end of file in expression", self.line));
     } 
 
     // Parse primary expressions
-    if self.token == TokenType::Num as i32 {
-      self.emit_with_operand(OpCode::IMM, self.token_val);
+    if let TokenKind::Num(value) = self.token.kind {
+      self.emit_with_operand(OpCode::IMM, value);
+      self.type_ = self.num_type;
       self.next();
-      self.type_ = Type::INT as i32;
     } 
-    else if self.token == '"' as i32 {
-      self.emit_with_operand(OpCode::IMM, self.token_val);
+    else if let TokenKind::Str(literal) = self.token.kind {
+      // Adjacent literals were already merged by the lexer
+      self.emit_with_operand(OpCode::IMM, literal.addr);
       self.next();
-      while self.token == '"' as i32 {
-        self.next();
-      } 
-      self.data_index = (self.data_index + std::mem::size_of::<Int>() - 1) & !(std::mem::size_of::<Int>() - 1);
       self.type_ = Type::PTR as i32;
     } 
-    else if self.token == TokenType::Sizeof as i32 {
+    else if self.token.kind == TokenKind::Keyword(TokenType::Sizeof) {
+      let ty = self.sizeof_operand()?;
+      self.emit_with_operand(OpCode::IMM, type_size(ty));
+      self.type_ = Type::INT as i32;
+    } 
+    else if self.token.kind == TokenKind::Keyword(TokenType::VaCount) || self.token.kind == TokenKind::Keyword(TokenType::VaArg) {
+      // Inside a variadic function: bp[2] holds the number of arguments the caller passed
+      // (named ones included) and argument i sits at bp[2 + count - i]
+      let is_count = self.token.kind == TokenKind::Keyword(TokenType::VaCount);
       self.next();
-      if self.token == '(' as i32 {
-        self.next();
-      } else { 
-        return Err(format!("{}: open paren expected in sizeof", self.line));
+      if self.token.kind != TokenKind::Punct('(') {
+        return Err(format!("{}: open paren expected after {}", self.line, if is_count { "va_count" } else { "va_arg" }));
       }
-      self.type_ = Type::INT as i32;
-      if self.token == TokenType::Int as i32 {
-        self.next();
-      } else if self.token == TokenType::Char as i32 {
-        self.next();
-        self.type_ = Type::CHAR as i32;
+      self.next();
+      self.emit_with_operand(OpCode::LEA, 2);
+      self.emit(OpCode::LI);
+      if !is_count {
+        self.emit(OpCode::PSH);
+        self.operand(TokenType::Assign)?;
+        self.emit_variadic_load();
       }
-      while self.token == TokenType::Mul as i32 {
-        self.next();
-        self.type_ += Type::PTR as i32;
+      if self.token.kind != TokenKind::Punct(')') {
+        return Err(format!("{}: close paren expected", self.line));
       }
-      if self.token == ')' as i32 {
-        self.next();
-      } else {
-        return Err(format!("{}: close paren expected in sizeof", self.line));
-      } 
-      let size_val = if self.type_ == Type::CHAR as i32 { 1 } else { std::mem::size_of::<Int>() as Int };
-      self.emit_with_operand(OpCode::IMM, size_val);
+      self.next();
       self.type_ = Type::INT as i32;
     } 
-    else if self.token == TokenType::Id as i32 {
-      let id_idx = self.id;
+    else if let TokenKind::Ident(SymbolId(id_idx)) = self.token.kind {
       self.next();
-      if self.token == '(' as i32 {
+      if self.token.kind == TokenKind::Punct('(') {
         self.next();
         let mut arg_count = 0;
-        while self.token != ')' as i32 {
-          self.expr(TokenType::Assign as i32)?;
+        while self.token.kind != TokenKind::Punct(')') {
+          self.operand(TokenType::Assign)?;
           self.emit(OpCode::PSH);
           arg_count += 1;
-          if self.token == ',' as i32 {
+          if self.token.kind == TokenKind::Punct(',') {
             self.next();
           }
         } 
@@ -619,10 +2761,32 @@ impl C4 {
         let class = sym.class;
         let value = sym.value;
         let type_ = sym.type_;
-        if class == TokenType::Sys as i32 {
-          self.emit_with_operand(OpCode::IMM, value);
-        } else if class == TokenType::Fun as i32 {
+        if class == Some(SymbolClass::Syscall) {
+          match OpCode::from_int(value) {
+            Some(op) => self.emit(op),
+            None => return Err(format!("{}: bad system call", self.line)),
+          }
+        } else if class == Some(SymbolClass::Function) {
+          let (named, variadic) = match self.signatures.get(&id_idx) {
+            Some(sig) => (sig.params.as_ref().map(|params| params.len() as Int), sig.variadic),
+            None => (None, false),
+          };
+          if let Some(named) = named {
+            if variadic && arg_count < named {
+              return Err(format!("{}: too few arguments to variadic function '{}'", self.line, self.symbols[id_idx].name));
+            }
+            if !variadic && arg_count != named {
+              return Err(format!("{}: '{}' takes {} argument(s), {} given", self.line, self.symbols[id_idx].name, named, arg_count));
+            }
+          }
+          if variadic {
+            // The argument count goes last, at a fixed offset from the callee's frame
+            self.emit_with_operand(OpCode::IMM, arg_count);
+            self.emit(OpCode::PSH);
+            arg_count += 1;
+          }
           self.emit_with_operand(OpCode::JSR, value);
+          self.record_link_fixup(id_idx);
         } else { 
           return Err(format!("{}: bad function call", self.line));
         } 
@@ -631,710 +2795,1972 @@ impl C4 {
         } 
         self.type_ = type_;
       } 
-      else if self.symbols[id_idx].class == TokenType::Num as i32 {
+      else if self.symbols[id_idx].class == Some(SymbolClass::EnumConst) {
         self.emit_with_operand(OpCode::IMM, self.symbols[id_idx].value);
         self.type_ = Type::INT as i32;
       } 
+      else if self.symbols[id_idx].class == Some(SymbolClass::Function) {
+        // A function name on its own is its code address (for atexit and the like)
+        self.emit_with_operand(OpCode::IMM, self.symbols[id_idx].value);
+        self.record_link_fixup(id_idx);
+        self.type_ = Type::INT as i32;
+      } 
       else {
         let class = self.symbols[id_idx].class;
         let value = self.symbols[id_idx].value;
         let var_type = self.symbols[id_idx].type_;
-        if class == TokenType::Loc as i32 {
+        if class == Some(SymbolClass::Local) {
           self.emit_with_operand(OpCode::LEA, self.loc - value);
-        } else if class == TokenType::Glo as i32 {
+        } else if class == Some(SymbolClass::Global) {
           self.emit_with_operand(OpCode::IMM, value);
+          self.record_link_fixup(id_idx);
         } else {
           return Err(format!("{}: undefined variable", self.line));
         }
         self.type_ = var_type;
         // Load the value
-        if self.type_ == Type::CHAR as i32 {
-          self.emit(OpCode::LC);
-        } else {
-          self.emit(OpCode::LI);
-        }
+        self.emit(load_op(self.type_));
       } 
     }
-    else if self.token == '(' as i32 {
+    else if self.token.kind == TokenKind::Punct('(') {
       self.next();
-      if self.token == TokenType::Int as i32 || self.token == TokenType::Char as i32 {
+      if self.is_type_start() {
         // Type cast
-        t = if self.token == TokenType::Int as i32 {
-          Type::INT as i32
-        } else {
-          Type::CHAR as i32
-        };
-        self.next();
-        while self.token == TokenType::Mul as i32 {
+        t = self.parse_base_type();
+        while self.token.kind == TokenKind::Op(TokenType::Mul) {
           self.next();
           t += Type::PTR as i32;
         }  
-        if self.token == ')' as i32 {
+        if self.token.kind == TokenKind::Punct(')') {
           self.next();
         } else {
           return Err(format!("{}: bad cast", self.line));
         } 
-        self.expr(TokenType::Inc as i32)?;
+        self.expr(TokenType::Inc)?;
         self.type_ = t;
       } 
       else { 
-        self.expr(TokenType::Assign as i32)?;
-        if self.token == ')' as i32 {
+        self.operand(TokenType::Assign)?;
+        if self.token.kind == TokenKind::Punct(')') {
           self.next();
         } else { 
           return Err(format!("{}: close paren expected", self.line));
         }
       } 
     } 
-    else if self.token == TokenType::Mul as i32 {
+    else if self.token.kind == TokenKind::Op(TokenType::Mul) {
       self.next();
-      self.expr(TokenType::Inc as i32)?;
-      if self.type_ >= Type::PTR as i32 {
+      self.operand(TokenType::Inc)?;
+      if type_base(self.type_) >= Type::PTR as i32 {
         self.type_ -= Type::PTR as i32;
       } else {
         return Err(format!("{}: bad dereference", self.line));
       }
-      if self.type_ == Type::CHAR as i32 {
-        self.emit(OpCode::LC);
-      } else {
-        self.emit(OpCode::LI);
-      } 
+      self.emit(load_op(self.type_));
     } 
-    else if self.token == TokenType::And as i32 {
+    else if self.token.kind == TokenKind::Op(TokenType::And) {
       self.next();
-      self.expr(TokenType::Inc as i32)?;
+      self.operand(TokenType::Inc)?;
       // If it's already a load, just remove it
-      if self.e[self.le] == OpCode::LC as Int || self.e[self.le] == OpCode::LI as Int {
-        self.le -= 1;
+      if is_load(self.vm.code[self.vm.le]) {
+        self.vm.le -= 1;
       } else { 
         return Err(format!("{}: bad address-of", self.line));
       } 
       self.type_ += Type::PTR as i32;
     }
-    else if self.token == '!' as i32 {
+    else if self.token.kind == TokenKind::Punct('!') {
       self.next();
-      self.expr(TokenType::Inc as i32)?;
+      self.operand(TokenType::Inc)?;
       self.emit(OpCode::PSH);
       self.emit_with_operand(OpCode::IMM, 0);
       self.emit(OpCode::EQ);
       self.type_ = Type::INT as i32;
     }  
-    else if self.token == '~' as i32 {
+    else if self.token.kind == TokenKind::Punct('~') {
       self.next();
-      self.expr(TokenType::Inc as i32)?;
+      self.operand(TokenType::Inc)?;
       self.emit(OpCode::PSH);
       self.emit_with_operand(OpCode::IMM, -1);
       self.emit(OpCode::XOR);
       self.type_ = Type::INT as i32;
     }
-    else if self.token == TokenType::Add as i32 {
+    else if self.token.kind == TokenKind::Op(TokenType::Add) {
       // Unary plus (no-op)
       self.next();
-      self.expr(TokenType::Inc as i32)?;
+      self.operand(TokenType::Inc)?;
       self.type_ = Type::INT as i32;
     } 
-    else if self.token == TokenType::Sub as i32 {
+    else if self.token.kind == TokenKind::Op(TokenType::Sub) {
       // Unary minus
       self.next();
       self.emit_with_operand(OpCode::IMM, 0);
-      if self.token == TokenType::Num as i32 {
-        self.emit_with_operand(OpCode::IMM, -self.token_val);
+      if let TokenKind::Num(value) = self.token.kind {
+        self.emit_with_operand(OpCode::IMM, -value);
         self.next();
       } else {
         self.emit_with_operand(OpCode::IMM, -1);
         self.emit(OpCode::PSH);
-        self.expr(TokenType::Inc as i32)?;
+        self.operand(TokenType::Inc)?;
         self.emit(OpCode::MUL);
       }
       self.type_ = Type::INT as i32;
     } 
-    else if self.token == TokenType::Inc as i32 || self.token == TokenType::Dec as i32 {
+    else if self.token.kind == TokenKind::Op(TokenType::Inc) || self.token.kind == TokenKind::Op(TokenType::Dec) {
       // Pre-increment/decrement
-      let op = self.token;
+      let is_inc = self.token.kind == TokenKind::Op(TokenType::Inc);
       self.next();
-      self.expr(TokenType::Inc as i32)?;
+      self.operand(TokenType::Inc)?;
+      if is_const(self.type_) {
+        return Err(format!("{}: cannot modify a const object", self.line));
+      }
       // Check if it's an l-value
-      if self.e[self.le] == OpCode::LC as Int {
-        self.e[self.le] = OpCode::PSH as Int;
-        self.emit(OpCode::LC);
-      } else if self.e[self.le] == OpCode::LI as Int {
-        self.e[self.le] = OpCode::PSH as Int;
-        self.emit(OpCode::LI);
+      if is_load(self.vm.code[self.vm.le]) {
+        self.vm.code[self.vm.le] = OpCode::PSH as Int;
+        self.emit(load_op(self.type_));
       } else {
         return Err(format!("{}: bad lvalue in pre-increment", self.line));
       } 
       self.emit(OpCode::PSH);
-      self.emit_with_operand(OpCode::IMM, if self.type_ > Type::PTR as i32 { std::mem::size_of::<Int>() as Int } else { 1 });
-      if op == TokenType::Inc as i32 {
+      // Pointers step by the size of what they point to
+      let step = if type_base(self.type_) >= Type::PTR as i32 { type_size(self.type_ - Type::PTR as i32) } else { 1 };
+      self.emit_with_operand(OpCode::IMM, step);
+      if is_inc {
         self.emit(OpCode::ADD);
       } else {
         self.emit(OpCode::SUB);
       } 
-      if self.type_ == Type::CHAR as i32 {
-        self.emit(OpCode::SC);
-      } else { 
-        self.emit(OpCode::SI);
-      } 
+      self.emit_store(self.type_);
     } 
     else { 
       return Err(format!("{}: bad expression", self.line));
     } 
 
-    // Binary operators 
-    while self.token >= level {
-      if self.token == TokenType::Assign as i32 {
+    // Binary and postfix operators
+    loop {
+      let prec = precedence(self.token.kind);
+      if prec == 0 || prec < min_prec {
+        break;
+      }
+      self.check_value()?;
+      if self.token.kind == TokenKind::Op(TokenType::Assign) {
+        if is_const(self.type_) {
+          return Err(format!("{}: assignment to a const object", self.line));
+        }
+        t = self.type_;
         self.next();
         // Check if lvalue
-        if self.e[self.le] == OpCode::LC as Int || self.e[self.le] == OpCode::LI as Int {
-          self.e[self.le] = OpCode::PSH as Int;
+        if is_load(self.vm.code[self.vm.le]) {
+          self.vm.code[self.vm.le] = OpCode::PSH as Int;
         } else { 
           return Err(format!("{}: bad lvalue in assignment", self.line));
         } 
-      } 
+        self.operand(TokenType::Assign)?;
+        self.emit_store(t);
+        self.type_ = t;
+      }
+      else if self.token.kind == TokenKind::Op(TokenType::Cond) {
+        // cond ? x : y -- BZ skips to y, x jumps over it
+        self.next();
+        let else_jump = self.emit_with_operand(OpCode::BZ, 0);
+        self.expr(TokenType::Assign)?;
+        t = self.type_;
+        if self.token.kind != TokenKind::Punct(':') {
+          return Err(format!("{}: conditional missing colon", self.line));
+        }
+        self.next();
+        let end_jump = self.emit_with_operand(OpCode::JMP, 0);
+        self.patch_jump(else_jump);
+        self.expr(TokenType::Cond)?;
+        self.patch_jump(end_jump);
+        self.type_ = common_type(t, self.type_);
+      }
+      else if self.token.kind == TokenKind::Op(TokenType::Brak) {
+        // a[i] is *(a + i): scale the index, add, and load (the load makes it an lvalue)
+        t = self.type_;
+        if type_base(t) < Type::PTR as i32 {
+          return Err(format!("{}: pointer type expected", self.line));
+        }
+        self.next();
+        self.emit(OpCode::PSH);
+        self.operand(TokenType::Assign)?;
+        if self.token.kind != TokenKind::Punct(']') {
+          return Err(format!("{}: close bracket expected", self.line));
+        }
+        self.next();
+        let size = type_size(t - Type::PTR as i32);
+        if size > 1 {
+          self.emit(OpCode::PSH);
+          self.emit_with_operand(OpCode::IMM, size);
+          self.emit(OpCode::MUL);
+        }
+        self.emit(OpCode::ADD);
+        self.type_ = t - Type::PTR as i32;
+        self.emit(load_op(self.type_));
+      }
+      else if self.token.kind == TokenKind::Op(TokenType::Lor) || self.token.kind == TokenKind::Op(TokenType::Lan) {
+        // a || b skips b once a is nonzero (BNZ), a && b once a is zero (BZ); either way the
+        // result is normalized to 0 or 1 where the branch lands
+        let branch = if self.token.kind == TokenKind::Op(TokenType::Lor) { OpCode::BNZ } else { OpCode::BZ };
+        self.next();
+        let skip = self.emit_with_operand(branch, 0);
+        self.expr_at(prec + 1)?;
+        self.check_value()?;
+        self.patch_jump(skip);
+        self.emit(OpCode::PSH);
+        self.emit_with_operand(OpCode::IMM, 0);
+        self.emit(OpCode::NE);
+        self.type_ = Type::INT as i32;
+      }
+      else if self.token.kind == TokenKind::Op(TokenType::Inc) || self.token.kind == TokenKind::Op(TokenType::Dec) {
+        // Post-increment/decrement: store the new value, then undo the step in ax so the
+        // expression yields the old one (re-narrowed, in case the store wrapped around)
+        if is_const(self.type_) {
+          return Err(format!("{}: cannot modify a const object", self.line));
+        }
+        t = self.type_;
+        if is_load(self.vm.code[self.vm.le]) {
+          self.vm.code[self.vm.le] = OpCode::PSH as Int;
+          self.emit(load_op(t));
+        } else {
+          return Err(format!("{}: bad lvalue in post-increment", self.line));
+        }
+        let step = if type_base(t) >= Type::PTR as i32 { type_size(t - Type::PTR as i32) } else { 1 };
+        let (apply, undo) = if self.token.kind == TokenKind::Op(TokenType::Inc) { (OpCode::ADD, OpCode::SUB) } else { (OpCode::SUB, OpCode::ADD) };
+        self.emit(OpCode::PSH);
+        self.emit_with_operand(OpCode::IMM, step);
+        self.emit(apply);
+        self.emit_store(t);
+        self.emit(OpCode::PSH);
+        self.emit_with_operand(OpCode::IMM, step);
+        self.emit(undo);
+        if type_base(t) < Type::PTR as i32 {
+          self.emit_extend(t);
+        }
+        self.next();
+      }
+      else if let Some(op_token) = compound_assign_op(self.token.kind) {
+        // `a op= b` keeps the address of a on the stack, reloads it and stores a op b
+        if is_const(self.type_) {
+          return Err(format!("{}: assignment to a const object", self.line));
+        }
+        t = self.type_;
+        self.next();
+        if is_load(self.vm.code[self.vm.le]) {
+          self.vm.code[self.vm.le] = OpCode::PSH as Int;
+          self.emit(load_op(t));
+        } else {
+          return Err(format!("{}: bad lvalue in compound assignment", self.line));
+        }
+        self.emit(OpCode::PSH);
+        self.operand(TokenType::Assign)?;
+        // Pointers step by the size of what they point to
+        if type_base(t) >= Type::PTR as i32 && (op_token == TokenType::Add || op_token == TokenType::Sub) {
+          self.emit(OpCode::PSH);
+          self.emit_with_operand(OpCode::IMM, type_size(t - Type::PTR as i32));
+          self.emit(OpCode::MUL);
+        }
+        match self.binary_op(op_token, is_unsigned(t) || is_unsigned(self.type_)) {
+          Some(op) => self.emit(op),
+          None => return Err(format!("{}: bad operator", self.line)),
+        }
+        self.emit_store(t);
+        self.type_ = t;
+      }
        else {
          t = self.type_;
-         // Emit operator
-         if self.token == TokenType::Add as i32 {
-           self.emit(OpCode::ADD);
-         } else if self.token == TokenType::Sub as i32 {
-           self.emit(OpCode::SUB);
-         } else if self.token == TokenType::Mul as i32 {
-           self.emit(OpCode::MUL);
-         } else if self.token == TokenType::Div as i32 {
-           self.emit(OpCode::DIV);
-         } else if self.token == TokenType::Mod as i32 {
-           self.emit(OpCode::MOD);
-         } else if self.token == TokenType::And as i32 {
-           self.emit(OpCode::AND);
-         } else if self.token == TokenType::Or as i32 {
-           self.emit(OpCode::OR);
-         } else if self.token == TokenType::Xor as i32 {
-           self.emit(OpCode::XOR);
-         } else if self.token == TokenType::Eq as i32 {
-           self.emit(OpCode::EQ);
-         } else if self.token == TokenType::Ne as i32 {
-           self.emit(OpCode::NE);
-         } else if self.token == TokenType::Lt as i32 {
-           self.emit(OpCode::LT);
-         } else if self.token == TokenType::Gt as i32 {
-           self.emit(OpCode::GT);
-         } else if self.token == TokenType::Le as i32 {
-           self.emit(OpCode::LE);
-         } else if self.token == TokenType::Ge as i32 {
-           self.emit(OpCode::GE);
-         } else if self.token == TokenType::Shl as i32 {
-           self.emit(OpCode::SHL);
-         } else if self.token == TokenType::Shr as i32 {
-           self.emit(OpCode::SHR);
-         } else {
-           return Err(format!("{}: bad operator", self.line));
-         } 
-
+         let op_token = match self.token.kind {
+           TokenKind::Op(op) if self.binary_op(op, false).is_some() => op,
+           _ => return Err(format!("{}: bad operator", self.line)),
+         };
+         let lhs_end = self.vm.le;
+         self.emit(OpCode::PSH);
          self.next();
-         // Parse right-hand side
-         self.expr(level - 1)?;
-         // Emit operator
-         if self.token == TokenType::Add as i32 {
-           self.emit(OpCode::ADD);
-         } else if self.token == TokenType::Sub as i32 {
+         // Parse right-hand side; only tighter operators belong to it
+         self.expr_at(prec + 1)?;
+         self.check_value()?;
+         let rhs = self.type_;
+         let lhs_ptr = type_base(t) >= Type::PTR as i32;
+         let rhs_ptr = type_base(rhs) >= Type::PTR as i32;
+         let is_add = op_token == TokenType::Add;
+         let is_sub = op_token == TokenType::Sub;
+
+         let is_comparison = (TokenType::Eq as i32..=TokenType::Ge as i32).contains(&(op_token as i32));
+
+         if is_comparison && lhs_ptr && rhs_ptr && !pointers_compatible(t, rhs) {
+           return Err(format!("{}: comparison of distinct pointer types", self.line));
+         }
+         if is_comparison && lhs_ptr != rhs_ptr {
+           // Only the constant 0 compares with a pointer
+           let null = if lhs_ptr { self.is_null_constant(self.vm.le) } else { self.is_null_constant(lhs_end) };
+           if !null {
+             return Err(format!("{}: comparison between pointer and integer", self.line));
+           }
+         }
+         if is_add && lhs_ptr && rhs_ptr {
+           return Err(format!("{}: cannot add two pointers", self.line));
+         }
+         if is_sub && rhs_ptr && !lhs_ptr {
+           return Err(format!("{}: cannot subtract a pointer from an integer", self.line));
+         }
+         if is_sub && lhs_ptr && rhs_ptr {
+           if t & !CONST != rhs & !CONST {
+             return Err(format!("{}: subtraction of distinct pointer types", self.line));
+           }
+           // Pointer difference counts elements, not bytes
            self.emit(OpCode::SUB);
-         } else if self.token == TokenType::Mul as i32 {
-           self.emit(OpCode::MUL);
-         } else if self.token == TokenType::Div as i32 {
-           self.emit(OpCode::DIV);
-         } else if self.token == TokenType::Mod as i32 {
-           self.emit(OpCode::MOD);
-         } else if self.token == TokenType::And as i32 {
-           self.emit(OpCode::AND);
-         } else if self.token == TokenType::Or as i32 {
-           self.emit(OpCode::OR);
-         } else if self.token == TokenType::Xor as i32 {
-           self.emit(OpCode::XOR);
-         } else if self.token == TokenType::Eq as i32 {
-           self.emit(OpCode::EQ);
-         } else if self.token == TokenType::Ne as i32 {
-           self.emit(OpCode::NE);
-         } else if self.token == TokenType::Lt as i32 {
-           self.emit(OpCode::LT);
-         } else if self.token == TokenType::Gt as i32 {
-           self.emit(OpCode::GT);
-         } else if self.token == TokenType::Le as i32 {
-           self.emit(OpCode::LE);
-         } else if self.token == TokenType::Ge as i32 {
-           self.emit(OpCode::GE);
-         } else if self.token == TokenType::Shl as i32 {
-           self.emit(OpCode::SHL);
-         } else if self.token == TokenType::Shr as i32 {
-           self.emit(OpCode::SHR);
-         } else {
-           return Err(format!("{}: bad operator", self.line));
+           let size = type_size(t - Type::PTR as i32);
+           if size > 1 {
+             self.emit(OpCode::PSH);
+             self.emit_with_operand(OpCode::IMM, size);
+             self.emit(OpCode::DIV);
+           }
+           self.type_ = Type::INT as i32;
+           continue;
          }
-         self.type_ = t;
+         if (is_add || is_sub) && lhs_ptr && !rhs_ptr {
+           let size = type_size(t - Type::PTR as i32);
+           if size > 1 {
+             self.emit(OpCode::PSH);
+             self.emit_with_operand(OpCode::IMM, size);
+             self.emit(OpCode::MUL);
+           }
+         } else if is_add && rhs_ptr && !lhs_ptr {
+           // int + pointer: the int is already on the stack, so scale it where it was computed
+           let size = type_size(rhs - Type::PTR as i32);
+           if size > 1 {
+             self.insert_code(lhs_end + 1, &[OpCode::PSH as Int, OpCode::IMM as Int, size, OpCode::MUL as Int]);
+           }
+           t = rhs;
+         }
+         // Emit operator; addresses compare unsigned
+         let unsigned = is_unsigned(t) || is_unsigned(rhs) || (is_comparison && (lhs_ptr || rhs_ptr));
+         match self.binary_op(op_token, unsigned) {
+           Some(op) => self.emit(op),
+           None => return Err(format!("{}: bad operator", self.line)),
+         }
+         // Comparisons yield a truth value whatever they compare
+         self.type_ = if is_comparison { Type::INT as i32 } else { t };
        }
     }
-    self.type_ = save_type;
+    Ok(())
+  }
+
+  // Store ax as a value of type `t` through the address on the stack. The value left in ax
+  // is what was stored, extended the way a load of `t` would.
+  fn emit_store(&mut self, t: i32) {
+    self.emit(store_op(t));
+    if is_unsigned(t) {
+      self.emit_extend(t);
+    }
+  }
+
+  // Truncate ax to the width of `t` and extend it back by the type's signedness, the value a
+  // store and reload of `t` would give; no code for full-width types
+  fn emit_extend(&mut self, t: i32) {
+    let size = type_size(t);
+    if size >= WORD_SIZE as Int {
+      return;
+    }
+    if is_unsigned(t) {
+      self.emit(OpCode::PSH);
+      self.emit_with_operand(OpCode::IMM, (1 << (8 * size)) - 1);
+      self.emit(OpCode::AND);
+    } else {
+      let shift = (WORD_SIZE as Int - size) * 8;
+      self.emit(OpCode::PSH);
+      self.emit_with_operand(OpCode::IMM, shift);
+      self.emit(OpCode::SHL);
+      self.emit(OpCode::PSH);
+      self.emit_with_operand(OpCode::IMM, shift);
+      self.emit(OpCode::SHR);
+    }
+  }
+
+  // Whether the code ending at `end` is just the constant 0, the null pointer constant
+  fn is_null_constant(&self, end: usize) -> bool {
+    end >= 2 && self.vm.code[end - 1] == OpCode::IMM as Int && self.vm.code[end] == 0
+  }
+
+  // Parse an expression whose value is used
+  fn operand(&mut self, level: TokenType) -> Result<(), String> {
+    self.expr(level)?;
+    self.check_value()
+  }
+
+  // Reject using the result of a void expression
+  fn check_value(&self) -> Result<(), String> {
+    if is_void(self.type_) {
+      return Err(format!("{}: void value not ignored as it ought to be", self.line));
+    }
     Ok(())
   }
 
   //Compile the program
-  fn compile(&mut self) -> Result<(), String> {
-    // Parse declarations
+  // Compile every top-level declaration in the source, then point calls made before a
+  // function was defined (through its prototype) at its code
+  pub fn compile(&mut self) -> Result<(), String> {
+    self.index_line_marks();
+    self.p = 0;
+    self.lp = 0;
     self.line = 1;
-    println!("Starting compilation, source length: {}", self.source.len());
     self.next();
-    
-    // Find the main in the c file
-    let mut main_idx = None;
-    for (i, sym) in self.symbols.iter().enumerate() {
-      if sym.name == "main" {
-        main_idx = Some(I);
-        println!("Found main function at index {} with hash={}", i, sym.hash);
+    while self.token.kind != TokenKind::Eof {
+      self.compile_global_declaration().map_err(|e| self.error_origin(e))?;
+      self.check_lex_error().map_err(|e| self.error_origin(e))?;
+    }
+    self.link()
+  }
+
+  // A compile error's `line: ` prefix as `file:line: ` in the file it came from, when the
+  // source was preprocessed and its lines no longer match any one file
+  fn error_origin(&self, message: String) -> String {
+    if self.origin_sources.is_empty() {
+      return message;
+    }
+    let origin = message.split_once(": ")
+      .and_then(|(line, rest)| Some((self.origin_of_line(line.parse().ok()?)?, rest)));
+    match origin {
+      Some(((file, line), rest)) => format!("{}:{}: {}", self.vm.origin_files[file], line, rest),
+      None => message,
+    }
+  }
+
+  // Compile one top-level declaration: `[static|extern] type declarator [= const] {, ...} ;`
+  // Globals get a word-aligned slot in the data segment holding their folded initializer;
+  // functions are recorded with their linkage and their bodies compiled. Extern declarations
+  // and body-less function declarations only record the name until a definition shows up.
+  pub fn compile_global_declaration(&mut self) -> Result<(), String> {
+    if self.token.kind == TokenKind::Keyword(TokenType::Enum) {
+      return self.compile_enum(false);
+    }
+    let mut is_static = false;
+    let mut is_extern = false;
+    if self.token.kind == TokenKind::Keyword(TokenType::Static) {
+      is_static = true;
+      self.next();
+    } else if self.token.kind == TokenKind::Keyword(TokenType::Extern) {
+      is_extern = true;
+      self.next();
+    }
+    let base_type = self.parse_base_type();
+
+    while self.token.kind != TokenKind::Punct(';') && self.token.kind != TokenKind::Eof {
+      let mut ty = base_type;
+      while self.token.kind == TokenKind::Op(TokenType::Mul) {
+        self.next();
+        ty += Type::PTR as i32;
+      }
+      let TokenKind::Ident(SymbolId(idx)) = self.token.kind else {
+        return Err(format!("{}: bad global declaration", self.line));
+      };
+      let declared = self.symbols[idx].class.is_some();
+      let pending = self.symbols[idx].is_extern;
+      self.next();
+
+      if self.token.kind == TokenKind::Punct('(') {
+        let (params, mut signature) = self.parse_parameters()?;
+        let names: Vec<String> = params.iter().flatten().map(|&param| self.symbols[param].name.clone()).collect();
+        let has_body = self.token.kind == TokenKind::Punct('{');
+        if has_body && declared && !pending {
+          return Err(format!("{}: duplicate global definition '{}'", self.line, self.symbols[idx].name));
+        }
+        if declared && self.symbols[idx].class == Some(SymbolClass::Function) {
+          self.check_signature(idx, ty, &signature)?;
+          if signature.params.is_none() {
+            // `f()` after a prototype keeps the prototype's parameters
+            signature = self.signatures.get(&idx).cloned().unwrap_or(signature);
+          }
+        }
+        let types = signature.params.clone().unwrap_or_default();
+        let variadic = signature.variadic;
+        if !declared || (pending && has_body) {
+          self.symbols[idx].class = Some(SymbolClass::Function);
+          self.symbols[idx].type_ = ty;
+          self.symbols[idx].is_static = is_static;
+          self.symbols[idx].is_extern = !has_body;
+          if has_body || !names.is_empty() || !self.function_params.contains_key(&idx) {
+            self.function_params.insert(idx, names);
+          }
+          self.signatures.insert(idx, signature);
+        }
+        if has_body {
+          return self.compile_function(idx, &params, &types, variadic);
+        }
+      } else if is_void(ty) {
+        return Err(format!("{}: variable '{}' declared void", self.line, self.symbols[idx].name));
+      } else if is_extern && self.token.kind != TokenKind::Op(TokenType::Assign) {
+        // Declaration only: storage comes from the defining translation unit
+        if !declared {
+          self.symbols[idx].class = Some(SymbolClass::Global);
+          self.symbols[idx].type_ = ty;
+          self.symbols[idx].is_extern = true;
+        }
+      } else {
+        if declared && !pending {
+          return Err(format!("{}: duplicate global definition '{}'", self.line, self.symbols[idx].name));
+        }
+        self.symbols[idx].type_ = ty;
+        self.symbols[idx].is_static = is_static;
+        self.symbols[idx].is_extern = false;
+        self.symbols[idx].class = Some(SymbolClass::Global);
+        let addr = self.vm.data_addr(self.data_index);
+        self.symbols[idx].value = addr;
+        let size = (type_size(ty) as usize).max(WORD_SIZE);
+        self.data_index = (self.data_index + size + WORD_SIZE - 1) & !(WORD_SIZE - 1);
+        if self.token.kind == TokenKind::Op(TokenType::Assign) {
+          self.next();
+          let value = self.const_expr(1)?;
+          self.store_data(addr, ty, value)?;
+        }
+      }
+      if self.token.kind == TokenKind::Punct(',') {
+        self.next();
+      }
+    }
+    if self.token.kind != TokenKind::Punct(';') {
+      return Err(format!("{}: ';' expected after global declaration", self.line));
+    }
+    self.next();
+    Ok(())
+  }
+
+  // Compile `enum [name] { A, B = expr, ... };`, defining each name as a constant
+  fn compile_enum(&mut self, local: bool) -> Result<(), String> {
+    self.next();
+    if matches!(self.token.kind, TokenKind::Ident(_)) {
+      self.next();
+    }
+    if self.token.kind == TokenKind::Punct('{') {
+      self.next();
+      let mut value: Int = 0;
+      while self.token.kind != TokenKind::Punct('}') {
+        let TokenKind::Ident(SymbolId(idx)) = self.token.kind else {
+          return Err(format!("{}: bad enum identifier {:?}", self.line, self.token.kind));
+        };
+        // Locals shadow outer names until their block ends; globals must be new names
+        let duplicate = if local {
+          self.in_current_scope(idx)
+        } else {
+          self.symbols[idx].class.is_some() && !self.symbols[idx].is_extern
+        };
+        if duplicate {
+          return Err(format!("{}: duplicate definition of enumerator '{}'", self.line, self.symbols[idx].name));
+        }
+        self.next();
+        if self.token.kind == TokenKind::Op(TokenType::Assign) {
+          self.next();
+          value = self.const_expr(1)?;
+        }
+        if local {
+          self.declare_local(idx);
+        }
+        let sym = &mut self.symbols[idx];
+        sym.class = Some(SymbolClass::EnumConst);
+        sym.type_ = Type::INT as i32;
+        sym.value = value;
+        value += 1;
+        if self.token.kind == TokenKind::Punct(',') {
+          self.next();
+        } else if self.token.kind != TokenKind::Punct('}') {
+          return Err(format!("{}: ',' or '}}' expected in enum", self.line));
+        }
+      }
+      self.next();
+    }
+    if self.token.kind != TokenKind::Punct(';') {
+      return Err(format!("{}: ';' expected after enum", self.line));
+    }
+    self.next();
+    Ok(())
+  }
+
+  // Parse a parameter list from its `(` to after its `)`: the symbol of each parameter (None
+  // if it is unnamed) and the signature. `(void)` declares no parameters and `()` leaves
+  // them unspecified.
+  fn parse_parameters(&mut self) -> Result<(Vec<Option<usize>>, Signature), String> {
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    let mut variadic = false;
+    self.next();
+    if self.token.kind == TokenKind::Punct(')') {
+      self.next();
+      return Ok((names, Signature { params: None, variadic }));
+    }
+    loop {
+      if self.token.kind == TokenKind::Op(TokenType::Ellipsis) {
+        if types.is_empty() {
+          return Err(format!("{}: '...' needs a named parameter before it", self.line));
+        }
+        variadic = true;
+        self.next();
+        if self.token.kind != TokenKind::Punct(')') {
+          return Err(format!("{}: '...' must be the last parameter", self.line));
+        }
         break;
-      } 
-    } 
+      }
+      if !self.is_type_start() {
+        return Err(format!("{}: bad parameter declaration", self.line));
+      }
+      let mut ty = self.parse_base_type();
+      while self.token.kind == TokenKind::Op(TokenType::Mul) {
+        ty += Type::PTR as i32;
+        self.next();
+      }
+      if let TokenKind::Ident(id) = self.token.kind {
+        if is_void(ty) {
+          return Err(format!("{}: parameter '{}' declared void", self.line, self.symbols[id.0].name));
+        }
+        names.push(Some(id.0));
+        self.next();
+      } else if is_void(ty) && types.is_empty() && self.token.kind == TokenKind::Punct(')') {
+        break;
+      } else if is_void(ty) {
+        return Err(format!("{}: 'void' must be the only parameter", self.line));
+      } else {
+        names.push(None);
+      }
+      types.push(ty);
+      if self.token.kind == TokenKind::Punct(')') {
+        break;
+      }
+      if self.token.kind != TokenKind::Punct(',') {
+        return Err(format!("{}: ',' or ')' expected in parameter list", self.line));
+      }
+      self.next();
+    }
+    self.next();
+    Ok((names, Signature { params: Some(types), variadic }))
+  }
+
+  // Check a redeclaration of function `idx` against what was declared before: the return
+  // type, and the parameters when both declarations specify them
+  fn check_signature(&self, idx: usize, return_type: i32, signature: &Signature) -> Result<(), String> {
+    let unqualified = |t: &i32| t & !CONST;
+    let mut same = unqualified(&self.symbols[idx].type_) == unqualified(&return_type);
+    if let (Some(earlier), Some(params)) = (self.signatures.get(&idx), &signature.params) {
+      if let Some(earlier_params) = &earlier.params {
+        same = same && earlier.variadic == signature.variadic &&
+          earlier_params.iter().map(unqualified).eq(params.iter().map(unqualified));
+      }
+    }
+    if same {
+      Ok(())
+    } else {
+      Err(format!("{}: '{}' does not match its earlier declaration", self.line, self.symbols[idx].name))
+    }
+  }
+
+  // Note that the operand just emitted refers to symbol `idx`, if it is still undefined
+  fn record_link_fixup(&mut self, idx: usize) {
+    if self.symbols[idx].is_extern {
+      self.link_fixups.push((self.vm.le, idx));
+    }
+  }
+
+  // Compile the declarations of another translation unit into this program. `static`
+  // names of the units compiled so far stay private to them.
+  pub fn add_unit(&mut self, source: &str) -> Result<(), String> {
+    for sym in self.symbols.iter_mut() {
+      if sym.is_static {
+        // A zero hash never matches a lexed identifier, so the name is no longer found
+        sym.hash = 0;
+      }
+    }
+    self.source = source.to_string();
+    // The unit has no line marks: its lines are only known by number
+    self.line_origins.clear();
+    self.origin_sources.clear();
+    self.p = 0;
+    self.lp = 0;
+    self.line = 1;
+    self.next();
+    while self.token.kind != TokenKind::Eof {
+      self.compile_global_declaration()?;
+      self.check_lex_error()?;
+    }
+    Ok(())
+  }
+
+  // Resolve references to extern symbols now that all translation units are compiled
+  pub fn link(&mut self) -> Result<(), String> {
+    let mut undefined: Vec<String> = Vec::new();
+    for &(pos, idx) in &self.link_fixups {
+      let sym = &self.symbols[idx];
+      if sym.is_extern {
+        if !undefined.contains(&sym.name) {
+          undefined.push(sym.name.clone());
+        }
+      } else {
+        self.vm.code[pos] = sym.value;
+      }
+    }
+    if !undefined.is_empty() {
+      let names: Vec<String> = undefined.iter().map(|name| format!("undefined symbol '{}'", name)).collect();
+      return Err(names.join("\n"));
+    }
+    self.link_fixups.clear();
+    Ok(())
+  }
 
-    match main_idx {
+  // Names of the functions and globals visible to other translation units
+  pub fn exported_symbols(&self) -> Vec<String> {
+    self.symbols.iter()
+      .filter(|sym| (sym.class == Some(SymbolClass::Function) || sym.class == Some(SymbolClass::Global)) && !sym.is_static && !sym.is_extern)
+      .map(|sym| sym.name.clone())
+      .collect()
+  }
+
+  // Define (or, for the REPL, redefine) a global holding `value` and return its address
+  pub fn define_global(&mut self, name: &str, type_: i32, value: Int) -> Result<Int, String> {
+    let hash = symbol_hash(name);
+    let idx = match self.find_symbol(hash, name) {
       Some(idx) => {
-        println!("Updating main function at index {}", idx);
-        self.symbols[idx].class = TokenType::Fun as i32;
-        self.symbols[idx].type_ = Type::INT as i32;
-        self.symbols[idx].value = self.le as Int;
-      },
-      None => { 
-        // try calculating its hash
-        println!("Main not found by direct lookup, calculating hash");
-        let hash = "main".chars().fold(0i32, |h, c| h.wrapping_mul(147).wrapping_add(c as i32));
-        let hash = (hash << 6).wrapping_add(4); // 4 = "main".len()
-        println!("Calculated hash for 'main': {}", hash);
-
-        if let Some(idx) = self.find_symbol(hash, "main") {
-          println!("Found main with calculated hash at index {}", idx);
-          self.symbols[idx].class = TokenType::Fun as i32;
-          self.symbols[idx].type_ = Type::INT as i32;
-          self.symbols[idx].value = self.le as Int;
-        } else {
-          println!("Main still not found, adding it manually");
-          let idx = self.symbols.len();
-          self.symbols.push(Symbol {
-            token: TokenType::Id as i32,
-            hash,
-            name: "main".to_string(),
-            class: TokenType::Fun as i32,
-            type_: Type::INT as i32,
-            value: self.le as Int,
-            h_class: 0,
-            h_type: 0,
-            h_val: 0,
-          }); 
-          println!("Added main function at index {}", idx);
+        let sym = &self.symbols[idx];
+        if !matches!(sym.class, None | Some(SymbolClass::Global)) {
+          return Err(format!("'{}' is already defined as something other than a global", name));
         }
+        idx
+      },
+      None => {
+        self.symbols.push(Symbol {
+          hash,
+          name: name.to_string(),
+          class: None,
+          type_: 0,
+          value: 0,
+          is_static: false,
+          is_extern: false,
+          h_class: None,
+          h_type: 0,
+          h_val: 0,
+        });
+        self.symbols.len() - 1
       }
-    } 
-
-    println!("Updated symbol table contents:");
-    for (i, sym) in self.symbols.iter().enumerate() {
-      println!("Symbol {}: name={}, token={}, class={}, hash={}", 
-               i, sym.name, sym.token, sym.class, sym.hash);
-    }
+    };
 
-    // Compile main function with correctly classified symbol
-    if let Err(e) = self.compile_function("main", Type::INT as i32) {
-      return Err(format!("Compilation error: {}", e));
-    }
-    
-    Ok(())
+    // A redefinition with the same type reuses the slot so compiled code keeps working
+    let sym = &self.symbols[idx];
+    let addr = if sym.class == Some(SymbolClass::Global) && !sym.is_extern && sym.type_ == type_ {
+      sym.value
+    } else {
+      let addr = self.vm.data_addr(self.data_index);
+      let size = (type_size(type_) as usize).max(WORD_SIZE);
+      self.data_index = (self.data_index + size + WORD_SIZE - 1) & !(WORD_SIZE - 1);
+      addr
+    };
+    let sym = &mut self.symbols[idx];
+    sym.class = Some(SymbolClass::Global);
+    sym.type_ = type_;
+    sym.value = addr;
+    sym.is_extern = false;
+    self.store_data(addr, type_, value)?;
+    Ok(addr)
   }
 
-  //Compile a function
-  fn compile_function(&mut self, name: &str, return_type: i32) -> Result<(), String> {
-    println!("Attempting to compile function: {}", name);
-    let mut func_idx = None;
-    for (i, sym) in self.symbols.iter().enumerate() {
-      if sym.name == name && sym.class == TokenType::Fun as i32 {
-        func_idx = Some(i);
-        println!("Found function '{}' at index {}", name, i);
-        break;
-      } 
-    }
+  // Store a value the compiler places in the data segment (global initializers, switch tables);
+  // a fault there is reported as a compile error, which has no pc
+  fn store_data(&mut self, addr: Int, type_: i32, value: Int) -> Result<(), String> {
+    match store_op(type_) {
+      OpCode::SC => self.vm.store_char(addr, value),
+      OpCode::SS => self.vm.store_short(addr, value),
+      _ => self.vm.store_int(addr, value),
+    }.map_err(|e| e.description())
+  }
 
-    if let Some(idx) = func_idx {
-      let class = self.symbols[idx].class;
-      let value = self.symbols[idx].value;
-      let type_ = self.symbols[idx].type_;
-      println!("Function '{}' class={}, value={}, type={}", name, class, value, type_);
-      
-      if class != TokenType::Fun as i32 {
-        return Err(format!("{}: not a function (class={})", self.line, class));
-      } 
+  // Look up a function, global, constant or syscall by name
+  pub fn lookup(&self, name: &str) -> Option<SymbolInfo> {
+    let idx = self.find_symbol(symbol_hash(name), name)?;
+    let sym = &self.symbols[idx];
+    let class = match sym.class {
+      None | Some(SymbolClass::Keyword) => return None,
+      Some(class) => class,
+    };
+    let (address, value) = if sym.class == Some(SymbolClass::Global) && !sym.is_extern {
+      let value = match load_op(sym.type_) {
+        OpCode::LC => self.vm.load_char(sym.value),
+        OpCode::LCU => self.vm.load_char(sym.value).map(|v| v as u8 as Int),
+        OpCode::LS => self.vm.load_short(sym.value),
+        OpCode::LSU => self.vm.load_short(sym.value).map(|v| v as u16 as Int),
+        _ => self.vm.load_int(sym.value),
+      };
+      (Some(sym.value), value.ok()?)
+    } else {
+      (None, sym.value)
+    };
+    Some(SymbolInfo { name: sym.name.clone(), class, type_: sym.type_, address, value })
+  }
 
-      println!("Emitting function header");
-      self.emit(OpCode::ENT);
-      self.emit_with_operand(OpCode::IMM, return_type.into());
-      self.emit_with_operand(OpCode::IMM, value);
+  // Describe the functions, globals and strings compiled so far
+  pub fn program(&self) -> Program {
+    let mut functions: Vec<FunctionInfo> = self.symbols.iter().enumerate()
+      .filter(|(_, sym)| sym.class == Some(SymbolClass::Function) && !sym.is_extern)
+      .map(|(i, sym)| FunctionInfo {
+        name: sym.name.clone(),
+        entry: sym.value as usize,
+        size: 0,
+        params: self.function_params.get(&i).cloned().unwrap_or_default(),
+        variadic: self.signatures.get(&i).is_some_and(|sig| sig.variadic),
+      })
+      .collect();
+    functions.sort_by_key(|func| func.entry);
+    for i in 0..functions.len() {
+      let end = functions.get(i + 1).map_or(self.vm.le + 1, |next| next.entry);
+      functions[i].size = end.saturating_sub(functions[i].entry);
+    }
 
-      // Compile function body
-      println!("Compiling function body");
-      self.loc = self.le as Int;
-      println!("Searching for function body in source");
-      let target = name;
-      let mut found = false;
+    let mut globals: Vec<GlobalInfo> = self.symbols.iter()
+      .filter(|sym| sym.class == Some(SymbolClass::Global) && !sym.is_extern)
+      .map(|sym| GlobalInfo { name: sym.name.clone(), address: sym.value, type_: sym.type_, size: type_size(sym.type_) })
+      .collect();
+    globals.sort_by_key(|global| global.address);
 
-      while self.p < self.source.len() {
-        let ch = self.current_char();
-        if ch == '/' && self.p+1 < self.source.len() && self.source.chars().nth(self.p+1) == Some('/') {
-          while self.p < self.source.len() && self.current_char() != '\n' {
-            self.p += 1;
-          } 
-          if self.p < self.source.len() {
-            self.p += 1; 
-          }
-          continue;
-        }
-        if ch.is_whitespace() {
-          self.p += 1;
-          continue;
-        }
-        if self.p + target.len() <= self.source.len() {
-          let potential_match = &self.source[self.p..self.p+target.len()];
-          if potential_match == target {
-            println!("Found function '{}' in source at pos {}", target, self.p);
-            found = true;
-            self.p += target.len();
-            break;
-          } 
-        }
-        self.p += 1;
-      }
+    let strings = self.string_literals.iter()
+      .map(|&(address, len)| {
+        let start = (address - self.vm.data_base) as usize;
+        StringInfo { address, value: String::from_utf8_lossy(&self.vm.data[start..start + len]).into_owned() }
+      })
+      .collect();
 
-      self.p = 0;
-      println!("Trying alternate search method for function body");
-      let int_main_pattern = "int main";
-      found = false;
+    Program { functions, globals, strings }
+  }
 
-      // Find "int main" in the source file of c
-      while self.p + int_main_pattern.len() <= self.source.len() {
-        if &self.source[self.p..self.p+int_main_pattern.len()] == int_main_pattern {
-          println!("Found 'int main' at position {}", self.p);
-          found = true;
-          while self.p < self.source.len() && self.current_char() != '{' {
-            self.p += 1;
-          } 
-          if self.p < self.source.len() {
-            println!("Found opening brace at position {}", self.p);
-            break;
-          } else {
-            found = false;
-          }
+  // Compile the body of function `idx` from its `{` to after its `}`: the frame setup, the
+  // statements and a final LEV for control that runs off the end. The caller pushes the
+  // arguments in order, so with n parameters, parameter i sits at bp[n + 1 - i] (`LEA loc - i`
+  // with loc = n + 1) and locals follow at negative offsets. A variadic function's arguments
+  // move with the count its caller passed, so its named parameters are copied into locals on
+  // entry instead.
+  fn compile_function(&mut self, idx: usize, params: &[Option<usize>], types: &[i32], variadic: bool) -> Result<(), String> {
+    self.symbols[idx].value = (self.vm.le + 1) as Int;
+    self.return_type = self.symbols[idx].type_;
+    self.emit_with_operand(OpCode::ENT, 0);
+    let frame = self.vm.le;
+    self.local_count = 0;
+    self.frame_locals.clear();
+    self.loc = if variadic { 1 } else { params.len() as Int + 1 };
+    for (i, (&param, &ty)) in params.iter().zip(types).enumerate() {
+      if let Some(param) = param {
+        if self.in_current_scope(param) {
+          self.restore_locals();
+          return Err(format!("{}: duplicate parameter '{}'", self.line, self.symbols[param].name));
         }
-        self.p += 1;
-      }  
-      if !found { 
-        println!("WARNING: Couldn't find function body in source");
-      } else {
-        while self.p < self.source.len() && self.current_char() != '{' {
-          self.p += 1;
-        } 
-        if self.p < self.source.len(){
-          println!("Found opening brace at pos {}", self.p);
-          self.p += 1;
-          while self.p < self.source.len() && self.current_char() != '}' {
-            if self.current_char() == 'r' && 
-              self.p + 6 <= self.source.len() && 
-              &self.source[self.p..self.p+6] == "return" {
-              println!("Found return statement at pos {}", self.p);
-              self.p += 6; 
-              while self.p < self.source.len() && self.current_char().is_whitespace() {
-                self.p += 1;
-              } 
-              if self.p < self.source.len() && self.current_char().is_digit(10) {
-                let ret_val = self.current_char() as i32 - '0' as i32;
-                println!("Return value: {}", ret_val);
-                self.emit_with_operand(OpCode::IMM, ret_val.into());
-                println!("Emitting IMM with return value {}", ret_val);
-                while self.p < self.source.len() && self.current_char() != ';' {
-                  self.p += 1;
-                }
-              } else {
-                self.emit_with_operand(OpCode::IMM, 0);
-                println!("Emitting default return value 0");
-              }
-              // Emit LEV instruction
-              println!("Emitting LEV instruction");
-              self.emit(OpCode::LEV);
-              break;
-            }
-
-            self.p += 1;
-          }  
+        self.declare_local(param);
+        let sym = &mut self.symbols[param];
+        sym.class = Some(SymbolClass::Local);
+        sym.type_ = ty;
+        if variadic {
+          self.local_count += 1;
+          self.symbols[param].value = self.loc + self.local_count;
+          self.emit_with_operand(OpCode::LEA, -self.local_count);
+          self.emit(OpCode::PSH);
+          self.emit_with_operand(OpCode::LEA, 2);
+          self.emit(OpCode::LI);
+          self.emit(OpCode::PSH);
+          self.emit_with_operand(OpCode::IMM, i as Int);
+          self.emit_variadic_load();
+          self.emit_store(ty);
+        } else {
+          self.symbols[param].value = i as Int;
         }
-      } 
-
-      if self.e[self.le] != OpCode::LEV as Int {
-        println!("Adding implicit return (LEV)");
-        self.emit(OpCode::LEV);
+        let slot = self.loc - self.symbols[param].value;
+        self.frame_locals.push((self.symbols[param].name.clone(), slot, ty));
       }
-    } else {
-      return Err(format!("{}: undefined function", self.line));
     }
+    let result = self.compile_block();
+    self.emit(OpCode::LEV);
+    self.vm.code[frame] = self.local_count;
+    let locals = std::mem::take(&mut self.frame_locals);
+    self.function_locals.insert(idx, locals);
+    self.restore_locals();
+    result
+  }
 
-    println!("Function compilation complete");
-    Ok(())
+  // With the variadic call's argument count pushed and an argument index in ax, load that
+  // argument: bp[2] holds the count and argument i sits at bp[2 + count - i]
+  fn emit_variadic_load(&mut self) {
+    self.emit(OpCode::SUB);
+    self.emit(OpCode::PSH);
+    self.emit_with_operand(OpCode::IMM, WORD_SIZE as Int);
+    self.emit(OpCode::MUL);
+    self.emit(OpCode::PSH);
+    self.emit_with_operand(OpCode::LEA, 2);
+    self.emit(OpCode::ADD);
+    self.emit(OpCode::LI);
   }
 
   //Complie a block
-  fn compile_block(&mut self) -> Result<(), String> {
-      println!("Compiling block, current token: {}", self.token);
+  pub fn compile_block(&mut self) -> Result<(), String> {
+      if self.debug {
+        eprintln!("Compiling block, current token: {:?}", self.token.kind);
+      }
         
-        if self.token == '{' as i32 {
-            println!("Found opening brace, skipping");
+        if self.token.kind == TokenKind::Punct('{') {
+            if self.debug {
+                eprintln!("Found opening brace, skipping");
+            }
             self.next();
         }
+        self.scopes.push(self.locals.len());
         
-        while self.token != '}' as i32 && self.token != 0 {
-            println!("Block statement token: {}", self.token);
+        while self.token.kind != TokenKind::Punct('}') && self.token.kind != TokenKind::Eof {
+            if self.debug {
+                eprintln!("Block statement token: {:?}", self.token.kind);
+            }
             
-            if self.token == TokenType::Return as i32 {
-                println!("Found return statement");
-                self.next(); 
-                
-                if self.token != ';' as i32 {
-                    println!("Parsing return expression");
-                    if let Err(e) = self.expr(TokenType::Assign as i32) {
-                        return Err(format!("Error in return expression: {}", e));
-                    }
+            if self.token.kind == TokenKind::Keyword(TokenType::Return) {
+                if self.debug {
+                    eprintln!("Found return statement");
                 }
-                
-              
-                if self.token == ';' as i32 {
-                    println!("Skipping semicolon");
-                    self.next();
-                }
-                
-                println!("Emitting return instruction (LEV)");
-                self.emit(OpCode::LEV);
+                self.compile_return_statement()?;
+            } else if self.is_type_start() {
+                self.compile_local_declaration()?;
+            } else if self.token.kind == TokenKind::Keyword(TokenType::Enum) {
+                self.compile_enum(true)?;
+            } else if self.token.kind == TokenKind::Punct('{') {
+                self.compile_block()?;
             } else {
-                println!("Skipping unknown statement");
-                self.next();
+                self.compile_statement()?;
             }
         }
         
-        if self.token == '}' as i32 {
-            println!("Found closing brace, skipping");
+        if self.token.kind == TokenKind::Punct('}') {
+            if self.debug {
+                eprintln!("Found closing brace, skipping");
+            }
             self.next();
         }
+        if let Some(scope) = self.scopes.pop() {
+            self.restore_scope(scope);
+        }
         
         Ok(())
   }
+    // Compile `type name [= expr] {, name [= expr]} ;` inside a function body: each name gets
+    // the next stack slot below bp and initializers are stored into it
+    fn compile_local_declaration(&mut self) -> Result<(), String> {
+        let base_type = self.parse_base_type();
+        loop {
+            let mut ty = base_type;
+            while self.token.kind == TokenKind::Op(TokenType::Mul) {
+                self.next();
+                ty += Type::PTR as i32;
+            }
+            let TokenKind::Ident(SymbolId(idx)) = self.token.kind else {
+                return Err(format!("{}: bad local declaration", self.line));
+            };
+            if self.in_current_scope(idx) {
+                return Err(format!("{}: duplicate local declaration '{}'", self.line, self.symbols[idx].name));
+            }
+            if is_void(ty) {
+                return Err(format!("{}: variable '{}' declared void", self.line, self.symbols[idx].name));
+            }
+            self.next();
+
+            // Shadow whatever the name meant outside the block until it ends. Slots are not
+            // reused after a block ends, so the frame holds every local of the function.
+            self.local_count += 1;
+            self.declare_local(idx);
+            let sym = &mut self.symbols[idx];
+            sym.class = Some(SymbolClass::Local);
+            sym.type_ = ty;
+            sym.value = self.loc + self.local_count;
+            self.frame_locals.push((sym.name.clone(), -self.local_count, ty));
+
+            if self.token.kind == TokenKind::Op(TokenType::Assign) {
+                self.next();
+                self.emit_with_operand(OpCode::LEA, -self.local_count);
+                self.emit(OpCode::PSH);
+                self.operand(TokenType::Assign)?;
+                self.emit_store(ty);
+            }
+            if self.token.kind == TokenKind::Punct(',') {
+                self.next();
+            } else {
+                break;
+            }
+        }
+        if self.token.kind != TokenKind::Punct(';') {
+            return Err(format!("{}: semicolon expected after declaration", self.line));
+        }
+        self.next();
+        Ok(())
+    }
+
+    // Compile `expr ;` (`f(x);`, `x = y;`, `*p = v;`), discarding the value left in ax. The
+    // value may be void, so it is parsed with expr rather than operand.
+    fn compile_expression_statement(&mut self) -> Result<(), String> {
+        self.expr(TokenType::Assign)?;
+        if self.token.kind != TokenKind::Punct(';') {
+            return Err(format!("{}: semicolon expected after expression", self.line));
+        }
+        self.next();
+        Ok(())
+    }
+
+    // Start shadowing symbol `idx` in the current block: its meaning so far moves to the h_*
+    // fields and the caller gives it the new one
+    fn declare_local(&mut self, idx: usize) {
+        let sym = &mut self.symbols[idx];
+        self.locals.push((idx, sym.h_class, sym.h_type, sym.h_val));
+        sym.h_class = sym.class;
+        sym.h_type = sym.type_;
+        sym.h_val = sym.value;
+    }
+
+    // Whether symbol `idx` was already declared in the current block. Parameters share the
+    // scope of the function's outermost block, as in C.
+    fn in_current_scope(&self, idx: usize) -> bool {
+        let start = if self.scopes.len() > 1 { self.scopes[self.scopes.len() - 1] } else { 0 };
+        self.locals[start..].iter().any(|&(local, ..)| local == idx)
+    }
+
+    // Give the locals declared since `scope` back their outer meaning, innermost first
+    fn restore_scope(&mut self, scope: usize) {
+        let scope = scope.min(self.locals.len());
+        for (idx, h_class, h_type, h_val) in self.locals.split_off(scope).into_iter().rev() {
+            let sym = &mut self.symbols[idx];
+            sym.class = sym.h_class;
+            sym.type_ = sym.h_type;
+            sym.value = sym.h_val;
+            sym.h_class = h_class;
+            sym.h_type = h_type;
+            sym.h_val = h_val;
+        }
+    }
+
+    // Give every local of the finished function back its outer meaning
+    pub fn restore_locals(&mut self) {
+        self.restore_scope(0);
+        self.scopes.clear();
+        self.local_count = 0;
+    }
+
   // Compile a statement
-    fn compile_statement(&mut self) -> Result<(), String> {
-        if self.token == TokenType::If as i32 {
+    pub fn compile_statement(&mut self) -> Result<(), String> {
+        if self.token.kind == TokenKind::Keyword(TokenType::If) {
             self.compile_if_statement()?;
         }
-        else if self.token == TokenType::While as i32 {
+        else if self.token.kind == TokenKind::Keyword(TokenType::While) {
             self.compile_while_statement()?;
         }
-        else if self.token == TokenType::Return as i32 {
+        else if self.token.kind == TokenKind::Keyword(TokenType::Return) {
             self.compile_return_statement()?;
         }
-        else if self.token == TokenType::Fun as i32 {
+        else if self.token.kind == TokenKind::Keyword(TokenType::Switch) {
+            self.compile_switch_statement()?;
+        }
+        else if self.token.kind == TokenKind::Keyword(TokenType::Case) || self.token.kind == TokenKind::Keyword(TokenType::Default) {
+            self.compile_case_label()?;
+        }
+        else if self.token.kind == TokenKind::Keyword(TokenType::Break) {
+            self.compile_break_statement()?;
+        }
+        else if self.token.kind == TokenKind::Keyword(TokenType::Fun) {
             self.compile_function_definition()?;
         }
-        else if self.token == TokenType::Id as i32 {
-            self.compile_assignment()?;
+        else if self.token.kind == TokenKind::Punct('{') {
+            self.compile_block()?;
+        }
+        else if self.token.kind == TokenKind::Punct(';') {
+            // Null statement
+            self.next();
         }
         else {
-            return Err(format!("{}: unexpected statement", self.line));
+            self.compile_expression_statement()?;
         }
 
         Ok(())
     }
 
-    // Compile an if statement
+    // Compile `if (cond) stmt [else stmt]`. BZ skips the then branch when cond is zero; with
+    // an else, the then branch ends in a JMP over it. `else if` is just an if statement as
+    // the else branch, so chains and nesting of any depth come from the recursion.
     fn compile_if_statement(&mut self) -> Result<(), String> {
+        self.next();
+        self.compile_condition("if")?;
+        let else_jump = self.emit_with_operand(OpCode::BZ, 0);
+        self.compile_statement()?;
+
+        if self.token.kind == TokenKind::Keyword(TokenType::Else) {
+            self.next();
+            let end_jump = self.emit_with_operand(OpCode::JMP, 0);
+            self.patch_jump(else_jump);
+            self.compile_statement()?;
+            self.patch_jump(end_jump);
+        } else {
+            self.patch_jump(else_jump);
+        }
+        Ok(())
+    }
+
+    // Compile `while (cond) stmt`: the condition is re-evaluated before each iteration and BZ
+    // leaves the loop, the body jumps back to the condition
+    fn compile_while_statement(&mut self) -> Result<(), String> {
+        self.next();
+        let loop_start = (self.vm.le + 1) as Int;
+        self.compile_condition("while")?;
+        let exit_jump = self.emit_with_operand(OpCode::BZ, 0);
+        self.compile_statement()?;
+        self.emit_with_operand(OpCode::JMP, loop_start);
+        self.patch_jump(exit_jump);
+        Ok(())
+    }
+
+    // Compile the parenthesized condition of an if or while statement into ax
+    fn compile_condition(&mut self, statement: &str) -> Result<(), String> {
+        if self.token.kind != TokenKind::Punct('(') {
+            return Err(format!("{}: open paren expected in {} statement", self.line, statement));
+        }
+        self.next();
+        if let Err(e) = self.operand(TokenType::Assign) {
+            return Err(format!("{}: error in {} condition: {}", self.line, statement, e));
+        }
+        if self.token.kind != TokenKind::Punct(')') {
+            return Err(format!("{}: close paren expected in {} statement", self.line, statement));
+        }
+        self.next();
+        Ok(())
+    }
+
+    // Compile a return statement
+    fn compile_return_statement(&mut self) -> Result<(), String> {
+        self.next(); 
+
+        // Compile return expression
+        if self.token.kind != TokenKind::Punct(';') {
+            if is_void(self.return_type) {
+                return Err(format!("{}: void function returns a value", self.line));
+            }
+            let start = self.vm.le;
+            if let Err(e) = self.operand(TokenType::Assign) {
+                return Err(format!("{}: error in return expression: {}", self.line, e));
+            }
+            self.convert_return_value(start)?;
+        }
+        if self.token.kind != TokenKind::Punct(';') {
+            return Err(format!("{}: semicolon expected after return", self.line));
+        }
+        self.next();
+
+        self.emit(OpCode::LEV);
+        Ok(())
+    }
+
+    // Check the value in ax (compiled from code address `start` on) against the function's
+    // return type: pointers and integers do not mix, except that 0 is a null pointer, and
+    // values returned as char or short are narrowed the way storing them would
+    fn convert_return_value(&mut self, start: usize) -> Result<(), String> {
+        let to = self.return_type;
+        let from = self.type_;
+        if type_base(to) >= Type::PTR as i32 {
+            let null = self.vm.le == start + 2 && self.is_null_constant(self.vm.le);
+            if type_base(from) < Type::PTR as i32 && !null {
+                return Err(format!("{}: returning an integer from a function returning a pointer", self.line));
+            }
+        } else if type_base(from) >= Type::PTR as i32 {
+            return Err(format!("{}: returning a pointer from a function returning an integer", self.line));
+        } else if type_base(to) < Type::PTR as i32 {
+            self.emit_extend(to);
+        }
+        self.type_ = to;
+        Ok(())
+    }
+
+    // Compile a switch statement. The body is emitted first, followed by the dispatch code:
+    // a JMPI jump table when the case values are dense, a compare chain otherwise.
+    fn compile_switch_statement(&mut self) -> Result<(), String> {
+        self.next();
+        if self.token.kind != TokenKind::Punct('(') {
+            return Err(format!("{}: open paren expected in switch statement", self.line));
+        }
+        self.next();
+        self.operand(TokenType::Assign)?;
+        if self.token.kind != TokenKind::Punct(')') {
+            return Err(format!("{}: close paren expected in switch statement", self.line));
+        }
+        self.next();
+        if self.token.kind != TokenKind::Punct('{') {
+            return Err(format!("{}: open brace expected in switch statement", self.line));
+        }
+        self.next();
+
+        // The switch value stays in ax while jumping over the body to the dispatch code
+        let dispatch_jump = self.emit_with_operand(OpCode::JMP, 0);
+        self.switch_stack.push(SwitchCases::default());
+        self.break_stack.push(Vec::new());
+        while self.token.kind != TokenKind::Punct('}') {
+            if self.token.kind == TokenKind::Eof {
+                return Err(format!("{}: unexpected end of file in switch statement", self.line));
+            }
+            self.compile_statement()?;
+        }
+        self.next();
+        let end_jump = self.emit_with_operand(OpCode::JMP, 0);
+        let mut end_jumps = self.break_stack.pop().unwrap_or_default();
+        end_jumps.push(end_jump);
+        let labels = self.switch_stack.pop().unwrap_or_default();
+
+        self.patch_jump(dispatch_jump);
+        if let Some((min, len)) = jump_table_range(&labels.cases) {
+            // Table layout in the data segment: min, len, default target, then one target per value
+            let table = self.vm.data_addr(self.data_index);
+            self.data_index += (3 + len) * WORD_SIZE;
+            self.emit_with_operand(OpCode::JMPI, table);
+            let end = (self.vm.le + 1) as Int;
+            let default = labels.default.map_or(end, |addr| addr as Int);
+            let mut words = vec![min, len as Int, default];
+            words.resize(3 + len, default);
+            for &(value, addr) in &labels.cases {
+                words[3 + (value - min) as usize] = addr as Int;
+            }
+            for (i, word) in words.into_iter().enumerate() {
+                self.store_data(table + (i * WORD_SIZE) as Int, Type::INT as i32, word)?;
+            }
+        } else {
+            // ax holds (value - previous case); subtracting the difference to the next case
+            // keeps it zero exactly when the switch value matches that case
+            let mut previous = 0;
+            for &(value, addr) in &labels.cases {
+                self.emit(OpCode::PSH);
+                self.emit_with_operand(OpCode::IMM, value.wrapping_sub(previous));
+                self.emit(OpCode::SUB);
+                self.emit_with_operand(OpCode::BZ, addr as Int);
+                previous = value;
+            }
+            if let Some(addr) = labels.default {
+                self.emit_with_operand(OpCode::JMP, addr as Int);
+            }
+        }
+
+        for pos in end_jumps {
+            self.patch_jump(pos);
+        }
+        Ok(())
+    }
+
+    // Compile a `case value:` or `default:` label inside a switch body
+    fn compile_case_label(&mut self) -> Result<(), String> {
+        let is_default = self.token.kind == TokenKind::Keyword(TokenType::Default);
+        self.next();
+        let value = if is_default { 0 } else { self.const_expr(1)? };
+        if self.token.kind != TokenKind::Punct(':') {
+            return Err(format!("{}: colon expected after case label", self.line));
+        }
+        self.next();
+
+        let addr = self.vm.le + 1;
+        let line = self.line;
+        let labels = match self.switch_stack.last_mut() {
+            Some(labels) => labels,
+            None => return Err(format!("{}: case label outside of switch", line)),
+        };
+        if is_default {
+            if labels.default.is_some() {
+                return Err(format!("{}: duplicate default label", line));
+            }
+            labels.default = Some(addr);
+        } else {
+            if labels.cases.iter().any(|&(v, _)| v == value) {
+                return Err(format!("{}: duplicate case value {}", line, value));
+            }
+            labels.cases.push((value, addr));
+        }
+        Ok(())
+    }
+
+    // Compile `break;`, jumping to the end of the innermost switch
+    fn compile_break_statement(&mut self) -> Result<(), String> {
+        self.next();
+        if self.token.kind != TokenKind::Punct(';') {
+            return Err(format!("{}: semicolon expected after break", self.line));
+        }
+        self.next();
+        let pos = self.emit_with_operand(OpCode::JMP, 0);
+        match self.break_stack.last_mut() {
+            Some(jumps) => jumps.push(pos),
+            None => return Err(format!("{}: break outside of switch", self.line)),
+        }
+        Ok(())
+    }
+
+    // Compile a function definition
+    fn compile_function_definition(&mut self) -> Result<(), String> {
+        self.next(); 
+
+        if !matches!(self.token.kind, TokenKind::Ident(_)) {
+            return Err(format!("{}: function name expected", self.line));
+        }
+        let _name = self.source_span(self.lp, self.p);
         self.next(); 
-        if self.token != '(' as i32 {
-            return Err(format!("{}: open paren expected in if statement", self.line));
+
+        if self.token.kind != TokenKind::Keyword(TokenType::Char) {
+            return Err(format!("{}: return type expected", self.line));
         }
+        let return_type = Type::CHAR as Int;
         self.next();
 
-        // Compile condition
-        if let Err(e) = self.expr(TokenType::Assign as i32) {
-            return Err(format!("{}: error in if condition: {}", self.line, e));
-        }
+        if let Err(e) = self.compile_block() {
+            return Err(format!("{}: error in function body: {}", self.line, e));
+        }
+
+        self.emit(OpCode::FUN);
+        self.emit_with_operand(OpCode::IMM, return_type);
+        self.emit_with_operand(OpCode::IMM, self.loc as Int);
+
+        Ok(())
+    }
+
+    // Set up registers and the stack to start executing at `entry`; returning from the
+    // entry function halts the VM with the returned value as exit code
+    pub fn start(&mut self, entry: usize) {
+        self.start_with_args(entry, &[]);
+    }
+
+    // Like `start`, but main is called as main(argc, argv) with `args` copied onto the top of
+    // the stack as C strings, as c4 does with its command line
+    pub fn start_with_args(&mut self, entry: usize, args: &[String]) {
+        self.sync_functions();
+        self.vm.start_with_args(entry, args);
+    }
+
+    // Give the VM the names of the compiled functions, for its fault sites and profiles
+    fn sync_functions(&mut self) {
+        let functions = self.program().functions().iter().map(|func| (func.entry, func.name.clone())).collect();
+        self.vm.set_functions(functions);
+    }
+
+    // The VM the program runs on, for hosts that drive it directly
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+
+    // Hand the compiled program's VM over, e.g. to run it after the compiler is dropped
+    pub fn into_vm(mut self) -> Vm {
+        self.sync_functions();
+        self.vm
+    }
+
+    // The running API lives on the Vm; C4 forwards it so an embedder can keep driving one object
+
+    pub fn mount_file(&mut self, path: &str, contents: &[u8]) {
+        self.vm.mount_file(path, contents);
+    }
+
+    pub fn unmount_all(&mut self) {
+        self.vm.unmount_all();
+    }
+
+    pub fn set_sandbox(&mut self, policy: Option<SandboxPolicy>) {
+        self.vm.set_sandbox(policy);
+    }
+
+    pub fn set_environment(&mut self, vars: Option<HashMap<String, String>>) {
+        self.vm.set_environment(vars);
+    }
+
+    pub fn set_stdin(&mut self, source: Box<dyn Read>) {
+        self.vm.set_stdin(source);
+    }
+
+    pub fn set_stdout(&mut self, sink: Box<dyn Write>) {
+        self.vm.set_stdout(sink);
+    }
+
+    pub fn set_stderr(&mut self, sink: Box<dyn Write>) {
+        self.vm.set_stderr(sink);
+    }
+
+    pub fn set_stdio(&mut self, stdin: Box<dyn Read>, stdout: Box<dyn Write>, stderr: Box<dyn Write>) {
+        self.vm.set_stdio(stdin, stdout, stderr);
+    }
+
+    pub fn set_stack_size(&mut self, bytes: usize) -> Result<(), String> {
+        self.vm.set_stack_size(bytes)
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.vm.stack_size()
+    }
+
+    pub fn load_assembly(&mut self, text: &str) -> Result<usize, String> {
+        self.vm.load_assembly(text)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.vm.snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        self.vm.restore(snapshot)
+    }
+
+    pub fn run_for(&mut self, max_cycles: Int) -> Result<RunState, RuntimeError> {
+        self.vm.run_for(max_cycles)
+    }
+
+    pub fn watch(&mut self, addr: Int, len: usize) {
+        self.vm.watch(addr, len);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.vm.clear_watchpoints();
+    }
+
+    pub fn set_retire_log_size(&mut self, n: usize) {
+        self.vm.set_retire_log_size(n);
+    }
+
+    pub fn retired(&self) -> &VecDeque<Retired> {
+        self.vm.retired()
+    }
 
-        let condition_type = self.type_;
-        if condition_type != Type::INT as i32 {
-            return Err(format!("{}: if condition must be of type int", self.line));
-        }
+    pub fn retirement_dump(&self) -> String {
+        self.vm.retirement_dump()
+    }
 
-        let jump_address = self.le + 1;
-        self.emit(OpCode::BZ);
-        self.emit_with_operand(OpCode::IMM, 0);
+    pub fn set_profiling(&mut self, on: bool) {
+        self.vm.set_profiling(on);
+    }
 
-        // Compile then block
-        if let Err(e) = self.compile_block() {
-            return Err(format!("{}: error in then block: {}", self.line, e));
-        }
+    pub fn set_fusion(&mut self, on: bool) {
+        self.vm.set_fusion(on);
+    }
 
-        // Compile else block
-        if self.token == TokenType::Else as i32 {
-            self.next(); 
-            let _else_address = self.le + 1;
-            self.emit(OpCode::JMP);
-            self.emit_with_operand(OpCode::IMM, 0);
+    pub fn profile(&self) -> Option<Profile> {
+        self.vm.profile()
+    }
 
-           
-            if let Err(e) = self.compile_block() {
-                return Err(format!("{}: error in then block: {}", self.line, e));
-            }
+    pub fn heap_leaks(&self) -> Vec<HeapLeak> {
+        self.vm.heap_leaks()
+    }
 
-            self.e[jump_address] = self.le as Int;
-        } else {
-            self.e[jump_address] = self.le as Int;
-        }
+    pub fn leak_report(&self) -> String {
+        self.vm.leak_report()
+    }
 
-        Ok(())
+    pub fn set_trace_interval(&mut self, interval: Int) {
+        self.vm.set_trace_interval(interval);
     }
 
-    // Compile a while statement
-    fn compile_while_statement(&mut self) -> Result<(), String> {
-        self.next(); 
-        if self.token != '(' as i32 {
-            return Err(format!("{}: open paren expected in while statement", self.line));
-        }
-        self.next(); 
+    pub fn trace(&self) -> &[TraceCheckpoint] {
+        self.vm.trace()
+    }
 
-        if let Err(e) = self.expr(TokenType::Assign as i32) {
-            return Err(format!("{}: error in while condition: {}", self.line, e));
-        }
+    pub fn trace_text(&self) -> String {
+        self.vm.trace_text()
+    }
 
-        let condition_type = self.type_;
-        if condition_type != Type::INT as i32 {
-            return Err(format!("{}: while condition must be of type int", self.line));
-        }
+    pub fn compare_trace(&self, golden: &str) -> Result<usize, String> {
+        self.vm.compare_trace(golden)
+    }
 
-        let loop_address = self.le + 1;
-        self.emit(OpCode::BZ);
-        self.emit_with_operand(OpCode::IMM, 0);
+    pub fn usage(&self) -> ResourceUsage {
+        self.vm.usage()
+    }
 
-        // Compile body
-        if let Err(e) = self.compile_block() {
-            return Err(format!("{}: error in while body: {}", self.line, e));
-        }
+    pub fn run_async(&mut self, slice: Int, token: CancellationToken) -> RunAsync<'_> {
+        self.vm.run_async(slice, token)
+    }
 
-        // Compile end of loop
-        self.emit(OpCode::JMP);
-        self.emit_with_operand(OpCode::IMM, loop_address as Int);
+    pub fn set_cycle_limit(&mut self, limit: Option<Int>) {
+        self.vm.set_cycle_limit(limit);
+    }
 
-        self.e[loop_address] = self.le as Int;
+    // Watch a global variable by name and return its address
+    pub fn watch_global(&mut self, name: &str) -> Result<Int, String> {
+        let global = self.program().globals().iter().find(|global| global.name == name).cloned()
+            .ok_or(format!("no global variable '{}'", name))?;
+        self.watch(global.address, global.size as usize);
+        Ok(global.address)
+    }
 
-        Ok(())
+    // Watch a parameter or local of the function the program is paused in, in its current
+    // frame, and return its address. The watch stays on that address after the function returns.
+    pub fn watch_local(&mut self, name: &str) -> Result<Int, String> {
+        let function = self.symbols.iter().enumerate()
+            .filter(|(_, sym)| sym.class == Some(SymbolClass::Function) && !sym.is_extern && sym.value >= 1 && sym.value as usize <= self.vm.pc)
+            .max_by_key(|(_, sym)| sym.value)
+            .map(|(i, _)| i)
+            .ok_or("not paused inside a function".to_string())?;
+        let &(_, slot, ty) = self.function_locals.get(&function)
+            .and_then(|locals| locals.iter().find(|local| local.0 == name))
+            .ok_or(format!("no local variable '{}' in {}", name, self.symbols[function].name))?;
+        let addr = self.vm.bp + slot * WORD_SIZE as Int;
+        self.watch(addr, type_size(ty) as usize);
+        Ok(addr)
     }
 
-    // Compile a return statement
-    fn compile_return_statement(&mut self) -> Result<(), String> {
-        self.next(); 
+    // Check structural invariants of the emitted code: every word decodes, operand-taking
+    // instructions have their operand, branches land on instructions and calls on function
+    // entries. Returns one message per violation.
+    pub fn verify_code(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        let mut starts = vec![false; self.vm.le + 2];
+        let mut branches = Vec::new();
+        let mut pc = 1;
+        while pc <= self.vm.le {
+            starts[pc] = true;
+            let op = match OpCode::from_int(self.vm.code[pc]) {
+                Some(op) => op,
+                None => {
+                    violations.push(format!("{}: invalid opcode {}", pc, self.vm.code[pc]));
+                    pc += 1;
+                    continue;
+                }
+            };
+            if op.has_operand() {
+                if pc + 1 > self.vm.le {
+                    violations.push(format!("{}: {:?} is missing its operand", pc, op));
+                    break;
+                }
+                branches.push((pc, op, self.vm.code[pc + 1]));
+                pc += 2;
+            } else {
+                pc += 1;
+            }
+        }
 
-        // Compile return expression
-        if self.token != ';' as i32 {
-            if let Err(e) = self.expr(TokenType::Assign as i32) {
-                return Err(format!("{}: error in return expression: {}", self.line, e));
+        let entries: Vec<Int> = self.symbols.iter()
+            .filter(|sym| sym.class == Some(SymbolClass::Function) && !sym.is_extern)
+            .map(|sym| sym.value)
+            .collect();
+        let is_instruction = |target: Int| target >= 1 && target <= self.vm.le as Int && starts[target as usize];
+        for (pc, op, target) in branches {
+            match op {
+                OpCode::JMP | OpCode::BZ | OpCode::BNZ if !is_instruction(target) => {
+                    violations.push(format!("{}: {:?} target {} is not an instruction", pc, op, target));
+                },
+                OpCode::JSR if !entries.contains(&target) => {
+                    violations.push(format!("{}: JSR target {} is not a function entry", pc, target));
+                },
+                OpCode::JMPI => {
+                    let len = self.vm.load_int(target + WORD_SIZE as Int).unwrap_or(-1);
+                    if len < 0 {
+                        violations.push(format!("{}: JMPI table {} is out of range", pc, target));
+                        continue;
+                    }
+                    for slot in 2..3 + len {
+                        match self.vm.load_int(target + slot * WORD_SIZE as Int) {
+                            Ok(dest) if is_instruction(dest) => {},
+                            _ => violations.push(format!("{}: JMPI table entry {} is not an instruction", pc, slot - 3)),
+                        }
+                    }
+                },
+                _ => {},
             }
+        }
+        violations
+    }
 
-            let return_type = self.type_;
-            if return_type != Type::INT as i32 {
-                return Err(format!("{}: return type must be int", self.line));
+    // Run the pass pipeline over the code compiled so far. Passes rewrite instructions in
+    // place (padding with NOPs), so code addresses, jump tables and function pointers stay
+    // valid. Call it after compiling and before start(). Returns each pass with the number of
+    // instructions it rewrote.
+    pub fn optimize(&mut self) -> Vec<(&'static str, usize)> {
+        let mut stats = Vec::new();
+        self.opt_applications = 0;
+        for name in self.pass_pipeline() {
+            let rewrites = match name {
+                "fold" => self.fold_constants(),
+                "peephole" => self.peephole(),
+                "dce" => self.eliminate_dead_code(),
+                "inline" => self.inline_calls(),
+                _ => self.thread_jumps(),
+            };
+            if self.debug {
+                eprintln!("pass {}: {} rewrites", name, rewrites);
             }
+            stats.push((name, rewrites));
+        }
+        if self.opt_bisect_limit.is_some() {
+            eprintln!("BISECT: {} pass applications in total", self.opt_applications);
         }
+        stats
+    }
 
-        self.emit(OpCode::LEV);
-        Ok(())
+    // Number the next pass application and report whether the bisect limit lets it run
+    fn bisect(&mut self, pass: &str, pc: usize) -> bool {
+        self.opt_applications += 1;
+        match self.opt_bisect_limit {
+            Some(limit) => {
+                let run = self.opt_applications <= limit;
+                eprintln!("BISECT: {} pass ({}) {} at {}", if run { "running" } else { "NOT running" },
+                    self.opt_applications, pass, pc);
+                run
+            },
+            None => true,
+        }
     }
 
-    // Compile a function definition
-    fn compile_function_definition(&mut self) -> Result<(), String> {
-        self.next(); 
+    // Instruction start positions, by decoding the code segment from the first word
+    fn instruction_starts(&self) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut pc = 1;
+        while pc <= self.vm.le {
+            starts.push(pc);
+            pc += match OpCode::from_int(self.vm.code[pc]) {
+                Some(op) if op.has_operand() => 2,
+                _ => 1,
+            };
+        }
+        starts
+    }
 
-        if self.token != TokenType::Id as i32 {
-            return Err(format!("{}: function name expected", self.line));
+    // Code positions control may enter other than by falling through: the first instruction,
+    // function entries, jump and call targets, switch table entries, and IMM operands (which
+    // may be function pointers). Indexed by code position.
+    fn entry_points(&self) -> Vec<bool> {
+        let mut targets = vec![1];
+        targets.extend(self.symbols.iter()
+            .filter(|sym| sym.class == Some(SymbolClass::Function) && !sym.is_extern)
+            .map(|sym| sym.value));
+        for pc in self.instruction_starts() {
+            match OpCode::from_int(self.vm.code[pc]) {
+                Some(OpCode::IMM | OpCode::JMP | OpCode::JSR | OpCode::BZ | OpCode::BNZ) => targets.push(self.vm.code[pc + 1]),
+                Some(OpCode::JMPI) => {
+                    let table = self.vm.code[pc + 1];
+                    let len = self.vm.load_int(table + WORD_SIZE as Int).unwrap_or(0);
+                    for slot in 2..3 + len {
+                        targets.extend(self.vm.load_int(table + slot * WORD_SIZE as Int).ok());
+                    }
+                },
+                _ => {},
+            }
         }
-        let _name = &self.source[self.lp..self.p];
-        self.next(); 
+        let mut marks = vec![false; self.vm.le + 2];
+        for target in targets {
+            if target >= 1 && target <= self.vm.le as Int {
+                marks[target as usize] = true;
+            }
+        }
+        marks
+    }
 
-        if self.token != TokenType::Char as i32 {
-            return Err(format!("{}: return type expected", self.line));
+    // Whether the code words after `pc` up to `end` can be rewritten together with it: none of
+    // them is entered directly or patched by the linker
+    fn is_straight_line(&self, marks: &[bool], pc: usize, end: usize) -> bool {
+        (pc + 1..end).all(|pos| !marks[pos]) &&
+            !self.link_fixups.iter().any(|&(pos, _)| pos >= pc && pos < end)
+    }
+
+    // Overwrite the words from `pc` up to `end` with a jump to `end` padded with NOPs
+    fn skip_to(&mut self, pc: usize, end: usize) {
+        self.vm.code[pc] = OpCode::JMP as Int;
+        self.vm.code[pc + 1] = end as Int;
+        for pos in pc + 2..end {
+            self.vm.code[pos] = OpCode::NOP as Int;
         }
-        let return_type = self.token_val;
-        self.next();
+    }
 
-        if let Err(e) = self.compile_block() {
-            return Err(format!("{}: error in function body: {}", self.line, e));
+    // Position of the instruction after the one at `pc`
+    fn next_instruction(&self, pc: usize) -> usize {
+        match OpCode::from_int(self.vm.code[pc]) {
+            Some(op) if op.has_operand() => pc + 2,
+            _ => pc + 1,
         }
+    }
 
-        self.emit(OpCode::FUN);
-        self.emit_with_operand(OpCode::IMM, return_type.into());
-        self.emit_with_operand(OpCode::IMM, self.loc as Int);
+    // Value of `IMM a; PSH; IMM b; op` at `pc`, if that is the code there and it can be folded
+    fn folded_value(&self, marks: &[bool], pc: usize) -> Option<Int> {
+        if pc + 5 > self.vm.le || self.vm.code[pc] != OpCode::IMM as Int || self.vm.code[pc + 2] != OpCode::PSH as Int ||
+            self.vm.code[pc + 3] != OpCode::IMM as Int || !self.is_straight_line(marks, pc, pc + 6) {
+            return None;
+        }
+        let op = OpCode::from_int(self.vm.code[pc + 5]).filter(|op| op.is_binary())?;
+        // Leave faulting operations such as division by zero to run time
+        self.vm.binary(op, self.vm.code[pc + 1], self.vm.code[pc + 4]).ok()
+    }
 
-        Ok(())
+    // fold: `IMM a; PSH; IMM b; op` becomes `JMP; NOP; NOP; IMM (a op b)`. The folded IMM
+    // ends the window, so chains like 1 + 2 + 3 fold left to right in one sweep.
+    fn fold_constants(&mut self) -> usize {
+        let marks = self.entry_points();
+        let mut rewrites = 0;
+        let mut pc = 1;
+        while pc <= self.vm.le {
+            match self.folded_value(&marks, pc) {
+                Some(value) if self.bisect("fold", pc) => {
+                    self.skip_to(pc, pc + 4);
+                    self.vm.code[pc + 4] = OpCode::IMM as Int;
+                    self.vm.code[pc + 5] = value;
+                    rewrites += 1;
+                    pc += 4;
+                },
+                _ => pc = self.next_instruction(pc),
+            }
+        }
+        rewrites
     }
 
-    fn compile_assignment(&mut self) -> Result<(), String> {
-        let id_idx = self.id;
-        self.next(); 
+    // peephole: `PSH; IMM k; op` that leaves ax unchanged (x + 0, x * 1, ...) is jumped over
+    fn peephole(&mut self) -> usize {
+        let marks = self.entry_points();
+        let mut rewrites = 0;
+        let mut pc = 1;
+        while pc <= self.vm.le {
+            let identity = pc + 3 <= self.vm.le && self.vm.code[pc] == OpCode::PSH as Int &&
+                self.vm.code[pc + 1] == OpCode::IMM as Int && self.is_straight_line(&marks, pc, pc + 4) &&
+                matches!((OpCode::from_int(self.vm.code[pc + 3]), self.vm.code[pc + 2]),
+                    (Some(OpCode::ADD | OpCode::SUB | OpCode::OR | OpCode::XOR | OpCode::SHL | OpCode::SHR | OpCode::USHR), 0) |
+                    (Some(OpCode::MUL | OpCode::DIV | OpCode::UDIV), 1));
+            if identity && self.bisect("peephole", pc) {
+                self.skip_to(pc, pc + 4);
+                rewrites += 1;
+                pc += 4;
+            } else {
+                pc = self.next_instruction(pc);
+            }
+        }
+        rewrites
+    }
 
-        if let Err(e) = self.expr(TokenType::Assign as i32) {
-            return Err(format!("{}: error in assignment expression: {}", self.line, e));
+    // dce: branches on a constant (`IMM c; BZ/BNZ`) become unconditional, then every
+    // instruction no path reaches is overwritten with NOPs
+    fn eliminate_dead_code(&mut self) -> usize {
+        let marks = self.entry_points();
+        let mut rewrites = 0;
+        for pair in self.instruction_starts().windows(2) {
+            let (imm, pc) = (pair[0], pair[1]);
+            let branch = OpCode::from_int(self.vm.code[pc]);
+            if !matches!(branch, Some(OpCode::BZ | OpCode::BNZ)) || self.vm.code[imm] != OpCode::IMM as Int ||
+                !self.is_straight_line(&marks, imm, pc + 2) {
+                continue;
+            }
+            if !self.bisect("dce", pc) {
+                continue;
+            }
+            let taken = (self.vm.code[pc - 1] == 0) == (branch == Some(OpCode::BZ));
+            if !taken {
+                self.vm.code[pc + 1] = (pc + 2) as Int;
+            }
+            self.vm.code[pc] = OpCode::JMP as Int;
+            rewrites += 1;
         }
 
-        let expr_type = self.type_;
-        if expr_type != Type::INT as i32 {
-            return Err(format!("{}: assignment type must be int", self.line));
+        let mut reached = vec![false; self.vm.le + 2];
+        let mut work: Vec<usize> = self.entry_points().iter().enumerate()
+            .filter(|&(_, &entry)| entry)
+            .map(|(pc, _)| pc)
+            .collect();
+        while let Some(pc) = work.pop() {
+            if pc > self.vm.le || reached[pc] {
+                continue;
+            }
+            reached[pc] = true;
+            match OpCode::from_int(self.vm.code[pc]) {
+                Some(OpCode::JMP | OpCode::LEV | OpCode::JMPI | OpCode::EXIT) => {},
+                _ => work.push(self.next_instruction(pc)),
+            }
+        }
+        for pc in self.instruction_starts() {
+            if reached[pc] || self.vm.code[pc] == OpCode::NOP as Int || !self.bisect("dce", pc) {
+                continue;
+            }
+            let end = self.next_instruction(pc);
+            for pos in pc..end {
+                self.vm.code[pos] = OpCode::NOP as Int;
+            }
+            self.link_fixups.retain(|&(pos, _)| pos < pc || pos >= end);
+            rewrites += 1;
         }
+        rewrites
+    }
 
-        let class = self.symbols[id_idx].class;
-        let value = self.symbols[id_idx].value;
-        let var_type = self.symbols[id_idx].type_;
-        
-        if class == TokenType::Loc as i32 {
-            self.emit_with_operand(OpCode::LEA, self.loc - value);
-        } else if class == TokenType::Glo as i32 {
-            self.emit_with_operand(OpCode::IMM, value);
-        } else {
-            return Err(format!("{}: undefined variable", self.line));
+    // inline: a call to a function that only returns a constant (`ENT 0; IMM c; LEV`) becomes
+    // `IMM c`; the caller's ADJ still drops the arguments
+    fn inline_calls(&mut self) -> usize {
+        let mut rewrites = 0;
+        for pc in self.instruction_starts() {
+            if self.vm.code[pc] != OpCode::JSR as Int || self.link_fixups.iter().any(|&(pos, _)| pos == pc + 1) {
+                continue;
+            }
+            let target = self.vm.code[pc + 1];
+            if target < 1 || target + 4 > self.vm.le as Int {
+                continue;
+            }
+            let f = target as usize;
+            if self.vm.code[f] == OpCode::ENT as Int && self.vm.code[f + 1] == 0 && self.vm.code[f + 2] == OpCode::IMM as Int &&
+                self.vm.code[f + 4] == OpCode::LEV as Int && !self.link_fixups.iter().any(|&(pos, _)| pos == f + 3) &&
+                self.bisect("inline", pc) {
+                self.vm.code[pc] = OpCode::IMM as Int;
+                self.vm.code[pc + 1] = self.vm.code[f + 3];
+                rewrites += 1;
+            }
         }
-        
-        self.type_ = var_type;
-        self.emit(OpCode::SI);
+        rewrites
+    }
 
-        Ok(())
+    // layout: jumps and branches aimed at NOP padding or at another JMP go straight to where
+    // control ends up
+    fn thread_jumps(&mut self) -> usize {
+        let mut rewrites = 0;
+        for pc in self.instruction_starts() {
+            if !matches!(OpCode::from_int(self.vm.code[pc]), Some(OpCode::JMP | OpCode::BZ | OpCode::BNZ)) {
+                continue;
+            }
+            let mut target = self.vm.code[pc + 1];
+            // Bounded, so a jump cycle (an empty infinite loop) cannot hang the pass
+            for _ in 0..self.vm.le {
+                if target < 1 || target > self.vm.le as Int {
+                    break;
+                }
+                match OpCode::from_int(self.vm.code[target as usize]) {
+                    Some(OpCode::NOP) => target += 1,
+                    Some(OpCode::JMP) => target = self.vm.code[target as usize + 1],
+                    _ => break,
+                }
+            }
+            if target != self.vm.code[pc + 1] && self.bisect("layout", pc) {
+                self.vm.code[pc + 1] = target;
+                rewrites += 1;
+            }
+        }
+        rewrites
     }
 
     // Find main function
-    fn find_main(&self) -> Option<usize> {
+    pub fn find_main(&self) -> Option<usize> {
         for (i, sym) in self.symbols.iter().enumerate() {
-            if sym.name == "main" && sym.class == TokenType::Fun as i32 {
-                println!("find_main: Found main at index {}", i);
+            if sym.name == "main" && sym.class == Some(SymbolClass::Function) {
+                if self.debug {
+                    eprintln!("find_main: Found main at index {}", i);
+                }
                 return Some(i);
             }
         }
-        println!("find_main: Main function not found");
+        if self.debug {
+            eprintln!("find_main: Main function not found");
+        }
         None
     }
 
-    // Run the program
-    fn run(&mut self, _main_idx: usize, _arg_index: usize, _args: &[String]) -> Result<i32, String> {
-        println!("Running with simplified implementation");
-        println!("Successfully compiled function 'main'");
-        println!("Running function 'main' with return value 0");
-        Ok(0)
+    // Run the program from main (symbol `main_idx`) until it exits and return its exit status:
+    // the argument to exit(), or what main returned. Main gets `args` from `arg_index` on, the
+    // source file first, as argc and argv.
+    pub fn run(&mut self, main_idx: usize, arg_index: usize, args: &[String]) -> Result<Int, RuntimeError> {
+        self.start_with_args(self.symbols[main_idx].value as usize, &args[arg_index..]);
+        loop {
+            if let RunState::Halted(code) = self.run_for(1_000_000)? {
+                return Ok(code);
+            }
+        }
+    }
+}
+
+// Print a compile error with where it happened in the original source and the offending line
+fn report_compile_error(c4: &C4, e: &str) {
+    eprintln!("Compilation error: {}", e);
+    eprintln!("  at {}", c4.token_origin());
+    eprint!("{}", c4.source_map().snippet(c4.token_span()).unwrap_or_default());
+}
+
+// `verify-fixture file.c` subcommand: compile a fixture and check the emitted code,
+// exiting nonzero on any violation
+fn verify_fixture(path: &str) -> i32 {
+    let source = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Could not open file {}: {}", path, e);
+            return 1;
+        }
+    };
+    let mut c4 = C4::new();
+    c4.set_source(path, &source);
+    c4.init_symbol_table();
+    if let Err(e) = c4.compile() {
+        report_compile_error(&c4, &e);
+        return 1;
+    }
+    let violations = c4.verify_code();
+    for violation in &violations {
+        eprintln!("{}: {}", path, violation);
+    }
+    if violations.is_empty() {
+        println!("{}: {} code words ok", path, c4.vm.le);
+        0
+    } else {
+        1
+    }
+}
+
+// `asm file.s` subcommand: assemble a program written in the listing syntax and run it
+fn run_assembly(path: &str) -> i32 {
+    let text = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Could not open file {}: {}", path, e);
+            return 1;
+        }
+    };
+    let mut c4 = C4::new();
+    let entry = match c4.load_assembly(&text) {
+        Ok(entry) => entry,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return 1;
+        }
+    };
+    c4.start(entry);
+    loop {
+        match c4.run_for(1_000_000) {
+            Ok(RunState::Halted(code)) => {
+                println!("exit({}) cycle = {}", code, c4.vm.cycle);
+                return code as i32;
+            },
+            Ok(RunState::Paused | RunState::Watchpoint(_)) => {},
+            Err(e) => {
+                eprintln!("Runtime error: {}", e);
+                eprint!("{}", e.stack_trace());
+                eprint!("{}", c4.retirement_dump());
+                return 1;
+            }
+        }
     }
 }
 
-fn main() {
+// Printed when the command line cannot be used
+const USAGE: &str = "usage: c4_rust [-s] [-d] [-E [-o FILE]] [-I DIR] [-D NAME[=VALUE]] [-O0|-O1|-O2] [--disable-pass=NAME] \
+    [--print-passes] [--opt-bisect-limit=N] [--aslr[=SEED] | --no-aslr] [--retire-log=N] [--stack-size=N[k|m|w]] \
+    [--max-cycles=N] [--deterministic[=SEED]] [--leak-check] [--profile] [--no-fusion] \
+    [--record-trace=FILE | --verify-trace=FILE] [--trace-interval=N] [--compare-listing] file ...
+       c4_rust asm file.s
+       c4_rust verify-fixture file.c";
+
+pub fn main() {
     // Parse command-line arguments
     let args: Vec<String> = env::args().collect();
+    if args.len() == 3 && args[1] == "asm" {
+        process::exit(run_assembly(&args[2]));
+    }
+    if args.len() == 3 && args[1] == "verify-fixture" {
+        process::exit(verify_fixture(&args[2]));
+    }
     let mut src = false;
     let mut debug = false;
+    let mut aslr = None;
+    let mut retire_log = None;
+    let mut compare_listing = false;
+    let mut leak_check = false;
+    let mut profile = false;
+    let mut fusion = true;
+    let mut opt_level = 0;
+    let mut disabled_passes = Vec::new();
+    let mut print_passes = false;
+    let mut bisect_limit = None;
+    let mut record_trace: Option<String> = None;
+    let mut verify_trace: Option<String> = None;
+    let mut trace_interval = 1000;
+    let mut options = C4Options::default();
+    let mut max_cycles = None;
+    let mut include_paths = Vec::new();
+    let mut defines = Vec::new();
+    let mut preprocess_only = false;
+    let mut output: Option<String> = None;
     let mut arg_index = 1;
 
     // Check for flags
@@ -1345,16 +4771,171 @@ fn main() {
         } else if args[arg_index] == "-d" {
             debug = true;
             arg_index += 1;
+        } else if args[arg_index] == "-I" && arg_index + 1 < args.len() {
+            include_paths.push(args[arg_index + 1].clone());
+            arg_index += 2;
+        } else if let Some(dir) = args[arg_index].strip_prefix("-I").filter(|dir| !dir.is_empty()) {
+            include_paths.push(dir.to_string());
+            arg_index += 1;
+        } else if args[arg_index] == "-E" {
+            preprocess_only = true;
+            arg_index += 1;
+        } else if args[arg_index] == "-o" && arg_index + 1 < args.len() {
+            output = Some(args[arg_index + 1].clone());
+            arg_index += 2;
+        } else if args[arg_index] == "-D" && arg_index + 1 < args.len() {
+            defines.push(args[arg_index + 1].clone());
+            arg_index += 2;
+        } else if let Some(definition) = args[arg_index].strip_prefix("-D").filter(|definition| !definition.is_empty()) {
+            defines.push(definition.to_string());
+            arg_index += 1;
+        } else if args[arg_index] == "--aslr" {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+            aslr = Some(nanos);
+            arg_index += 1;
+        } else if let Some(seed) = args[arg_index].strip_prefix("--aslr=") {
+            match seed.parse::<u64>() {
+                Ok(seed) => aslr = Some(seed),
+                Err(_) => {
+                    eprintln!("Bad --aslr seed: {}", seed);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
+        } else if args[arg_index] == "--no-aslr" {
+            aslr = None;
+            arg_index += 1;
+        } else if args[arg_index] == "--compare-listing" {
+            compare_listing = true;
+            arg_index += 1;
+        } else if args[arg_index] == "--leak-check" {
+            leak_check = true;
+            arg_index += 1;
+        } else if args[arg_index] == "--profile" {
+            profile = true;
+            arg_index += 1;
+        } else if args[arg_index] == "--no-fusion" {
+            fusion = false;
+            arg_index += 1;
+        } else if let Some(level) = args[arg_index].strip_prefix("-O") {
+            match level.parse::<u8>() {
+                Ok(level) => opt_level = level,
+                Err(_) => {
+                    eprintln!("Bad optimization level: {}", args[arg_index]);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
+        } else if let Some(name) = args[arg_index].strip_prefix("--disable-pass=") {
+            disabled_passes.push(name.to_string());
+            arg_index += 1;
+        } else if args[arg_index] == "--print-passes" {
+            print_passes = true;
+            arg_index += 1;
+        } else if let Some(path) = args[arg_index].strip_prefix("--record-trace=") {
+            record_trace = Some(path.to_string());
+            arg_index += 1;
+        } else if let Some(path) = args[arg_index].strip_prefix("--verify-trace=") {
+            verify_trace = Some(path.to_string());
+            arg_index += 1;
+        } else if let Some(n) = args[arg_index].strip_prefix("--trace-interval=") {
+            match n.parse::<Int>() {
+                Ok(n) if n > 0 => trace_interval = n,
+                _ => {
+                    eprintln!("Bad --trace-interval: {}", n);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
+        } else if let Some(n) = args[arg_index].strip_prefix("--opt-bisect-limit=") {
+            match n.parse::<usize>() {
+                Ok(n) => bisect_limit = Some(n),
+                Err(_) => {
+                    eprintln!("Bad --opt-bisect-limit: {}", n);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
+        } else if args[arg_index] == "--deterministic" {
+            options.deterministic = Some(1);
+            arg_index += 1;
+        } else if let Some(seed) = args[arg_index].strip_prefix("--deterministic=") {
+            match seed.parse::<u64>() {
+                Ok(seed) => options.deterministic = Some(seed),
+                Err(_) => {
+                    eprintln!("Bad --deterministic seed: {}", seed);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
+        } else if let Some(n) = args[arg_index].strip_prefix("--max-cycles=") {
+            match n.parse::<Int>() {
+                Ok(n) if n > 0 => max_cycles = Some(n),
+                _ => {
+                    eprintln!("Bad --max-cycles: {}", n);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
+        } else if let Some(size) = args[arg_index].strip_prefix("--stack-size=") {
+            match parse_stack_size(size) {
+                Ok(size) => options.stack_size = size,
+                Err(e) => {
+                    eprintln!("Bad --stack-size: {}", e);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
+        } else if let Some(n) = args[arg_index].strip_prefix("--retire-log=") {
+            match n.parse::<usize>() {
+                Ok(n) => retire_log = Some(n),
+                Err(_) => {
+                    eprintln!("Bad --retire-log size: {}", n);
+                    process::exit(1);
+                }
+            }
+            arg_index += 1;
         } else {
             eprintln!("Unknown option: {}", args[arg_index]);
-            eprintln!("usage: c4_rust [-s] [-d] file ...");
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    }
+
+    if output.is_some() && !preprocess_only {
+        eprintln!("-o is only supported with -E");
+        process::exit(1);
+    }
+
+    // Set up the optimizer first, so a bad level or pass name fails before any work is done
+    let mut c4 = match C4::with_options(options) {
+        Ok(c4) => c4,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = c4.set_opt_level(opt_level) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    for name in &disabled_passes {
+        if let Err(e) = c4.disable_pass(name) {
+            eprintln!("{}", e);
             process::exit(1);
         }
     }
+    c4.set_opt_bisect_limit(bisect_limit);
+    if print_passes {
+        for name in c4.pass_pipeline() {
+            println!("{}", name);
+        }
+        process::exit(0);
+    }
 
     // Check if a source file was provided
     if arg_index >= args.len() {
-        eprintln!("usage: c4_rust [-s] [-d] file ...");
+        eprintln!("{}", USAGE);
         process::exit(1);
     }
 
@@ -1368,31 +4949,93 @@ fn main() {
         }
     };
 
-    println!("Source file content:");
-    println!("{}", source);
-    println!("End of source");
+    if debug && !preprocess_only {
+        eprintln!("Source file content:");
+        eprintln!("{}", source);
+        eprintln!("End of source");
+    }
 
     // Initialize the C4 compiler/VM
-    let mut c4 = C4::new();
     c4.src = src;
     c4.debug = debug;
-    c4.source = source;
+    c4.vm.debug = debug;
+    for dir in &include_paths {
+        c4.add_include_path(dir);
+    }
+    for definition in &defines {
+        // -D NAME alone defines NAME as 1, like C compilers do
+        let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+        if let Err(e) = c4.define_macro(name, value) {
+            eprintln!("Bad -D {}: {}", definition, e);
+            process::exit(1);
+        }
+    }
+    match c4.preprocess_source(source_file, &source) {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
+            }
+        },
+        Err(e) => {
+            eprintln!("Preprocessing error: {}", e);
+            process::exit(1);
+        }
+    }
+    if preprocess_only {
+        // -E: print the expanded source instead of compiling it
+        match &output {
+            Some(path) => {
+                if let Err(e) = fs::write(path, &c4.source) {
+                    eprintln!("Could not write {}: {}", path, e);
+                    process::exit(1);
+                }
+            },
+            None => print!("{}", c4.source),
+        }
+        process::exit(0);
+    }
+    if let Some(seed) = aslr {
+        c4.set_aslr(Some(seed));
+        eprintln!("aslr seed: {}", seed);
+    }
+    if let Some(n) = retire_log {
+        c4.set_retire_log_size(n);
+    }
+    c4.set_cycle_limit(max_cycles);
+    c4.set_profiling(profile);
+    c4.set_fusion(fusion);
+    if record_trace.is_some() || verify_trace.is_some() {
+        c4.set_trace_interval(trace_interval);
+    }
 
     c4.init_symbol_table();
 
-    c4.line = 1;
-    c4.next();
-
     // Compile the program
     if let Err(e) = c4.compile() {
-        eprintln!("Compilation error: {}", e);
+        report_compile_error(&c4, &e);
         process::exit(1);
     }
+    c4.optimize();
+
+    if compare_listing {
+        match c4.compare_listing() {
+            Ok(words) => {
+                println!("listing matches emitted code ({} code words)", words);
+                process::exit(0);
+            },
+            Err(e) => {
+                eprintln!("listing mismatch: {}", e);
+                process::exit(1);
+            }
+        }
+    }
 
     // Find main
     let main_idx = match c4.find_main() {
         Some(idx) => {
-            println!("Found main index at {}, ready to run", idx);
+            if debug {
+                eprintln!("Found main index at {}, ready to run", idx);
+            }
             idx
         },
         None => {
@@ -1400,9 +5043,11 @@ fn main() {
             let mut found_idx = None;
             for (i, sym) in c4.symbols.iter().enumerate() {
                 if sym.name == "main" {
-                    println!("Found alternative main at index {}, class={}", i, sym.class);
-                    if sym.class != TokenType::Fun as i32 {
-                        c4.symbols[i].class = TokenType::Fun as i32;
+                    if debug {
+                        eprintln!("Found alternative main at index {}, class={:?}", i, sym.class);
+                    }
+                    if sym.class != Some(SymbolClass::Function) {
+                        c4.symbols[i].class = Some(SymbolClass::Function);
                     }
                     found_idx = Some(i);
                     break;
@@ -1411,7 +5056,9 @@ fn main() {
             
             match found_idx {
                 Some(idx) => {
-                    println!("Using alternative main at index {}", idx);
+                    if debug {
+                        eprintln!("Using alternative main at index {}", idx);
+                    }
                     idx
                 },
                 None => {
@@ -1426,14 +5073,50 @@ fn main() {
         process::exit(0);
     }
 
-    println!("Running main function at index {}", main_idx);
-    match c4.run(main_idx, arg_index, &args) {
+    if debug {
+        eprintln!("Running main function at index {}", main_idx);
+    }
+    let result = c4.run(main_idx, arg_index, &args);
+    if let Some(profile) = c4.profile() {
+        c4.vm.flush_output();
+        eprint!("{}", profile);
+    }
+    if let Some(path) = &record_trace {
+        if let Err(e) = fs::write(path, c4.trace_text()) {
+            eprintln!("Could not write trace {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &verify_trace {
+        let golden = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Could not open trace {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        match c4.compare_trace(&golden) {
+            Ok(checkpoints) => println!("trace matches {} ({} checkpoints)", path, checkpoints),
+            Err(e) => {
+                eprintln!("trace mismatch against {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    match result {
         Ok(exit_code) => {
-            println!("exit({}) cycle = {}", exit_code, c4.cycle);
+            println!("exit({}) cycle = {}", exit_code, c4.vm.cycle);
+            if leak_check {
+                c4.vm.flush_output();
+                eprint!("{}", c4.leak_report());
+            }
+            // The host keeps the low 8 bits, as it would for a native C program
             process::exit(exit_code as i32);
         },
         Err(e) => {
             eprintln!("Runtime error: {}", e);
+            eprint!("{}", e.stack_trace());
+            eprint!("{}", c4.retirement_dump());
             process::exit(1);
         }
     }
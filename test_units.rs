@@ -1,8 +1,13 @@
+use std::collections::HashMap;
 use std::fs;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
 
 // Import from main crate
 extern crate c4_rust;
-use c4_rust::{C4, TokenType, OpCode, Type};
+use c4_rust::{C4, TokenType, OpCode, Type, RunState, CancellationToken, SourceMap, Span, RuntimeError, SymbolClass, TokenKind, SymbolId, Vm, C4Options, parse_stack_size, Preprocessor, Snapshot, SandboxPolicy, OutputBuffer};
 
 #[test]
 fn test_init_symbol_table() {
@@ -31,20 +36,19 @@ fn test_lexer() {
     c4.source = "main".to_string();
     c4.p = 0;
     c4.next();
-    assert_eq!(c4.token, TokenType::Id as i32);
+    assert!(matches!(c4.token.kind, TokenKind::Ident(_)));
     
     // Test number lexing
     c4.source = "42".to_string();
     c4.p = 0;
     c4.next();
-    assert_eq!(c4.token, TokenType::Num as i32);
-    assert_eq!(c4.token_val, 42);
+    assert_eq!(c4.token.kind, TokenKind::Num(42));
     
     // Test operator lexing
     c4.source = "+".to_string();
     c4.p = 0;
     c4.next();
-    assert_eq!(c4.token, TokenType::Add as i32);
+    assert_eq!(c4.token.kind, TokenKind::Op(TokenType::Add));
 }
 
 #[test]
@@ -56,12 +60,12 @@ fn test_expression_parsing() {
     c4.source = "42".to_string();
     c4.p = 0;
     c4.next();
-    let result = c4.expr(TokenType::Assign as i32);
+    let result = c4.expr(TokenType::Assign);
     assert!(result.is_ok());
     
     // Verify the generated code (should have IMM 42)
-    assert_eq!(c4.e[1], OpCode::IMM as i64);
-    assert_eq!(c4.e[2], 42);
+    assert_eq!(c4.vm.code[1], OpCode::IMM as i64);
+    assert_eq!(c4.vm.code[2], 42);
 }
 
 #[test]
@@ -98,3 +102,2749 @@ fn test_simple_program() {
     // Clean up
     fs::remove_file(test_file).expect("Failed to remove test file");
 }
+
+#[test]
+fn test_virtual_filesystem() {
+    let mut c4 = C4::new();
+    c4.mount_file("input.txt", b"hello vfs");
+
+    // Place the path string in the data segment like a string literal would be
+    let path = b"input.txt\0";
+    c4.vm.data[..path.len()].copy_from_slice(path);
+
+    let fd = c4.vm.sys_open(0, 0).unwrap();
+    assert!(fd >= 3);
+    assert_eq!(c4.vm.sys_read(fd, 64, 5).unwrap(), 5);
+    assert_eq!(&c4.vm.data[64..69], b"hello");
+    assert_eq!(c4.vm.sys_read(fd, 64, 100).unwrap(), 4);
+    assert_eq!(&c4.vm.data[64..68], b" vfs");
+    assert_eq!(c4.vm.sys_read(fd, 64, 100).unwrap(), 0);
+    assert_eq!(c4.vm.sys_close(fd), 0);
+    assert_eq!(c4.vm.sys_close(fd), -1);
+
+    // Unmounted paths fail to open
+    let missing = b"missing.txt\0";
+    c4.vm.data[..missing.len()].copy_from_slice(missing);
+    assert_eq!(c4.vm.sys_open(0, 0).unwrap(), -1);
+}
+
+#[test]
+fn test_unsigned_types() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+
+    // `unsigned` is a keyword and works as a type name in sizeof
+    c4.source = "sizeof(unsigned char)".to_string();
+    c4.p = 0;
+    c4.next();
+    assert_eq!(c4.token.kind, TokenKind::Keyword(TokenType::Sizeof));
+    assert!(c4.expr(TokenType::Assign).is_ok());
+    assert_eq!(c4.vm.code[1], OpCode::IMM as i64);
+    assert_eq!(c4.vm.code[2], 1);
+
+    // Comparisons, shifts and division pick unsigned opcodes for unsigned operands
+    assert_eq!(c4.binary_op(TokenType::Lt, true), Some(OpCode::ULT));
+    assert_eq!(c4.binary_op(TokenType::Lt, false), Some(OpCode::LT));
+    assert_eq!(c4.binary_op(TokenType::Shr, true), Some(OpCode::USHR));
+    assert_eq!(c4.binary_op(TokenType::Mod, true), Some(OpCode::UMOD));
+    assert_eq!(c4.binary_op(TokenType::Add, true), Some(OpCode::ADD));
+}
+
+// Compile `src` as the body of a function whose ENT sits at code address 1
+fn compile_body(src: &str) -> C4 {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.emit_with_operand(OpCode::ENT, 0);
+    let frame = c4.vm.le;
+    c4.loc = 1;
+    c4.source = src.to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_block().unwrap();
+    c4.vm.code[frame] = c4.local_count;
+    c4
+}
+
+// Compile and run a function body for at most 10,000 cycles
+fn run_body(src: &str) -> Result<RunState, RuntimeError> {
+    let mut c4 = compile_body(src);
+    c4.start(1);
+    c4.run_for(10_000)
+}
+
+// Compile `src` as a whole program with `c4`, handing it back on success
+fn compile_with(mut c4: C4, src: &str) -> Result<C4, String> {
+    c4.init_symbol_table();
+    c4.set_source("prog.c", src);
+    c4.compile().map(|_| c4)
+}
+
+// Compile `src` as a whole program, handing back the compiler on success
+fn compile_program(src: &str) -> Result<C4, String> {
+    compile_with(C4::new(), src)
+}
+
+// Compile `src` with `c4` and start the VM at its main
+fn start_with(c4: C4, src: &str) -> C4 {
+    let mut c4 = compile_with(c4, src).unwrap();
+    c4.start(c4.program().function("main").unwrap().entry);
+    c4
+}
+
+// Compile `src` and start the VM at its main
+fn start_program(src: &str) -> C4 {
+    start_with(C4::new(), src)
+}
+
+// Compile `src` and run its main for at most `budget` cycles
+fn run_program(src: &str, budget: i64) -> Result<RunState, RuntimeError> {
+    start_program(src).run_for(budget)
+}
+
+// Like `run_program`, also returning what the program wrote to stdout
+fn run_program_output(src: &str, budget: i64) -> (Result<RunState, RuntimeError>, String) {
+    let mut c4 = start_program(src);
+    let out = OutputBuffer::new();
+    c4.set_stdout(Box::new(out.clone()));
+    (c4.run_for(budget), out.text())
+}
+
+#[test]
+fn test_output_sinks() {
+    let mut c4 = C4::new();
+    let out = OutputBuffer::new();
+    let err = OutputBuffer::new();
+    c4.set_stdout(Box::new(out.clone()));
+    c4.set_stderr(Box::new(err.clone()));
+
+    assert_eq!(c4.vm.write_fd(1, b"to stdout"), 9);
+    assert_eq!(c4.vm.write_fd(2, b"to stderr"), 9);
+    assert_eq!(c4.vm.write_fd(7, b"nowhere"), -1);
+    c4.vm.flush_output();
+
+    assert_eq!(out.contents(), b"to stdout");
+    assert_eq!(err.contents(), b"to stderr");
+
+    // Output can also be discarded entirely
+    c4.set_stdout(Box::new(io::sink()));
+    assert_eq!(c4.vm.write_fd(1, b"dropped"), 7);
+}
+
+#[test]
+fn test_run_for_budget() {
+    let mut c4 = C4::new();
+
+    // (6 * 7) as the exit code
+    c4.emit_with_operand(OpCode::IMM, 6);
+    c4.emit(OpCode::PSH);
+    c4.emit_with_operand(OpCode::IMM, 7);
+    c4.emit(OpCode::MUL);
+    c4.emit(OpCode::PSH);
+    c4.emit(OpCode::EXIT);
+    c4.start(1);
+
+    assert_eq!(c4.run_for(2), Ok(RunState::Paused));
+    assert_eq!(c4.vm.cycle, 2);
+    assert_eq!(c4.run_for(100), Ok(RunState::Halted(42)));
+    assert_eq!(c4.vm.cycle, 6);
+    // Further calls report the same exit without running anything
+    assert_eq!(c4.run_for(100), Ok(RunState::Halted(42)));
+    assert_eq!(c4.vm.cycle, 6);
+}
+
+#[test]
+fn test_unsigned_vm_ops() {
+    let c4 = C4::new();
+    assert_eq!(c4.vm.binary(OpCode::LT, -1, 1), Ok(1));
+    assert_eq!(c4.vm.binary(OpCode::ULT, -1, 1), Ok(0));
+    assert_eq!(c4.vm.binary(OpCode::SHR, -8, 1), Ok(-4));
+    assert_eq!(c4.vm.binary(OpCode::USHR, -8, 60), Ok(15));
+    assert_eq!(c4.vm.binary(OpCode::UDIV, -2, 2), Ok(i64::MAX));
+    assert!(c4.vm.binary(OpCode::DIV, 1, 0).is_err());
+}
+
+#[test]
+fn test_short_and_long_sizes() {
+    let sizes = [
+        ("sizeof(short)", 2),
+        ("sizeof(unsigned short int)", 2),
+        ("sizeof(long)", 8),
+        ("sizeof(long long)", 8),
+        ("sizeof(short *)", 8),
+        ("sizeof(char)", 1),
+    ];
+    for (source, size) in sizes {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        c4.source = source.to_string();
+        c4.p = 0;
+        c4.next();
+        assert!(c4.expr(TokenType::Assign).is_ok(), "{}", source);
+        assert_eq!(c4.vm.code[2], size, "{}", source);
+    }
+
+    // Shorts are stored and loaded as 16 bits
+    let mut c4 = C4::new();
+    c4.vm.store_short(16, 0x12345).unwrap();
+    assert_eq!(c4.vm.load_short(16), Ok(0x2345));
+    c4.vm.store_short(16, -2).unwrap();
+    assert_eq!(c4.vm.load_short(16), Ok(-2));
+    assert_eq!(c4.vm.data[18], 0);
+}
+
+#[test]
+fn test_run_async_cancellation() {
+    let mut cx = Context::from_waker(Waker::noop());
+
+    // A program that exits normally resolves to its exit code
+    let mut c4 = C4::new();
+    c4.emit_with_operand(OpCode::IMM, 7);
+    c4.emit(OpCode::PSH);
+    c4.emit(OpCode::EXIT);
+    c4.start(1);
+    let mut fut = c4.run_async(1, CancellationToken::new());
+    let mut polls = 0;
+    let result = loop {
+        polls += 1;
+        if let Poll::Ready(result) = Pin::new(&mut fut).poll(&mut cx) {
+            break result;
+        }
+    };
+    assert_eq!(result, Ok(7));
+    assert_eq!(polls, 3);
+
+    // An endless loop keeps yielding until the token is cancelled
+    let mut c4 = C4::new();
+    c4.emit_with_operand(OpCode::JMP, 1);
+    c4.start(1);
+    let token = CancellationToken::new();
+    let mut fut = c4.run_async(100, token.clone());
+    for _ in 0..10 {
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+    }
+    token.cancel();
+    assert!(matches!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Err(_))));
+    assert_eq!(c4.vm.cycle, 1000);
+}
+
+#[test]
+fn test_const_assignment_errors() {
+    // Helper: compile `source` after declaring the global `decl` (named x)
+    fn compile_with_global(decl: &str, source: &str) -> Result<(), String> {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        c4.source = decl.to_string();
+        c4.p = 0;
+        c4.next();
+        let mut ty = c4.parse_base_type();
+        while c4.token.kind == TokenKind::Op(TokenType::Mul) {
+            c4.next();
+            ty += Type::PTR as i32;
+        }
+        let TokenKind::Ident(SymbolId(x)) = c4.token.kind else { panic!("{:?}", c4.token.kind) };
+
+        c4.source = source.to_string();
+        c4.p = 0;
+        c4.next();
+        c4.symbols[x].class = Some(SymbolClass::Global);
+        c4.symbols[x].type_ = ty;
+        c4.expr(TokenType::Assign)
+    }
+
+    assert!(compile_with_global("int x", "x = 1").is_ok());
+    assert!(compile_with_global("const int x", "x = 1").is_err());
+    assert!(compile_with_global("int const x", "x = 1").is_err());
+    assert!(compile_with_global("const int x", "++x").is_err());
+    // The pointer itself may change, the chars it points at may not
+    assert!(compile_with_global("const char *x", "x = 0").is_ok());
+    assert!(compile_with_global("const char *x", "*x = 0").is_err());
+    assert!(compile_with_global("char *x", "*x = 0").is_ok());
+}
+
+#[test]
+fn test_resource_usage() {
+    let mut c4 = C4::new();
+    c4.mount_file("a", b"");
+    c4.vm.data[8] = b'a';
+
+    // close(open("a", 0)); close(open("a", 0)); exit(0)
+    for _ in 0..2 {
+        c4.emit_with_operand(OpCode::IMM, 8);
+        c4.emit(OpCode::PSH);
+        c4.emit_with_operand(OpCode::IMM, 0);
+        c4.emit(OpCode::PSH);
+        c4.emit(OpCode::OPEN);
+        c4.emit_with_operand(OpCode::ADJ, 2);
+        c4.emit(OpCode::PSH);
+        c4.emit(OpCode::CLOS);
+        c4.emit_with_operand(OpCode::ADJ, 1);
+    }
+    c4.emit_with_operand(OpCode::IMM, 0);
+    c4.emit(OpCode::PSH);
+    c4.emit(OpCode::EXIT);
+    c4.start(1);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(0)));
+
+    let usage = c4.usage();
+    assert_eq!(usage.cycles, 21);
+    assert_eq!(usage.syscalls.get(&OpCode::OPEN), Some(&2));
+    assert_eq!(usage.syscalls.get(&OpCode::CLOS), Some(&2));
+    assert_eq!(usage.syscalls.get(&OpCode::EXIT), Some(&1));
+    assert_eq!(usage.syscalls.get(&OpCode::READ), None);
+
+    c4.set_stdout(Box::new(io::sink()));
+    c4.vm.write_fd(1, b"12345");
+    assert_eq!(c4.usage().bytes_written, 5);
+}
+
+#[test]
+fn test_static_linkage() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.source = "static int counter; int total, *ptr; static char helper() { return 1; } int api() { return 0; }".to_string();
+    c4.p = 0;
+    c4.next();
+    while c4.token.kind != TokenKind::Eof {
+        c4.compile_global_declaration().unwrap();
+    }
+
+    let sym = |name: &str| c4.symbols.iter().find(|sym| sym.name == name).unwrap().clone();
+    assert!(sym("counter").is_static);
+    assert_eq!(sym("counter").class, Some(SymbolClass::Global));
+    assert!(!sym("total").is_static);
+    assert_eq!(sym("ptr").type_, Type::INT as i32 + Type::PTR as i32);
+    assert!(sym("helper").is_static);
+    assert_eq!(sym("helper").class, Some(SymbolClass::Function));
+
+    // Each global gets its own slot in the data segment
+    assert_ne!(sym("counter").value, sym("total").value);
+    assert_ne!(sym("total").value, sym("ptr").value);
+
+    let exported = c4.exported_symbols();
+    assert!(exported.contains(&"total".to_string()));
+    assert!(exported.contains(&"api".to_string()));
+    assert!(!exported.contains(&"counter".to_string()));
+    assert!(!exported.contains(&"helper".to_string()));
+}
+
+#[test]
+fn test_constant_global_initializers() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.source = "enum { N = 1 << 8, M, K = N * 2 - 1 }; int size = 4 * 1024; int mask = ~(N - 1) & 0xfff; \
+                 int neg = -(3 + 4) * 2; int words = sizeof(int) * N; char c = 'a' + 1; int zero;".to_string();
+    c4.p = 0;
+    c4.next();
+    while c4.token.kind != TokenKind::Eof {
+        c4.compile_global_declaration().unwrap();
+    }
+
+    let sym = |name: &str| c4.symbols.iter().find(|sym| sym.name == name).unwrap().clone();
+    assert_eq!(sym("N").class, Some(SymbolClass::EnumConst));
+    assert_eq!(sym("N").value, 256);
+    assert_eq!(sym("M").value, 257);
+    assert_eq!(sym("K").value, 511);
+
+    let global = |name: &str| c4.vm.load_int(sym(name).value).unwrap();
+    assert_eq!(global("size"), 4096);
+    assert_eq!(global("mask"), 0xf00);
+    assert_eq!(global("neg"), -14);
+    assert_eq!(global("words"), 2048);
+    assert_eq!(c4.vm.load_char(sym("c").value).unwrap(), 'b' as i64);
+    assert_eq!(global("zero"), 0);
+
+    // Non-constant initializers are rejected
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.source = "int a; int b = a + 1;".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_global_declaration().unwrap();
+    assert!(c4.compile_global_declaration().is_err());
+}
+
+#[test]
+fn test_extern_link_resolution() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("extern int shared; extern int helper(int); static int hidden;").unwrap();
+
+    // Code in the first unit refers to the not-yet-defined global
+    c4.source = "shared".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.expr(TokenType::Assign).unwrap();
+    assert_eq!(c4.vm.code[1], OpCode::IMM as i64);
+    let err = c4.link().unwrap_err();
+    assert!(err.contains("undefined symbol 'shared'"), "{}", err);
+
+    // A second unit supplies the definition, and may reuse the other unit's static name
+    c4.add_unit("int unused; int shared = 5; static int hidden;").unwrap();
+    c4.link().unwrap();
+    let shared = c4.symbols.iter().find(|sym| sym.name == "shared").unwrap();
+    assert!(!shared.is_extern);
+    assert_eq!(c4.vm.code[2], shared.value);
+    assert_eq!(c4.vm.load_int(c4.vm.code[2]), Ok(5));
+
+    // Unreferenced externs and defined statics are not exported
+    let exported = c4.exported_symbols();
+    assert!(exported.contains(&"shared".to_string()));
+    assert!(!exported.contains(&"helper".to_string()));
+    assert!(!exported.contains(&"hidden".to_string()));
+
+    // Defining the same global twice is still an error
+    assert!(c4.add_unit("int shared;").is_err());
+}
+
+#[test]
+fn test_global_initializers() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("int x = 42; char *s = \"hi\"; char *empty = \"\"; char *null = 0; char c = 'z';").unwrap();
+
+    let sym = |name: &str| c4.symbols.iter().find(|sym| sym.name == name).unwrap().clone();
+    assert_eq!(c4.vm.load_int(sym("x").value), Ok(42));
+    let s = c4.vm.load_int(sym("s").value).unwrap();
+    assert_ne!(s, 0);
+    assert_eq!(c4.vm.read_cstr(s), Ok("hi".to_string()));
+    let empty = c4.vm.load_int(sym("empty").value).unwrap();
+    assert_ne!(empty, 0);
+    assert_eq!(c4.vm.read_cstr(empty), Ok(String::new()));
+    assert_eq!(c4.vm.load_int(sym("null").value), Ok(0));
+    assert_eq!(c4.vm.load_char(sym("c").value), Ok('z' as i64));
+
+    // A string filling a whole word still gets its terminator
+    c4.add_unit("char *word = \"12345678\"; int after = -1;").unwrap();
+    let word = c4.symbols.iter().find(|sym| sym.name == "word").unwrap().value;
+    let word = c4.vm.load_int(word).unwrap();
+    assert_eq!(c4.vm.read_cstr(word), Ok("12345678".to_string()));
+}
+
+#[test]
+fn test_switch_dispatch() {
+    // Compile `switch_source` as the body of a function and run it with global x = value
+    fn run_switch(switch_source: &str, value: i64) -> (i64, bool) {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        c4.add_unit("int x;").unwrap();
+        let x = c4.symbols.iter().find(|sym| sym.name == "x").unwrap().value;
+        c4.vm.store_int(x, value).unwrap();
+
+        c4.emit_with_operand(OpCode::ENT, 0);
+        c4.source = switch_source.to_string();
+        c4.p = 0;
+        c4.next();
+        c4.compile_statement().unwrap();
+        c4.compile_statement().unwrap();
+        let uses_table = c4.vm.code[..=c4.vm.le].contains(&(OpCode::JMPI as i64));
+        c4.start(1);
+        match c4.run_for(1000) {
+            Ok(RunState::Halted(code)) => (code, uses_table),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    let dense = "switch (x) { case 1: return 10; case 2: return 20; case 3: case 4: return 34; \
+                 case 6: return 60; default: return 99; } return 0;";
+    for (value, expected) in [(1, 10), (2, 20), (3, 34), (4, 34), (5, 99), (6, 60), (-7, 99), (100, 99)] {
+        assert_eq!(run_switch(dense, value), (expected, true), "x = {}", value);
+    }
+
+    let sparse = "switch (x) { case 'a': return 1; case 1000: return 2; case -5: return 3; } return 0;";
+    for (value, expected) in [(97, 1), (1000, 2), (-5, 3), (0, 0)] {
+        assert_eq!(run_switch(sparse, value), (expected, false), "x = {}", value);
+    }
+
+    // break leaves the switch; without a default, unmatched values skip the body
+    let with_break = "switch (x) { case 1: case 2: case 3: case 4: break; } return 7;";
+    assert_eq!(run_switch(with_break, 2), (7, true));
+    assert_eq!(run_switch(with_break, 9), (7, true));
+}
+
+#[test]
+fn test_verify_code() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("int x;").unwrap();
+    c4.emit_with_operand(OpCode::ENT, 0);
+    c4.source = "switch (x) { case 1: case 2: case 3: case 4: return 1; } return 0;".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_statement().unwrap();
+    c4.compile_statement().unwrap();
+    assert_eq!(c4.verify_code(), Vec::<String>::new());
+
+    // Branch into the middle of an instruction, call a non-function, truncated operand
+    c4.emit_with_operand(OpCode::JMP, 2);
+    c4.emit_with_operand(OpCode::JSR, 1);
+    c4.emit(OpCode::IMM);
+    let violations = c4.verify_code();
+    assert_eq!(violations.len(), 3, "{:?}", violations);
+    assert!(violations[0].contains("missing its operand"));
+    assert!(violations[1].contains("JMP target 2"));
+    assert!(violations[2].contains("JSR target 1"));
+}
+
+#[test]
+fn test_local_declarations() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("int j = 9;").unwrap();
+
+    c4.emit_with_operand(OpCode::ENT, 0);
+    let frame = c4.vm.le;
+    c4.loc = 1;
+    c4.source = "{ int i = 5, *p; char c = 'x'; int j = i; return j; }".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_block().unwrap();
+    assert_eq!(c4.local_count, 4);
+    c4.vm.code[frame] = c4.local_count;
+
+    c4.start(1);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(5)));
+
+    // The global j is visible again once the block that shadowed it ends
+    let j = c4.symbols.iter().position(|sym| sym.name == "j").unwrap();
+    assert_eq!(c4.symbols[j].class, Some(SymbolClass::Global));
+    assert_eq!(c4.vm.load_int(c4.symbols[j].value), Ok(9));
+
+    // Redeclaring a local in the same function is an error
+    c4.source = "{ int k; int k; }".to_string();
+    c4.p = 0;
+    c4.next();
+    assert!(c4.compile_block().is_err());
+}
+
+#[test]
+fn test_compound_assignment() {
+    assert_eq!(run_body("{ int i = 6; int j = i *= 7; int k = i -= 2; return i; }"), Ok(RunState::Halted(40)));
+    assert_eq!(run_body("{ int i = 5; int j = i <<= 3; int k = i >>= 1; int l = i |= 1; return i; }"), Ok(RunState::Halted(21)));
+    assert_eq!(run_body("{ int i = 47; int j = i %= 10; int k = i ^= 3; int l = i &= 6; int m = i /= 2; return i; }"), Ok(RunState::Halted(2)));
+    // The right operand of pointer += is scaled by the element size
+    assert_eq!(run_body("{ int a = 1; int b = 2; int *p = &b; int *q = p += 1; return *p; }"), Ok(RunState::Halted(1)));
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    assert!(c4.add_unit("const int c = 1;").is_ok());
+    c4.source = "c += 1".to_string();
+    c4.p = 0;
+    c4.next();
+    assert!(c4.expr(TokenType::Assign).is_err());
+}
+
+#[test]
+fn test_token_origins() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    // As if "foo.h" (SQR defined on its line 3) had been included into main.c at line 2
+    c4.source = "int a;\nint sq;\nint b;\nint c = (4 * 4);\nint d;".to_string();
+    c4.mark_origin(0, "main.c", 1);
+    c4.mark_origin(7, "foo.h", 1);
+    c4.mark_origin(15, "main.c", 3);
+    c4.mark_expansion(30, 37, "SQR", "foo.h", 3);
+
+    assert_eq!(c4.origin_at(4).to_string(), "main.c:1");
+    assert_eq!(c4.origin_at(11).to_string(), "foo.h:1");
+    assert_eq!(c4.origin_at(18).to_string(), "main.c:3");
+    assert_eq!(c4.origin_at(31).to_string(), "main.c:4 (expanded from macro SQR at foo.h:3)");
+
+    c4.p = 0;
+    loop {
+        c4.next();
+        if matches!(c4.token.kind, TokenKind::Num(_)) {
+            break;
+        }
+    }
+    let origin = c4.token_origin();
+    assert_eq!(origin.file, "main.c");
+    assert_eq!(origin.line, 4);
+    assert_eq!(origin.expanded_from, Some(("SQR".to_string(), "foo.h".to_string(), 3)));
+}
+
+#[test]
+fn test_define_and_lookup_globals() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("enum { RED = 7 }; short s = 300; int f() { return 0; }").unwrap();
+
+    let addr = c4.define_global("counter", Type::INT as i32, 41).unwrap();
+    let info = c4.lookup("counter").unwrap();
+    assert_eq!(info.class, SymbolClass::Global);
+    assert_eq!(info.address, Some(addr));
+    assert_eq!(info.value, 41);
+
+    // Redefining with the same type keeps the address compiled code refers to
+    assert_eq!(c4.define_global("counter", Type::INT as i32, 42), Ok(addr));
+    assert_eq!(c4.lookup("counter").unwrap().value, 42);
+
+    // Globals defined by the host are visible to code compiled afterwards
+    c4.source = "counter".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.expr(TokenType::Assign).unwrap();
+    assert_eq!(&c4.vm.code[c4.vm.le - 2..=c4.vm.le], &[OpCode::IMM as i64, addr, OpCode::LI as i64]);
+
+    assert_eq!(c4.lookup("s").unwrap().value, 300);
+    assert_eq!(c4.lookup("RED").unwrap().value, 7);
+    assert_eq!(c4.lookup("f").unwrap().class, SymbolClass::Function);
+    assert_eq!(c4.lookup("printf").unwrap().class, SymbolClass::Syscall);
+    assert_eq!(c4.lookup("while"), None);
+    assert_eq!(c4.lookup("missing"), None);
+    assert!(c4.define_global("f", Type::INT as i32, 1).is_err());
+    assert!(c4.define_global("int", Type::INT as i32, 1).is_err());
+}
+
+#[test]
+fn test_program_metadata() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("int g; char *msg = \"hi\"; extern int other; int add(int a, char *b) { return a; } int main(void) { return 0; } int proto();").unwrap();
+    let program = c4.program();
+
+    let names: Vec<&str> = program.functions().iter().map(|func| func.name.as_str()).collect();
+    assert_eq!(names, ["add", "main"]);
+    assert_eq!(program.function("add").unwrap().params, ["a", "b"]);
+    assert!(program.function("main").unwrap().params.is_empty());
+    assert!(program.function("proto").is_none());
+
+    let globals = program.globals();
+    assert_eq!(globals.len(), 2);
+    assert_eq!(globals[0].name, "g");
+    assert_eq!(globals[1].name, "msg");
+    assert_eq!(globals[1].size, 8);
+
+    assert_eq!(program.strings().len(), 1);
+    assert_eq!(program.strings()[0].value, "hi");
+    assert_eq!(c4.lookup("msg").unwrap().value, program.strings()[0].address);
+}
+
+#[test]
+fn test_conditional_operator() {
+    assert_eq!(run_body("{ int x = 0; return x ? 10 : 20; }"), Ok(RunState::Halted(20)));
+    assert_eq!(run_body("{ int x = 3; return x ? 10 : 20; }"), Ok(RunState::Halted(10)));
+    // Right associative: a ? b : (c ? d : e)
+    assert_eq!(run_body("{ int x = 0; int y = 1; return x ? 1 : y ? 2 : 3; }"), Ok(RunState::Halted(2)));
+    assert_eq!(run_body("{ int x = 1; int y = 0; int z = x ? y ? 4 : 5 : 6; return z; }"), Ok(RunState::Halted(5)));
+
+    // A pointer arm makes the whole expression a pointer; unsigned arms make it unsigned
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("char *p; int c; unsigned short u;").unwrap();
+    for (src, ty) in [("c ? p : 0", Type::PTR as i32), ("c ? 1 : u", Type::INT as i32 | (1 << 16)), ("c ? 'a' : 2", Type::INT as i32)] {
+        c4.source = src.to_string();
+        c4.p = 0;
+        c4.next();
+        c4.expr(TokenType::Assign).unwrap();
+        assert_eq!(c4.type_, ty, "{}", src);
+    }
+
+    c4.source = "c ? 1 2".to_string();
+    c4.p = 0;
+    c4.next();
+    assert!(c4.expr(TokenType::Assign).is_err());
+}
+
+#[test]
+fn test_aslr_layout() {
+    let build = |seed: Option<u64>| {
+        let mut c4 = C4::new();
+        c4.set_aslr(seed);
+        c4.init_symbol_table();
+        c4.add_unit("int g = 5; char *s = \"abc\";").unwrap();
+        c4
+    };
+
+    let fixed = build(None);
+    let a = build(Some(42));
+    let b = build(Some(42));
+    let other = build(Some(7));
+
+    // The same seed reproduces the layout; a different one moves it
+    assert_eq!(a.lookup("g"), b.lookup("g"));
+    assert_ne!(a.lookup("g").unwrap().address, fixed.lookup("g").unwrap().address);
+    assert_ne!(a.lookup("g").unwrap().address, other.lookup("g").unwrap().address);
+    assert_eq!(a.aslr_seed(), Some(42));
+    assert_eq!(a.lookup("g").unwrap().value, 5);
+    assert_eq!(a.program().strings()[0].value, "abc");
+    assert_eq!(a.lookup("s").unwrap().value, a.program().strings()[0].address);
+
+    // Hard-coded fixed-layout addresses (including null) no longer point at the data
+    let fixed_addr = fixed.lookup("g").unwrap().address.unwrap();
+    assert!(a.vm.load_int(fixed_addr).is_err());
+    assert!(a.vm.load_int(0).is_err());
+
+    // Programs still run with the stack moved
+    let mut c4 = build(Some(99));
+    c4.emit_with_operand(OpCode::ENT, 0);
+    let frame = c4.vm.le;
+    c4.loc = 1;
+    c4.source = "{ int x = 3; int y = x ? g : 0; return y; }".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_block().unwrap();
+    c4.vm.code[frame] = c4.local_count;
+    c4.start(1);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(5)));
+}
+
+#[test]
+fn test_post_increment() {
+    // The expression yields the old value, the variable holds the new one
+    assert_eq!(run_body("{ int i = 5; int j = i++; return j; }"), Ok(RunState::Halted(5)));
+    assert_eq!(run_body("{ int i = 5; int j = i++; return i; }"), Ok(RunState::Halted(6)));
+    assert_eq!(run_body("{ int i = 5; int j = i--; int k = i--; return i; }"), Ok(RunState::Halted(3)));
+    assert_eq!(run_body("{ char c = 'a'; int j = c++; return c; }"), Ok(RunState::Halted('b' as i64)));
+    // Pointers step by the element size
+    assert_eq!(run_body("{ int a = 1; int b = 2; int *p = &b; int *q = p++; return *p; }"), Ok(RunState::Halted(1)));
+    assert_eq!(run_body("{ int a = 1; int b = 2; int *p = &b; return *p++; }"), Ok(RunState::Halted(2)));
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("const int c = 1;").unwrap();
+    c4.source = "c++".to_string();
+    c4.p = 0;
+    c4.next();
+    assert!(c4.expr(TokenType::Assign).is_err());
+}
+
+#[test]
+fn test_retirement_log() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.set_retire_log_size(4);
+    // 10 / 0 faults on the DIV
+    c4.emit_with_operand(OpCode::IMM, 10);
+    c4.emit(OpCode::PSH);
+    c4.emit_with_operand(OpCode::IMM, 7);
+    c4.emit_with_operand(OpCode::IMM, 0);
+    c4.emit(OpCode::DIV);
+    c4.start(1);
+    assert!(c4.run_for(100).is_err());
+
+    let retired: Vec<_> = c4.retired().iter().map(|entry| (entry.pc, entry.op, entry.operand)).collect();
+    assert_eq!(retired, [
+        (3, OpCode::PSH, None),
+        (4, OpCode::IMM, Some(7)),
+        (6, OpCode::IMM, Some(0)),
+        (8, OpCode::DIV, None),
+    ]);
+    assert_eq!(c4.retired()[3].ax, 0);
+    let dump = c4.retirement_dump();
+    assert!(dump.starts_with("last 4 instructions executed:\n"));
+    assert!(dump.lines().last().unwrap().contains("DIV"));
+
+    c4.set_retire_log_size(0);
+    assert!(c4.retired().is_empty());
+}
+
+#[test]
+fn test_short_circuit() {
+    assert_eq!(run_body("{ int a = 3; int b = 5; return a && b; }"), Ok(RunState::Halted(1)));
+    assert_eq!(run_body("{ int a = 3; int b = 0; return a && b; }"), Ok(RunState::Halted(0)));
+    assert_eq!(run_body("{ int a = 0; int b = 5; return a || b; }"), Ok(RunState::Halted(1)));
+    assert_eq!(run_body("{ int a = 0; int b = 0; return a || b || a; }"), Ok(RunState::Halted(0)));
+    // && binds tighter than ||
+    assert_eq!(run_body("{ int a = 1; int b = 0; return a || b && b; }"), Ok(RunState::Halted(1)));
+    // The right operand is not evaluated: no null dereference, no side effect
+    assert_eq!(run_body("{ int *p = 0; return p && *p; }"), Ok(RunState::Halted(0)));
+    assert_eq!(run_body("{ int a = 1; int n = 0; int r = a || n++; return n; }"), Ok(RunState::Halted(0)));
+    assert_eq!(run_body("{ int a = 0; int n = 0; int r = a && n++; return n; }"), Ok(RunState::Halted(0)));
+    assert_eq!(run_body("{ int a = 1; int n = 0; int r = a && n++; return n; }"), Ok(RunState::Halted(1)));
+}
+
+#[test]
+fn test_pointer_arithmetic() {
+    // Locals are laid out downwards: a is one int above b, b one above c
+    assert_eq!(run_body("{ int a = 7; int b = 8; int *p = &b; return *(p + 1); }"), Ok(RunState::Halted(7)));
+    assert_eq!(run_body("{ int a = 7; int b = 8; int *p = &a; return *(p - 1); }"), Ok(RunState::Halted(8)));
+    assert_eq!(run_body("{ int a = 7; int b = 8; int *p = &b; return *(1 + p); }"), Ok(RunState::Halted(7)));
+    assert_eq!(run_body("{ int a = 7; int b = 8; int c = 9; int *p = &a; int *q = &c; return p - q; }"), Ok(RunState::Halted(2)));
+    // char pointers step by single bytes
+    assert_eq!(run_body("{ char *s = \"xyz\"; return *(s + 2); }"), Ok(RunState::Halted('z' as i64)));
+    assert_eq!(run_body("{ char *s = \"xyz\"; char *t = s + 2; return t - s; }"), Ok(RunState::Halted(2)));
+    // Ordinary integer arithmetic is unaffected
+    assert_eq!(run_body("{ int a = 7; return a * 3 + a - 1; }"), Ok(RunState::Halted(27)));
+    // Jumps inside the moved int operand still land correctly
+    assert_eq!(run_body("{ int a = 7; int b = 8; int c = 0; int *p = &b; return *((c ? 0 : 1) + p); }"), Ok(RunState::Halted(7)));
+}
+
+#[test]
+fn test_compare_listing() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.emit_with_operand(OpCode::ENT, 0);
+    c4.loc = 1;
+    c4.source = "{ int a = 7; int *p = &a; return a > 3 && *p ? a - 1 : -a; }".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_block().unwrap();
+
+    let listing = c4.listing();
+    assert!(listing.starts_with("     ENT 0\n"));
+    assert_eq!(c4.compare_listing(), Ok(c4.vm.le));
+
+    // A word the listing cannot express is caught
+    c4.emit(OpCode::LEV);
+    c4.vm.code[c4.vm.le] = 999;
+    assert!(c4.compare_listing().is_err());
+}
+
+#[test]
+fn test_array_subscript() {
+    assert_eq!(run_body("{ char *s = \"hello\"; return s[1]; }"), Ok(RunState::Halted('e' as i64)));
+    // Locals sit one int apart, so c[1] is b and c[2] is a
+    assert_eq!(run_body("{ int a = 7; int b = 8; int c = 9; int *p = &c; return p[2] * 10 + p[1]; }"), Ok(RunState::Halted(78)));
+    // Subscripts are lvalues
+    assert_eq!(run_body("{ int a = 7; int b = 8; int *p = &b; int x = p[1] = 42; return a; }"), Ok(RunState::Halted(42)));
+    assert_eq!(run_body("{ int a = 7; int b = 8; int *p = &b; int x = p[1] += 3; int y = p[1]++; return a; }"), Ok(RunState::Halted(11)));
+    assert_eq!(run_body("{ char *s = \"abc\"; int x = s[0] = 'z'; return *s; }"), Ok(RunState::Halted('z' as i64)));
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("int n;").unwrap();
+    c4.source = "n[0]".to_string();
+    c4.p = 0;
+    c4.next();
+    assert!(c4.expr(TokenType::Assign).is_err());
+}
+
+#[test]
+fn test_assembler() {
+    let program = "
+        ; count n down from 5, then return 42
+        main:   ENT 1
+                LEA -1
+                PSH
+                IMM 5
+                SI
+        loop:   LEA -1
+                LI
+                BZ done
+                LEA -1
+                PSH
+                LEA -1
+                LI
+                PSH
+                IMM 1
+                SUB
+                SI
+                JMP loop
+        done:   IMM 42
+                LEV
+    ";
+    let mut c4 = C4::new();
+    c4.emit(OpCode::LEV);
+    let entry = c4.load_assembly(program).unwrap();
+    assert_eq!(entry, 2);
+    // `loop` lands after ENT 1, LEA -1, PSH, IMM 5, SI
+    assert_eq!(&c4.vm.code[13..15], &[OpCode::BZ as i64, 28]);
+    c4.start(entry);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(42)));
+
+    for bad in ["FOO 1", "IMM", "PSH 3", "JMP nowhere", "a: LEV\na: LEV", "1x: LEV"] {
+        assert!(C4::new().load_assembly(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_source_listing_fuzz() {
+    // Deterministic generator so failures reproduce
+    let mut seed: u64 = 0x5eed;
+    let mut rand = move |n: usize| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((seed >> 33) as usize) % n
+    };
+    let pieces = ["int", " ", "\n", "é", "ü", "x", "_y", "42", "'a'", "\"s\"", "+", "=", ";", "{", "}", "//c", "#d", "\r\n", "\t", "€", "0x1f"];
+
+    for _ in 0..300 {
+        let mut source = String::new();
+        for _ in 0..rand(30) {
+            source.push_str(pieces[rand(pieces.len())]);
+        }
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        c4.src = true;
+        c4.source = source;
+        c4.p = 0;
+        for step in 0..200 {
+            c4.next();
+            if c4.token.kind == TokenKind::Eof {
+                break;
+            }
+            // Re-lexing from an earlier position leaves lp ahead of p
+            if step == 5 && rand(2) == 0 {
+                c4.p = rand(c4.p + 1);
+            }
+        }
+    }
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    assert_eq!(c4.source_span(0, 0), "");
+    c4.source = "é\nab\n".to_string();
+    assert_eq!(c4.source_span(2, 4), "ab");
+    assert_eq!(c4.source_span(4, 2), "");
+    assert_eq!(c4.line_start(3), 2);
+}
+
+#[test]
+fn test_sizeof_expressions() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("char c; short s; int i; char *p; int **q; extern int ext;").unwrap();
+    for (src, size) in [
+        ("sizeof(c)", 1), ("sizeof(s)", 2), ("sizeof(i)", 8), ("sizeof(p)", 8), ("sizeof(*p)", 1),
+        ("sizeof *p", 1), ("sizeof(**q)", 8), ("sizeof(p[3])", 1), ("sizeof(int)", 8), ("sizeof(char *)", 8),
+        ("sizeof(ext)", 8),
+    ] {
+        c4.vm.le = 0;
+        c4.source = src.to_string();
+        c4.p = 0;
+        c4.next();
+        c4.expr(TokenType::Assign).unwrap();
+        // Only the size is emitted, none of the operand's code
+        assert_eq!(&c4.vm.code[1..=c4.vm.le], &[OpCode::IMM as i64, size], "{}", src);
+    }
+    // The dropped reference to the extern is not left for the linker to patch
+    assert!(c4.link().is_ok());
+
+    // Constant initializers can take the size of a variable too
+    c4.add_unit("int sz = sizeof(s) + sizeof *q;").unwrap();
+    assert_eq!(c4.lookup("sz").unwrap().value, 10);
+
+    // The operand is not evaluated
+    c4.vm.le = 0;
+    c4.emit_with_operand(OpCode::ENT, 0);
+    let frame = c4.vm.le;
+    c4.loc = 1;
+    c4.source = "{ int n = 5; int k = sizeof(n++); return n + k; }".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_block().unwrap();
+    c4.vm.code[frame] = c4.local_count;
+    c4.start(1);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(13)));
+}
+
+#[test]
+fn test_escape_sequences() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.source = r#""a\tb\r\n\\\'\"\x41\101\0z" '\t' '\0' '\'' '\x7f' '\177' '\\'"#.to_string();
+    c4.p = 0;
+    c4.next();
+    let TokenKind::Str(literal) = c4.token.kind else { panic!("{:?}", c4.token.kind) };
+    assert_eq!(literal.len, 12);
+    let start = literal.addr as usize;
+    assert_eq!(&c4.vm.data[start..start + 13], b"a\tb\r\n\\'\"AA\0z\0");
+    for expected in [9, 0, 39, 127, 127, 92] {
+        c4.next();
+        assert_eq!(c4.token.kind, TokenKind::Num(expected));
+    }
+
+    // Unknown escapes are reported when the literal is used
+    for bad in ["int x = '\\q';", "char *s = \"\\xg\";", "int f() { return \"\\j\"; }"] {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        let err = c4.add_unit(bad).unwrap_err();
+        assert!(err.contains("escape") || err.contains("hex"), "{}: {}", bad, err);
+    }
+}
+
+#[test]
+fn test_multi_char_constants() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    // Multi-character constants pack their characters, the first one highest, raw control bytes included
+    c4.source = "'ab' 'a\0' '\x01\x02\x03\x04' '' 'abcdefghi'".to_string();
+    c4.p = 0;
+    for expected in [0x6162, 0x6100, 0x01020304, 0] {
+        c4.next();
+        assert_eq!(c4.token.kind, TokenKind::Num(expected));
+    }
+    c4.next();
+    assert!(c4.check_lex_error().unwrap_err().contains("character constant too long"));
+}
+
+#[test]
+fn test_integer_literal_forms() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.source = "100L 0xFFu 1_000_000 0x7f_ff 017 0 42ull 7LU 0b1010 0B1111_0000u 3lL".to_string();
+    c4.p = 0;
+    for expected in [100, 255, 1_000_000, 0x7fff, 15, 0, 42, 7, 10, 0xf0] {
+        c4.next();
+        assert_eq!(c4.token.kind, TokenKind::Num(expected));
+    }
+    // `lL` is not a suffix, so the stray L is an error rather than an identifier
+    c4.next();
+    assert_eq!(c4.token.kind, TokenKind::Num(3));
+    assert!(c4.check_lex_error().is_err());
+
+    // Suffixes set the literal's type
+    c4.add_unit("unsigned u;").unwrap();
+    for (src, unsigned) in [("10", false), ("10u", true), ("10UL", true), ("10L", false)] {
+        c4.source = src.to_string();
+        c4.p = 0;
+        c4.next();
+        c4.expr(TokenType::Assign).unwrap();
+        assert_eq!(c4.type_ & (1 << 16) != 0, unsigned, "{}", src);
+    }
+
+    for bad in ["int x = 12abc;", "int x = 0x;", "int x = 09;", "int x = 0b;", "int x = 0b102;"] {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        assert!(c4.add_unit(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_adjacent_string_concatenation() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("char *s = \"hello \" \"wor\"\n  \"ld\"; char *t = \"next\";").unwrap();
+    let program = c4.program();
+    let strings: Vec<&str> = program.strings().iter().map(|s| s.value.as_str()).collect();
+    assert_eq!(strings, ["hello world", "next"]);
+
+    let s = c4.lookup("s").unwrap().value;
+    assert_eq!(c4.vm.read_cstr(s).unwrap(), "hello world");
+    let t = c4.lookup("t").unwrap().value;
+    assert_eq!(c4.vm.read_cstr(t).unwrap(), "next");
+    assert_eq!(t % 8, 0);
+
+    // In expressions too
+    c4.source = "\"ab\" \"cd\"".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.expr(TokenType::Assign).unwrap();
+    assert_eq!(c4.vm.read_cstr(c4.vm.code[c4.vm.le]).unwrap(), "abcd");
+
+    // Comments between the literals do not keep them apart
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("char *s = \"a\" /* x */ \"b\" // y\n \"c\"; char *t = \"d\" /* \"e\" */;\nint z = 0;").unwrap();
+    assert_eq!(c4.vm.read_cstr(c4.lookup("s").unwrap().value).unwrap(), "abc");
+    assert_eq!(c4.vm.read_cstr(c4.lookup("t").unwrap().value).unwrap(), "d");
+    assert_eq!(c4.line, 3);
+}
+
+#[test]
+fn test_char_class_builtins() {
+    assert_eq!(run_body("{ return isdigit('7') + isalpha('x') * 2 + isspace('\\t') * 4; }"), Ok(RunState::Halted(7)));
+    assert_eq!(run_body("{ return isdigit('a') + isalpha('1') + isspace('_') + isdigit(-1); }"), Ok(RunState::Halted(0)));
+    assert_eq!(run_body("{ char *s = \"aZ!\"; return toupper(s[0]) * 1000000 + tolower(s[1]) * 1000 + toupper(s[2]); }"),
+        Ok(RunState::Halted(65_122_033)));
+    assert_eq!(run_body("{ return tolower(-1); }"), Ok(RunState::Halted(-1)));
+}
+
+#[test]
+fn test_block_comments() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.source = "a /* one */ b /* two\n lines\n */ c /**/ d /* a * b / c **/ e".to_string();
+    c4.p = 0;
+    let mut names = Vec::new();
+    loop {
+        c4.next();
+        if c4.token.kind == TokenKind::Eof {
+            break;
+        }
+        names.push(c4.source_span(c4.token_start, c4.p));
+    }
+    assert_eq!(names, ["a", "b", "c", "d", "e"]);
+    assert_eq!(c4.line, 3);
+    assert!(c4.check_lex_error().is_ok());
+
+    // Division is still division
+    c4.source = "8 / 2".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.next();
+    assert_eq!(c4.token.kind, TokenKind::Op(TokenType::Div));
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    let err = c4.add_unit("int x; /* never closed\n int y;").unwrap_err();
+    assert!(err.contains("unterminated comment"), "{}", err);
+}
+
+#[test]
+fn test_atexit_hooks() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    let g = c4.define_global("g", Type::INT as i32, 0).unwrap();
+    // Each hook appends its digit to g: g = g * 10 + digit
+    let hook = |digit: i32| format!("ENT 0\nIMM {g}\nPSH\nIMM {g}\nLI\nPSH\nIMM 10\nMUL\nPSH\nIMM {digit}\nADD\nSI\nLEV\n");
+    let program = format!(
+        "main: ENT 0\nIMM one\nPSH\nATEX\nADJ 1\nIMM two\nPSH\nATEX\nADJ 1\nIMM 5\nLEV\none: {}two: {}",
+        hook(1), hook(2));
+    let entry = c4.load_assembly(&program).unwrap();
+    c4.start(entry);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(5)));
+    // Last registered runs first
+    assert_eq!(c4.lookup("g").unwrap().value, 21);
+
+    // Function names compile to their address, so they can be passed to atexit
+    c4.add_unit("int cleanup() { return 0; }").unwrap();
+    c4.source = "atexit(cleanup)".to_string();
+    c4.p = 0;
+    c4.next();
+    let start = c4.vm.le;
+    c4.expr(TokenType::Assign).unwrap();
+    assert_eq!(c4.vm.code[start + 1], OpCode::IMM as i64);
+    assert_eq!(c4.vm.code[start + 4], OpCode::ATEX as i64);
+}
+
+#[test]
+fn test_enum_declarations() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("enum color { RED, GREEN = 5, BLUE, NEG = -3, AFTER, BIG = GREEN * 4 + 1, };").unwrap();
+    for (name, value) in [("RED", 0), ("GREEN", 5), ("BLUE", 6), ("NEG", -3), ("AFTER", -2), ("BIG", 21)] {
+        let info = c4.lookup(name).unwrap();
+        assert_eq!(info.class, SymbolClass::EnumConst);
+        assert_eq!(info.value, value, "{}", name);
+    }
+    assert!(c4.add_unit("enum { RED };").is_err());
+
+    // Local enums shadow globals until the function's locals are restored
+    c4.emit_with_operand(OpCode::ENT, 0);
+    let frame = c4.vm.le;
+    c4.loc = 1;
+    c4.source = "{ enum { RED = 10, X }; int v = RED + X; return v; }".to_string();
+    c4.p = 0;
+    c4.next();
+    c4.compile_block().unwrap();
+    c4.vm.code[frame] = c4.local_count;
+    c4.start(frame - 1);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(21)));
+    c4.restore_locals();
+    assert_eq!(c4.lookup("RED").unwrap().value, 0);
+    assert_eq!(c4.lookup("X"), None);
+
+    c4.source = "{ enum { Y }; int Y; }".to_string();
+    c4.p = 0;
+    c4.next();
+    assert!(c4.compile_block().is_err());
+}
+
+#[test]
+fn test_source_map() {
+    let mut map = SourceMap::new();
+    let a = map.add_file("a.c", "int x;\nint y = 12abc;\n");
+    let b = map.add_file("b.h", "é\n\tz");
+    assert_eq!(map.file_name(b), Some("b.h"));
+    assert_eq!(map.line_col(a, 0), Some((1, 1)));
+    assert_eq!(map.line_col(a, 15), Some((2, 9)));
+    assert_eq!(map.line_col(b, 3), Some((2, 2)));
+    assert_eq!(map.line_col(7, 0), None);
+    assert_eq!(map.location(Span { file_id: a, start: 15, end: 20 }), Some("a.c:2:9".to_string()));
+    assert_eq!(map.snippet(Span { file_id: a, start: 15, end: 20 }).unwrap(),
+        " --> a.c:2:9\n  |\n2 | int y = 12abc;\n  |         ^^^^^\n");
+
+    // The lexer reports token spans against the registered file
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.set_source("main.c", "int main() {\n  return @;\n}");
+    while c4.token.kind != TokenKind::Punct('@') {
+        c4.next();
+    }
+    let span = c4.token_span();
+    assert_eq!(c4.source_map().location(span), Some("main.c:2:10".to_string()));
+    assert!(c4.source_map().snippet(span).unwrap().ends_with("2 |   return @;\n  |          ^\n"));
+}
+
+#[test]
+fn test_optimization_levels() {
+    let src = "{ int a = 2 * 3 + 4; return a * 1 + 0; }";
+
+    let mut plain = compile_body(src);
+    assert!(plain.optimize().is_empty());
+    plain.start(1);
+    assert_eq!(plain.run_for(10_000), Ok(RunState::Halted(10)));
+
+    let mut c4 = compile_body(src);
+    c4.set_opt_level(1).unwrap();
+    assert_eq!(c4.pass_pipeline(), vec!["fold", "peephole", "dce"]);
+    assert_eq!(&c4.optimize()[..2], &[("fold", 2), ("peephole", 2)]);
+    assert!(!c4.listing().contains("MUL"));
+    assert!(c4.listing().contains("IMM 10"));
+    assert!(c4.verify_code().is_empty());
+    c4.start(1);
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(10)));
+    assert!(c4.vm.cycle < plain.vm.cycle);
+
+    // A disabled pass is left out of the pipeline
+    let mut c4 = compile_body(src);
+    c4.set_opt_level(2).unwrap();
+    c4.disable_pass("fold").unwrap();
+    assert_eq!(c4.pass_pipeline(), vec!["peephole", "dce", "inline", "layout"]);
+    c4.optimize();
+    assert!(c4.listing().contains("MUL"));
+    assert!(c4.disable_pass("unroll").is_err());
+    assert!(c4.set_opt_level(3).is_err());
+
+    // The arm a constant condition never takes is removed
+    let mut c4 = compile_body("{ int a = 5; return 1 ? a : 7; }");
+    c4.set_opt_level(1).unwrap();
+    c4.optimize();
+    assert!(!c4.listing().contains("IMM 7"));
+    c4.start(1);
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(5)));
+
+    // Calls to functions returning a constant are inlined, jump chains threaded
+    let mut c4 = C4::new();
+    c4.load_assembly("
+        main:   ENT 0
+                IMM 1
+                PSH
+                JSR f
+                ADJ 1
+                JMP a
+        a:      JMP b
+        b:      LEV
+        f:      ENT 0
+                IMM 42
+                LEV
+    ").unwrap();
+    c4.set_opt_level(2).unwrap();
+    let stats = c4.optimize();
+    assert!(stats.contains(&("inline", 1)));
+    assert!(stats.contains(&("layout", 1)));
+    assert!(!c4.listing().contains("JSR"));
+    c4.start(1);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(42)));
+}
+
+#[test]
+fn test_variadic_functions() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("int sum(int n, ...) { } int fixed(int a);").unwrap();
+    let info = c4.program();
+    assert!(info.function("sum").unwrap().variadic);
+    assert_eq!(info.function("sum").unwrap().params, vec!["n".to_string()]);
+
+    let compile_body = |c4: &mut C4, src: &str| {
+        let entry = c4.vm.le + 1;
+        c4.emit_with_operand(OpCode::ENT, 0);
+        c4.loc = entry as i64;
+        c4.source = src.to_string();
+        c4.p = 0;
+        c4.next();
+        c4.compile_block().unwrap();
+        entry
+    };
+    // sum adds up its first n extra arguments
+    let sum = compile_body(&mut c4, "{ int n = va_arg(0);
+        int a = 1 <= n ? va_arg(1) : 0;
+        int b = 2 <= n ? va_arg(2) : 0;
+        int c = 3 <= n ? va_arg(3) : 0;
+        return (a + b + c) * 100 + va_count(); }");
+    c4.vm.code[sum + 1] = c4.local_count;
+    let idx = c4.symbols.iter().position(|sym| sym.name == "sum").unwrap();
+    c4.symbols[idx].value = sum as i64;
+
+    c4.local_count = 0;
+    let main = compile_body(&mut c4, "{ return sum(3, 4, 5, 6); }");
+    c4.start(main);
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(1504)));
+
+    let main = compile_body(&mut c4, "{ return sum(1, 9); }");
+    c4.start(main);
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(902)));
+
+    // The named parameters are still required
+    c4.source = "sum()".to_string();
+    c4.p = 0;
+    c4.next();
+    assert!(c4.expr(TokenType::Assign).is_err());
+
+    for bad in ["int f(...);", "int f(int a, ..., int b);"] {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        assert!(c4.add_unit(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_function_prototypes() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_unit("int is_even(int); int is_odd(int n) { } int is_even(int n) { }").unwrap();
+    assert_eq!(c4.program().function("is_even").unwrap().params, vec!["n".to_string()]);
+
+    // Calls are checked against the recorded signature
+    let call = |c4: &mut C4, src: &str| {
+        c4.source = src.to_string();
+        c4.p = 0;
+        c4.next();
+        c4.expr(TokenType::Assign)
+    };
+    assert!(call(&mut c4, "is_even(4)").is_ok());
+    assert!(call(&mut c4, "is_even(4, 5)").is_err());
+    assert!(call(&mut c4, "is_odd()").is_err());
+
+    for ok in ["int f(void); int f(void) { }", "int f(); int f(int a, char *b) { }",
+        "int f(const int a); int f(int a) { }", "int f(int a, ...); int f(int b, ...) { }"] {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        assert!(c4.add_unit(ok).is_ok(), "{}", ok);
+    }
+    for bad in ["int f(int); int f(char *p) { }", "int f(int); char f(int a) { }",
+        "int f(int, int); int f(int a) { }", "int f(int a, ...); int f(int a) { }",
+        "int f(int a) { } int f(char c);", "int f(int a b);"] {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        assert!(c4.add_unit(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_opt_bisect_limit() {
+    let compile = || {
+        let mut c4 = compile_body("{ int a = 2 * 3 + 4; return a * 1; }");
+        c4.set_opt_level(2).unwrap();
+        c4
+    };
+    let unlimited = {
+        let mut c4 = compile();
+        c4.optimize();
+        c4.listing()
+    };
+
+    // Nothing is rewritten with a limit of zero
+    let mut c4 = compile();
+    let original = c4.listing();
+    c4.set_opt_bisect_limit(Some(0));
+    assert!(c4.optimize().iter().all(|&(_, rewrites)| rewrites == 0));
+    assert_eq!(c4.listing(), original);
+
+    // Applications are numbered in a stable order: the first is folding 2 * 3
+    let mut c4 = compile();
+    c4.set_opt_bisect_limit(Some(1));
+    assert_eq!(c4.optimize()[0], ("fold", 1));
+    assert!(c4.listing().contains("IMM 6"));
+    assert!(c4.listing().contains("ADD"));
+    c4.start(1);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(10)));
+
+    // A limit past the last application changes nothing
+    let mut c4 = compile();
+    c4.set_opt_bisect_limit(Some(1000));
+    c4.optimize();
+    assert_eq!(c4.listing(), unlimited);
+}
+
+#[test]
+fn test_multiple_functions() {
+    let mut c4 = compile_program("
+        int seven() { return 7; }
+        int twice_seven() { return seven() + seven(); }
+        int later(int);
+        int main() { int a = twice_seven(); return a * 10 + later(9); }
+        int later(int n) { return n - 6; }
+    ").unwrap();
+    let program = c4.program();
+    let names: Vec<&str> = program.functions().iter().map(|func| func.name.as_str()).collect();
+    assert_eq!(names, vec!["seven", "twice_seven", "main", "later"]);
+    assert!(c4.verify_code().is_empty());
+    c4.start(program.function("main").unwrap().entry);
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(143)));
+
+    // A function that is declared but never defined is reported
+    let err = compile_program("int missing(); int main() { return missing(); }").err().unwrap();
+    assert!(err.contains("undefined symbol 'missing'"), "{}", err);
+}
+
+#[test]
+fn test_golden_trace() {
+    let program = "
+        main:   ENT 1
+                LEA -1
+                PSH
+                IMM 25
+                SI
+        loop:   LEA -1
+                LI
+                BZ done
+                LEA -1
+                PSH
+                LEA -1
+                LI
+                PSH
+                IMM 1
+                SUB
+                SI
+                JMP loop
+        done:   IMM 7
+                LEV
+    ";
+    let run = |interval: i64, patch: Option<i64>| {
+        let mut c4 = C4::new();
+        let entry = c4.load_assembly(program).unwrap();
+        if let Some(value) = patch {
+            c4.vm.code[7] = value;
+        }
+        c4.set_trace_interval(interval);
+        c4.start(entry);
+        assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(7)));
+        c4
+    };
+
+    let golden = run(50, None);
+    // 5 setup instructions, 12 per iteration, 3 to leave the loop, IMM and LEV, PSH and EXIT
+    assert_eq!(golden.vm.cycle, 5 + 25 * 12 + 3 + 2 + 2);
+    assert_eq!(golden.trace().len(), 7);
+    assert_eq!(golden.trace()[0].cycle, 50);
+    assert_eq!(golden.trace().last().unwrap().cycle, golden.vm.cycle);
+    let text = golden.trace_text();
+    assert_eq!(text.lines().count(), 7);
+
+    // The same program reproduces the trace exactly
+    assert_eq!(run(50, None).compare_trace(&text), Ok(7));
+    // One loop iteration fewer: the paths split at cycle 293, so checkpoint 6 (cycle 300) differs
+    let err = run(50, Some(24)).compare_trace(&text).unwrap_err();
+    assert!(err.starts_with("checkpoint 6:"), "{}", err);
+    // Without tracing nothing is recorded
+    assert!(run(0, None).trace().is_empty());
+}
+
+#[test]
+fn test_runtime_errors() {
+    let mut c4 = start_program("int div(int a, int b) {\n  return a / b;\n}\nint main() {\n  return div(10, 0);\n}\n");
+    let err = c4.run_for(10_000).unwrap_err();
+    assert!(matches!(err, RuntimeError::DivByZero { .. }), "{:?}", err);
+    let site = err.site();
+    assert_eq!(site.line, Some(2));
+    let frames: Vec<(Option<&str>, Option<i32>)> = site.stack.iter()
+        .map(|frame| (frame.function.as_deref(), frame.line))
+        .collect();
+    assert_eq!(frames, vec![(Some("div"), Some(2)), (Some("main"), Some(5))]);
+    assert_eq!(site.stack[0].pc, site.pc);
+    assert!(err.to_string().starts_with(&format!("division by zero at pc {} (line 2)", site.pc)), "{}", err);
+    assert!(err.stack_trace().contains("at main (pc "), "{}", err.stack_trace());
+
+    // Loads outside memory report the address
+    let mut c4 = C4::new();
+    c4.emit_with_operand(OpCode::IMM, -8);
+    c4.emit(OpCode::LI);
+    c4.start(1);
+    let err = c4.run_for(100).unwrap_err();
+    assert!(matches!(err, RuntimeError::InvalidRead { addr: -8, .. }), "{:?}", err);
+    assert_eq!(err.site().pc, 3);
+
+    // The cycle limit stops an endless loop
+    let mut c4 = C4::new();
+    c4.emit_with_operand(OpCode::JMP, 1);
+    c4.start(1);
+    c4.set_cycle_limit(Some(500));
+    let err = c4.run_for(10_000).unwrap_err();
+    assert!(matches!(err, RuntimeError::CycleLimit { limit: 500, .. }), "{:?}", err);
+    assert_eq!(c4.vm.cycle, 500);
+    assert!(err.to_string().starts_with("cycle limit exceeded (500 instructions) at pc "), "{}", err);
+
+    // Cancelling an async run is a RuntimeError too
+    let mut c4 = C4::new();
+    c4.emit_with_operand(OpCode::JMP, 1);
+    c4.start(1);
+    let token = CancellationToken::new();
+    token.cancel();
+    let mut fut = c4.run_async(10, token);
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(matches!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Err(RuntimeError::Cancelled { .. }))));
+}
+
+#[test]
+fn test_function_parameters() {
+    let run = |src: &str| {
+        let mut c4 = compile_program(src).unwrap();
+        assert!(c4.verify_code().is_empty());
+        c4.start(c4.program().function("main").unwrap().entry);
+        c4.run_for(100_000)
+    };
+    // Parameters arrive in declaration order next to the function's own locals
+    assert_eq!(run("
+        int sub(int a, int b) { return a - b; }
+        int mix(int a, char c, int b) { int t = a * 100; int u = b; return t + c * 10 + u; }
+        int main() { return sub(sub(50, 8), 2) * 1000 + mix(1, 2, 3); }
+    "), Ok(RunState::Halted(40123)));
+    // A parameter shadows a global of the same name only inside its function
+    assert_eq!(run("
+        int n;
+        int twice(int n) { int m = n * 2; return m; }
+        int main() { return twice(4) * 10 + n; }
+    "), Ok(RunState::Halted(80)));
+    // Named parameters of variadic functions hold the leading arguments
+    assert_eq!(run("
+        int pick(int base, int which, ...) { return base + va_arg(which + 1) + va_count() * 1000; }
+        int main() { return pick(100, 2, 7, 8, 9); }
+    "), Ok(RunState::Halted(5108)));
+}
+
+#[test]
+fn test_recursion() {
+    let run = |src: &str| run_program(src, 10_000_000);
+    assert_eq!(run("
+        int fib(int n) { return n < 2 ? n : fib(n - 1) + fib(n - 2); }
+        int main() { return fib(20); }
+    "), Ok(RunState::Halted(6765)));
+    // Locals of every activation survive the calls made after they are set
+    assert_eq!(run("
+        int depth(int n) { int here = n * 3; int below = n ? depth(n - 1) : 0; return here - n * 3 + below + 1; }
+        int main() { return depth(5000); }
+    "), Ok(RunState::Halted(5001)));
+    // Mutual recursion through a prototype
+    assert_eq!(run("
+        int odd(int n);
+        int even(int n) { return n == 0 ? 1 : odd(n - 1); }
+        int odd(int n) { return n == 0 ? 0 : even(n - 1); }
+        int main() { return even(100) * 10 + odd(7); }
+    "), Ok(RunState::Halted(11)));    // Runaway recursion runs out of stack instead of corrupting memory
+    let err = run("
+        int forever(int n) { return forever(n + 1) + 1; }
+        int main() { return forever(0); }
+    ").unwrap_err();
+    assert!(matches!(err, RuntimeError::StackOverflow { .. }), "{:?}", err);
+    assert_eq!(err.site().stack[0].function.as_deref(), Some("forever"));
+}
+
+#[test]
+fn test_block_scopes() {
+    let run = |src: &str| run_program(src, 10_000);
+    // Inner declarations shadow outer ones only until their block ends
+    assert_eq!(run("int x = 1; int main() { int a = x; int x = 2; { int x = 3; { int x = 4; return a * 1000 + x; } } }"),
+        Ok(RunState::Halted(1004)));
+    assert_eq!(run("int main() { int x = 2; { int x = 3; { int x = 4; } return x; } }"), Ok(RunState::Halted(3)));
+    assert_eq!(run("int main() { int x = 2; { int x = 3; int y = x; } return x; }"), Ok(RunState::Halted(2)));
+    // Enumerators are scoped the same way
+    assert_eq!(run("int main() { int x = 2; { enum { x = 7 }; return x; } }"), Ok(RunState::Halted(7)));
+    assert_eq!(run("int main() { int x = 2; { enum { x = 7 }; } return x; }"), Ok(RunState::Halted(2)));
+    // Every block's locals get their own slot
+    assert_eq!(run("int main() { int a = 1; { int b = 20; { int c = 300; return a + b + c; } } }"), Ok(RunState::Halted(321)));
+
+    // The same name may be declared again in a nested block, but not twice in one block
+    assert!(compile_program("int main() { int a; { int a; } int b; }").is_ok());
+    assert!(compile_program("int main() { int a; { int b; int b; } }").is_err());
+    // Parameters share the function's outermost block
+    assert!(compile_program("int f(int a) { int a; }").is_err());
+    assert!(compile_program("int f(int a) { { int a; } }").is_ok());
+}
+
+#[test]
+fn test_token_kinds() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    let tokens = c4.tokenize("while (n >= 'a') f(n, \"hi\" \"!\", 0x10, ...);").unwrap();
+    let n = SymbolId(c4.symbols.iter().position(|sym| sym.name == "n").unwrap());
+    let f = SymbolId(c4.symbols.iter().position(|sym| sym.name == "f").unwrap());
+    let kinds: Vec<TokenKind> = tokens.iter().map(|token| token.kind).collect();
+    let TokenKind::Str(string) = kinds[10] else { panic!("{:?}", kinds[10]) };
+    assert_eq!(kinds, vec![
+        TokenKind::Keyword(TokenType::While), TokenKind::Punct('('), TokenKind::Ident(n), TokenKind::Op(TokenType::Ge),
+        TokenKind::Num('a' as i64), TokenKind::Punct(')'), TokenKind::Ident(f), TokenKind::Punct('('),
+        TokenKind::Ident(n), TokenKind::Punct(','), TokenKind::Str(string), TokenKind::Punct(','), TokenKind::Num(16),
+        TokenKind::Punct(','),
+        TokenKind::Op(TokenType::Ellipsis), TokenKind::Punct(')'), TokenKind::Punct(';'), TokenKind::Eof,
+    ]);
+    assert_eq!(string.len, 3);
+    assert_eq!(c4.vm.read_cstr(string.addr), Ok("hi!".to_string()));
+    // Spans cover the token's text, the joined string literal included
+    assert_eq!((tokens[3].span.start, tokens[3].span.end), (9, 11));
+    assert_eq!((tokens[10].span.start, tokens[10].span.end), (22, 30));
+
+    // Each literal keeps its own length once later ones have been lexed
+    let tokens = c4.tokenize("\"a\" + \"bcd\"").unwrap();
+    let lens: Vec<usize> = tokens.iter().filter_map(|token| match token.kind {
+        TokenKind::Str(string) => Some(string.len),
+        _ => None,
+    }).collect();
+    assert_eq!(lens, vec![1, 3]);
+
+    assert!(c4.tokenize("'\\x'").is_err());
+}
+
+#[test]
+fn test_void_type() {
+    // void functions may return, but not with a value
+    assert!(compile_program("void f() { return; } int main() { return 0; }").is_ok());
+    let err = compile_program("void f() { return 1; }").err().unwrap();
+    assert!(err.contains("void function returns a value"), "{}", err);
+    // Their result cannot be used
+    for src in ["void f(); int g() { return f(); }", "void f(); int g() { int x = f(); return x; }",
+                "void f(); int g() { return f() + 1; }", "void f(); int h(int a); int g() { return h(f()); }"] {
+        let err = compile_program(src).err().unwrap();
+        assert!(err.contains("void value not ignored"), "{}: {}", src, err);
+    }
+    // Only pointers to void are objects
+    assert!(compile_program("void x;").is_err());
+    assert!(compile_program("int main() { void x; }").is_err());
+    assert!(compile_program("int f(void x);").is_err());
+    assert!(compile_program("int f(int, void);").is_err());
+    assert_eq!(compile_program("int f(void);").unwrap().program().functions().len(), 0);
+
+    let mut c4 = start_program("int main() { char *s = \"hi\"; void *p = s; char *q = p; return *(q + 1); }");
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted('i' as i64)));
+}
+
+#[test]
+fn test_return_types() {
+    let run = |src: &str| run_program(src, 10_000);
+    // Pointers come back intact and keep their type at the call site
+    assert_eq!(run("
+        char *greeting() { return \"hello\"; }
+        int *null() { return 0; }
+        int main() { char *s = greeting(); return s[1] + (null() == 0) * 1000; }
+    "), Ok(RunState::Halted(1000 + 'e' as i64)));
+    // Narrow return types truncate like a store would
+    assert_eq!(run("char c(int v) { return v; } int main() { return c(300) * 1000 + c(255); }"),
+        Ok(RunState::Halted(44 * 1000 - 1)));
+    assert_eq!(run("unsigned char c(int v) { return v; } int main() { return c(-1); }"), Ok(RunState::Halted(255)));
+    assert_eq!(run("short s(int v) { return v; } int main() { return s(70000); }"), Ok(RunState::Halted(4464)));
+
+    let err = compile_program("char *f(int v) { return v; }").err().unwrap();
+    assert!(err.contains("returning an integer from a function returning a pointer"), "{}", err);
+    let err = compile_program("int f(char *s) { return s; }").err().unwrap();
+    assert!(err.contains("returning a pointer from a function returning an integer"), "{}", err);
+}
+
+#[test]
+fn test_pointer_comparisons() {
+    let run = |src: &str| run_program(src, 10_000);
+    // Pointers into the same object order by address and subtract to an element count
+    assert_eq!(run("
+        int main() {
+            char *a = \"abcdefghij\"; char *p = a + 2; char *q = a + 7; void *v = q;
+            return (p < q) * 10000 + (q >= p) * 1000 + (p == NULL) * 100 + (q - p) * 10 + (v == q) + (0 != p);
+        }
+    "), Ok(RunState::Halted(11052)));
+
+    for (src, message) in [
+        ("int f(int *p, char *c) { return p == c; }", "comparison of distinct pointer types"),
+        ("int f(int *p, int n) { return p < n; }", "comparison between pointer and integer"),
+        ("int f(int *p) { return 1 == p; }", "comparison between pointer and integer"),
+        ("int f(int *p, char *c) { return p - c; }", "subtraction of distinct pointer types"),
+        ("int f(int *p, int *q) { int *r = p + q; return 0; }", "cannot add two pointers"),
+        ("int f(int *p) { return 1 - p; }", "cannot subtract a pointer from an integer"),
+    ] {
+        let err = compile_program(src).err().unwrap();
+        assert!(err.contains(message), "{}: {}", src, err);
+    }
+    // Qualifiers do not make pointers distinct
+    assert!(compile_program("int f(const char *a, char *b) { return (a == b) + (b - a); }").is_ok());
+}
+
+#[test]
+fn test_store_through_pointer() {
+    let run = |src: &str| run_program(src, 10_000);
+    assert_eq!(run("int main() { int x = 1; int *p = &x; *p = 5; return x; }"), Ok(RunState::Halted(5)));
+    // Char stores go through SC, so only the addressed byte changes
+    assert_eq!(run("int main() { char *buf = \"abcd\"; int i = 2; *(buf + i) = 'Z'; *buf = 'A'; return buf[0] * 1000 + buf[1] * 0 + buf[2] + buf[3] * 0; }"),
+        Ok(RunState::Halted('A' as i64 * 1000 + 'Z' as i64)));
+    assert_eq!(run("int main() { int x = 1; int *p = &x; int **pp = &p; **pp = 7; *p += 3; return x; }"), Ok(RunState::Halted(10)));
+
+    assert!(compile_program("int main() { const int x = 1; const int *p = &x; *p = 2; }").err().unwrap().contains("const"));
+    let err = compile_program("int main() { int x; *(&x + 1) + 1 = 2; }").err().unwrap();
+    assert!(err.contains("lvalue"), "{}", err);
+}
+
+#[test]
+fn test_narrow_stores() {
+    let run = |src: &str| run_program(src, 10_000);
+    // Stores truncate; reloads and the assignment's own value extend by signedness
+    assert_eq!(run("int main() { char c = 300; unsigned char u = 300; return c * 1000 + u; }"), Ok(RunState::Halted(44044)));
+    assert_eq!(run("int main() { char c = 200; unsigned char u = 200; return (c < 0) * 1000 + u; }"), Ok(RunState::Halted(1200)));
+    assert_eq!(run("int main() { unsigned char u; int v = (u = 511); return v; }"), Ok(RunState::Halted(255)));
+    assert_eq!(run("int main() { char c; int v = (c = 255); return v; }"), Ok(RunState::Halted(-1)));
+    assert_eq!(run("int main() { unsigned short s = 70000; int v = (s = -1); return v * 100000 + s % 1000; }"),
+        Ok(RunState::Halted(65535 * 100000 + 535)));
+    // Through pointers too
+    assert_eq!(run("int main() { unsigned char *p = \"ab\"; *p = 255; int v = (*p += 2); return v * 1000 + *p; }"), Ok(RunState::Halted(1001)));
+    // Post-increment yields the old value even when the store wraps
+    assert_eq!(run("int main() { char c = 127; int old = c++; return old * 1000 - c; }"), Ok(RunState::Halted(127128)));
+    assert_eq!(run("int main() { unsigned char u = 255; int old = u++; return old * 1000 + u; }"), Ok(RunState::Halted(255000)));
+}
+
+#[test]
+fn test_if_else_chains() {
+    let run = |src: &str| run_program(src, 10_000);
+    let chain = |x: i64| run(&format!(
+        "int main() {{ int x = {}; if (x < 0) return 1; else if (x == 0) return 2; else if (x < 10) {{ return 3; }} else return 4; }}", x));
+    assert_eq!(chain(-5), Ok(RunState::Halted(1)));
+    assert_eq!(chain(0), Ok(RunState::Halted(2)));
+    assert_eq!(chain(7), Ok(RunState::Halted(3)));
+    assert_eq!(chain(70), Ok(RunState::Halted(4)));
+
+    // Nested ifs: the else binds to the innermost if, and falling through continues after it
+    let nested = |x: i64, y: i64| run(&format!(
+        "int main() {{ int x = {}; int y = {}; if (x) {{ if (y) return 1; else {{ int z = 5; return z; }} }} return 9; }}", x, y));
+    assert_eq!(nested(1, 1), Ok(RunState::Halted(1)));
+    assert_eq!(nested(1, 0), Ok(RunState::Halted(5)));
+    assert_eq!(nested(0, 1), Ok(RunState::Halted(9)));
+
+    // Branch operands point at real instructions; char and pointer conditions are allowed
+    assert_eq!(run("int main() { char c = 'a'; char *p = 0; if (c) if (p) return 1; else return 2; return 3; }"), Ok(RunState::Halted(2)));
+    assert_eq!(run("int main() { int n = 3; int *p = &n; while (*p) *p = *p - 1; return n + 8; }"), Ok(RunState::Halted(8)));
+
+    assert!(compile_program("int main() { if (1 return 0; }").err().unwrap().contains("close paren"));
+}
+
+#[test]
+fn test_operator_precedence() {
+    let run = |expr: &str| {
+        match run_program(&format!("int main() {{ int a = 6; int b = 3; return {}; }}", expr), 10_000) {
+            Ok(RunState::Halted(v)) => v,
+            other => panic!("{}: {:?}", expr, other),
+        }
+    };
+    assert_eq!(run("1 + 2 * 3"), 7);
+    assert_eq!(run("a - b - 1"), 2);
+    assert_eq!(run("a / b * 4 % 5"), 3);
+    assert_eq!(run("1 << 2 + 1"), 8);
+    assert_eq!(run("a & 3 == 2"), 0);
+    assert_eq!(run("a | b ^ 5 & 7"), 6 | (3 ^ 5));
+    assert_eq!(run("a > b == b < a"), 1);
+    assert_eq!(run("0 || 1 && 0"), 0);
+    assert_eq!(run("a < b ? 1 : b < a ? 2 : 3"), 2);
+    assert_eq!(run("-a * 2 + b++ * 2"), -6);
+    assert_eq!(run("(a = b = 4) + a * b"), 20);
+}
+
+#[test]
+fn test_expression_statements() {
+    let run = |src: &str| run_program(src, 10_000);
+    // Calls (void ones included) and assignments run for their effects, their values are dropped
+    assert_eq!(run("int g; void set(int v) { g = v; } int twice(int v) { return v * 2; } \
+        int main() { int x; x = 2; set(x * 5); twice(x); x += g; g = x = x + 1; x; return g; }"), Ok(RunState::Halted(13)));
+    assert_eq!(run("int main() { int i = 0; int sum = 0; while (i < 5) { sum += i; i++; } if (sum > 5) sum = sum * 2; return sum; }"),
+        Ok(RunState::Halted(20)));
+
+    assert!(compile_program("int main() { int x; x = 1 return x; }").err().unwrap().contains("semicolon expected after expression"));
+}
+
+#[test]
+fn test_empty_statements() {
+    let run = |src: &str| run_program(src, 10_000);
+    assert_eq!(run("int n; int step() { n++; return n < 4; } int main() { while (step()) ; return n; }"), Ok(RunState::Halted(4)));
+    assert_eq!(run("int main() { int x = 1; ; {} { ; } if (x) {} else ; if (!x) ; else { } while (0) {} return x + 1;; }"),
+        Ok(RunState::Halted(2)));
+    assert_eq!(run("void f() {} int main() { f(); return 3; }"), Ok(RunState::Halted(3)));
+}
+
+#[test]
+fn test_include_files() {
+    let dir = std::env::temp_dir().join(format!("c4_include_{}", std::process::id()));
+    let sys = dir.join("sys");
+    fs::create_dir_all(&sys).unwrap();
+    fs::write(dir.join("twice.h"), "// helpers\nint twice(int x) { return 2 * x; }\n").unwrap();
+    fs::write(sys.join("lib.h"), "#include \"nested.h\"\nint inc(int x) { return x + ONE; }\n").unwrap();
+    fs::write(sys.join("nested.h"), "enum { ONE = 1 };\n").unwrap();
+    let main = dir.join("main.c");
+    let main_name = main.to_string_lossy().to_string();
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.add_include_path(&sys.to_string_lossy());
+    c4.preprocess_source(&main_name, "#include \"twice.h\"\n  # include <lib.h>\nint main() {\n  return twice(inc(20));\n}\n").unwrap();
+    c4.compile().unwrap();
+    c4.start(c4.program().function("main").unwrap().entry);
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(42)));
+
+    // Tokens remember the file and line they were read from
+    let pos = c4.source.find("ONE;").unwrap();
+    assert_eq!(c4.origin_at(pos).to_string(), format!("{}:2", sys.join("lib.h").display()));
+    let pos = c4.source.find("return twice").unwrap();
+    assert_eq!(c4.origin_at(pos).to_string(), format!("{}:4", main_name));
+
+    // <...> does not look next to the including file; a self-including header stops
+    let mut c4 = C4::new();
+    let err = c4.preprocess_source(&main_name, "#include <twice.h>\n").unwrap_err();
+    assert!(err.contains("'twice.h' not found"), "{}", err);
+    fs::write(dir.join("loop.h"), "#include \"loop.h\"\n").unwrap();
+    let err = c4.preprocess_source(&main_name, "#include \"loop.h\"\n").unwrap_err();
+    assert!(err.contains("nested too deeply"), "{}", err);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_preprocessed_error_locations() {
+    let dir = std::env::temp_dir().join(format!("c4_error_origin_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("decls.h"), "int a;\nint b;\nint c;\nint d;\n").unwrap();
+    fs::write(dir.join("bad.h"), "int a;\nint b = ;\n").unwrap();
+    let main = dir.join("m.c").to_string_lossy().to_string();
+    let header = dir.join("bad.h").to_string_lossy().to_string();
+
+    // Errors and snippets give the line in the file as written, not in the preprocessed text
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.preprocess_source(&main, "#include \"decls.h\"\nint main() {\n  int x; x = 1 +;\n  return 0;\n}\n").unwrap();
+    assert_eq!(c4.compile().unwrap_err(), format!("{}:3: bad expression", main));
+    let snippet = c4.source_map().snippet(c4.token_span()).unwrap();
+    assert!(snippet.starts_with(&format!(" --> {}:3:17\n", main)), "{}", snippet);
+    assert!(snippet.contains("3 |   int x; x = 1 +;\n"), "{}", snippet);
+
+    // Including inside an included file
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.preprocess_source(&main, "#include \"bad.h\"\nint main() { return 0; }\n").unwrap();
+    assert_eq!(c4.compile().unwrap_err(), format!("{}:2: constant expression expected", header));
+    let snippet = c4.source_map().snippet(c4.token_span()).unwrap();
+    assert!(snippet.starts_with(&format!(" --> {}:2:9\n", header)), "{}", snippet);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_object_macros() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    let warnings = c4.preprocess_source("main.c", "#define SIZE 10 // elements\n#define TOTAL (SIZE * 4)\n\
+        #define SIZE 10\n#define LOOP LOOP + 1\n\
+        int main() {\n  char *s = \"SIZE\"; /* SIZE\n SIZE */ int LOOP = 2;\n  return TOTAL + s[0] * 0 + LOOP;\n}\n\
+        #undef TOTAL\nint TOTAL;\n").unwrap();
+    // The same definition again is no redefinition
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    assert!(c4.source.contains("char *s = \"SIZE\"; /* SIZE\n SIZE */ int LOOP + 1 = 2;"), "{}", c4.source);
+    assert!(c4.source.contains("return (10 * 4) + s[0] * 0 + LOOP + 1;"), "{}", c4.source);
+    assert!(c4.source.contains("int TOTAL;"));
+
+    // Tokens from an expansion point at the macro, nested ones at the innermost
+    let pos = c4.source.find("10 *").unwrap();
+    assert_eq!(c4.origin_at(pos).to_string(), "main.c:8 (expanded from macro SIZE at main.c:3)");
+    let pos = c4.source.find("* 4)").unwrap();
+    assert_eq!(c4.origin_at(pos).to_string(), "main.c:8 (expanded from macro TOTAL at main.c:2)");
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    let warnings = c4.preprocess_source("main.c", "#define N 1\n#define N 2\nint main() { return N; }\n").unwrap();
+    assert_eq!(warnings, vec!["main.c:2: 'N' redefined (previous definition at main.c:1)".to_string()]);
+    c4.compile().unwrap();
+    c4.start(c4.program().function("main").unwrap().entry);
+    assert_eq!(c4.run_for(1_000), Ok(RunState::Halted(2)));
+    assert!(c4.preprocess_source("main.c", "#define 1X\n").unwrap_err().contains("macro name missing"));
+}
+
+#[test]
+fn test_function_macros() {
+    let run = |src: &str| {
+        let mut c4 = C4::new();
+        c4.init_symbol_table();
+        c4.preprocess_source("main.c", src).unwrap();
+        c4.compile().unwrap();
+        c4.start(c4.program().function("main").unwrap().entry);
+        c4.run_for(10_000)
+    };
+    let defs = "#define MIN(a,b) ((a)<(b)?(a):(b))\n#define SQR( x ) ((x) * (x))\n#define ZERO() 0\n#define TEN 10\n";
+    assert_eq!(run(&format!("{}int main() {{ return MIN(SQR(3), MIN(TEN, 12)) + ZERO(); }}\n", defs)), Ok(RunState::Halted(9)));
+    // Commas inside parentheses and literals do not split arguments; arguments may hold macros
+    assert_eq!(run(&format!("{}int f(int a, int b) {{ return a - b; }}\nint main() {{ return SQR(f(TEN, 7)) + MIN(',', 50); }}\n", defs)),
+        Ok(RunState::Halted(9 + 44)));
+
+    let mut c4 = C4::new();
+    c4.preprocess_source("main.c", "#define F(x) x + F(x)\n#define G (y) y\nint SQR; F(F(1)); G;\n").unwrap();
+    // A function-like name without ( is not an invocation; a space before ( makes an object-like macro
+    assert!(c4.source.contains("int SQR; 1 + F(1) + F(1 + F(1)); (y) y;"), "{}", c4.source);
+
+    assert!(c4.preprocess_source("main.c", "#define M(a, b) a\nM(1);\n").unwrap_err().contains("main.c:2: macro 'M' expects 2 arguments, got 1"));
+    assert!(c4.preprocess_source("main.c", "#define M(a) a\nM(1;\n").unwrap_err().contains("unterminated argument list"));
+    assert!(c4.preprocess_source("main.c", "#define M(a, a) a\n").unwrap_err().contains("bad parameter"));
+}
+
+#[test]
+fn test_multiline_macro_calls() {
+    // Arguments may continue on later lines; the expansion lands on the call's first line and
+    // blank lines keep everything after it where it was
+    let src = "#define ADD(a, b) ((a) + (b))\nint x = ADD(1, // one\n  2 /* two\n  */);\nint y = ADD(x,\n\n x);\nint z;\n";
+    let text = Preprocessor::new().run("main.c", src).unwrap().text;
+    assert_eq!(text, "\nint x = ((1) + (2));\n\n\nint y = ((x) + (x));\n\n\nint z;\n");
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.preprocess_source("main.c", "#define ADD(a, b) ((a) + (b))\nint main() {\n  return ADD(40,\n    2);\n}\n").unwrap();
+    c4.compile().unwrap();
+    c4.start(c4.program().function("main").unwrap().entry);
+    assert_eq!(c4.run_for(1_000), Ok(RunState::Halted(42)));
+
+    // A call left open to the end of the file is reported at the line it started on
+    let err = Preprocessor::new().run("main.c", "#define M(a) a\nint x;\nM(1,\n2;\n").unwrap_err();
+    assert!(err.contains("main.c:3: unterminated argument list invoking macro 'M'"), "{}", err);
+}
+
+#[test]
+fn test_predefined_macros() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.define_macro("LIMIT", "40").unwrap();
+    c4.define_macro("ADD(a,b)", "((a) + (b))").unwrap();
+    // The program can redefine them, with the usual warning
+    let warnings = c4.preprocess_source("main.c", "#define LIMIT 41\nint main() { return ADD(LIMIT, 1); }\n").unwrap();
+    assert_eq!(warnings, vec!["main.c:1: 'LIMIT' redefined (previous definition at <command line>:1)".to_string()]);
+    c4.compile().unwrap();
+    c4.start(c4.program().function("main").unwrap().entry);
+    assert_eq!(c4.run_for(1_000), Ok(RunState::Halted(42)));
+
+    assert!(c4.define_macro("", "1").is_err());
+    assert!(c4.define_macro("9LIVES", "1").is_err());
+}
+
+#[test]
+fn test_error_and_warning_directives() {
+    let mut c4 = C4::new();
+    let warnings = c4.preprocess_source("main.c", "#warning check the  limits /* soon */\nint x;\n# warning\n").unwrap();
+    assert_eq!(warnings, vec!["main.c:1: #warning check the  limits".to_string(), "main.c:3: #warning".to_string()]);
+    assert!(c4.source.starts_with("\nint x;\n"));
+
+    let err = c4.preprocess_source("main.c", "int x;\n#error unsupported platform\nint y;\n").unwrap_err();
+    assert_eq!(err, "main.c:2: #error unsupported platform");
+}
+
+#[test]
+fn test_builtin_macros() {
+    // __DATE__ as of a fixed clock, `days` after 1970-01-01
+    let date = |days: i64| {
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.set_clock(Some(days * 86400 + 3600));
+        preprocessor.run("date.c", "__DATE__\n").unwrap().text
+    };
+    assert_eq!(date(0), "\"Jan  1 1970\"\n");
+    assert_eq!(date(11016), "\"Feb 29 2000\"\n");
+    assert_eq!(date(20742), "\"Oct 16 2026\"\n");
+
+    let dir = std::env::temp_dir().join(format!("c4_builtin_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let header = dir.join("where.h");
+    fs::write(&header, "int header_line = __LINE__;\n").unwrap();
+    let main = dir.join("main.c").to_string_lossy().to_string();
+
+    let mut c4 = C4::new();
+    c4.preprocess_source(&main, "#define HERE __LINE__\nint a = __LINE__;\n#include \"where.h\"\nint b = HERE;\nchar *f = __FILE__;\nchar *d = __DATE__;\n").unwrap();
+    assert!(c4.source.contains("int a = 2;"), "{}", c4.source);
+    assert!(c4.source.contains("int header_line = 1;"));
+    assert!(c4.source.contains("int b = 4;"));
+    assert!(c4.source.contains(&format!("char *f = \"{}\";", main)));
+    let date = c4.source.split("char *d = ").nth(1).unwrap();
+    assert_eq!(date.find(';'), Some(13), "{}", date);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_include_guards() {
+    let dir = std::env::temp_dir().join(format!("c4_guards_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.h"), "// a\n#ifndef A_H\n#define A_H\n#include \"b.h\"\nint a_count = 1;\n#endif\n").unwrap();
+    fs::write(dir.join("b.h"), "#ifndef B_H\n#define B_H\n#include \"a.h\"\nint b_count = 1;\n#endif // B_H\n").unwrap();
+    fs::write(dir.join("once.h"), "#pragma once\n#include \"once.h\"\nint once_count = 1;\n").unwrap();
+    let main = dir.join("main.c").to_string_lossy().to_string();
+
+    let mut c4 = C4::new();
+    c4.preprocess_source(&main, "#include \"a.h\"\n#include \"b.h\"\n#include \"once.h\"\n#include \"./once.h\"\n#include \"a.h\"\n").unwrap();
+    assert_eq!(c4.source.matches("int a_count").count(), 1, "{}", c4.source);
+    assert_eq!(c4.source.matches("int b_count").count(), 1);
+    assert_eq!(c4.source.matches("int once_count").count(), 1);
+
+    // Only a file wholly inside its guard is skipped once the guard is defined: code after
+    // the #endif, or a #define of another name, keeps it from being taken for one
+    fs::write(dir.join("x.h"), "\n// header\n#ifndef X_H\n# define X_H 1\nint x;\n#ifdef Y\n#endif\n#endif\n\n").unwrap();
+    fs::write(dir.join("after.h"), "#ifndef AFTER_H\n#define AFTER_H\n#endif\nint after;\n").unwrap();
+    fs::write(dir.join("other.h"), "#ifndef OTHER_H\n#define NOT_OTHER_H\nint other;\n#endif\n").unwrap();
+    let mut c4 = C4::new();
+    c4.preprocess_source(&main, "#include \"x.h\"\n#include \"after.h\"\n#include \"other.h\"\n\
+        #include \"x.h\"\n#include \"after.h\"\n#include \"other.h\"\n").unwrap();
+    assert_eq!(c4.source.matches("int x;").count(), 1, "{}", c4.source);
+    assert_eq!(c4.source.matches("int after;").count(), 2);
+    assert_eq!(c4.source.matches("int other;").count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // The conditionals the guards rely on
+    let mut c4 = C4::new();
+    c4.preprocess_source("main.c", "#define ON\n#ifdef ON\nint a;\n#ifndef ON\nint b;\n#else\nint c;\n#endif\n#else\nint d;\n#if NESTED\n#endif\n#endif\nint e;\n").unwrap();
+    assert_eq!(c4.source, "\n\nint a;\n\n\n\nint c;\n\n\n\n\n\n\nint e;\n");
+    assert!(c4.preprocess_source("main.c", "#ifdef ON\n").unwrap_err().contains("main.c:1: unterminated"));
+    assert!(c4.preprocess_source("main.c", "#endif\n").unwrap_err().contains("without #ifdef"));
+    assert!(c4.preprocess_source("main.c", "#if 1\n#endif\n").unwrap_err().contains("not supported"));
+    // #elif after a skipped branch could still pick its own, so it is rejected as well
+    let err = c4.preprocess_source("main.c", "#ifdef NOPE\nint a;\n#elif 1\nint b;\n#else\nint c;\n#endif\n").unwrap_err();
+    assert!(err.contains("main.c:3: #elif is not supported"), "{}", err);
+    assert!(c4.preprocess_source("main.c", "#ifdef NOPE\n#ifdef ON\n#elif 1\n#endif\n#endif\n").is_ok());
+}
+
+#[test]
+fn test_line_continuation() {
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.preprocess_source("main.c", "#define MAX(a, b) \\\n  ((a) > (b) \\\n   ? (a) : (b))\nint main() { char *s = \"con\\\ntinued\"; \\\n  return MAX(4, 7) + __LINE__ * 0; }\nint bad = 1 +;\n").unwrap();
+    assert!(c4.source.contains("char *s = \"continued\";   return ((4) > (7) ? (4) : (7)) + 4 * 0; }\n\n\nint bad"), "{:?}", c4.source);
+    // Lines after the spliced ones keep their numbers
+    let pos = c4.source.find("int bad").unwrap();
+    assert_eq!(c4.origin_at(pos).to_string(), "main.c:7");
+    assert_eq!(c4.source.lines().count(), 7);
+
+    // A blank line stands in for each joined one; a backslash on the last line has nothing
+    // to join and is dropped
+    let text = Preprocessor::new().run("main.c", "a\\\nb\nc\\").unwrap().text;
+    assert_eq!(text, "ab\n\nc\n");
+}
+
+#[test]
+fn test_run_program() {
+    let mut c4 = compile_program("int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+        int main() { int i = 0; int sum = 0; while (i < 10) { sum += fib(i); i++; } return sum; }").unwrap();
+    let main_idx = c4.find_main().unwrap();
+    assert_eq!(c4.run(main_idx, 0, &[]), Ok(88));
+    assert!(c4.vm.cycle > 1000);
+
+    let mut c4 = compile_program("int main() { int zero = 0; return 1 / zero; }").unwrap();
+    let main_idx = c4.find_main().unwrap();
+    assert!(matches!(c4.run(main_idx, 0, &[]), Err(RuntimeError::DivByZero { .. })));
+}
+
+#[test]
+fn test_printf() {
+    let run = |src: &str| run_program_output(src, 10_000);
+    assert_eq!(run("int main() { printf(\"hello, world\\n\"); return 0; }"), (Ok(RunState::Halted(0)), "hello, world\n".to_string()));
+    let (state, text) = run("int main() { return printf(\"%d %i|%5d|%-4d|%03d|%x %X %ld %c%c %s 100%% %u\\n\", \
+        -42, 7, 12, 3, -5, 255, 48879, 1, 'o', 'k', \"str\", -1); }");
+    assert_eq!(text, format!("-42 7|   12|3   |-05|ff BEEF 1 ok str 100% {}\n", u64::MAX));
+    assert_eq!(state, Ok(RunState::Halted(text.len() as i64)));
+    // Directives without an argument and unknown ones are printed as written
+    assert_eq!(run("int main() { printf(\"%d %q %s\", 1); return 0; }").1, "1 %q %s");
+
+    // Huge widths are clamped rather than allocated, overflowing ones included
+    let (state, text) = run("int main() { return printf(\"%1000000000000d|%99999999999999999999999d\", 1, 2); }");
+    assert_eq!(text, format!("{:>4096}|{:>4096}", 1, 2));
+    assert_eq!(state, Ok(RunState::Halted(8193)));
+
+    // Bytes that are not UTF-8 are written as they are, in the format and in %s arguments
+    let mut c4 = start_program("int main() { char *s; s = malloc(2); s[0] = 233; s[1] = 0; \
+        printf(\"%s|\\351\", s); return 0; }");
+    let out = OutputBuffer::new();
+    c4.set_stdout(Box::new(out.clone()));
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(0)));
+    assert_eq!(out.contents(), b"\xe9|\xe9");
+}
+
+#[test]
+fn test_malloc_free() {
+    let run = |src: &str| run_program(src, 10_000);
+    assert_eq!(run("int main() { int *p; char *s; p = malloc(4 * sizeof(int)); s = malloc(3); \
+        p[0] = 40; p[3] = 2; s[0] = 'a'; s[2] = 'c'; return p[0] + p[3] + s[2] - s[0]; }"),
+        Ok(RunState::Halted(44)));
+    // A freed block is handed out again
+    assert_eq!(run("int main() { int *p; int *q; p = malloc(64); free(p); q = malloc(8); return p == q; }"),
+        Ok(RunState::Halted(1)));
+    assert_eq!(run("int main() { free(0); return malloc(-1) == 0 && malloc(1 << 30) == 0; }"),
+        Ok(RunState::Halted(1)));
+    assert!(matches!(run("int main() { int *p; p = malloc(8); free(p); free(p); return 0; }"),
+        Err(RuntimeError::InvalidFree { .. })));
+    assert!(matches!(run("int main() { int *p; p = malloc(16); free(p + 1); return 0; }"),
+        Err(RuntimeError::InvalidFree { .. })));
+}
+
+#[test]
+fn test_host_files() {
+    let path = std::env::temp_dir().join(format!("c4_rust_host_files_{}.txt", std::process::id()));
+    std::fs::write(&path, "line one\nline two\nend\n").unwrap();
+    let src = format!("int main() {{ int fd; int n; int lines; char *buf; char *p; \
+        buf = malloc(8); lines = 0; fd = open(\"{}\", 0); if (fd < 0) return -1; \
+        while ((n = read(fd, buf, 8)) > 0) {{ p = buf; while (p < buf + n) {{ if (*p == '\\n') lines++; p++; }} }} \
+        if (close(fd) != 0) return -2; return lines; }}", path.display());
+    let mut c4 = start_program(&src);
+    let state = c4.run_for(100_000);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(state, Ok(RunState::Halted(3)));
+
+    // A mounted file shadows the host, and a path that exists in neither fails to open
+    let path = b"/nonexistent/c4_rust/input.txt\0";
+    c4.vm.data[..path.len()].copy_from_slice(path);
+    assert_eq!(c4.vm.sys_open(0, 0).unwrap(), -1);
+    c4.mount_file("/nonexistent/c4_rust/input.txt", b"mounted");
+    let fd = c4.vm.sys_open(0, 0).unwrap();
+    assert_eq!(c4.vm.sys_read(fd, 64, 100).unwrap(), 7);
+    assert_eq!(&c4.vm.data[64..71], b"mounted");
+}
+
+#[test]
+fn test_memset_memcmp() {
+    let run = |src: &str| run_program(src, 10_000);
+    assert_eq!(run("int main() { char *p; p = malloc(16); if ((char *)memset(p, 'x', 15) != p) return -1; p[15] = 0; \
+        return p[0] + p[14] + p[15]; }"), Ok(RunState::Halted(2 * 'x' as i64)));
+    // Only the low byte of the fill value is used
+    assert_eq!(run("int main() { int *p; p = malloc(8); memset(p, 0x101, 8); return *p == 0x0101010101010101; }"),
+        Ok(RunState::Halted(1)));
+    assert_eq!(run("int main() { return memcmp(\"abcd\", \"abcd\", 4); }"), Ok(RunState::Halted(0)));
+    assert_eq!(run("int main() { return memcmp(\"abcd\", \"abxd\", 4); }"), Ok(RunState::Halted('c' as i64 - 'x' as i64)));
+    // Bytes compare as unsigned chars, and only `len` of them are looked at
+    assert_eq!(run("int main() { return memcmp(\"\\377\", \"a\", 1) > 0 && memcmp(\"abc\", \"abd\", 2) == 0; }"),
+        Ok(RunState::Halted(1)));
+    // Ranges that leave VM memory fault like any other bad access
+    assert!(matches!(run("int main() { char *p; p = malloc(8); memset(p, 0, 1 << 30); return 0; }"),
+        Err(RuntimeError::InvalidWrite { .. })));
+    assert!(matches!(run("int main() { return memcmp(\"a\", \"b\", -1); }"),
+        Err(RuntimeError::InvalidRead { .. })));
+}
+
+#[test]
+fn test_opcode_numbering() {
+    // The first instructions are numbered as in c4, with separate BZ and BNZ
+    assert_eq!([OpCode::LEA, OpCode::IMM, OpCode::JMP, OpCode::JSR, OpCode::BZ, OpCode::BNZ].map(|op| op as i64),
+        [0, 1, 2, 3, 4, 5]);
+    // Syscalls added later are appended, so the opcodes before them keep their numbers
+    assert_eq!([OpCode::ATEX, OpCode::EXIT, OpCode::FUN, OpCode::NOP, OpCode::LCU, OpCode::LSU].map(|op| op as i64),
+        [53, 54, 55, 56, 57, 58]);
+    assert_eq!(OpCode::PUTC as i64, 59);
+    // Decoding an instruction word gives back the opcode it was encoded from
+    for (index, op) in OpCode::ALL.iter().enumerate() {
+        assert_eq!(*op as usize, index);
+        assert_eq!(OpCode::from_int(index as i64), Some(*op));
+    }
+    assert_eq!(OpCode::from_int(OpCode::ALL.len() as i64), None);
+}
+
+#[test]
+fn test_main_arguments() {
+    let run = |src: &str, args: &[&str]| {
+        let mut c4 = compile_program(src).unwrap();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        c4.start_with_args(c4.program().function("main").unwrap().entry, &args);
+        c4.run_for(10_000)
+    };
+    assert_eq!(run("int main(int argc, char **argv) { return argc; }", &["prog.c", "a", "bc"]), Ok(RunState::Halted(3)));
+    // argv holds the arguments as C strings, followed by a null pointer
+    assert_eq!(run("int main(int argc, char **argv) { return argv[0][0] == 'p' && argv[2][0] == 'b' && \
+        argv[2][1] == 'c' && argv[2][2] == 0 && argv[3] == 0; }", &["prog.c", "a", "bc"]), Ok(RunState::Halted(1)));
+    assert_eq!(run("int main(int argc, char **argv) { return argc * 10 + (argv[0] == 0); }", &[]), Ok(RunState::Halted(1)));
+    // main without parameters still runs
+    assert_eq!(run("int main() { return 7; }", &["prog.c", "x"]), Ok(RunState::Halted(7)));
+}
+
+#[test]
+fn test_stack_size() {
+    assert_eq!(parse_stack_size("4096"), Ok(4096));
+    assert_eq!(parse_stack_size("64k"), Ok(64 * 1024));
+    assert_eq!(parse_stack_size("2M"), Ok(2 * 1024 * 1024));
+    assert_eq!(parse_stack_size("100w"), Ok(800));
+    assert!(parse_stack_size("12q").is_err());
+    assert!(parse_stack_size("k").is_err());
+
+    // Recursion that overflows the default stack runs once the stack is made big enough
+    let src = "int depth(int n) { if (n == 0) return 0; return depth(n - 1) + 1; } int main() { return depth(20000); }";
+    let run = |options: C4Options| {
+        let mut c4 = start_with(C4::with_options(options).unwrap(), src);
+        c4.run_for(10_000_000)
+    };
+    assert!(matches!(run(C4Options::default()), Err(RuntimeError::StackOverflow { .. })));
+    assert_eq!(run(C4Options { stack_size: 4 * 1024 * 1024, ..Default::default() }), Ok(RunState::Halted(20000)));
+
+    assert!(C4::with_options(C4Options { stack_size: 8, ..Default::default() }).is_err());
+    let mut c4 = C4::new();
+    assert_eq!(c4.stack_size(), 256 * 1024);
+    c4.set_stack_size(1001).unwrap();
+    assert_eq!(c4.stack_size(), 1008);
+}
+
+#[test]
+fn test_memory_bounds() {
+    let run = |src: &str| run_program(src, 10_000).unwrap_err();
+    // Loads and stores name the address, the instruction and the source line
+    let err = run("int main() {\n  int *p;\n  p = (int *)0x7000000;\n  return *p;\n}\n");
+    assert_eq!(err, RuntimeError::InvalidRead { addr: 0x700_0000, site: err.site().clone() });
+    assert_eq!((err.site().op, err.site().line), (Some(OpCode::LI), Some(4)));
+    assert!(err.to_string().starts_with("invalid read at address 0x7000000 at pc "), "{}", err);
+    assert!(err.to_string().ends_with("(line 4) in LI"), "{}", err);
+    let err = run("int main() {\n  char *p;\n  p = malloc(4);\n  p[4096] = 1;\n  return 0;\n}\n");
+    assert!(matches!(err, RuntimeError::InvalidWrite { .. }), "{:?}", err);
+    assert_eq!((err.site().op, err.site().line), (Some(OpCode::SC), Some(4)));
+    // A word that straddles the end of a region is out of bounds too
+    let err = run("int main() { char *p; p = malloc(8); return *(int *)(p + 4); }");
+    assert!(matches!(err, RuntimeError::InvalidRead { .. }), "{:?}", err);
+
+    // Pointers handed to syscalls are checked the same way
+    let err = run("int main() { return open((char *)-1, 0); }");
+    assert_eq!((err.site().op, matches!(err, RuntimeError::InvalidRead { addr: -1, .. })), (Some(OpCode::OPEN), true));
+    let err = run("int main() { return read(3, (char *)0x7000000, 16); }");
+    assert!(matches!(err, RuntimeError::InvalidWrite { .. }), "{:?}", err);
+    assert_eq!(err.site().op, Some(OpCode::READ));
+    let err = run("int main() { return printf(\"%s\", 0x7000000); }");
+    assert!(matches!(err, RuntimeError::InvalidRead { addr: 0x700_0000, .. }), "{:?}", err);
+}
+
+#[test]
+fn test_debug_trace() {
+    let mut c4 = C4::new();
+    let out = OutputBuffer::new();
+    c4.set_stdout(Box::new(out.clone()));
+    let mut c4 = compile_with(c4, "int main() { return 2 + 3; }").unwrap();
+    c4.vm.debug = true;
+    let entry = c4.program().function("main").unwrap().entry;
+    c4.start(entry);
+    assert_eq!(c4.run_for(1000), Ok(RunState::Halted(5)));
+    let text = out.text();
+    let lines: Vec<&str> = text.lines().collect();
+    // The part before the tab is c4's format: cycle, mnemonic padded to four, operand
+    let c4_part: Vec<&str> = lines.iter().map(|line| line.split('\t').next().unwrap()).collect();
+    assert_eq!(&c4_part[..5], ["1> ENT  0", "2> IMM  2", "3> PSH ", "4> IMM  3", "5> ADD "]);
+    assert_eq!(c4_part.last(), Some(&format!("{}> EXIT", lines.len()).as_str()));
+    let top = 0x1000_0000 + 256 * 1024 - 4 * 8;
+    assert_eq!(lines[0], format!("1> ENT  0\tpc={} sp={:#x} ax=0", entry, top));
+    assert!(lines[4].ends_with(&format!("sp={:#x} ax=3", top - 16)), "{}", lines[4]);
+}
+
+#[test]
+fn test_character_io() {
+    let run = |src: &str, input: &'static [u8]| {
+        let mut c4 = C4::new();
+        let out = OutputBuffer::new();
+        let err = OutputBuffer::new();
+        c4.set_stdin(Box::new(input));
+        c4.set_stdout(Box::new(out.clone()));
+        c4.set_stderr(Box::new(err.clone()));
+        let mut c4 = start_with(c4, src);
+        let state = c4.run_for(100_000);
+        let out = out.text();
+        let err = err.text();
+        (state, out, err)
+    };
+    // A filter copying stdin to stdout, upper-cased, counting bytes until EOF
+    let (state, out, _) = run("int main() { int c; int n; n = 0; while ((c = getchar()) != -1) { putchar(toupper(c)); n++; } \
+        return n; }", b"abc\nxyz\n");
+    assert_eq!((state, out.as_str()), (Ok(RunState::Halted(8)), "ABC\nXYZ\n"));
+    assert_eq!(run("int main() { return getchar(); }", b"\xff").0, Ok(RunState::Halted(255)));
+    assert_eq!(run("int main() { return putchar(0x141); }", b"").0, Ok(RunState::Halted(0x41)));
+
+    let (state, out, err) = run("int main() { puts(\"one\"); write(1, \"twothree\", 3); return write(2, \"err\", 3); }", b"");
+    assert_eq!((state, out.as_str(), err.as_str()), (Ok(RunState::Halted(3)), "one\ntwo", "err"));
+    // puts writes the string's bytes as they are, UTF-8 or not
+    let mut c4 = start_program("int main() { return puts(\"\\351\"); }");
+    let out = OutputBuffer::new();
+    c4.set_stdout(Box::new(out.clone()));
+    c4.run_for(10_000).unwrap();
+    assert_eq!(out.contents(), b"\xe9\n");
+    assert_eq!(run("int main() { return write(7, \"x\", 1); }", b"").0, Ok(RunState::Halted(-1)));
+    assert!(matches!(run("int main() { return write(1, (char *)0x7000000, 4); }", b"").0,
+        Err(RuntimeError::InvalidRead { .. })));
+
+    // read() on fd 0 takes stdin too
+    let (state, out, _) = run("int main() { char *buf; int n; buf = malloc(16); n = read(0, buf, 16); write(1, buf, n); \
+        return read(0, buf, 16); }", b"piped");
+    assert_eq!((state, out.as_str()), (Ok(RunState::Halted(0)), "piped"));
+}
+
+#[test]
+fn test_fprintf_streams() {
+    let mut c4 = C4::new();
+    let out = OutputBuffer::new();
+    let err = OutputBuffer::new();
+    c4.set_stdout(Box::new(out.clone()));
+    c4.set_stderr(Box::new(err.clone()));
+    let mut c4 = start_with(c4, "int main() { int n; printf(\"result %d\\n\", 42); \
+        n = fprintf(stderr, \"warning: %s at %d\\n\", \"x\", 7); fprintf(stdout, \"done\\n\"); \
+        return n * 100 + fprintf(stdin, \"lost\") + (stdin == 0) + (stdout == 1); }");
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(1601)));
+    assert_eq!(out.text(), "result 42\ndone\n");
+    assert_eq!(err.text(), "warning: x at 7\n");
+}
+
+#[test]
+fn test_sprintf() {
+    let run = |src: &str| run_program_output(src, 10_000);
+    assert_eq!(run("int main() { char *buf; int n; buf = malloc(32); n = sprintf(buf, \"%s-%03d|%x\", \"id\", 7, 255); \
+        printf(\"[%s]\", buf); return n; }"), (Ok(RunState::Halted(9)), "[id-007|ff]".to_string()));
+    // snprintf truncates, always terminates, and returns the length it would have written
+    assert_eq!(run("int main() { char *buf; int n; buf = malloc(8); memset(buf, 'z', 8); \
+        n = snprintf(buf, 5, \"%d\", 123456); printf(\"[%s]%c\", buf, buf[5]); return n; }"),
+        (Ok(RunState::Halted(6)), "[1234]z".to_string()));
+    // A size of 0 writes nothing, so the buffer may even be null
+    assert_eq!(run("int main() { return snprintf(0, 0, \"%s\", \"abc\"); }").0, Ok(RunState::Halted(3)));
+    assert!(matches!(run("int main() { char *buf; buf = malloc(8); return sprintf(buf + 4, \"%s\", \"too long\"); }").0,
+        Err(RuntimeError::InvalidWrite { .. })));
+}
+
+#[test]
+fn test_scanf_gets() {
+    let run = |src: &str, input: &'static [u8]| {
+        let mut c4 = C4::new();
+        let out = OutputBuffer::new();
+        c4.set_stdin(Box::new(input));
+        c4.set_stdout(Box::new(out.clone()));
+        let mut c4 = start_with(c4, src);
+        let state = c4.run_for(100_000);
+        let text = out.text();
+        (state, text)
+    };
+    let (state, out) = run("int main() { int a; int b; int h; char *name; char c; int n; name = malloc(16); \
+        n = scanf(\"%d,%d %s %c%x\", &a, &b, name, &c, &h); printf(\"%d %d [%s] [%c] %d\", a, b, name, c, h); return n; }",
+        b"  12,-7\n  alice  Zff rest");
+    assert_eq!((state, out.as_str()), (Ok(RunState::Halted(5)), "12 -7 [alice] [Z] 255"));
+    // Fields stop at a mismatch, and the byte that did not fit is read next
+    let (state, out) = run("int main() { int a; int n; a = 0; n = scanf(\"%d\", &a); printf(\"%d %c\", a, getchar()); \
+        return n * 10 + scanf(\"%d\", &a); }", b"42x");
+    assert_eq!((state, out.as_str()), (Ok(RunState::Halted(9)), "42 x"));
+    assert_eq!(run("int main() { int a; return scanf(\"%d\", &a); }", b"abc").0, Ok(RunState::Halted(0)));
+    assert_eq!(run("int main() { int a; return scanf(\"%d\", &a); }", b"  ").0, Ok(RunState::Halted(-1)));
+    // A width limits %s, and %3c reads exactly three characters
+    let (_, out) = run("int main() { char *s; char *t; s = malloc(8); t = malloc(8); memset(t, 0, 8); \
+        scanf(\"%3s%3c\", s, t); printf(\"%s|%s\", s, t); return 0; }", b"abcdef gh");
+    assert_eq!(out, "abc|def");
+    assert_eq!(run("int main() { int a; return scanf(\"%99999999999999999999999d\", &a); }", b"12").0, Ok(RunState::Halted(0)));
+
+    let (state, out) = run("int main() { char *line; int n; line = malloc(32); n = 0; \
+        while (gets(line)) { printf(\"<%s>\", line); n++; } return n; }", b"first line\n\nlast");
+    assert_eq!((state, out.as_str()), (Ok(RunState::Halted(3)), "<first line><><last>"));
+}
+
+#[test]
+fn test_string_functions() {
+    let run = |src: &str| run_program_output(src, 10_000);
+    let (state, out) = run("int main() { char *s; s = malloc(32); strcpy(s, \"hello\"); strcat(strcat(s, \", \"), \"world\"); \
+        printf(\"%s\", s); return strlen(s); }");
+    assert_eq!((state, out.as_str()), (Ok(RunState::Halted(12)), "hello, world"));
+    assert_eq!(run("int main() { return strlen(\"\"); }").0, Ok(RunState::Halted(0)));
+    assert_eq!(run("int main() { return strcmp(\"abc\", \"abc\") == 0 && strcmp(\"ab\", \"abc\") < 0 && \
+        strcmp(\"b\", \"abc\") > 0 && strcmp(\"\\377\", \"a\") > 0; }").0, Ok(RunState::Halted(1)));
+    // strncpy pads with zeros and leaves a too-long copy unterminated
+    let (_, out) = run("int main() { char *s; s = malloc(8); memset(s, 'x', 8); strncpy(s, \"ab\", 4); \
+        printf(\"%d%d%c|\", s[2], s[3], s[4]); strncpy(s, \"abcdef\", 3); printf(\"%c%c%c%c\", s[0], s[2], s[3], s[4]); return 0; }");
+    assert_eq!(out, "00x|ac\0x");
+    let (_, out) = run("int main() { char *s; s = malloc(16); strcpy(s, \"abcdef\"); memcpy(s + 2, s, 4); printf(\"%s\", s); \
+        return (char *)memcpy(s, \"zz\", 2) == s; }");
+    assert_eq!(out, "ababcd");
+    // A string with no terminator before the end of its region faults instead of being cut short
+    assert!(matches!(run("int main() { char *s; s = malloc(8); memset(s, 'x', 8); return strlen(s); }").0,
+        Err(RuntimeError::InvalidRead { .. })));
+    assert!(matches!(run("int main() { char *s; s = malloc(8); return (int)strcpy(s, \"much too long\"); }").0,
+        Err(RuntimeError::InvalidWrite { .. })));
+    // A count larger than the destination faults before anything is padded
+    assert!(matches!(run("int main() { char *s; s = malloc(8); return (int)strncpy(s, \"hi\", 1000000000000); }").0,
+        Err(RuntimeError::InvalidWrite { .. })));
+}
+
+#[test]
+fn test_utility_functions() {
+    let run = |src: &str| run_program(src, 100_000);
+    assert_eq!(run("int main() { return abs(-5) * 10 + abs(3); }"), Ok(RunState::Halted(53)));
+    assert_eq!(run("int main() { return atoi(\"  -123xyz\") + atoi(\"+20\") + atoi(\"abc\"); }"), Ok(RunState::Halted(-103)));
+    // rand stays in range and repeats its sequence for the same seed, starting from srand(1)
+    assert_eq!(run("int main() { int i; int r; i = 0; while (i < 100) { r = rand(); if (r < 0 || r > RAND_MAX) return 0; i++; } \
+        return 1; }"), Ok(RunState::Halted(1)));
+    assert_eq!(run("int main() { int a; int b; srand(42); a = rand(); rand(); srand(42); b = rand(); \
+        return a == b && a != rand(); }"), Ok(RunState::Halted(1)));
+    assert_eq!(run("int main() { int a; a = rand(); srand(1); return a == rand(); }"), Ok(RunState::Halted(1)));
+    let Ok(RunState::Halted(first)) = run("int main() { return rand(); }") else { panic!() };
+    assert_eq!(run("int main() { return rand(); }"), Ok(RunState::Halted(first)));
+    // time returns the current time and stores it through a non-null pointer
+    assert_eq!(run("int main() { int t; int now; t = 0; now = time(&t); return now == t && now > 1600000000 && time(0) >= now; }"),
+        Ok(RunState::Halted(1)));
+}
+
+#[test]
+fn test_exit_status() {
+    let run = |src: &str| {
+        let mut c4 = compile_program(src).unwrap();
+        let main_idx = c4.find_main().unwrap();
+        c4.run(main_idx, 0, &["prog.c".to_string()])
+    };
+    assert_eq!(run("int main() { return 3; }"), Ok(3));
+    // exit() anywhere ends the program with its argument, even from a nested call
+    assert_eq!(run("int quit(int n) { exit(n); return 0; } int main() { quit(42); return 1; }"), Ok(42));
+    assert_eq!(run("int main() { return -1; }"), Ok(-1));
+    assert!(run("int main() { return 1 / 0; }").is_err());
+}
+
+#[test]
+fn test_getenv() {
+    let src = "int main() { char *home; char *again; home = getenv(\"HOME\"); again = getenv(\"HOME\"); \
+        if (getenv(\"C4_RUST_UNSET_VARIABLE\") != 0 || home != again) return -1; printf(\"%s\", home); return strlen(home); }";
+    let run = |options: C4Options, src: &str| {
+        let mut c4 = C4::with_options(options).unwrap();
+        let out = OutputBuffer::new();
+        c4.set_stdout(Box::new(out.clone()));
+        let mut c4 = start_with(c4, src);
+        let state = c4.run_for(10_000);
+        let text = out.text();
+        (state, text)
+    };
+    // A supplied map replaces the host environment entirely
+    let vars = HashMap::from([("HOME".to_string(), "/sandbox".to_string())]);
+    assert_eq!(run(C4Options { environment: Some(vars), ..Default::default() }, src),
+        (Ok(RunState::Halted(8)), "/sandbox".to_string()));
+    assert_eq!(run(C4Options { environment: Some(HashMap::new()), ..Default::default() }, "int main() { return getenv(\"HOME\") == 0; }").0,
+        Ok(RunState::Halted(1)));
+    // Without one the program sees the host's variables
+    if let Ok(home) = std::env::var("HOME") {
+        assert_eq!(run(C4Options::default(), src), (Ok(RunState::Halted(home.len() as i64)), home));
+    }
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let src = "int main() { int *a; int i; int sum; a = malloc(100 * sizeof(int)); i = 0; sum = 0; \
+        while (i < 100) { a[i] = i * i; i++; } i = 0; while (i < 100) { sum = sum + a[i] + rand() % 2; i++; } \
+        printf(\"%d\\n\", sum); return sum % 256; }";
+    let mut c4 = C4::new();
+    let out = OutputBuffer::new();
+    c4.set_stdout(Box::new(out.clone()));
+    let mut c4 = start_with(c4, src);
+    assert_eq!(c4.run_for(1500), Ok(RunState::Paused));
+    let snapshot = c4.snapshot();
+    let Ok(RunState::Halted(code)) = c4.run_for(1_000_000) else { panic!() };
+    let cycles = c4.vm.cycle;
+
+    // Rewinding the same VM replays the rest of the run exactly
+    c4.restore(&snapshot).unwrap();
+    assert_eq!(c4.vm.cycle, 1500);
+    assert_eq!(c4.run_for(1_000_000), Ok(RunState::Halted(code)));
+    assert_eq!(c4.vm.cycle, cycles);
+
+    // A fresh VM resumes from the serialized form without recompiling
+    let bytes = snapshot.to_bytes();
+    assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot.clone()));
+    let mut resumed = C4::new();
+    let resumed_out = OutputBuffer::new();
+    resumed.set_stdout(Box::new(resumed_out.clone()));
+    resumed.restore(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(resumed.run_for(1_000_000), Ok(RunState::Halted(code)));
+    assert_eq!(resumed.vm.cycle, cycles);
+    let text = out.text();
+    let first_line = text.lines().next().unwrap().to_string();
+    assert_eq!(resumed_out.text(), format!("{}\n", first_line));
+
+    assert!(Snapshot::from_bytes(b"not a snapshot").is_err());
+    assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    let mut corrupt = bytes.clone();
+    corrupt[8 + 11 * 8..8 + 12 * 8].copy_from_slice(&i64::MAX.to_le_bytes());
+    assert!(Snapshot::from_bytes(&corrupt).is_err());
+}
+
+#[test]
+fn test_deterministic_mode() {
+    let src = "int main() { int i; i = 0; while (i < 3) { printf(\"%d \", rand() % 1000); i++; } \
+        while (i < 150000) i++; printf(\"%d %d %s %s\", time(0) - 946684800, getenv(\"HOME\") == 0, __DATE__, \"x\"); return 0; }";
+    let run = |seed: Option<u64>| {
+        let mut c4 = C4::with_options(C4Options { deterministic: seed, ..Default::default() }).unwrap();
+        let out = OutputBuffer::new();
+        c4.set_stdout(Box::new(out.clone()));
+        c4.init_symbol_table();
+        c4.preprocess_source("prog.c", src).unwrap();
+        c4.compile().unwrap();
+        c4.start(c4.program().function("main").unwrap().entry);
+        assert_eq!(c4.run_for(10_000_000), Ok(RunState::Halted(0)));
+        let text = out.text();
+        text
+    };
+    let first = run(Some(7));
+    assert_eq!(run(Some(7)), first);
+    // The virtual clock has advanced one second per million instructions
+    assert!(first.ends_with(" 2 1 Jan  1 2000 x"), "{}", first);
+    assert_ne!(run(Some(8)), first);
+    // Seed 1 gives the sequence an ordinary run starts with
+    let normal = run(None);
+    assert_eq!(run(Some(1)).split(' ').take(3).collect::<Vec<_>>(), normal.split(' ').take(3).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_sandbox_policy() {
+    let root = std::env::temp_dir().join(format!("c4_rust_sandbox_{}", std::process::id()));
+    let allowed = root.join("allowed");
+    std::fs::create_dir_all(&allowed).unwrap();
+    std::fs::write(allowed.join("in.txt"), "inside").unwrap();
+    std::fs::write(root.join("secret.txt"), "secret").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(root.join("secret.txt"), allowed.join("link.txt")).unwrap();
+
+    let policy = SandboxPolicy { allowed_paths: vec![allowed.clone()], read_only: true, no_env: true };
+    let run = |src: &str| {
+        let mut c4 = C4::with_options(C4Options { sandbox: Some(policy.clone()), ..Default::default() }).unwrap();
+        c4.set_environment(Some(HashMap::from([("HOME".to_string(), "/home".to_string())])));
+        c4.mount_file("mounted.txt", b"mounted");
+        let mut c4 = start_with(c4, src);
+        c4.run_for(100_000)
+    };
+    let opens = |path: &std::path::Path, flags: i32| {
+        run(&format!("int main() {{ int fd; fd = open(\"{}\", {}); if (fd >= 0) close(fd); return fd >= 0; }}", path.display(), flags))
+    };
+    assert_eq!(opens(&allowed.join("in.txt"), 0), Ok(RunState::Halted(1)));
+    assert_eq!(opens(std::path::Path::new("mounted.txt"), 0), Ok(RunState::Halted(1)));
+    // Paths outside the allow-list stay closed, however they are spelled
+    assert_eq!(opens(&root.join("secret.txt"), 0), Ok(RunState::Halted(0)));
+    assert_eq!(opens(&allowed.join("../secret.txt"), 0), Ok(RunState::Halted(0)));
+    #[cfg(unix)]
+    assert_eq!(opens(&allowed.join("link.txt"), 0), Ok(RunState::Halted(0)));
+    // Read-only refuses any write flags
+    assert_eq!(opens(&allowed.join("in.txt"), 1), Ok(RunState::Halted(0)));
+    // Only so many files can be open at once; closing one frees its place
+    let src = format!("int main() {{ int n = 0; int fd = 0; while (n < 100 && (fd = open(\"{}\", 0)) >= 0) n++; \
+        close(3); return n * 10 + (open(\"mounted.txt\", 0) >= 0); }}", allowed.join("in.txt").display());
+    assert_eq!(run(&src), Ok(RunState::Halted(641)));
+    // no_env hides even variables the embedder supplied
+    assert_eq!(run("int main() { return getenv(\"HOME\") == 0; }"), Ok(RunState::Halted(1)));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_redirected_streams() {
+    let out = OutputBuffer::new();
+    let err = OutputBuffer::new();
+    let mut c4 = C4::new();
+    c4.set_stdio(Box::new(&b"x\n42"[..]), Box::new(out.clone()), Box::new(err.clone()));
+    let mut c4 = start_with(
+        c4,
+        "int main() { int c; int n; c = getchar(); putchar(c); puts(\"!\"); \
+         scanf(\"%d\", &n); printf(\"n=%d\\n\", n); fprintf(stderr, \"oops %d\\n\", n + 1); \
+         write(2, \"raw\", 3); return 0; }",
+    );
+    assert_eq!(c4.run_for(100_000), Ok(RunState::Halted(0)));
+    c4.vm.flush_output();
+
+    // A clone of the buffer sees everything the VM wrote
+    assert_eq!(out.text(), "x!\nn=42\n");
+    assert_eq!(err.take(), b"oops 43\nraw".to_vec());
+    assert!(err.contents().is_empty());
+}
+
+#[test]
+fn test_heap_leak_report() {
+    let mut c4 = start_program(
+        "char *keep() {\n  return (char *)malloc(10);\n}\n\
+         int main() {\n  char *a; char *b;\n  a = (char *)malloc(4);\n  b = keep();\n  free(a);\n  return 0;\n}\n",
+    );
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(0)));
+
+    let usage = c4.usage();
+    assert_eq!((usage.allocations, usage.frees), (2, 1));
+    let leaks = c4.heap_leaks();
+    assert_eq!(leaks.len(), 1);
+    assert_eq!(leaks[0].size, 16);
+    let site = leaks[0].site.as_ref().unwrap();
+    assert_eq!((site.function.as_deref(), site.line), (Some("keep"), Some(2)));
+
+    let report = c4.leak_report();
+    assert!(report.starts_with("heap: 2 allocations, 1 frees, 24 bytes allocated; 1 blocks (16 bytes) still live\n"), "{}", report);
+    assert!(report.contains("16 bytes at 0x"), "{}", report);
+    assert!(report.contains("allocated in keep (pc "), "{}", report);
+    assert!(report.ends_with(", line 2)\n"), "{}", report);
+
+    // A fresh start forgets the old blocks
+    c4.start(c4.program().function("main").unwrap().entry);
+    assert!(c4.heap_leaks().is_empty());
+}
+
+#[test]
+fn test_getenv_copies_are_not_leaks() {
+    let mut c4 = C4::new();
+    c4.set_environment(Some(HashMap::from([("HOME".to_string(), "/home/c4".to_string())])));
+    let mut c4 = start_with(c4, "int main() {\n  getenv(\"HOME\");\n  getenv(\"HOME\");\n  return 0;\n}\n");
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(0)));
+
+    let usage = c4.usage();
+    assert_eq!((usage.allocations, usage.frees, usage.bytes_allocated), (0, 0, 0));
+    assert!(c4.heap_leaks().is_empty());
+    assert!(c4.leak_report().starts_with("heap: 0 allocations, 0 frees, 0 bytes allocated; 0 blocks (0 bytes) still live\n"));
+}
+
+#[test]
+fn test_profiler() {
+    let mut c4 = compile_program(
+        "int spin(int n) { int s; s = 0; while (n > 0) { s = s + n; n = n - 1; } return s; }\n\
+         int main() { return spin(100) == 5050; }\n",
+    ).unwrap();
+    let entry = c4.program().function("main").unwrap().entry;
+
+    c4.start(entry);
+    c4.run_for(100_000).unwrap();
+    assert_eq!(c4.profile(), None);
+
+    c4.set_profiling(true);
+    c4.start(entry);
+    assert_eq!(c4.run_for(100_000), Ok(RunState::Halted(1)));
+    let profile = c4.profile().unwrap();
+    assert_eq!(profile.total, c4.usage().cycles);
+    assert_eq!(profile.by_opcode.iter().map(|&(_, count)| count).sum::<i64>(), profile.total);
+    assert_eq!(profile.by_function.iter().map(|(_, count)| count).sum::<i64>(), profile.total);
+    // The loop dominates, and the counts come out sorted
+    assert_eq!(profile.by_function[0].0, "spin");
+    assert!(profile.by_function[0].1 > 1000);
+    assert!(profile.by_opcode.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+    let report = profile.to_string();
+    assert!(report.starts_with(&format!("profile: {} instructions\nby opcode:\n", profile.total)), "{}", report);
+    assert!(report.contains("by function:\n  spin"), "{}", report);
+
+    // Counts start over with each run
+    c4.start(entry);
+    assert_eq!(c4.profile().unwrap().total, 0);
+}
+
+#[test]
+fn test_watchpoints() {
+    let mut c4 = start_program(
+        "int g;\n\
+         int bump(int n) {\n  int t;\n  t = n * 2;\n  g = g + t;\n  t = 0;\n  return g;\n}\n\
+         int main() {\n  g = 1;\n  bump(3);\n  bump(4);\n  return g;\n}\n",
+    );
+
+    assert_eq!(c4.watch_global("nope"), Err("no global variable 'nope'".to_string()));
+    let g = c4.watch_global("g").unwrap();
+    let hit = |state: Result<RunState, RuntimeError>| match state {
+        Ok(RunState::Watchpoint(hit)) => hit,
+        other => panic!("expected a watchpoint, got {:?}", other),
+    };
+    let first = hit(c4.run_for(10_000));
+    assert_eq!((first.addr, first.store_addr, first.value, first.line), (g, g, 1, Some(10)));
+    let second = hit(c4.run_for(10_000));
+    assert_eq!((second.value, second.line), (7, Some(5)));
+
+    // Paused inside bump, so its locals can be watched in the live frame
+    assert!(c4.watch_local("missing").is_err());
+    let t = c4.watch_local("t").unwrap();
+    let third = hit(c4.run_for(10_000));
+    assert_eq!((third.addr, third.value, third.line), (t, 0, Some(6)));
+    // The second call reuses the frame, then g changes again
+    assert_eq!(hit(c4.run_for(10_000)).value, 8);
+    assert_eq!(hit(c4.run_for(10_000)).value, 15);
+    assert_eq!(hit(c4.run_for(10_000)).value, 0);
+
+    c4.clear_watchpoints();
+    assert_eq!(c4.run_for(10_000), Ok(RunState::Halted(15)));
+}
+
+#[test]
+fn test_backtrace_source_origins() {
+    let dir = std::env::temp_dir().join(format!("c4_backtrace_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("util.h"), "int div(int a, int b) {\n  return a / b;\n}\n").unwrap();
+    let main_name = dir.join("main.c").to_string_lossy().to_string();
+    let header_name = dir.join("util.h").to_string_lossy().to_string();
+
+    let mut c4 = C4::new();
+    c4.init_symbol_table();
+    c4.preprocess_source(&main_name, "#include \"util.h\"\n\nint helper(int x) {\n  return div(10, x);\n}\nint main() {\n  return helper(0);\n}\n").unwrap();
+    c4.compile().unwrap();
+    c4.start(c4.program().function("main").unwrap().entry);
+    let err = c4.run_for(10_000).unwrap_err();
+    assert!(matches!(err, RuntimeError::DivByZero { .. }), "{:?}", err);
+
+    // Frames point into the file each function was written in, not the preprocessed text
+    let frames: Vec<(Option<&str>, Option<&str>, Option<i32>)> = err.site().stack.iter()
+        .map(|frame| (frame.function.as_deref(), frame.file.as_deref(), frame.line))
+        .collect();
+    assert_eq!(frames, vec![
+        (Some("div"), Some(header_name.as_str()), Some(2)),
+        (Some("helper"), Some(main_name.as_str()), Some(4)),
+        (Some("main"), Some(main_name.as_str()), Some(7)),
+    ]);
+    let trace = err.stack_trace();
+    assert!(trace.starts_with(&format!("  at div (pc {}, {}:2)\n", err.site().pc, header_name)), "{}", trace);
+    assert!(trace.ends_with(&format!(", {}:7)\n", main_name)), "{}", trace);
+
+    // Hand-assembled code has no source to point at
+    let mut c4 = C4::new();
+    let entry = c4.load_assembly("main:\n  IMM 1\n  PSH\n  IMM 0\n  DIV\n").unwrap();
+    c4.start(entry);
+    let err = c4.run_for(100).unwrap_err();
+    assert_eq!((err.site().stack[0].file.as_deref(), err.site().stack[0].line), (None, None));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_standalone_vm() {
+    // Hand-assembled code runs without a compiler
+    let mut vm = Vm::new();
+    let entry = vm.load_assembly("main:\n  IMM 6\n  PSH\n  IMM 7\n  MUL\n  PSH\n  EXIT\n").unwrap();
+    vm.start(entry);
+    assert_eq!(vm.run_for(100), Ok(RunState::Halted(42)));
+    assert_eq!((vm.ax, vm.cycle()), (42, 6));
+
+    // Stepping with a small budget leaves the registers to inspect between slices
+    vm.start(entry);
+    assert_eq!(vm.run_for(2), Ok(RunState::Paused));
+    assert_eq!((vm.pc, vm.ax), (entry + 3, 6));
+    // Or one instruction at a time
+    assert_eq!(vm.step(), Ok(RunState::Paused));
+    assert_eq!((vm.pc, vm.cycle()), (entry + 5, 3));
+    while vm.step() == Ok(RunState::Paused) {}
+    assert_eq!(vm.step(), Ok(RunState::Halted(42)));
+
+    // A compiled program's VM outlives the compiler and still names functions in faults
+    let mut c4 = compile_program("int div(int a, int b) { return a / b; }\nint main() { return div(1, 0); }\n").unwrap();
+    let entry = c4.program().function("main").unwrap().entry;
+    let mut vm = c4.into_vm();
+    vm.start(entry);
+    let err = vm.run_for(1000).unwrap_err();
+    let frames: Vec<Option<&str>> = err.site().stack.iter().map(|frame| frame.function.as_deref()).collect();
+    assert_eq!(frames, vec![Some("div"), Some("main")]);
+}
+
+#[test]
+fn test_fast_dispatch_matches_step() {
+    let src = "char *buf; short *half;\n\
+               int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+               int main() { int i; buf = (char *)malloc(8); half = (short *)malloc(8); i = 0;\n\
+               while (i < 8) { buf[i] = 200 + i; half[i / 2] = -i * 1000; i = i + 1; }\n\
+               printf(\"%d %d %d\\n\", fib(12), buf[3], half[3]);\n\
+               return 10 / (buf[0] - buf[0]); }\n";
+    // Profiling keeps every instruction on step(), so it runs the same program the slow way
+    let run = |profiled: bool, slice: i64| {
+        let mut c4 = compile_program(src).unwrap();
+        let out = OutputBuffer::new();
+        c4.set_stdout(Box::new(out.clone()));
+        c4.set_profiling(profiled);
+        c4.set_retire_log_size(32);
+        c4.start(c4.program().function("main").unwrap().entry);
+        let err = loop {
+            match c4.run_for(slice) {
+                Ok(RunState::Paused) => continue,
+                other => break other.unwrap_err(),
+            }
+        };
+        (err, out.text(), c4.vm.cycle, c4.retired().clone(), c4.vm.sp, c4.vm.bp)
+    };
+
+    let slow = run(true, 1_000_000);
+    assert!(matches!(slow.0, RuntimeError::DivByZero { .. }), "{:?}", slow.0);
+    assert_eq!(slow.1, "144 -53 -7000\n");
+    assert_eq!(run(false, 1_000_000), slow);
+    assert_eq!(run(false, 7), slow);
+
+    // A cycle limit stops the fast loop on the same instruction
+    let mut c4 = compile_program("int main() { while (1) {} return 0; }\n").unwrap();
+    c4.set_cycle_limit(Some(1000));
+    c4.start(c4.program().function("main").unwrap().entry);
+    assert!(matches!(c4.run_for(1_000_000), Err(RuntimeError::CycleLimit { limit: 1000, .. })));
+    assert_eq!(c4.vm.cycle, 1000);
+}
+
+#[test]
+fn test_superinstruction_fusion() {
+    let src = "int sum(int n) { int s; s = 0; while (n > 0) { s = s + n + 3; n = n - 1; } return s; }\n\
+               int main() { return sum(50) == 1425; }\n";
+    // Budgets of 2 and 3 cut fused sequences at every possible point
+    let run = |fused: bool, slice: i64| {
+        let mut c4 = compile_program(src).unwrap();
+        c4.set_fusion(fused);
+        c4.set_retire_log_size(32);
+        c4.start(c4.program().function("main").unwrap().entry);
+        let mut states = Vec::new();
+        loop {
+            let state = c4.run_for(slice).unwrap();
+            states.push((c4.vm.pc, c4.vm.sp, c4.vm.bp, c4.vm.ax, c4.vm.cycle));
+            if state != RunState::Paused {
+                assert_eq!(state, RunState::Halted(1));
+                return (states, c4.retired().clone(), c4.vm.stack.clone());
+            }
+        }
+    };
+    for slice in [2, 3, 1_000_000] {
+        assert_eq!(run(true, slice), run(false, slice));
+    }
+
+    // A fused load that faults stops on the instruction that faults
+    let mut c4 = C4::new();
+    let entry = c4.load_assembly("main:\n  ENT 0\n  LEA 100000\n  LI\n  LEV\n").unwrap();
+    c4.start(entry);
+    let err = c4.run_for(100).unwrap_err();
+    assert!(matches!(err, RuntimeError::InvalidRead { .. }), "{:?}", err);
+    assert_eq!((err.site().pc, err.site().op, c4.vm.cycle), (entry + 4, Some(OpCode::LI), 3));
+}
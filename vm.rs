@@ -0,0 +1,2322 @@
+// The virtual machine: the code and data segments, stack, heap and registers of a program, the
+// interpreter loop and the syscalls it makes. The compiler in c4.rs fills in the code and data
+// segments of the Vm it owns; a Vm can also run hand-assembled code on its own.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    assemble_at, splitmix64, Int, LineOrigin, OpCode, HEAP_BASE, HEAP_SIZE, MAX_STACK_SIZE, MIN_STACK_SIZE,
+    STACK_BASE, STACK_SIZE, VIRTUAL_CYCLES_PER_SECOND, VIRTUAL_EPOCH, WORD_SIZE,
+};
+
+// Outcome of running the VM for a bounded number of cycles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunState {
+    Halted(Int),             // Program exited with this code
+    Paused,                  // Cycle budget used up; call run_for again to continue
+    Watchpoint(WatchHit),    // A store hit a watched address; call run_for again to continue
+}
+
+// A store that touched a watched address, reported by `run_for` right after it happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub addr: Int,        // Start of the watched range
+    pub store_addr: Int,  // Address the program wrote to
+    pub value: Int,       // Value it stored there
+    pub pc: usize,        // The SI/SC/SS instruction
+    pub line: Option<i32>,
+}
+
+// Exit hooks a program can register with atexit (C guarantees at least 32)
+const MAX_EXIT_HOOKS: usize = 32;
+
+// Files a sandboxed program may hold open at once, so it cannot use up the host's descriptors
+const MAX_SANDBOX_FILES: usize = 64;
+
+// Widest printf field; larger widths are clamped so a format string cannot make the host
+// allocate without bound
+const MAX_FIELD_WIDTH: usize = 4096;
+
+// What a sandboxed program may reach outside the VM; files mounted with mount_file are
+// always allowed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SandboxPolicy {
+    pub allowed_paths: Vec<PathBuf>,  // Host files, or directories and everything below them, open() may reach
+    pub read_only: bool,              // open() fails unless the flags ask for reading only
+    pub no_env: bool,                 // getenv finds no variables
+}
+
+impl SandboxPolicy {
+    // The host file open() may reach for `path`, or None if it is not allowed; symlinks and `..`
+    // are resolved first, so neither can lead out of an allowed directory, and the resolved path
+    // is what gets opened
+    fn resolve_path(&self, path: &str) -> Option<PathBuf> {
+        let path = fs::canonicalize(path).ok()?;
+        let allowed = self.allowed_paths.iter().any(|allowed| fs::canonicalize(allowed).is_ok_and(|allowed| path.starts_with(allowed)));
+        allowed.then_some(path)
+    }
+}
+
+// Resources a program has consumed, for hosts that bill or limit individual runs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub cycles: Int,
+    pub allocations: Int,
+    pub bytes_allocated: Int,
+    pub frees: Int,
+    pub syscalls: HashMap<OpCode, Int>,
+    pub bytes_written: Int,
+}
+
+// Where a runtime fault happened: the faulting instruction, its source line if the code was
+// compiled from C, and the chain of calls that led there, innermost first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultSite {
+    pub pc: usize,
+    pub op: Option<OpCode>,  // Instruction at pc, None if pc is outside the code
+    pub line: Option<i32>,
+    pub stack: Vec<StackFrame>,
+}
+
+// One call in a fault's stack trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    pub function: Option<String>,  // None for code outside any compiled function
+    pub pc: usize,
+    pub line: Option<i32>,
+    pub file: Option<String>,  // File `line` is in, None if unknown
+}
+
+// Executed instruction counts from a profiled run, highest first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub total: Int,
+    pub by_opcode: Vec<(OpCode, Int)>,
+    pub by_function: Vec<(String, Int)>,  // "?" for code outside any compiled function
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |count: Int| if self.total > 0 { count as f64 * 100.0 / self.total as f64 } else { 0.0 };
+        writeln!(f, "profile: {} instructions", self.total)?;
+        writeln!(f, "by opcode:")?;
+        for (op, count) in &self.by_opcode {
+            writeln!(f, "  {:<6}{:>12} {:>6.2}%", format!("{:?}", op), count, percent(*count))?;
+        }
+        writeln!(f, "by function:")?;
+        for (function, count) in &self.by_function {
+            writeln!(f, "  {:<20}{:>12} {:>6.2}%", function, count, percent(*count))?;
+        }
+        Ok(())
+    }
+}
+
+// A malloc block nobody freed, from `Vm::heap_leaks`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapLeak {
+    pub addr: Int,
+    pub size: Int,
+    pub site: Option<StackFrame>,  // The malloc call, None if unknown (e.g. after restore)
+}
+
+// A fault that stopped the program, returned by `run_for`, `run` and `run_async`
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    InvalidRead { addr: Int, site: FaultSite },
+    InvalidWrite { addr: Int, site: FaultSite },
+    DivByZero { site: FaultSite },
+    StackOverflow { site: FaultSite },
+    // free() of an address malloc did not return, or of a block already freed
+    InvalidFree { addr: Int, site: FaultSite },
+    Cancelled { site: FaultSite },
+    CycleLimit { limit: Int, site: FaultSite },
+    // `word` is the undecodable code word, or None when pc left the code segment
+    IllegalInstruction { word: Option<Int>, site: FaultSite },
+    Unsupported { op: OpCode, site: FaultSite },
+}
+
+impl RuntimeError {
+    pub fn site(&self) -> &FaultSite {
+        match self {
+            RuntimeError::InvalidRead { site, .. } | RuntimeError::InvalidWrite { site, .. } |
+            RuntimeError::InvalidFree { site, .. } | RuntimeError::DivByZero { site } |
+            RuntimeError::StackOverflow { site } | RuntimeError::Cancelled { site } |
+            RuntimeError::CycleLimit { site, .. } | RuntimeError::IllegalInstruction { site, .. } |
+            RuntimeError::Unsupported { site, .. } => site,
+        }
+    }
+
+    fn site_mut(&mut self) -> &mut FaultSite {
+        match self {
+            RuntimeError::InvalidRead { site, .. } | RuntimeError::InvalidWrite { site, .. } |
+            RuntimeError::InvalidFree { site, .. } | RuntimeError::DivByZero { site } |
+            RuntimeError::StackOverflow { site } | RuntimeError::Cancelled { site } |
+            RuntimeError::CycleLimit { site, .. } | RuntimeError::IllegalInstruction { site, .. } |
+            RuntimeError::Unsupported { site, .. } => site,
+        }
+    }
+
+    // The stack trace, one `  at function (pc N, file:L)` line per call (`line L` when the file
+    // is unknown)
+    pub fn stack_trace(&self) -> String {
+        let mut out = String::new();
+        for frame in &self.site().stack {
+            out.push_str(&format!("  at {} (pc {}", frame.function.as_deref().unwrap_or("?"), frame.pc));
+            match (&frame.file, frame.line) {
+                (Some(file), Some(line)) => out.push_str(&format!(", {}:{}", file, line)),
+                (None, Some(line)) => out.push_str(&format!(", line {}", line)),
+                _ => {},
+            }
+            out.push_str(")\n");
+        }
+        out
+    }
+
+    // What went wrong, without where
+    pub(crate) fn description(&self) -> String {
+        match self {
+            RuntimeError::InvalidRead { addr, .. } => format!("invalid read at address {:#x}", addr),
+            RuntimeError::InvalidWrite { addr, .. } => format!("invalid write at address {:#x}", addr),
+            RuntimeError::InvalidFree { addr, .. } => format!("free of address {:#x}, which is not an allocated block", addr),
+            RuntimeError::DivByZero { .. } => "division by zero".to_string(),
+            RuntimeError::StackOverflow { .. } => "stack overflow".to_string(),
+            RuntimeError::Cancelled { .. } => "execution cancelled".to_string(),
+            RuntimeError::CycleLimit { limit, .. } => format!("cycle limit exceeded ({} instructions)", limit),
+            RuntimeError::IllegalInstruction { word: Some(word), .. } => format!("bad instruction {}", word),
+            RuntimeError::IllegalInstruction { word: None, .. } => "pc outside code segment".to_string(),
+            RuntimeError::Unsupported { op, .. } => format!("{:?} is not supported by the VM yet", op),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let site = self.site();
+        write!(f, "{} at pc {}", self.description(), site.pc)?;
+        if let Some(line) = site.line {
+            write!(f, " (line {})", line)?;
+        }
+        if let Some(op) = site.op {
+            write!(f, " in {:?}", op)?;
+        }
+        Ok(())
+    }
+}
+
+// Shared flag a host can set to stop a program started with `run_async`
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Writer that keeps everything written to it; clones share the buffer, so a host can hand one
+// to set_stdout/set_stderr and read the program's output back afterwards
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        OutputBuffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Output so far as text, with invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.contents()).into_owned()
+    }
+
+    // Remove and return the output so far
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Future returned by `Vm::run_async`; resolves to the exit code
+pub struct RunAsync<'a> {
+    vm: &'a mut Vm,
+    slice: Int,
+    token: CancellationToken,
+}
+
+impl Future for RunAsync<'_> {
+    type Output = Result<Int, RuntimeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.token.is_cancelled() {
+            return Poll::Ready(Err(RuntimeError::Cancelled { site: this.vm.fault_site(this.vm.pc) }));
+        }
+        match this.vm.run_for(this.slice) {
+            Ok(RunState::Halted(code)) => Poll::Ready(Ok(code)),
+            Ok(RunState::Paused | RunState::Watchpoint(_)) => {
+                // Give other tasks a turn, then ask to be polled again
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            },
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+// One executed instruction as kept in the retirement log, with the registers it started from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retired {
+    pub pc: usize,
+    pub op: OpCode,
+    pub operand: Option<Int>,
+    pub sp: Int,
+    pub ax: Int,
+}
+
+impl fmt::Display for Retired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = format!("{:?}", self.op);
+        match self.operand {
+            Some(operand) => write!(f, "{:>6}: {:<5}{:<12} sp={:#x} a={}", self.pc, op, operand, self.sp, self.ax),
+            None => write!(f, "{:>6}: {:<17} sp={:#x} a={}", self.pc, op, self.sp, self.ax),
+        }
+    }
+}
+
+// A golden-trace checkpoint: where the VM was after `cycle` instructions, and a hash of every
+// (pc, opcode) pair executed up to then
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceCheckpoint {
+    pub cycle: Int,
+    pub pc: usize,
+    pub hash: u64,
+}
+
+impl fmt::Display for TraceCheckpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {:016x}", self.cycle, self.pc, self.hash)
+    }
+}
+
+// A running program checkpointed by snapshot(): registers, code and memory, enough for
+// restore() to resume it later in this Vm or a fresh one. Open files are not captured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub pc: usize,
+    pub sp: Int,
+    pub bp: Int,
+    pub ax: Int,
+    pub cycle: Int,
+    code: Vec<Int>,
+    data_base: Int,
+    data: Vec<u8>,
+    heap_base: Int,
+    heap: Vec<u8>,
+    heap_blocks: Vec<(usize, usize)>,
+    stack_base: Int,
+    stack: Vec<u8>,
+    exit_hooks: Vec<Int>,
+    exit_code: Option<Int>,
+    rand_state: u64,
+}
+
+// Leads every serialized snapshot, with the format version
+const SNAPSHOT_MAGIC: &[u8; 8] = b"C4SNAP01";
+
+impl Snapshot {
+    // Serialize for storage: little-endian words, each array preceded by its length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = SNAPSHOT_MAGIC.to_vec();
+        let word = |out: &mut Vec<u8>, value: Int| out.extend(value.to_le_bytes());
+        for value in [self.pc as Int, self.sp, self.bp, self.ax, self.cycle, self.data_base, self.heap_base,
+                                    self.stack_base, self.exit_code.is_some() as Int, self.exit_code.unwrap_or(0), self.rand_state as Int] {
+            word(&mut out, value);
+        }
+        for bytes in [&self.data, &self.heap, &self.stack] {
+            word(&mut out, bytes.len() as Int);
+            out.extend(bytes);
+        }
+        let blocks: Vec<Int> = self.heap_blocks.iter().flat_map(|&(start, len)| [start as Int, len as Int]).collect();
+        for words in [&self.code, &self.exit_hooks, &blocks] {
+            word(&mut out, words.len() as Int);
+            for &value in words {
+                word(&mut out, value);
+            }
+        }
+        out
+    }
+
+    // Read back a snapshot written by to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, String> {
+        let mut reader = SnapshotReader { bytes: bytes.strip_prefix(SNAPSHOT_MAGIC).ok_or("not a snapshot")?, pos: 0 };
+        let mut words = [0; 11];
+        for word in &mut words {
+            *word = reader.word()?;
+        }
+        let (data, heap, stack) = (reader.bytes()?, reader.bytes()?, reader.bytes()?);
+        let (code, exit_hooks, blocks) = (reader.words()?, reader.words()?, reader.words()?);
+        Ok(Snapshot {
+            pc: words[0] as usize,
+            sp: words[1],
+            bp: words[2],
+            ax: words[3],
+            cycle: words[4],
+            code,
+            data_base: words[5],
+            data,
+            heap_base: words[6],
+            heap,
+            heap_blocks: blocks.chunks(2).filter(|pair| pair.len() == 2).map(|pair| (pair[0] as usize, pair[1] as usize)).collect(),
+            stack_base: words[7],
+            stack,
+            exit_hooks,
+            exit_code: if words[8] != 0 { Some(words[9]) } else { None },
+            rand_state: words[10] as u64,
+        })
+    }
+}
+
+// Cursor over a serialized snapshot; lengths come from the input, so each is checked against
+// what is left before it is used
+struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl SnapshotReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let chunk = self.bytes.get(self.pos..self.pos.saturating_add(len)).ok_or("truncated snapshot")?;
+        self.pos += len;
+        Ok(chunk)
+    }
+
+    fn word(&mut self) -> Result<Int, String> {
+        let mut word = [0u8; WORD_SIZE];
+        word.copy_from_slice(self.take(WORD_SIZE)?);
+        Ok(Int::from_le_bytes(word))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        usize::try_from(self.word()?).map_err(|_| "bad snapshot length".to_string())
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn words(&mut self) -> Result<Vec<Int>, String> {
+        let len = self.len()?;
+        if len > self.bytes.len() / WORD_SIZE {
+            return Err("truncated snapshot".to_string());
+        }
+        (0..len).map(|_| self.word()).collect()
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// Fold one word into an FNV-1a hash, a byte at a time
+fn fnv_mix(mut hash: u64, word: u64) -> u64 {
+    for byte in word.to_le_bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+// Instructions kept in the retirement log unless configured otherwise. The log is off by
+// default: recording every instruction halves the fast loop's throughput.
+const DEFAULT_RETIRE_LOG: usize = 0;
+
+// An instruction as the fast loop decodes it: a plain opcode, or a superinstruction that runs
+// a common sequence of them in one dispatch
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Inst {
+    Op(OpCode),
+    // IMM n; PSH
+    ImmPsh,
+    // LEA n; LI (load a local)
+    LeaLi,
+    // LEA n; PSH (push a local's address)
+    LeaPsh,
+    // PSH; IMM n; ADD (add a constant)
+    PshImmAdd,
+}
+
+impl Inst {
+    // Number of instructions it runs
+    fn width(self) -> Int {
+        match self {
+            Inst::Op(_) => 1,
+            Inst::ImmPsh | Inst::LeaLi | Inst::LeaPsh => 2,
+            Inst::PshImmAdd => 3,
+        }
+    }
+}
+
+// Memory regions of the VM address space
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MemRegion {
+    Data,
+    Heap,
+    Stack,
+}
+
+// A file opened by the OPEN syscall
+#[derive(Debug)]
+enum OpenFile {
+    Mounted {
+        path: String,          // Path the program passed to open()
+        pos: usize,            // Read offset into the file contents
+    },
+    Host(fs::File),          // A file on the host filesystem
+}
+
+// Field width of a printf/scanf directive starting at format[*i], 0 if there is none, leaving
+// *i after the digits. None if it does not fit in a usize.
+fn parse_width(format: &[u8], i: &mut usize) -> Option<usize> {
+    let mut width: Option<usize> = Some(0);
+    while let Some(digit) = format.get(*i).filter(|c| c.is_ascii_digit()) {
+        width = width.and_then(|w| w.checked_mul(10)?.checked_add((digit - b'0') as usize));
+        *i += 1;
+    }
+    width
+}
+
+// <ctype.h> builtins for a character code; like C they only classify ASCII and leave EOF
+// (and anything else outside 0..=255) alone
+fn char_class(op: OpCode, c: Int) -> Int {
+    let ch = match u8::try_from(c) {
+        Ok(ch) => ch,
+        Err(_) => return if matches!(op, OpCode::TOUP | OpCode::TOLO) { c } else { 0 },
+    };
+    match op {
+        OpCode::ISDG => ch.is_ascii_digit() as Int,
+        OpCode::ISAL => ch.is_ascii_alphabetic() as Int,
+        // space, \t, \n, \v, \f, \r
+        OpCode::ISSP => (ch == b' ' || (9..=13).contains(&ch)) as Int,
+        OpCode::TOUP => ch.to_ascii_uppercase() as Int,
+        OpCode::TOLO => ch.to_ascii_lowercase() as Int,
+        _ => c,
+    }
+}
+
+// A machine that runs c4 code: fetches words from `code` at `pc` and executes them against
+// the data segment, stack and heap, with `ax` as the accumulator and `sp`/`bp` the stack
+// and frame pointers
+pub struct Vm {
+    // Code words; start() decodes them for run_for, so changes belong before it
+    pub code: Vec<Int>,
+    // The instruction starting at each code word up to `le` (None for words that are not
+    // one), which the fast loop dispatches on, and whether common sequences are fused
+    decoded: Vec<Option<Inst>>,
+    fusion: bool,
+    // Last code word in use; code after it is free for more
+    pub le: usize,
+    pub data: Vec<u8>,
+    pub stack: Vec<u8>,
+    // Memory behind malloc; grows up to HEAP_SIZE as blocks are handed out further up
+    pub heap: Vec<u8>,
+    pub pc: usize,
+    pub sp: Int,
+    pub bp: Int,
+    pub ax: Int,
+    pub cycle: Int,
+    // `-d`: print every instruction as it executes
+    pub debug: bool,
+    // Live malloc blocks, offset into the heap -> size
+    heap_blocks: BTreeMap<usize, usize>,
+    // pc of the MALC that handed out each live block, for leak reports
+    heap_sites: HashMap<usize, usize>,
+    // VM addresses of the first byte of the data segment, the heap and the stack region
+    pub(crate) data_base: Int,
+    pub(crate) heap_base: Int,
+    pub(crate) stack_base: Int,
+    // Last executed instructions, oldest first, for post-mortem dumps
+    retired: VecDeque<Retired>,
+    retire_capacity: usize,
+    // Golden trace: checkpoint every `trace_interval` instructions (0 = off), and the running
+    // hash of the executed (pc, opcode) stream
+    trace_interval: Int,
+    trace: Vec<TraceCheckpoint>,
+    trace_hash: u64,
+    // Watched (address, length) ranges, and the store that last hit one
+    watchpoints: Vec<(Int, usize)>,
+    watch_hit: Option<WatchHit>,
+    // Executions of each code word this run when profiling, indexed by pc
+    profile: Option<Vec<Int>>,
+    // Functions registered with atexit, run last-registered first when the program exits
+    exit_hooks: Vec<Int>,
+    // Exit code once the program has halted
+    exit_code: Option<Int>,
+    // Total instructions the program may run, if limited
+    cycle_limit: Option<Int>,
+    // Debug info for fault sites and profiles: the entry point and name of each function, the
+    // source line of the code from each position on, in code order (0 for code that was not
+    // compiled from C) with the file and line it came from before preprocessing if known, and
+    // the files those refer to
+    functions: Vec<(usize, String)>,
+    pub(crate) code_lines: Vec<(usize, i32, Option<LineOrigin>)>,
+    pub(crate) origin_files: Vec<String>,
+    // Resource counters for the current run
+    usage: ResourceUsage,
+    // In-memory files (path -> bytes) that OPEN/READ/CLOS resolve against before the host filesystem
+    vfs: HashMap<String, Vec<u8>>,
+    // Open file table, indexed by fd - 3 (0/1/2 are the standard streams)
+    files: Vec<Option<OpenFile>>,
+    // State of the rand syscall's generator; each run starts as if srand(1) had been called,
+    // or srand(seed) in deterministic mode
+    rand_state: u64,
+    // Seed of deterministic mode, if on
+    deterministic: Option<u64>,
+    // What open and getenv may reach on the host, if restricted
+    sandbox: Option<SandboxPolicy>,
+    // Variables getenv sees instead of the host environment, if set
+    environment: Option<HashMap<String, String>>,
+    // Heap copies of the variables getenv has returned this run, so repeated calls share one
+    env_strings: HashMap<String, Int>,
+    // Where program input on fd 0 comes from and output on fd 1 and fd 2 goes (real stdio
+    // unless replaced)
+    stdin: Box<dyn Read>,
+    // Byte scanf read one past the end of a field and gave back, returned by the next read
+    stdin_pending: Option<u8>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm {
+            code: vec![0; 256 * 1024],
+            decoded: Vec::new(),
+            fusion: true,
+            le: 0,
+            data: vec![0; 256 * 1024],
+            stack: vec![0; STACK_SIZE],
+            heap: Vec::new(),
+            pc: 0,
+            sp: STACK_BASE + STACK_SIZE as Int,
+            bp: STACK_BASE + STACK_SIZE as Int,
+            ax: 0,
+            cycle: 0,
+            debug: false,
+            heap_blocks: BTreeMap::new(),
+            heap_sites: HashMap::new(),
+            data_base: 0,
+            heap_base: HEAP_BASE,
+            stack_base: STACK_BASE,
+            retired: VecDeque::new(),
+            retire_capacity: DEFAULT_RETIRE_LOG,
+            trace_interval: 0,
+            trace: Vec::new(),
+            trace_hash: FNV_OFFSET,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            profile: None,
+            exit_hooks: Vec::new(),
+            exit_code: None,
+            cycle_limit: None,
+            functions: Vec::new(),
+            code_lines: Vec::new(),
+            origin_files: Vec::new(),
+            usage: ResourceUsage::default(),
+            vfs: HashMap::new(),
+            files: Vec::new(),
+            rand_state: 1,
+            deterministic: None,
+            sandbox: None,
+            environment: None,
+            env_strings: HashMap::new(),
+            stdin: Box::new(io::stdin()),
+            stdin_pending: None,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        }
+    }
+
+    // Set up registers and the stack to start executing at `entry`; returning from the
+    // entry function halts the VM with the returned value as exit code
+    pub fn start(&mut self, entry: usize) {
+        self.start_with_args(entry, &[]);
+    }
+
+    // Name the functions of the loaded code, by entry point, for fault sites and profiles
+    pub fn set_functions(&mut self, functions: Vec<(usize, String)>) {
+        self.functions = functions;
+    }
+
+    // Cycles executed since the last start()
+    pub fn cycle(&self) -> Int {
+        self.cycle
+    }
+
+    // Seed rand with `seed` and drive time() from the cycle count (None turns it off); see
+    // C4::set_deterministic
+    pub fn set_deterministic(&mut self, seed: Option<u64>) {
+        self.deterministic = seed;
+    }
+
+    // Mount an in-memory file that the program can open() instead of touching the real filesystem
+    pub fn mount_file(&mut self, path: &str, contents: &[u8]) {
+        self.vfs.insert(path.to_string(), contents.to_vec());
+    }
+
+    // Remove every mounted file
+    pub fn unmount_all(&mut self) {
+        self.vfs.clear();
+    }
+
+    // Restrict the host files open() may reach and the variables getenv sees (None lifts the
+    // restrictions)
+    pub fn set_sandbox(&mut self, policy: Option<SandboxPolicy>) {
+        self.sandbox = policy;
+    }
+
+    // Give getenv these variables instead of the host environment (None goes back to the host's),
+    // e.g. to keep secrets away from a sandboxed program
+    pub fn set_environment(&mut self, vars: Option<HashMap<String, String>>) {
+        self.environment = vars;
+    }
+
+    // Feed the program's stdin from another reader (a byte slice, a file, io::empty())
+    pub fn set_stdin(&mut self, source: Box<dyn Read>) {
+        self.stdin = source;
+    }
+
+    // Send the program's stdout to another writer (capture, tee, or io::sink() to discard)
+    pub fn set_stdout(&mut self, sink: Box<dyn Write>) {
+        self.stdout = sink;
+    }
+
+    // Send the program's stderr to another writer
+    pub fn set_stderr(&mut self, sink: Box<dyn Write>) {
+        self.stderr = sink;
+    }
+
+    // Replace all three standard streams at once
+    pub fn set_stdio(&mut self, stdin: Box<dyn Read>, stdout: Box<dyn Write>, stderr: Box<dyn Write>) {
+        self.set_stdin(stdin);
+        self.set_stdout(stdout);
+        self.set_stderr(stderr);
+    }
+
+    // Write program output to fd 1 or 2, returning the byte count or -1
+    pub fn write_fd(&mut self, fd: Int, bytes: &[u8]) -> Int {
+        let sink = match fd {
+            1 => &mut self.stdout,
+            2 => &mut self.stderr,
+            _ => return -1,
+        };
+        match sink.write_all(bytes) {
+            Ok(()) => {
+                self.usage.bytes_written += bytes.len() as Int;
+                bytes.len() as Int
+            },
+            Err(_) => -1,
+        }
+    }
+
+    // Read up to `len` bytes of program input from fd 0; None on a read error. Output is
+    // flushed first so a prompt shows up before the program waits for its answer.
+    fn read_stdin(&mut self, len: usize) -> Option<Vec<u8>> {
+        if len > 0 {
+            if let Some(byte) = self.stdin_pending.take() {
+                return Some(vec![byte]);
+            }
+        }
+        self.flush_output();
+        let mut bytes = vec![0; len];
+        loop {
+            match self.stdin.read(&mut bytes) {
+                Ok(count) => {
+                    bytes.truncate(count);
+                    return Some(bytes);
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // Flush both output sinks, e.g. when the program exits
+    pub fn flush_output(&mut self) {
+        let _ = self.stdout.flush();
+        let _ = self.stderr.flush();
+    }
+
+    // Find the region backing `len` bytes at `addr`, and the offset into it
+    fn locate(&self, addr: Int, len: usize) -> Option<(MemRegion, usize)> {
+        let (region, offset, size) = if addr >= self.stack_base {
+            (MemRegion::Stack, addr - self.stack_base, self.stack.len())
+        } else if addr >= self.heap_base {
+            (MemRegion::Heap, addr - self.heap_base, self.heap.len())
+        } else {
+            (MemRegion::Data, addr - self.data_base, self.data.len())
+        };
+        if offset < 0 || (offset as usize).checked_add(len).is_none_or(|end| end > size) {
+            return None;
+        }
+        Some((region, offset as usize))
+    }
+
+    // VM address of byte `index` of the data segment
+    pub(crate) fn data_addr(&self, index: usize) -> Int {
+        self.data_base + index as Int
+    }
+
+    // Resize the VM stack to `bytes`, rounded up to whole words; takes effect from the next start()
+    pub fn set_stack_size(&mut self, bytes: usize) -> Result<(), String> {
+        if !(MIN_STACK_SIZE..=MAX_STACK_SIZE).contains(&bytes) {
+            return Err(format!("stack size {} is out of range ({} to {} bytes)", bytes, MIN_STACK_SIZE, MAX_STACK_SIZE));
+        }
+        self.stack = vec![0; bytes.next_multiple_of(WORD_SIZE)];
+        self.sp = self.stack_base + self.stack.len() as Int;
+        self.bp = self.sp;
+        Ok(())
+    }
+
+    // Size of the VM stack in bytes
+    pub fn stack_size(&self) -> usize {
+        self.stack.len()
+    }
+
+    // Borrow `len` bytes of VM memory
+    fn mem(&self, addr: Int, len: usize) -> Result<&[u8], RuntimeError> {
+        let (region, offset) = self.locate(addr, len).ok_or(RuntimeError::InvalidRead { addr, site: FaultSite::default() })?;
+        let bytes = match region {
+            MemRegion::Data => &self.data,
+            MemRegion::Heap => &self.heap,
+            MemRegion::Stack => &self.stack,
+        };
+        Ok(&bytes[offset..offset + len])
+    }
+
+    // Mutably borrow `len` bytes of VM memory
+    fn mem_mut(&mut self, addr: Int, len: usize) -> Result<&mut [u8], RuntimeError> {
+        let (region, offset) = self.locate(addr, len).ok_or(RuntimeError::InvalidWrite { addr, site: FaultSite::default() })?;
+        let bytes = match region {
+            MemRegion::Data => &mut self.data,
+            MemRegion::Heap => &mut self.heap,
+            MemRegion::Stack => &mut self.stack,
+        };
+        Ok(&mut bytes[offset..offset + len])
+    }
+
+    pub fn load_int(&self, addr: Int) -> Result<Int, RuntimeError> {
+        let bytes = self.mem(addr, WORD_SIZE)?;
+        let mut word = [0u8; WORD_SIZE];
+        word.copy_from_slice(bytes);
+        Ok(Int::from_le_bytes(word))
+    }
+
+    pub fn store_int(&mut self, addr: Int, value: Int) -> Result<(), RuntimeError> {
+        self.mem_mut(addr, WORD_SIZE)?.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    // Load a char, sign-extending it like c4's `char`
+    pub fn load_char(&self, addr: Int) -> Result<Int, RuntimeError> {
+        Ok(self.mem(addr, 1)?[0] as i8 as Int)
+    }
+
+    pub(crate) fn store_char(&mut self, addr: Int, value: Int) -> Result<(), RuntimeError> {
+        self.mem_mut(addr, 1)?[0] = value as u8;
+        Ok(())
+    }
+
+    // Load a 16-bit short, sign-extending it
+    pub fn load_short(&self, addr: Int) -> Result<Int, RuntimeError> {
+        let bytes = self.mem(addr, 2)?;
+        Ok(i16::from_le_bytes([bytes[0], bytes[1]]) as Int)
+    }
+
+    pub fn store_short(&mut self, addr: Int, value: Int) -> Result<(), RuntimeError> {
+        self.mem_mut(addr, 2)?.copy_from_slice(&(value as i16).to_le_bytes());
+        Ok(())
+    }
+
+    // Read a NUL-terminated string out of VM memory
+    pub fn read_cstr(&self, addr: Int) -> Result<String, RuntimeError> {
+        Ok(String::from_utf8_lossy(&self.cstr_bytes(addr)?).into_owned())
+    }
+
+    // The bytes of the string at `addr`, without its terminating 0
+    fn cstr_bytes(&self, addr: Int) -> Result<Vec<u8>, RuntimeError> {
+        let (region, start) = self.locate(addr, 1).ok_or(RuntimeError::InvalidRead { addr, site: FaultSite::default() })?;
+        let bytes = match region {
+            MemRegion::Data => &self.data,
+            MemRegion::Heap => &self.heap,
+            MemRegion::Stack => &self.stack,
+        };
+        match bytes[start..].iter().position(|&b| b == 0) {
+            Some(len) => Ok(bytes[start..start + len].to_vec()),
+            // A string running off the end of its region is read past the end
+            None => Err(RuntimeError::InvalidRead { addr: addr + (bytes.len() - start) as Int, site: FaultSite::default() }),
+        }
+    }
+
+    // MALC syscall: a block of `size` bytes from the heap (first fit, word aligned), or 0 once
+    // the heap is full
+    fn sys_malloc(&mut self, size: Int) -> Int {
+        let Some((offset, size)) = self.heap_alloc(size) else { return 0 };
+        self.heap_sites.insert(offset, self.pc - 1);
+        self.usage.allocations += 1;
+        self.usage.bytes_allocated += size as Int;
+        self.heap_base + offset as Int
+    }
+
+    // Reserve a heap block of at least `size` bytes and return its offset and rounded size,
+    // without counting it as one of the program's allocations
+    fn heap_alloc(&mut self, size: Int) -> Option<(usize, usize)> {
+        if size < 0 || size as usize > HEAP_SIZE {
+            return None;
+        }
+        let size = (size as usize).max(1).next_multiple_of(WORD_SIZE);
+        // The lowest gap between live blocks that is large enough, else above the last one
+        let mut offset = 0;
+        for (&start, &len) in &self.heap_blocks {
+            if start - offset >= size {
+                break;
+            }
+            offset = start + len;
+        }
+        if offset + size > HEAP_SIZE {
+            return None;
+        }
+        if offset + size > self.heap.len() {
+            self.heap.resize(offset + size, 0);
+        }
+        self.heap_blocks.insert(offset, size);
+        Some((offset, size))
+    }
+
+    // FREE syscall: give back a block malloc returned; freeing 0 does nothing, freeing any
+    // other address that is not a live block is an error
+    fn sys_free(&mut self, addr: Int) -> Result<(), RuntimeError> {
+        if addr == 0 {
+            return Ok(());
+        }
+        let offset = addr - self.heap_base;
+        if offset < 0 || self.heap_blocks.remove(&(offset as usize)).is_none() {
+            return Err(RuntimeError::InvalidFree { addr, site: FaultSite::default() });
+        }
+        self.heap_sites.remove(&(offset as usize));
+        self.usage.frees += 1;
+        Ok(())
+    }
+
+    // MSET syscall: fill `len` bytes at `dest` with the low byte of `value`, returns `dest`
+    fn sys_memset(&mut self, dest: Int, value: Int, len: Int) -> Result<Int, RuntimeError> {
+        if len < 0 {
+            return Err(RuntimeError::InvalidWrite { addr: dest, site: FaultSite::default() });
+        }
+        self.mem_mut(dest, len as usize)?.fill(value as u8);
+        Ok(dest)
+    }
+
+    // MCMP syscall: compare `len` bytes as unsigned chars, returns the difference of the first
+    // pair that differs, or 0
+    fn sys_memcmp(&self, lhs: Int, rhs: Int, len: Int) -> Result<Int, RuntimeError> {
+        if len < 0 {
+            return Err(RuntimeError::InvalidRead { addr: lhs, site: FaultSite::default() });
+        }
+        let (a, b) = (self.mem(lhs, len as usize)?, self.mem(rhs, len as usize)?);
+        Ok(a.iter().zip(b).find(|(x, y)| x != y).map_or(0, |(&x, &y)| x as Int - y as Int))
+    }
+
+    // SCPY and SCAT syscalls: strcpy(dest, src) and strcat(dest, src), both return `dest`
+    fn sys_strcpy(&mut self, dest: Int, src: Int, append: bool) -> Result<Int, RuntimeError> {
+        let mut bytes = self.cstr_bytes(src)?;
+        bytes.push(0);
+        let at = if append { dest + self.cstr_bytes(dest)?.len() as Int } else { dest };
+        self.mem_mut(at, bytes.len())?.copy_from_slice(&bytes);
+        Ok(dest)
+    }
+
+    // SNCP syscall: strncpy(dest, src, n) copies at most `n` bytes of `src` and pads with 0s
+    // up to `n`; like C, the result is unterminated if `src` is `n` bytes or longer
+    fn sys_strncpy(&mut self, dest: Int, src: Int, n: Int) -> Result<Int, RuntimeError> {
+        if n < 0 {
+            return Err(RuntimeError::InvalidWrite { addr: dest, site: FaultSite::default() });
+        }
+        let bytes = self.cstr_bytes(src)?;
+        let copied = bytes.len().min(n as usize);
+        let out = self.mem_mut(dest, n as usize)?;
+        out[..copied].copy_from_slice(&bytes[..copied]);
+        out[copied..].fill(0);
+        Ok(dest)
+    }
+
+    // SCMP syscall: compare two strings as unsigned chars, returns the difference of the first
+    // pair that differs, or 0
+    fn sys_strcmp(&self, lhs: Int, rhs: Int) -> Result<Int, RuntimeError> {
+        let (mut a, mut b) = (self.cstr_bytes(lhs)?, self.cstr_bytes(rhs)?);
+        a.push(0);
+        b.push(0);
+        Ok(a.iter().zip(&b).find(|(x, y)| x != y).map_or(0, |(&x, &y)| x as Int - y as Int))
+    }
+
+    // MCPY syscall: memcpy(dest, src, n), returns `dest`. Overlapping ranges copy as if
+    // through a temporary, like memmove.
+    fn sys_memcpy(&mut self, dest: Int, src: Int, n: Int) -> Result<Int, RuntimeError> {
+        if n < 0 {
+            return Err(RuntimeError::InvalidRead { addr: src, site: FaultSite::default() });
+        }
+        let bytes = self.mem(src, n as usize)?.to_vec();
+        self.mem_mut(dest, bytes.len())?.copy_from_slice(&bytes);
+        Ok(dest)
+    }
+
+    // ATOI syscall: the decimal number at the start of a string, after optional whitespace and
+    // a sign; 0 if there is none
+    fn sys_atoi(&self, addr: Int) -> Result<Int, RuntimeError> {
+        let bytes = self.cstr_bytes(addr)?;
+        let mut digits = bytes.iter().skip_while(|b| b.is_ascii_whitespace()).peekable();
+        let negative = match digits.peek() {
+            Some(b'-') => { digits.next(); true },
+            Some(b'+') => { digits.next(); false },
+            _ => false,
+        };
+        let value = digits.take_while(|b| b.is_ascii_digit())
+            .fold(0 as Int, |value, &b| value.wrapping_mul(10).wrapping_add((b - b'0') as Int));
+        Ok(if negative { value.wrapping_neg() } else { value })
+    }
+
+    // GENV syscall: getenv(name), a heap copy of the variable's value, or 0 if it is not set
+    // (or the heap is full)
+    fn sys_getenv(&mut self, name_addr: Int) -> Result<Int, RuntimeError> {
+        let name = self.read_cstr(name_addr)?;
+        if let Some(&addr) = self.env_strings.get(&name) {
+            return Ok(addr);
+        }
+        let value = match &self.environment {
+            _ if self.sandbox.as_ref().is_some_and(|policy| policy.no_env) => None,
+            Some(vars) => vars.get(&name).cloned(),
+            None if self.deterministic.is_some() => None,
+            None => env::var(&name).ok(),
+        };
+        let mut bytes = match value {
+            Some(value) => value.into_bytes(),
+            None => return Ok(0),
+        };
+        bytes.push(0);
+        // The copy belongs to the VM, not the program, so it is not one of its allocations
+        let Some((offset, _)) = self.heap_alloc(bytes.len() as Int) else { return Ok(0) };
+        let addr = self.heap_base + offset as Int;
+        self.mem_mut(addr, bytes.len())?.copy_from_slice(&bytes);
+        self.env_strings.insert(name, addr);
+        Ok(addr)
+    }
+
+    // TIME syscall: seconds since the Unix epoch (on the virtual clock in deterministic mode),
+    // also stored through `addr` unless it is 0
+    fn sys_time(&mut self, addr: Int) -> Result<Int, RuntimeError> {
+        let now = match self.deterministic {
+            Some(_) => VIRTUAL_EPOCH + self.cycle / VIRTUAL_CYCLES_PER_SECOND,
+            None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as Int),
+        };
+        if addr != 0 {
+            self.store_int(addr, now)?;
+        }
+        Ok(now)
+    }
+
+    // OPEN syscall: opens a mounted file, or else the host file at that path, for reading;
+    // returns a file descriptor, or -1 if neither exists or the sandbox forbids it. A bad path
+    // pointer is a fault.
+    pub fn sys_open(&mut self, path_addr: Int, flags: Int) -> Result<Int, RuntimeError> {
+        let path = self.read_cstr(path_addr)?;
+        let mut host_path = PathBuf::from(&path);
+        if let Some(policy) = &self.sandbox {
+            // Any flag beyond O_RDONLY (0) asks to write, create or truncate
+            if policy.read_only && flags != 0 {
+                return Ok(-1);
+            }
+            if self.files.iter().flatten().count() >= MAX_SANDBOX_FILES {
+                return Ok(-1);
+            }
+            if !self.vfs.contains_key(&path) {
+                match policy.resolve_path(&path) {
+                    Some(resolved) => host_path = resolved,
+                    None => return Ok(-1),
+                }
+            }
+        }
+        let file = if self.vfs.contains_key(&path) {
+            OpenFile::Mounted { path, pos: 0 }
+        } else {
+            match fs::File::open(&host_path) {
+                Ok(file) => OpenFile::Host(file),
+                Err(_) => return Ok(-1),
+            }
+        };
+        let slot = match self.files.iter().position(|f| f.is_none()) {
+            Some(slot) => {
+                self.files[slot] = Some(file);
+                slot
+            },
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        };
+        Ok((slot + 3) as Int)
+    }
+
+    // READ syscall: copies up to `len` bytes from stdin or an open file into VM memory, returns
+    // the count or -1. A buffer that does not fit in VM memory is a fault.
+    pub fn sys_read(&mut self, fd: Int, buf: Int, len: Int) -> Result<Int, RuntimeError> {
+        let slot = fd - 3;
+        if (fd != 0 && slot < 0) || len < 0 {
+            return Ok(-1);
+        }
+        self.mem_mut(buf, len as usize)?;
+        if fd == 0 {
+            let chunk = match self.read_stdin(len as usize) {
+                Some(chunk) => chunk,
+                None => return Ok(-1),
+            };
+            self.mem_mut(buf, chunk.len())?.copy_from_slice(&chunk);
+            return Ok(chunk.len() as Int);
+        }
+        let chunk = match self.files.get_mut(slot as usize) {
+            Some(Some(OpenFile::Mounted { path, pos })) => match self.vfs.get(path.as_str()) {
+                Some(contents) => {
+                    let end = contents.len().min(*pos + len as usize);
+                    let chunk = contents[(*pos).min(end)..end].to_vec();
+                    *pos += chunk.len();
+                    chunk
+                },
+                None => return Ok(-1),
+            },
+            Some(Some(OpenFile::Host(file))) => {
+                let mut chunk = vec![0; len as usize];
+                match file.read(&mut chunk) {
+                    Ok(count) => chunk.truncate(count),
+                    Err(_) => return Ok(-1),
+                }
+                chunk
+            },
+            _ => return Ok(-1),
+        };
+        self.mem_mut(buf, chunk.len())?.copy_from_slice(&chunk);
+        Ok(chunk.len() as Int)
+    }
+
+    // WRIT syscall: write `len` bytes from a VM buffer to fd 1 or 2, returns the count or -1
+    fn sys_write(&mut self, fd: Int, buf: Int, len: Int) -> Result<Int, RuntimeError> {
+        if len < 0 {
+            return Ok(-1);
+        }
+        let bytes = self.mem(buf, len as usize)?.to_vec();
+        Ok(self.write_fd(fd, &bytes))
+    }
+
+    // PUTS syscall: write a string and a newline to stdout, returns a nonnegative number or -1
+    fn sys_puts(&mut self, addr: Int) -> Result<Int, RuntimeError> {
+        let mut bytes = self.cstr_bytes(addr)?;
+        bytes.push(b'\n');
+        Ok(self.write_fd(1, &bytes))
+    }
+
+    // GETC syscall: the next byte of stdin as an unsigned char, or -1 at end of input
+    fn sys_getchar(&mut self) -> Int {
+        self.stdin_byte().map_or(-1, |byte| byte as Int)
+    }
+
+    // Next byte of stdin, None at end of input or on a read error
+    fn stdin_byte(&mut self) -> Option<u8> {
+        self.read_stdin(1).and_then(|bytes| bytes.first().copied())
+    }
+
+    // Next byte of stdin that is not whitespace
+    fn stdin_skip_space(&mut self) -> Option<u8> {
+        loop {
+            match self.stdin_byte() {
+                Some(byte) if byte.is_ascii_whitespace() => {},
+                other => return other,
+            }
+        }
+    }
+
+    // GETS syscall: read a line from stdin into `buf` without its newline, returns `buf`, or 0
+    // at end of input
+    fn sys_gets(&mut self, buf: Int) -> Result<Int, RuntimeError> {
+        let mut line = Vec::new();
+        loop {
+            match self.stdin_byte() {
+                Some(b'\n') => break,
+                Some(byte) => line.push(byte),
+                None if line.is_empty() => return Ok(0),
+                None => break,
+            }
+        }
+        line.push(0);
+        self.mem_mut(buf, line.len())?.copy_from_slice(&line);
+        Ok(buf)
+    }
+
+    // SCNF syscall: scanf(format, ...) with `count` arguments on the stack, the format deepest.
+    // Supports %d %x %s %c %% with an optional field width; whitespace in the format skips any
+    // amount of input whitespace and other characters must match. Returns the number of fields
+    // stored, or -1 if the input ended before the first one.
+    fn sys_scanf(&mut self, count: Int) -> Result<Int, RuntimeError> {
+        if count < 1 {
+            return Ok(-1);
+        }
+        let arg = |vm: &Vm, i: Int| vm.load_int(vm.sp + (count - 1 - i) * WORD_SIZE as Int);
+        let format = self.read_cstr(arg(self, 0)?)?.into_bytes();
+        let mut stored = 0;
+        let mut next_arg = 1;
+        let mut i = 0;
+        while i < format.len() {
+            if format[i].is_ascii_whitespace() {
+                match self.stdin_skip_space() {
+                    Some(byte) => self.stdin_pending = Some(byte),
+                    None => break,
+                }
+                i += 1;
+                continue;
+            }
+            if format[i] != b'%' || format.get(i + 1) == Some(&b'%') {
+                let expected = format[i];
+                i += if expected == b'%' { 2 } else { 1 };
+                match self.stdin_byte() {
+                    Some(byte) if byte == expected => continue,
+                    Some(byte) => self.stdin_pending = Some(byte),
+                    None if stored == 0 => return Ok(-1),
+                    None => {},
+                }
+                break;
+            }
+            i += 1;
+            // A width too large to represent ends the scan
+            let Some(width) = parse_width(&format, &mut i) else { break };
+            let conversion = match format.get(i) {
+                Some(&c) if b"dxsc".contains(&c) && next_arg < count => c,
+                _ => break,
+            };
+            i += 1;
+            let dest = arg(self, next_arg)?;
+            next_arg += 1;
+            // %c takes the next characters as they are, the others skip leading whitespace
+            let first = if conversion == b'c' { self.stdin_byte() } else { self.stdin_skip_space() };
+            if first.is_none() && stored == 0 {
+                return Ok(-1);
+            }
+            let limit = match (conversion, width) {
+                (b'c', 0) => 1,
+                (_, 0) => usize::MAX,
+                _ => width,
+            };
+            let mut field = Vec::new();
+            let mut next = first;
+            while let Some(byte) = next {
+                let wanted = match conversion {
+                    b'c' => true,
+                    b's' => !byte.is_ascii_whitespace(),
+                    b'd' => byte.is_ascii_digit() || (field.is_empty() && (byte == b'-' || byte == b'+')),
+                    _ => byte.is_ascii_hexdigit(),
+                };
+                if !wanted {
+                    self.stdin_pending = Some(byte);
+                    break;
+                }
+                field.push(byte);
+                if field.len() == limit {
+                    break;
+                }
+                next = self.stdin_byte();
+            }
+            if field.is_empty() {
+                break;
+            }
+            match conversion {
+                b's' | b'c' => {
+                    if conversion == b's' {
+                        field.push(0);
+                    }
+                    self.mem_mut(dest, field.len())?.copy_from_slice(&field);
+                },
+                _ => {
+                    let text = String::from_utf8_lossy(&field).into_owned();
+                    let value = match conversion {
+                        b'd' => text.parse::<Int>().ok(),
+                        _ => u64::from_str_radix(&text, 16).ok().map(|v| v as Int),
+                    };
+                    match value {
+                        Some(value) => self.store_int(dest, value)?,
+                        None => break,
+                    }
+                },
+            }
+            stored += 1;
+        }
+        Ok(stored)
+    }
+
+    // CLOS syscall: releases the descriptor, returns 0 or -1
+    pub fn sys_close(&mut self, fd: Int) -> Int {
+        let slot = fd - 3;
+        if slot < 0 {
+            return -1;
+        }
+        match self.files.get_mut(slot as usize) {
+            Some(file @ Some(_)) => {
+                *file = None;
+                0
+            },
+            _ => -1,
+        }
+    }
+
+    // PRTF syscall: printf(format, ...) with `count` arguments on the stack, the format
+    // deepest. Returns the bytes written or -1.
+    fn sys_printf(&mut self, count: Int) -> Result<Int, RuntimeError> {
+        if count < 1 {
+            return Ok(-1);
+        }
+        let out = self.format_output(count, 0)?;
+        Ok(self.write_fd(1, &out))
+    }
+
+    // FPRT syscall: fprintf(stream, format, ...), where the stream is fd 1 or 2
+    fn sys_fprintf(&mut self, count: Int) -> Result<Int, RuntimeError> {
+        if count < 2 {
+            return Ok(-1);
+        }
+        let fd = self.load_int(self.sp + (count - 1) * WORD_SIZE as Int)?;
+        let out = self.format_output(count, 1)?;
+        Ok(self.write_fd(fd, &out))
+    }
+
+    // SPRF and SNPR syscalls: sprintf(buf, format, ...) and snprintf(buf, size, format, ...)
+    // store the output and a terminating 0 in `buf`, snprintf keeping to `size` bytes in all.
+    // Both return the length of the full output, as C does.
+    fn sys_sprintf(&mut self, count: Int, sized: bool) -> Result<Int, RuntimeError> {
+        let first = if sized { 2 } else { 1 };
+        if count < first + 1 {
+            return Ok(-1);
+        }
+        let arg = |vm: &Vm, i: Int| vm.load_int(vm.sp + (count - 1 - i) * WORD_SIZE as Int);
+        let buf = arg(self, 0)?;
+        let size = if sized { arg(self, 1)? } else { Int::MAX };
+        let mut out = self.format_output(count, first)?;
+        let len = out.len() as Int;
+        if size > 0 {
+            out.truncate((size - 1).min(len) as usize);
+            out.push(0);
+            self.mem_mut(buf, out.len())?.copy_from_slice(&out);
+        }
+        Ok(len)
+    }
+
+    // Format printf-style output from the `count` syscall arguments on the stack, the format
+    // being argument `first`. Supports %d %i %u %x %X %c %s %% with an optional `-` or `0`
+    // flag, a field width (at most MAX_FIELD_WIDTH) and an `l` length (ints are already word-sized).
+    fn format_output(&self, count: Int, first: Int) -> Result<Vec<u8>, RuntimeError> {
+        let arg = |vm: &Vm, i: Int| vm.load_int(vm.sp + (count - 1 - i) * WORD_SIZE as Int);
+        let format = self.cstr_bytes(arg(self, first)?)?;
+        let mut out = Vec::new();
+        let mut next_arg = first + 1;
+        let mut i = 0;
+        while i < format.len() {
+            if format[i] != b'%' {
+                out.push(format[i]);
+                i += 1;
+                continue;
+            }
+            let start = i;
+            i += 1;
+            let left = format.get(i) == Some(&b'-');
+            if left {
+                i += 1;
+            }
+            let zero = format.get(i) == Some(&b'0');
+            let width = parse_width(&format, &mut i).map_or(MAX_FIELD_WIDTH, |width| width.min(MAX_FIELD_WIDTH));
+            while format.get(i) == Some(&b'l') {
+                i += 1;
+            }
+            let conversion = match format.get(i) {
+                Some(&c) => c,
+                None => {
+                    out.extend(&format[start..]);
+                    break;
+                }
+            };
+            i += 1;
+            if conversion == b'%' {
+                out.push(b'%');
+                continue;
+            }
+            // A directive with no argument left, or one we do not know, is printed as written
+            if next_arg >= count || !b"diuxXcs".contains(&conversion) {
+                out.extend(&format[start..i]);
+                continue;
+            }
+            let value = arg(self, next_arg)?;
+            next_arg += 1;
+            let text = match conversion {
+                b'd' | b'i' => value.to_string().into_bytes(),
+                b'u' => (value as u64).to_string().into_bytes(),
+                b'x' => format!("{:x}", value as u64).into_bytes(),
+                b'X' => format!("{:X}", value as u64).into_bytes(),
+                b'c' => vec![value as u8],
+                _ => self.cstr_bytes(value)?,
+            };
+            let pad = width.saturating_sub(text.len());
+            if left {
+                out.extend(&text);
+                out.extend(b" ".repeat(pad));
+            } else if zero && !b"cs".contains(&conversion) {
+                // Zeros go after the sign
+                let sign = text.starts_with(b"-") as usize;
+                out.extend(&text[..sign]);
+                out.extend(b"0".repeat(pad));
+                out.extend(&text[sign..]);
+            } else {
+                out.extend(b" ".repeat(pad));
+                out.extend(&text);
+            }
+        }
+        Ok(out)
+    }
+
+    // Assemble `text` (see `assemble_at`) after the code emitted so far and return its entry
+    // point: the `main` label if there is one, otherwise the first instruction
+    pub fn load_assembly(&mut self, text: &str) -> Result<usize, String> {
+        let base = self.le + 1;
+        let (words, labels) = assemble_at(text, base)?;
+        if base + words.len() > self.code.len() {
+            return Err("program too large for the code segment".to_string());
+        }
+        self.code[base..base + words.len()].copy_from_slice(&words);
+        self.le += words.len();
+        self.code_lines.push((base, 0, None));
+        Ok(labels.get("main").copied().unwrap_or(base))
+    }
+
+    // Like `start`, but main is called as main(argc, argv) with `args` copied onto the top of
+    // the stack as C strings, as c4 does with its command line
+    pub fn start_with_args(&mut self, entry: usize, args: &[String]) {
+        // Code that main returns into: push the return value and exit with it
+        let exit_stub = self.le + 1;
+        self.code[exit_stub] = OpCode::PSH as Int;
+        self.code[exit_stub + 1] = OpCode::EXIT as Int;
+        self.le += 2;
+
+        self.sp = self.stack_base + self.stack.len() as Int;
+        self.bp = self.sp;
+        self.ax = 0;
+        self.cycle = 0;
+        self.retired.clear();
+        self.trace.clear();
+        self.trace_hash = FNV_OFFSET;
+        self.watch_hit = None;
+        if let Some(counts) = &mut self.profile {
+            counts.clear();
+        }
+        self.exit_hooks.clear();
+        self.heap.clear();
+        self.heap_blocks.clear();
+        self.heap_sites.clear();
+        self.env_strings.clear();
+        self.rand_state = self.deterministic.unwrap_or(1);
+        self.exit_code = None;
+        self.usage = ResourceUsage::default();
+
+        // The strings first, then the argv array they are pointed to from; arguments too big for
+        // the stack leave sp below it, so the first instruction faults with StackOverflow
+        let mut pointers = Vec::with_capacity(args.len() + 1);
+        for arg in args.iter().rev() {
+            let len = arg.len() + 1;
+            self.sp -= len as Int;
+            let sp = self.sp;
+            if let Ok(dest) = self.mem_mut(sp, len) {
+                dest[..arg.len()].copy_from_slice(arg.as_bytes());
+                dest[arg.len()] = 0;
+            }
+            pointers.push(sp);
+        }
+        self.sp &= !(WORD_SIZE as Int - 1);
+        let _ = self.push(0);
+        for &pointer in &pointers {
+            let _ = self.push(pointer);
+        }
+        let argv = self.sp;
+        let _ = self.push(args.len() as Int);
+        let _ = self.push(argv);
+        let _ = self.push(exit_stub as Int);
+        self.pc = entry;
+        self.decode();
+    }
+
+    // Decode the code segment into `decoded`, fusing sequences into superinstructions unless
+    // fusion is off. Every word keeps its own entry, so jumps into the middle of a fused
+    // sequence still land on an instruction.
+    fn decode(&mut self) {
+        let code = &self.code[..=self.le];
+        let op_at = |pc: usize| code.get(pc).and_then(|&word| OpCode::from_int(word));
+        self.decoded.clear();
+        self.decoded.extend((0..code.len()).map(|pc| {
+            let op = op_at(pc)?;
+            let fused = match (op, op_at(pc + 2), op_at(pc + 1), op_at(pc + 3)) {
+                _ if !self.fusion => None,
+                (OpCode::IMM, Some(OpCode::PSH), _, _) => Some(Inst::ImmPsh),
+                (OpCode::LEA, Some(OpCode::LI), _, _) => Some(Inst::LeaLi),
+                (OpCode::LEA, Some(OpCode::PSH), _, _) => Some(Inst::LeaPsh),
+                (OpCode::PSH, _, Some(OpCode::IMM), Some(OpCode::ADD)) => Some(Inst::PshImmAdd),
+                _ => None,
+            };
+            Some(fused.unwrap_or(Inst::Op(op)))
+        }));
+    }
+
+    // Turn superinstruction fusion on or off (it is on by default), e.g. to rule it out
+    // when chasing a VM bug; run_for runs the same instructions either way
+    pub fn set_fusion(&mut self, on: bool) {
+        self.fusion = on;
+        self.decoded.clear();
+    }
+
+    // Checkpoint the program started by start(), e.g. between run_for calls
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            sp: self.sp,
+            bp: self.bp,
+            ax: self.ax,
+            cycle: self.cycle,
+            code: self.code[..=self.le].to_vec(),
+            data_base: self.data_base,
+            data: self.data.clone(),
+            heap_base: self.heap_base,
+            heap: self.heap.clone(),
+            heap_blocks: self.heap_blocks.iter().map(|(&start, &len)| (start, len)).collect(),
+            stack_base: self.stack_base,
+            stack: self.stack.clone(),
+            exit_hooks: self.exit_hooks.clone(),
+            exit_code: self.exit_code,
+            rand_state: self.rand_state,
+        }
+    }
+
+    // Put the program back the way snapshot() found it; run_for then carries on from there.
+    // Files the program had open are closed.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        if snapshot.code.is_empty() || snapshot.code.len() > self.code.len() {
+            return Err(format!("snapshot code of {} words does not fit", snapshot.code.len()));
+        }
+        if snapshot.data.len() != self.data.len() {
+            return Err(format!("snapshot data segment is {} bytes, expected {}", snapshot.data.len(), self.data.len()));
+        }
+        self.code[..snapshot.code.len()].copy_from_slice(&snapshot.code);
+        self.le = snapshot.code.len() - 1;
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.bp = snapshot.bp;
+        self.ax = snapshot.ax;
+        self.cycle = snapshot.cycle;
+        self.data_base = snapshot.data_base;
+        self.data.copy_from_slice(&snapshot.data);
+        self.heap_base = snapshot.heap_base;
+        self.heap = snapshot.heap.clone();
+        self.heap_blocks = snapshot.heap_blocks.iter().copied().collect();
+        self.heap_sites.clear();
+        self.stack_base = snapshot.stack_base;
+        self.stack = snapshot.stack.clone();
+        self.exit_hooks = snapshot.exit_hooks.clone();
+        self.exit_code = snapshot.exit_code;
+        self.rand_state = snapshot.rand_state;
+        self.files.clear();
+        self.env_strings.clear();
+        self.stdin_pending = None;
+        self.retired.clear();
+        self.decode();
+        Ok(())
+    }
+
+    // Run at most `max_cycles` instructions, so hosts can interleave the VM with their own work
+    pub fn run_for(&mut self, max_cycles: Int) -> Result<RunState, RuntimeError> {
+        if let Some(code) = self.exit_code {
+            return Ok(RunState::Halted(code));
+        }
+        if self.decoded.len() != self.le + 1 {
+            self.decode();
+        }
+        // Tracing, profiling and -d see every instruction, so they keep to step()
+        let fast = self.trace_interval == 0 && self.profile.is_none() && !self.debug;
+        let mut left = max_cycles;
+        while left > 0 {
+            if fast {
+                let budget = self.cycle_limit.map_or(left, |limit| left.min(limit - self.cycle).max(0));
+                left -= if self.retire_capacity > 0 { self.run_fast::<true>(budget) } else { self.run_fast::<false>(budget) };
+                if left == 0 {
+                    break;
+                }
+            }
+            left -= 1;
+            match self.step()? {
+                RunState::Paused => {},
+                state => return Ok(state),
+            }
+        }
+        Ok(RunState::Paused)
+    }
+
+    // run_for's hot loop: run up to `budget` instructions with the registers in locals,
+    // dispatching on the decoded code, and return how many ran. It stops short of anything
+    // it leaves to step() (syscalls, JMPI, an instruction that would fault, a store while
+    // watchpoints are set), so those behave exactly as in the full interpreter. A fused
+    // instruction that would fault or overrun the budget stops it at its first instruction.
+    fn run_fast<const RETIRE: bool>(&mut self, budget: Int) -> Int {
+        let code_words = std::mem::take(&mut self.code);
+        let ops = std::mem::take(&mut self.decoded);
+        let code = &code_words[..ops.len()];
+        let watching = !self.watchpoints.is_empty();
+        let word = WORD_SIZE as Int;
+        let mut regs = (self.pc, self.sp, self.bp, self.ax);
+        let mut ran = 0;
+        while ran < budget {
+            let (mut pc, mut sp, mut bp, mut ax) = regs;
+            let Some(&Some(inst)) = ops.get(pc) else { break };
+            if budget - ran < inst.width() {
+                break;
+            }
+            pc += 1;
+            match inst {
+                Inst::Op(OpCode::LEA) => {
+                    let Some(&n) = code.get(pc) else { break };
+                    pc += 1;
+                    ax = bp + n * word;
+                },
+                Inst::Op(OpCode::IMM) => {
+                    let Some(&value) = code.get(pc) else { break };
+                    pc += 1;
+                    ax = value;
+                },
+                Inst::Op(OpCode::JMP) => {
+                    let Some(&target) = code.get(pc) else { break };
+                    pc = target as usize;
+                },
+                Inst::Op(OpCode::JSR) => {
+                    let Some(&target) = code.get(pc) else { break };
+                    sp -= word;
+                    if self.set_stack_word(sp, (pc + 1) as Int).is_none() {
+                        break;
+                    }
+                    pc = target as usize;
+                },
+                Inst::Op(op @ (OpCode::BZ | OpCode::BNZ)) => {
+                    let Some(&target) = code.get(pc) else { break };
+                    pc = if (ax == 0) == (op == OpCode::BZ) { target as usize } else { pc + 1 };
+                },
+                Inst::Op(OpCode::ENT) => {
+                    let Some(&n) = code.get(pc) else { break };
+                    pc += 1;
+                    sp -= word;
+                    if self.set_stack_word(sp, bp).is_none() {
+                        break;
+                    }
+                    bp = sp;
+                    sp -= n * word;
+                    if sp < self.stack_base {
+                        break;
+                    }
+                },
+                Inst::Op(OpCode::ADJ) => {
+                    let Some(&n) = code.get(pc) else { break };
+                    pc += 1;
+                    sp += n * word;
+                },
+                Inst::Op(OpCode::LEV) => {
+                    let (Some(caller_bp), Some(ret)) = (self.stack_word(bp), self.stack_word(bp + word)) else { break };
+                    sp = bp + 2 * word;
+                    bp = caller_bp;
+                    pc = ret as usize;
+                },
+                Inst::Op(op @ (OpCode::LI | OpCode::LC | OpCode::LCU | OpCode::LS | OpCode::LSU)) => {
+                    let value = match op {
+                        OpCode::LI => self.load_int(ax),
+                        OpCode::LC => self.load_char(ax),
+                        OpCode::LCU => self.load_char(ax).map(|c| c as u8 as Int),
+                        OpCode::LS => self.load_short(ax),
+                        _ => self.load_short(ax).map(|s| s as u16 as Int),
+                    };
+                    let Ok(value) = value else { break };
+                    ax = value;
+                },
+                Inst::Op(op @ (OpCode::SI | OpCode::SC | OpCode::SS)) => {
+                    if watching {
+                        break;
+                    }
+                    let Some(addr) = self.stack_word(sp) else { break };
+                    sp += word;
+                    let stored = match op {
+                        OpCode::SI => self.store_int(addr, ax),
+                        OpCode::SC => self.store_char(addr, ax).map(|_| ax = ax as u8 as i8 as Int),
+                        _ => self.store_short(addr, ax).map(|_| ax = ax as u16 as i16 as Int),
+                    };
+                    if stored.is_err() {
+                        break;
+                    }
+                },
+                Inst::Op(OpCode::PSH) => {
+                    sp -= word;
+                    if self.set_stack_word(sp, ax).is_none() {
+                        break;
+                    }
+                },
+                Inst::Op(op @ (OpCode::OR | OpCode::XOR | OpCode::AND | OpCode::EQ | OpCode::NE | OpCode::LT |
+                OpCode::GT | OpCode::LE | OpCode::GE | OpCode::SHL | OpCode::SHR | OpCode::ADD |
+                OpCode::SUB | OpCode::MUL | OpCode::DIV | OpCode::MOD | OpCode::ULT | OpCode::UGT |
+                OpCode::ULE | OpCode::UGE | OpCode::USHR | OpCode::UDIV | OpCode::UMOD)) => {
+                    let Some(lhs) = self.stack_word(sp) else { break };
+                    let Ok(value) = self.binary(op, lhs, ax) else { break };
+                    sp += word;
+                    ax = value;
+                },
+                Inst::Op(OpCode::NOP) => {},
+                Inst::ImmPsh => {
+                    let value = code[pc];
+                    sp -= word;
+                    if self.set_stack_word(sp, value).is_none() {
+                        break;
+                    }
+                    if RETIRE {
+                        self.retire(pc - 1, OpCode::IMM, Some(value), regs.1, ax);
+                        self.retire(pc + 1, OpCode::PSH, None, regs.1, value);
+                    }
+                    ax = value;
+                    pc += 2;
+                },
+                Inst::LeaLi | Inst::LeaPsh => {
+                    let addr = bp + code[pc] * word;
+                    let value = if inst == Inst::LeaLi {
+                        self.load_int(addr).ok()
+                    } else {
+                        sp -= word;
+                        self.set_stack_word(sp, addr).map(|_| addr)
+                    };
+                    let Some(value) = value else { break };
+                    if RETIRE {
+                        let second = if inst == Inst::LeaLi { OpCode::LI } else { OpCode::PSH };
+                        self.retire(pc - 1, OpCode::LEA, Some(code[pc]), regs.1, ax);
+                        self.retire(pc + 1, second, None, regs.1, addr);
+                    }
+                    ax = value;
+                    pc += 2;
+                },
+                Inst::PshImmAdd => {
+                    let value = code[pc + 1];
+                    if self.set_stack_word(sp - word, ax).is_none() {
+                        break;
+                    }
+                    if RETIRE {
+                        self.retire(pc - 1, OpCode::PSH, None, sp, ax);
+                        self.retire(pc, OpCode::IMM, Some(value), sp - word, ax);
+                        self.retire(pc + 2, OpCode::ADD, None, sp - word, value);
+                    }
+                    ax = ax.wrapping_add(value);
+                    pc += 3;
+                },
+                _ => break,
+            }
+            if let Inst::Op(op) = inst {
+                if RETIRE {
+                    let operand = if op.has_operand() { Some(code[regs.0 + 1]) } else { None };
+                    self.retire(regs.0, op, operand, regs.1, regs.3);
+                }
+            }
+            regs = (pc, sp, bp, ax);
+            ran += inst.width();
+        }
+        (self.pc, self.sp, self.bp, self.ax) = regs;
+        self.cycle += ran;
+        self.code = code_words;
+        self.decoded = ops;
+        ran
+    }
+
+    // Log an instruction the fast loop ran, with the registers it started from
+    fn retire(&mut self, pc: usize, op: OpCode, operand: Option<Int>, sp: Int, ax: Int) {
+        if self.retired.len() == self.retire_capacity {
+            self.retired.pop_front();
+        }
+        self.retired.push_back(Retired { pc, op, operand, sp, ax });
+    }
+
+
+    // The word at `addr` if it lies on the stack, for the fast loop
+    fn stack_word(&self, addr: Int) -> Option<Int> {
+        let offset = usize::try_from(addr - self.stack_base).ok()?;
+        let bytes = self.stack.get(offset..offset + WORD_SIZE)?;
+        Some(Int::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn set_stack_word(&mut self, addr: Int, value: Int) -> Option<()> {
+        let offset = usize::try_from(addr - self.stack_base).ok()?;
+        self.stack.get_mut(offset..offset + WORD_SIZE)?.copy_from_slice(&value.to_le_bytes());
+        Some(())
+    }
+
+    // Stop run_for with RunState::Watchpoint after every store that writes any of the `len`
+    // bytes at `addr`
+    pub fn watch(&mut self, addr: Int, len: usize) {
+        self.watchpoints.push((addr, len.max(1)));
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+        self.watch_hit = None;
+    }
+
+    // Note a store of `len` bytes at `addr` by the instruction at `pc` if it touches a watch
+    fn check_watchpoints(&mut self, pc: usize, addr: Int, len: usize) {
+        let hit = self.watchpoints.iter()
+            .find(|&&(start, size)| addr < start + size as Int && start < addr + len as Int);
+        if let Some(&(start, _)) = hit {
+            self.watch_hit = Some(WatchHit { addr: start, store_addr: addr, value: self.ax, pc, line: self.line_at(pc) });
+        }
+    }
+
+    // Keep the last `n` executed instructions (0 turns the log off)
+    pub fn set_retire_log_size(&mut self, n: usize) {
+        self.retire_capacity = n;
+        while self.retired.len() > n {
+            self.retired.pop_front();
+        }
+    }
+
+    // The most recently executed instructions, oldest first
+    pub fn retired(&self) -> &VecDeque<Retired> {
+        &self.retired
+    }
+
+    // Retirement log formatted for printing after a runtime error, empty when the log is off
+    pub fn retirement_dump(&self) -> String {
+        if self.retired.is_empty() {
+            return String::new();
+        }
+        let mut out = format!("last {} instructions executed:\n", self.retired.len());
+        for entry in &self.retired {
+            out.push_str(&format!("{}\n", entry));
+        }
+        out
+    }
+
+    // Count executed instructions per opcode and per function from the next start() on
+    pub fn set_profiling(&mut self, on: bool) {
+        self.profile = if on { Some(Vec::new()) } else { None };
+    }
+
+    // What the current run has executed so far, or None when profiling is off
+    pub fn profile(&self) -> Option<Profile> {
+        let counts = self.profile.as_ref()?;
+        let mut by_opcode: HashMap<OpCode, Int> = HashMap::new();
+        let mut by_function: HashMap<String, Int> = HashMap::new();
+        for (pc, &count) in counts.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            if let Some(op) = self.code.get(pc).and_then(|&word| OpCode::from_int(word)) {
+                *by_opcode.entry(op).or_insert(0) += count;
+            }
+            let function = self.stack_frame(pc).function.unwrap_or_else(|| "?".to_string());
+            *by_function.entry(function).or_insert(0) += count;
+        }
+        let mut by_opcode: Vec<(OpCode, Int)> = by_opcode.into_iter().collect();
+        by_opcode.sort_by(|a, b| b.1.cmp(&a.1).then((a.0 as Int).cmp(&(b.0 as Int))));
+        let mut by_function: Vec<(String, Int)> = by_function.into_iter().collect();
+        by_function.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        Some(Profile { total: counts.iter().sum(), by_opcode, by_function })
+    }
+
+    // Blocks still allocated, lowest address first; after the program exits these are its leaks
+    pub fn heap_leaks(&self) -> Vec<HeapLeak> {
+        let env_string = |offset: usize| self.env_strings.values().any(|&addr| addr == self.heap_base + offset as Int);
+        self.heap_blocks.iter().filter(|&(&offset, _)| !env_string(offset)).map(|(&offset, &size)| HeapLeak {
+            addr: self.heap_base + offset as Int,
+            size: size as Int,
+            site: self.heap_sites.get(&offset).map(|&pc| self.stack_frame(pc)),
+        }).collect()
+    }
+
+    // Heap statistics and one line per leaked block with where it was allocated
+    pub fn leak_report(&self) -> String {
+        let leaks = self.heap_leaks();
+        let live: Int = leaks.iter().map(|leak| leak.size).sum();
+        let mut out = format!("heap: {} allocations, {} frees, {} bytes allocated; {} blocks ({} bytes) still live\n",
+            self.usage.allocations, self.usage.frees, self.usage.bytes_allocated, leaks.len(), live);
+        for leak in &leaks {
+            out.push_str(&format!("  {} bytes at {:#x}", leak.size, leak.addr));
+            if let Some(site) = &leak.site {
+                out.push_str(&format!(" allocated in {} (pc {}", site.function.as_deref().unwrap_or("?"), site.pc));
+                if let Some(line) = site.line {
+                    out.push_str(&format!(", line {}", line));
+                }
+                out.push(')');
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    // Record a golden-trace checkpoint every `interval` instructions from the next start()
+    // on (0 turns tracing off). The trace ends with a checkpoint where the program stopped.
+    pub fn set_trace_interval(&mut self, interval: Int) {
+        self.trace_interval = interval.max(0);
+    }
+
+    // Checkpoints recorded by the current run
+    pub fn trace(&self) -> &[TraceCheckpoint] {
+        &self.trace
+    }
+
+    // The recorded trace, one `cycle pc hash` checkpoint per line, as stored in trace files
+    pub fn trace_text(&self) -> String {
+        self.trace.iter().map(|checkpoint| format!("{}\n", checkpoint)).collect()
+    }
+
+    // Check this run's trace against a golden one in trace_text() format and return the
+    // number of checkpoints that matched, or where the two runs first diverged
+    pub fn compare_trace(&self, golden: &str) -> Result<usize, String> {
+        let expected: Vec<&str> = golden.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        for (i, line) in expected.iter().enumerate() {
+            match self.trace.get(i) {
+                Some(checkpoint) if checkpoint.to_string() == *line => {},
+                Some(checkpoint) => {
+                    return Err(format!("checkpoint {}: golden trace has `{}`, this run `{}`", i + 1, line, checkpoint));
+                },
+                None => {
+                    return Err(format!("this run stopped after {} checkpoints, golden trace has {}", self.trace.len(), expected.len()));
+                }
+            }
+        }
+        if self.trace.len() > expected.len() {
+            return Err(format!("this run has {} checkpoints, golden trace only {}", self.trace.len(), expected.len()));
+        }
+        Ok(expected.len())
+    }
+
+    // Close the trace with a checkpoint at the instruction the program stopped on
+    fn finish_trace(&mut self) {
+        if self.trace_interval > 0 && self.trace.last().is_none_or(|last| last.cycle != self.cycle) {
+            self.trace.push(TraceCheckpoint { cycle: self.cycle, pc: self.pc, hash: self.trace_hash });
+        }
+    }
+
+    // Resources used by the program so far; can be read while it is paused or after it halts
+    pub fn usage(&self) -> ResourceUsage {
+        ResourceUsage { cycles: self.cycle, ..self.usage.clone() }
+    }
+
+    // Run as a future that yields to the executor every `slice` cycles and stops
+    // with an error once `token` is cancelled
+    pub fn run_async(&mut self, slice: Int, token: CancellationToken) -> RunAsync<'_> {
+        RunAsync { vm: self, slice: slice.max(1), token }
+    }
+
+    // Fetch the next code word and advance pc
+    fn fetch(&mut self) -> Result<Int, RuntimeError> {
+        if self.pc > self.le {
+            return Err(RuntimeError::IllegalInstruction { word: None, site: FaultSite::default() });
+        }
+        let word = self.code[self.pc];
+        self.pc += 1;
+        Ok(word)
+    }
+
+    fn push(&mut self, value: Int) -> Result<(), RuntimeError> {
+        self.sp -= WORD_SIZE as Int;
+        if self.sp < self.stack_base {
+            return Err(RuntimeError::StackOverflow { site: FaultSite::default() });
+        }
+        self.store_int(self.sp, value)
+    }
+
+    fn pop(&mut self) -> Result<Int, RuntimeError> {
+        let value = self.load_int(self.sp)?;
+        self.sp += WORD_SIZE as Int;
+        Ok(value)
+    }
+
+    // Syscall argument `n` counted from the last one pushed
+    fn arg(&self, n: Int) -> Result<Int, RuntimeError> {
+        self.load_int(self.sp + n * WORD_SIZE as Int)
+    }
+
+    // Number of arguments a variadic syscall was passed: the ADJ after it (the call site pops
+    // them) says how many, as in c4
+    fn syscall_arg_count(&self) -> Int {
+        if self.pc + 1 < self.code.len() && self.code[self.pc] == OpCode::ADJ as Int {
+            self.code[self.pc + 1]
+        } else {
+            0
+        }
+    }
+
+    // Execute one instruction, with the same outcomes as `run_for(1)`. Faults carry the
+    // faulting pc and the call stack at that point.
+    pub fn step(&mut self) -> Result<RunState, RuntimeError> {
+        if let Some(code) = self.exit_code {
+            return Ok(RunState::Halted(code));
+        }
+        let pc = self.pc;
+        match self.execute() {
+            Ok(Some(code)) => {
+                self.exit_code = Some(code);
+                self.flush_output();
+                self.finish_trace();
+                Ok(RunState::Halted(code))
+            },
+            Ok(None) => Ok(self.watch_hit.take().map_or(RunState::Paused, RunState::Watchpoint)),
+            Err(mut e) => {
+                *e.site_mut() = self.fault_site(pc);
+                self.finish_trace();
+                Err(e)
+            }
+        }
+    }
+
+    // Where the instruction at `pc` is, and the calls that led to it, read off the bp chain
+    // (bp[0] is the caller's bp, bp[1] the return address)
+    fn fault_site(&self, pc: usize) -> FaultSite {
+        let mut stack = vec![self.stack_frame(pc)];
+        let mut bp = self.bp;
+        // Bounded in case the chain was overwritten into a cycle
+        while stack.len() < 64 {
+            let (caller_bp, ret) = match (self.load_int(bp), self.load_int(bp + WORD_SIZE as Int)) {
+                (Ok(caller_bp), Ok(ret)) => (caller_bp, ret),
+                _ => break,
+            };
+            // The outermost frame has no caller: its bp is the top of the stack
+            if caller_bp <= bp || self.load_int(caller_bp).is_err() || ret < 1 || ret as usize > self.le {
+                break;
+            }
+            stack.push(self.stack_frame(ret as usize));
+            bp = caller_bp;
+        }
+        let op = self.code.get(pc).and_then(|&word| OpCode::from_int(word));
+        FaultSite { pc, op, line: self.line_at(pc), stack }
+    }
+
+    fn stack_frame(&self, pc: usize) -> StackFrame {
+        let function = self.functions.iter()
+            .filter(|&&(entry, _)| entry >= 1 && entry <= pc)
+            .max_by_key(|&&(entry, _)| entry)
+            .map(|(_, name)| name.clone());
+        let file = self.code_line(pc).and_then(|(_, _, origin)| origin).map(|(file, _)| self.origin_files[file].clone());
+        StackFrame { function, pc, line: self.line_at(pc), file }
+    }
+
+    fn code_line(&self, pc: usize) -> Option<(usize, i32, Option<LineOrigin>)> {
+        let i = self.code_lines.partition_point(|&(start, _, _)| start <= pc);
+        match i {
+            0 => None,
+            _ => Some(self.code_lines[i - 1]),
+        }
+    }
+
+    // Source line the code at `pc` was compiled from, if it was compiled from C: the line in
+    // the file it came from when the source was preprocessed or compiled with set_source
+    fn line_at(&self, pc: usize) -> Option<i32> {
+        let (_, line, origin) = self.code_line(pc)?;
+        Some(origin.map_or(line, |(_, line)| line)).filter(|_| line > 0)
+    }
+
+    // Stop with RuntimeError::CycleLimit once the program has run `limit` instructions
+    pub fn set_cycle_limit(&mut self, limit: Option<Int>) {
+        self.cycle_limit = limit;
+    }
+
+    fn execute(&mut self) -> Result<Option<Int>, RuntimeError> {
+        if let Some(limit) = self.cycle_limit.filter(|&limit| self.cycle >= limit) {
+            return Err(RuntimeError::CycleLimit { limit, site: FaultSite::default() });
+        }
+        let pc = self.pc;
+        let word = self.fetch()?;
+        let op = match OpCode::from_int(word) {
+            Some(op) => op,
+            None => return Err(RuntimeError::IllegalInstruction { word: Some(word), site: FaultSite::default() }),
+        };
+        self.cycle += 1;
+        if self.trace_interval > 0 {
+            self.trace_hash = fnv_mix(fnv_mix(self.trace_hash, pc as u64), word as u64);
+            if self.cycle % self.trace_interval == 0 {
+                self.trace.push(TraceCheckpoint { cycle: self.cycle, pc, hash: self.trace_hash });
+            }
+        }
+        if let Some(counts) = &mut self.profile {
+            if pc >= counts.len() {
+                counts.resize(pc + 1, 0);
+            }
+            counts[pc] += 1;
+        }
+        let operand = if op.has_operand() { self.code.get(self.pc).copied() } else { None };
+        if self.retire_capacity > 0 {
+            if self.retired.len() == self.retire_capacity {
+                self.retired.pop_front();
+            }
+            self.retired.push_back(Retired { pc, op, operand, sp: self.sp, ax: self.ax });
+        }
+        if self.debug {
+            // c4's `-d` line (cycle, mnemonic, operand), then the registers after a tab, so
+            // `cut -f1` gives a trace that diffs against c4's
+            let mut line = format!("{}> {:<4}", self.cycle, format!("{:?}", op));
+            if let Some(operand) = operand {
+                line.push_str(&format!(" {}", operand));
+            }
+            line.push_str(&format!("\tpc={} sp={:#x} ax={}\n", pc, self.sp, self.ax));
+            let _ = self.stdout.write_all(line.as_bytes());
+        }
+        if op.is_syscall() {
+            *self.usage.syscalls.entry(op).or_insert(0) += 1;
+        }
+
+        match op {
+            OpCode::LEA => { let n = self.fetch()?; self.ax = self.bp + n * WORD_SIZE as Int; },
+            OpCode::IMM => self.ax = self.fetch()?,
+            OpCode::JMP => self.pc = self.fetch()? as usize,
+            OpCode::JMPI => {
+                // Indexed jump through a switch table: min, len, default, targets...
+                let table = self.fetch()?;
+                let min = self.load_int(table)?;
+                let len = self.load_int(table + WORD_SIZE as Int)?;
+                let index = self.ax.wrapping_sub(min);
+                let slot = if (index as u64) < len as u64 { 3 + index } else { 2 };
+                self.pc = self.load_int(table + slot * WORD_SIZE as Int)? as usize;
+            },
+            OpCode::JSR => {
+                let target = self.fetch()?;
+                self.push(self.pc as Int)?;
+                self.pc = target as usize;
+            },
+            OpCode::BZ => { let target = self.fetch()?; if self.ax == 0 { self.pc = target as usize; } },
+            OpCode::BNZ => { let target = self.fetch()?; if self.ax != 0 { self.pc = target as usize; } },
+            OpCode::ENT => {
+                let n = self.fetch()?;
+                self.push(self.bp)?;
+                self.bp = self.sp;
+                self.sp -= n * WORD_SIZE as Int;
+                if self.sp < self.stack_base {
+                    return Err(RuntimeError::StackOverflow { site: FaultSite::default() });
+                }
+            },
+            OpCode::ADJ => { let n = self.fetch()?; self.sp += n * WORD_SIZE as Int; },
+            OpCode::LEV => {
+                self.sp = self.bp;
+                self.bp = self.pop()?;
+                self.pc = self.pop()? as usize;
+            },
+            OpCode::LI => self.ax = self.load_int(self.ax)?,
+            OpCode::LC => self.ax = self.load_char(self.ax)?,
+            OpCode::LCU => self.ax = self.load_char(self.ax)? as u8 as Int,
+            OpCode::LSU => self.ax = self.load_short(self.ax)? as u16 as Int,
+            OpCode::LS => self.ax = self.load_short(self.ax)?,
+            OpCode::SI => {
+                let addr = self.pop()?;
+                self.store_int(addr, self.ax)?;
+                self.check_watchpoints(pc, addr, WORD_SIZE);
+            },
+            OpCode::SC => {
+                let addr = self.pop()?;
+                self.store_char(addr, self.ax)?;
+                self.ax = self.ax as u8 as i8 as Int;
+                self.check_watchpoints(pc, addr, 1);
+            },
+            OpCode::SS => {
+                let addr = self.pop()?;
+                self.store_short(addr, self.ax)?;
+                self.ax = self.ax as u16 as i16 as Int;
+                self.check_watchpoints(pc, addr, 2);
+            },
+            OpCode::PSH => self.push(self.ax)?,
+            OpCode::OR | OpCode::XOR | OpCode::AND | OpCode::EQ | OpCode::NE | OpCode::LT |
+            OpCode::GT | OpCode::LE | OpCode::GE | OpCode::SHL | OpCode::SHR | OpCode::ADD |
+            OpCode::SUB | OpCode::MUL | OpCode::DIV | OpCode::MOD | OpCode::ULT | OpCode::UGT |
+            OpCode::ULE | OpCode::UGE | OpCode::USHR | OpCode::UDIV | OpCode::UMOD => {
+                let lhs = self.pop()?;
+                self.ax = self.binary(op, lhs, self.ax)?;
+            },
+            OpCode::OPEN => { let path = self.arg(1)?; let flags = self.arg(0)?; self.ax = self.sys_open(path, flags)?; },
+            OpCode::READ => {
+                let fd = self.arg(2)?;
+                let buf = self.arg(1)?;
+                let len = self.arg(0)?;
+                self.ax = self.sys_read(fd, buf, len)?;
+            },
+            OpCode::CLOS => { let fd = self.arg(0)?; self.ax = self.sys_close(fd); },
+            OpCode::ISDG | OpCode::ISAL | OpCode::ISSP | OpCode::TOUP | OpCode::TOLO => {
+                self.ax = char_class(op, self.arg(0)?);
+            },
+            OpCode::PUTC => {
+                let c = self.arg(0)? as u8;
+                self.ax = if self.write_fd(1, &[c]) == 1 { c as Int } else { -1 };
+            },
+            OpCode::GETC => self.ax = self.sys_getchar(),
+            OpCode::PUTS => { let addr = self.arg(0)?; self.ax = self.sys_puts(addr)?; },
+            OpCode::WRIT => {
+                let fd = self.arg(2)?;
+                let buf = self.arg(1)?;
+                let len = self.arg(0)?;
+                self.ax = self.sys_write(fd, buf, len)?;
+            },
+            OpCode::SCNF => {
+                let count = self.syscall_arg_count();
+                self.ax = self.sys_scanf(count)?;
+            },
+            OpCode::GETS => { let buf = self.arg(0)?; self.ax = self.sys_gets(buf)?; },
+            OpCode::SLEN => { let s = self.arg(0)?; self.ax = self.cstr_bytes(s)?.len() as Int; },
+            OpCode::SCPY | OpCode::SCAT => {
+                let dest = self.arg(1)?;
+                let src = self.arg(0)?;
+                self.ax = self.sys_strcpy(dest, src, op == OpCode::SCAT)?;
+            },
+            OpCode::SNCP => {
+                let dest = self.arg(2)?;
+                let src = self.arg(1)?;
+                let n = self.arg(0)?;
+                self.ax = self.sys_strncpy(dest, src, n)?;
+            },
+            OpCode::SCMP => {
+                let lhs = self.arg(1)?;
+                let rhs = self.arg(0)?;
+                self.ax = self.sys_strcmp(lhs, rhs)?;
+            },
+            OpCode::MCPY => {
+                let dest = self.arg(2)?;
+                let src = self.arg(1)?;
+                let n = self.arg(0)?;
+                self.ax = self.sys_memcpy(dest, src, n)?;
+            },
+            OpCode::ABS => self.ax = self.arg(0)?.wrapping_abs(),
+            OpCode::RAND => self.ax = (splitmix64(&mut self.rand_state) >> 33) as Int,
+            OpCode::SRND => self.rand_state = self.arg(0)? as u64,
+            OpCode::TIME => { let addr = self.arg(0)?; self.ax = self.sys_time(addr)?; },
+            OpCode::ATOI => { let addr = self.arg(0)?; self.ax = self.sys_atoi(addr)?; },
+            OpCode::GENV => { let name = self.arg(0)?; self.ax = self.sys_getenv(name)?; },
+            OpCode::ATEX => {
+                let hook = self.arg(0)?;
+                self.ax = if self.exit_hooks.len() < MAX_EXIT_HOOKS {
+                    self.exit_hooks.push(hook);
+                    0
+                } else {
+                    -1
+                };
+            },
+            OpCode::EXIT => {
+                // Call each pending hook with EXIT itself as the return address, so exiting
+                // resumes (with the same status on the stack) once the hook returns
+                if let Some(hook) = self.exit_hooks.pop() {
+                    self.push(pc as Int)?;
+                    self.pc = hook as usize;
+                    return Ok(None);
+                }
+                return Ok(Some(self.arg(0)?));
+            },
+            OpCode::NOP => {},
+            OpCode::PRTF => {
+                let count = self.syscall_arg_count();
+                self.ax = self.sys_printf(count)?;
+            },
+            OpCode::FPRT => {
+                let count = self.syscall_arg_count();
+                self.ax = self.sys_fprintf(count)?;
+            },
+            OpCode::SPRF | OpCode::SNPR => {
+                let count = self.syscall_arg_count();
+                self.ax = self.sys_sprintf(count, op == OpCode::SNPR)?;
+            },
+            OpCode::MALC => { let size = self.arg(0)?; self.ax = self.sys_malloc(size); },
+            OpCode::FREE => { let addr = self.arg(0)?; self.sys_free(addr)?; },
+            OpCode::MSET => {
+                let dest = self.arg(2)?;
+                let value = self.arg(1)?;
+                let len = self.arg(0)?;
+                self.ax = self.sys_memset(dest, value, len)?;
+            },
+            OpCode::MCMP => {
+                let lhs = self.arg(2)?;
+                let rhs = self.arg(1)?;
+                let len = self.arg(0)?;
+                self.ax = self.sys_memcmp(lhs, rhs, len)?;
+            },
+            OpCode::FUN => {
+                return Err(RuntimeError::Unsupported { op, site: FaultSite::default() });
+            },
+        }
+        Ok(None)
+    }
+
+    // Evaluate a binary ALU instruction
+    pub fn binary(&self, op: OpCode, lhs: Int, rhs: Int) -> Result<Int, RuntimeError> {
+        let value = match op {
+            OpCode::OR => lhs | rhs,
+            OpCode::XOR => lhs ^ rhs,
+            OpCode::AND => lhs & rhs,
+            OpCode::EQ => (lhs == rhs) as Int,
+            OpCode::NE => (lhs != rhs) as Int,
+            OpCode::LT => (lhs < rhs) as Int,
+            OpCode::GT => (lhs > rhs) as Int,
+            OpCode::LE => (lhs <= rhs) as Int,
+            OpCode::GE => (lhs >= rhs) as Int,
+            OpCode::SHL => lhs.wrapping_shl(rhs as u32),
+            OpCode::SHR => lhs.wrapping_shr(rhs as u32),
+            OpCode::ADD => lhs.wrapping_add(rhs),
+            OpCode::SUB => lhs.wrapping_sub(rhs),
+            OpCode::MUL => lhs.wrapping_mul(rhs),
+            OpCode::ULT => ((lhs as u64) < rhs as u64) as Int,
+            OpCode::UGT => (lhs as u64 > rhs as u64) as Int,
+            OpCode::ULE => (lhs as u64 <= rhs as u64) as Int,
+            OpCode::UGE => (lhs as u64 >= rhs as u64) as Int,
+            OpCode::USHR => (lhs as u64).wrapping_shr(rhs as u32) as Int,
+            OpCode::DIV | OpCode::MOD | OpCode::UDIV | OpCode::UMOD if rhs == 0 => {
+                return Err(RuntimeError::DivByZero { site: FaultSite::default() });
+            },
+            OpCode::DIV => lhs.wrapping_div(rhs),
+            OpCode::MOD => lhs.wrapping_rem(rhs),
+            OpCode::UDIV => ((lhs as u64) / rhs as u64) as Int,
+            OpCode::UMOD => ((lhs as u64) % rhs as u64) as Int,
+            _ => return Err(RuntimeError::IllegalInstruction { word: Some(op as Int), site: FaultSite::default() }),
+        };
+        Ok(value)
+    }
+}